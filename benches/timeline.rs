@@ -0,0 +1,153 @@
+//! Compares the timeline storage with the sorted vector it replaced, and drawing the visible
+//! window of a timeline with drawing all of it.
+//!
+//! Run with `cargo bench --bench timeline`.
+
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+
+#[allow(dead_code)]
+#[path = "../src/ui/timeline.rs"]
+mod timeline;
+
+use timeline::{Heights, Timeline};
+
+/// Height of every event, to keep the results comparable between runs
+const ROW_HEIGHT: f32 = 40.0;
+/// Height of the timeline view
+const VIEWPORT_HEIGHT: f32 = 768.0;
+/// Extra events drawn on both sides of the view
+const OVERSCAN: usize = 20;
+
+#[derive(Clone, Debug)]
+struct Event {
+    id: String,
+    ts: u64,
+    body: String,
+}
+
+/// Events the way a client receives them: mostly in order, with some arriving late, like
+/// backfilled history or events from servers with skewed clocks
+fn events(count: usize) -> Vec<Event> {
+    // Simple LCG, so runs are comparable without a dependency on rand
+    let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+    (0..count)
+        .map(|i| {
+            state = state
+                .wrapping_mul(6_364_136_223_846_793_005)
+                .wrapping_add(1_442_695_040_888_963_407);
+            let jitter = if state >> 60 == 0 { state >> 40 } else { 0 };
+            Event {
+                id: format!("$event{}:example.org", i),
+                ts: (i as u64 * 1000).saturating_sub(jitter),
+                body: format!("Message number {}", i),
+            }
+        })
+        .collect()
+}
+
+/// Stand-in for the work of building the widgets for an event
+fn draw(event: &Event) -> usize {
+    black_box(format!("{}: {}", event.id, event.body)).len()
+}
+
+fn insert(c: &mut Criterion) {
+    let mut group = c.benchmark_group("insert");
+    group.sample_size(10);
+    for &count in &[1_000, 5_000] {
+        let events = events(count);
+        group.bench_with_input(
+            BenchmarkId::new("sorted vec", count),
+            &events,
+            |b, events| {
+                b.iter_batched(
+                    || events.clone(),
+                    |events| {
+                        let mut buffer: Vec<Event> = Vec::new();
+                        for event in events {
+                            if buffer.iter().any(|e| e.id == event.id) {
+                                continue;
+                            }
+                            buffer.push(event);
+                            buffer.sort_unstable_by_key(|e| e.ts);
+                        }
+                        buffer
+                    },
+                    BatchSize::LargeInput,
+                )
+            },
+        );
+        group.bench_with_input(BenchmarkId::new("timeline", count), &events, |b, events| {
+            b.iter_batched(
+                || events.clone(),
+                |events| {
+                    let mut buffer = Timeline::default();
+                    for event in events {
+                        buffer.insert(event.ts, event.id.clone(), event);
+                    }
+                    buffer
+                },
+                BatchSize::LargeInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+fn lookup(c: &mut Criterion) {
+    let mut group = c.benchmark_group("lookup");
+    let count = 10_000;
+    let events = events(count);
+    let ids: Vec<String> = events.iter().step_by(97).map(|e| e.id.clone()).collect();
+    let mut sorted = events.clone();
+    sorted.sort_unstable_by_key(|e| e.ts);
+    let mut buffer = Timeline::default();
+    for event in events {
+        buffer.insert(event.ts, event.id.clone(), event);
+    }
+    group.bench_function(BenchmarkId::new("sorted vec", count), |b| {
+        b.iter(|| {
+            ids.iter()
+                .filter_map(|id| sorted.iter().find(|e| &e.id == id))
+                .count()
+        })
+    });
+    group.bench_function(BenchmarkId::new("timeline", count), |b| {
+        b.iter(|| ids.iter().filter_map(|id| buffer.get(id)).count())
+    });
+    group.finish();
+}
+
+fn render(c: &mut Criterion) {
+    let mut group = c.benchmark_group("render");
+    for &count in &[1_000, 10_000] {
+        let mut buffer = Timeline::default();
+        for event in events(count) {
+            buffer.insert(event.ts, event.id.clone(), event);
+        }
+        group.bench_with_input(
+            BenchmarkId::new("all events", count),
+            &buffer,
+            |b, buffer| b.iter(|| buffer.iter().map(draw).sum::<usize>()),
+        );
+        // Scrolled to the most recent events, where the timeline usually is
+        let heights = Heights::new(std::iter::repeat(ROW_HEIGHT).take(buffer.len()));
+        let bottom = heights.total() - VIEWPORT_HEIGHT;
+        group.bench_with_input(
+            BenchmarkId::new("visible window", count),
+            &buffer,
+            |b, buffer| {
+                b.iter(|| {
+                    let range = heights.visible_range(black_box(bottom), VIEWPORT_HEIGHT, OVERSCAN);
+                    buffer
+                        .window(range)
+                        .map(|(_, event)| draw(event))
+                        .sum::<usize>()
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, insert, lookup, render);
+criterion_main!(benches);
@@ -5,18 +5,51 @@ use std::{
 };
 
 use async_stream::stream;
+use async_trait::async_trait;
 use matrix_sdk::{
-    api::r0::{account::register::Request as RegistrationRequest, uiaa::AuthData},
+    api::r0::{
+        account::register::Request as RegistrationRequest,
+        media::{get_content, get_content_thumbnail},
+        presence::set_presence,
+        redact::redact_event,
+        uiaa::AuthData,
+    },
+    deserialized_responses::SyncResponse,
     events::{
-        room::message::{MessageEvent, MessageEventContent, MessageType},
-        AnyMessageEvent, AnyRoomEvent, AnySyncRoomEvent, AnyToDeviceEvent,
+        custom::CustomEventContent,
+        presence::PresenceEvent,
+        room::{
+            message::{
+                FormattedBody, MessageEvent, MessageEventContent, MessageFormat, MessageType,
+            },
+            EncryptedFile,
+        },
+        sticker::StickerEventContent,
+        AnyBasicEvent, AnyMessageEvent, AnyMessageEventContent, AnyRoomEvent, AnySyncRoomEvent,
+        AnyToDeviceEvent,
     },
     identifiers::{DeviceId, EventId, RoomId, ServerName, UserId},
-    reqwest::Url,
-    Client, ClientConfig, LoopCtrl, SyncSettings,
+    presence::PresenceState,
+    reqwest::{self, Url},
+    Client, ClientConfig, HttpError, HttpSend, LoopCtrl, SyncSettings, UInt,
 };
 use serde::{Deserialize, Serialize};
 
+pub mod attachment;
+pub mod cache;
+pub mod command;
+pub mod edit;
+pub mod file;
+pub mod location;
+pub mod markdown;
+pub mod outbox;
+pub mod push;
+pub mod reaction;
+pub mod reply;
+pub mod sticker;
+pub mod thread;
+pub mod upload;
+
 pub type Error = anyhow::Error;
 
 // Needed to be able to serialize `Session`s. Should be done with serde remote.
@@ -75,7 +108,6 @@ pub async fn signup(
     });
 
     let response = client.register(request).await?;
-    client.sync_once(SyncSettings::new()).await?;
 
     let session = Session {
         access_token: response.access_token.unwrap(),
@@ -112,27 +144,124 @@ pub async fn login(
         homeserver: server.to_owned(),
     };
     write_session(&session)?;
-    client.sync_once(SyncSettings::new()).await?;
 
     Ok((client, session))
 }
 
+/// Restore a saved session from the local store. This doesn't need network access, so it
+/// should be followed by a call to [`connect`].
 pub async fn restore_login(session: Session) -> Result<(Client, Session), Error> {
     let url = Url::parse(&session.homeserver)?;
     let client = client(url)?;
 
     client.restore_login(session.clone().into()).await?;
-    client.sync_once(SyncSettings::new()).await?;
 
     Ok((client, session))
 }
 
+/// Do an initial sync, continuing from the stored sync token if there is one. Returns the
+/// token to continue syncing from, and the events that arrived since the last sync, which
+/// should be handled like the ones from [`MatrixSync`].
+pub async fn connect(client: Client) -> Result<(String, Vec<Event>), Error> {
+    let mut settings = SyncSettings::new();
+    if let Some(token) = client.sync_token().await {
+        settings = settings.token(token);
+    }
+    let response = client.sync_once(settings).await?;
+    let token = response.next_batch.clone();
+
+    Ok((token, sync_events(&client, response)))
+}
+
+/// Set the presence and status message of the logged in user
+pub async fn set_presence(
+    client: Client,
+    user_id: UserId,
+    presence: PresenceState,
+    status: Option<String>,
+) -> Result<(), Error> {
+    let mut request = set_presence::Request::new(&user_id, presence);
+    request.status_msg = status.as_deref();
+    client.send(request, None).await?;
+
+    Ok(())
+}
+
+/// Redact an event, with an optional reason
+pub async fn redact(
+    client: Client,
+    room_id: RoomId,
+    event_id: EventId,
+    reason: Option<String>,
+) -> Result<(), Error> {
+    let txn_id = matrix_sdk::uuid::Uuid::new_v4().to_string();
+    let mut request = redact_event::Request::new(&room_id, &event_id, &txn_id);
+    request.reason = reason.as_deref();
+    client.send(request, None).await?;
+
+    Ok(())
+}
+
+/// Whether a user has the power level needed to redact other people's events in a room
+pub fn can_redact_others(room: &matrix_sdk::room::Joined, user_id: &UserId) -> bool {
+    match room.power_levels() {
+        Some(levels) => {
+            let level = levels
+                .users
+                .get(user_id)
+                .copied()
+                .unwrap_or(levels.users_default);
+            level >= levels.redact
+        }
+        None => false,
+    }
+}
+
 /// Create a matrix client handler with the desired configuration
 fn client(url: Url) -> Result<Client, matrix_sdk::Error> {
-    let config = ClientConfig::new().store_path(&dirs::config_dir().unwrap().join("retrix"));
+    let config = ClientConfig::new()
+        .store_path(&dirs::config_dir().unwrap().join("retrix"))
+        .client(Arc::new(HttpClient::default()));
     Client::new_with_config(url, config)
 }
 
+/// Sends the SDK's requests, noting thread relations in sync responses on the way
+#[derive(Debug, Default)]
+struct HttpClient {
+    client: reqwest::Client,
+}
+
+#[async_trait]
+impl HttpSend for HttpClient {
+    async fn send_request(
+        &self,
+        request: http::Request<Vec<u8>>,
+        timeout: Option<Duration>,
+    ) -> Result<http::Response<Vec<u8>>, HttpError> {
+        let sync = request.uri().path().ends_with("/sync");
+        let (parts, body) = request.into_parts();
+        let mut request = self
+            .client
+            .request(parts.method, &parts.uri.to_string())
+            .headers(parts.headers)
+            .body(body);
+        if let Some(timeout) = timeout {
+            request = request.timeout(timeout);
+        }
+        let response = request.send().await?;
+        let status = response.status();
+        let headers = response.headers().clone();
+        let body = response.bytes().await?.to_vec();
+        if sync && status.is_success() {
+            thread::note_synced(&body);
+        }
+        let mut response = http::Response::new(body);
+        *response.status_mut() = status;
+        *response.headers_mut() = headers;
+        Ok(response)
+    }
+}
+
 /// File path to store session data in
 fn session_path() -> std::path::PathBuf {
     dirs::config_dir()
@@ -159,6 +288,102 @@ fn write_session(session: &Session) -> Result<(), Error> {
     Ok(())
 }
 
+/// File path to store cached history for a room in
+fn history_path(room_id: &RoomId) -> std::path::PathBuf {
+    // Room ids can contain characters that aren't allowed in file names
+    let name: String = room_id
+        .as_str()
+        .bytes()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    dirs::config_dir()
+        .unwrap()
+        .join("retrix")
+        .join("history")
+        .join(name)
+        .with_extension("json")
+}
+
+/// Cached history of a room, with what's needed to keep loading it where it left off
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct History {
+    /// Runs of events with nothing missing between them, oldest first
+    pub chunks: Vec<HistoryChunk>,
+    /// Token for loading the events before the oldest one, if we know it
+    pub end: Option<String>,
+}
+
+/// Events in the history cache with nothing missing between them
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct HistoryChunk {
+    /// Token for loading the events missing before the chunk, if there are any
+    pub gap: Option<String>,
+    /// The events, oldest first, each followed by its edits and reactions. Events that fail to
+    /// deserialize are skipped.
+    #[serde(deserialize_with = "deserialize_events")]
+    pub events: Vec<AnyRoomEvent>,
+}
+
+/// Deserialize a list of events, leaving out the ones that fail to
+fn deserialize_events<'de, D>(deserializer: D) -> Result<Vec<AnyRoomEvent>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let events: Vec<serde_json::Value> = Deserialize::deserialize(deserializer)?;
+    Ok(events
+        .into_iter()
+        .filter_map(|e| serde_json::from_value(e).ok())
+        .collect())
+}
+
+/// Read the cached history of a room
+pub fn read_history(room_id: &RoomId) -> Result<History, Error> {
+    let path = history_path(room_id);
+    if !path.is_file() {
+        return Ok(History::default());
+    }
+    Ok(serde_json::from_slice(&std::fs::read(path)?)?)
+}
+
+/// Save the history of a room so it can be shown when starting offline
+pub fn write_history(room_id: &RoomId, history: &History) -> Result<(), Error> {
+    let path = history_path(room_id);
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(path, serde_json::to_vec(history)?)?;
+
+    Ok(())
+}
+
+/// Builds a URL for the client-server API from path segments, for endpoints the SDK doesn't have
+fn api_url(session: &Session, segments: &[&str]) -> Result<Url, Error> {
+    homeserver_url(session, &[&["_matrix", "client"][..], segments].concat())
+}
+
+/// Builds a URL for the media repository API from path segments
+fn media_url(session: &Session, segments: &[&str]) -> Result<Url, Error> {
+    homeserver_url(session, &[&["_matrix", "media"][..], segments].concat())
+}
+
+/// Builds a URL on the homeserver from path segments
+fn homeserver_url(session: &Session, segments: &[&str]) -> Result<Url, Error> {
+    let mut url = Url::parse(&session.homeserver)?;
+    url.path_segments_mut()
+        .map_err(|_| anyhow::anyhow!("Invalid homeserver URL"))?
+        .pop_if_empty()
+        .extend(segments);
+    Ok(url)
+}
+
+/// Message event content with fields the SDK doesn't know about, which it sends as they are
+pub fn custom_content(event_type: &str, content: serde_json::Value) -> AnyMessageEventContent {
+    AnyMessageEventContent::Custom(CustomEventContent {
+        event_type: event_type.to_owned(),
+        data: serde_json::from_value(content).unwrap_or_default(),
+    })
+}
+
 /// Break down an mxc url to its authority and path
 pub fn parse_mxc(url: &str) -> Result<(Box<ServerName>, String), Error> {
     let url = Url::parse(&url)?;
@@ -192,14 +417,61 @@ pub enum Event {
     Invited(AnyRoomEvent, Arc<matrix_sdk::room::Invited>),
     /// An event for a joined room
     Joined(AnyRoomEvent, Arc<matrix_sdk::room::Joined>),
+    /// A synced event in a joined room is a reply in the thread with the given root
+    Thread(RoomId, EventId, EventId),
+    /// Sync skipped some events in a joined room, which can be fetched by paginating back
+    /// from the token
+    Gap(RoomId, String),
     /// An event for a left room
     Left(AnyRoomEvent, Arc<matrix_sdk::room::Left>),
     /// A to-device event
     ToDevice(AnyToDeviceEvent),
+    /// A global account data event
+    AccountData(AnyBasicEvent),
+    /// A presence update for a user
+    Presence(PresenceEvent),
     /// Synchronization token
     Token(String),
 }
 
+/// The events in a sync response, in the order they should be handled
+fn sync_events(client: &Client, response: SyncResponse) -> Vec<Event> {
+    let mut events = Vec::new();
+    for (id, room) in response.rooms.join {
+        let joined = match client.get_joined_room(&id) {
+            Some(joined) => Arc::new(joined),
+            None => continue,
+        };
+        if room.timeline.limited {
+            if let Some(prev_batch) = room.timeline.prev_batch {
+                events.push(Event::Gap(id.clone(), prev_batch));
+            }
+        }
+        for event in room.state.events {
+            let event = AnyRoomEvent::State(event.into_full_event(id.clone()));
+            events.push(Event::Joined(event, Arc::clone(&joined)));
+        }
+        for event in room.timeline.events {
+            let event = event.into_full_event(id.clone());
+            let event_id = event.event_id().clone();
+            events.push(Event::Joined(event, Arc::clone(&joined)));
+            if let Some(root) = thread::take_synced(&event_id) {
+                events.push(Event::Thread(id.clone(), root, event_id));
+            }
+        }
+    }
+    events.extend(response.to_device.events.into_iter().map(Event::ToDevice));
+    events.extend(
+        response
+            .account_data
+            .events
+            .into_iter()
+            .map(Event::AccountData),
+    );
+    events.extend(response.presence.events.into_iter().map(Event::Presence));
+    events
+}
+
 impl<H, I> iced_futures::subscription::Recipe<H, I> for MatrixSync
 where
     H: std::hash::Hasher,
@@ -226,20 +498,8 @@ where
                         .token(client.sync_token().await.unwrap())
                         .timeout(Duration::from_secs(30)),
                     |response| async {
-                        for (id, room) in response.rooms.join {
-                            let joined = Arc::new(client.get_joined_room(&id).unwrap());
-                            for event in room.state.events {
-                                let id = id.clone();
-                                let event = AnyRoomEvent::State(event.into_full_event(id));
-                                sender.send(Event::Joined(event, Arc::clone(&joined))).ok();
-                            }
-                            for event in room.timeline.events {
-                                let event = event.into_full_event(id.clone());
-                                sender.send(Event::Joined(event, Arc::clone(&joined))).ok();
-                            }
-                        }
-                        for event in response.to_device.events {
-                            sender.send(Event::ToDevice(event)).ok();
+                        for event in sync_events(&client, response) {
+                            sender.send(event).ok();
                         }
                         LoopCtrl::Continue
                     },
@@ -256,13 +516,191 @@ where
     }
 }
 
+/// Gets the body of a message, which is a description for non-text messages
+pub fn message_body(msgtype: &MessageType) -> &str {
+    match msgtype {
+        MessageType::Audio(audio) => &audio.body,
+        MessageType::Emote(emote) => &emote.body,
+        MessageType::File(file) => &file.body,
+        MessageType::Image(image) => &image.body,
+        MessageType::Location(location) => &location.body,
+        MessageType::Notice(notice) => &notice.body,
+        MessageType::ServerNotice(notice) => &notice.body,
+        MessageType::Text(text) => &text.body,
+        MessageType::Video(video) => &video.body,
+        _ => "",
+    }
+}
+
+/// Gets the HTML formatted body of a message, if it has one
+pub fn message_formatted_body(msgtype: &MessageType) -> Option<&str> {
+    let formatted = match msgtype {
+        MessageType::Emote(emote) => emote.formatted.as_ref(),
+        MessageType::Notice(notice) => notice.formatted.as_ref(),
+        MessageType::Text(text) => text.formatted.as_ref(),
+        _ => None,
+    }?;
+    match formatted.format {
+        MessageFormat::Html => Some(&formatted.body),
+        _ => None,
+    }
+}
+
+/// The body and formatted body of message types that can have both
+pub fn message_body_mut(
+    msgtype: &mut MessageType,
+) -> Option<(&mut String, &mut Option<FormattedBody>)> {
+    match msgtype {
+        MessageType::Text(text) => Some((&mut text.body, &mut text.formatted)),
+        MessageType::Notice(notice) => Some((&mut notice.body, &mut notice.formatted)),
+        MessageType::Emote(emote) => Some((&mut emote.body, &mut emote.formatted)),
+        _ => None,
+    }
+}
+
+/// A piece of media, which may be encrypted
+#[derive(Clone, Debug)]
+pub enum Media {
+    /// Unencrypted media at an mxc url
+    Plain(String),
+    /// Media that needs to be decrypted after downloading it
+    Encrypted(Box<EncryptedFile>),
+}
+
+impl Media {
+    /// The mxc url of the media
+    pub fn url(&self) -> &str {
+        match self {
+            Media::Plain(url) => url,
+            Media::Encrypted(file) => &file.url,
+        }
+    }
+}
+
+/// Gets the media attached to a message, if it has any
+pub fn message_media(msgtype: &MessageType) -> Option<Media> {
+    let (url, file) = match msgtype {
+        MessageType::Audio(audio) => (&audio.url, &audio.file),
+        MessageType::File(file) => (&file.url, &file.file),
+        MessageType::Image(image) => (&image.url, &image.file),
+        MessageType::Video(video) => (&video.url, &video.file),
+        _ => return None,
+    };
+    match (url, file) {
+        (_, Some(file)) => Some(Media::Encrypted(file.clone())),
+        (Some(url), None) => Some(Media::Plain(url.clone())),
+        (None, None) => None,
+    }
+}
+
+/// An image in a message, with what's needed to show it at the right size
+#[derive(Clone, Debug)]
+pub struct ImageSource {
+    /// The full resolution image
+    pub media: Media,
+    /// Width and height of the full image, if the sender included them
+    pub dimensions: Option<(u32, u32)>,
+    /// MIME type of the full image, if the sender included it
+    pub mimetype: Option<String>,
+    /// Thumbnail uploaded by the sender. Encrypted images need this, since the server can't
+    /// make thumbnails of them.
+    pub thumbnail: Option<Media>,
+}
+
+/// Gets the image in a message, if it is one
+pub fn message_image(msgtype: &MessageType) -> Option<ImageSource> {
+    let image = match msgtype {
+        MessageType::Image(image) => image,
+        _ => return None,
+    };
+    let media = message_media(msgtype)?;
+    let info = image.info.as_deref();
+    let size = |size: Option<UInt>| size.and_then(|s| u32::try_from(u64::from(s)).ok());
+    let dimensions = info.and_then(|info| Some((size(info.width)?, size(info.height)?)));
+    let thumbnail = info.and_then(|info| match (&info.thumbnail_url, &info.thumbnail_file) {
+        (_, Some(file)) => Some(Media::Encrypted(file.clone())),
+        (Some(url), None) => Some(Media::Plain(url.clone())),
+        (None, None) => None,
+    });
+    Some(ImageSource {
+        media,
+        dimensions,
+        mimetype: info.and_then(|info| info.mimetype.clone()),
+        thumbnail,
+    })
+}
+
+/// Gets the image of a sticker
+pub fn sticker_image(content: &StickerEventContent) -> ImageSource {
+    let size = |size: Option<UInt>| size.and_then(|s| u32::try_from(u64::from(s)).ok());
+    let info = &content.info;
+    ImageSource {
+        media: Media::Plain(content.url.to_string()),
+        dimensions: size(info.width).zip(size(info.height)),
+        mimetype: info.mimetype.clone(),
+        thumbnail: None,
+    }
+}
+
+/// Download a thumbnail of an image that fits in `size`. Unencrypted images are scaled by the
+/// server, while encrypted images use the thumbnail from the message, or the full image if there
+/// isn't one.
+pub async fn download_thumbnail(
+    client: Client,
+    cache: cache::SharedCache,
+    image: ImageSource,
+    size: (u32, u32),
+) -> Result<Vec<u8>, Error> {
+    let url = match image.media {
+        Media::Plain(url) => url,
+        Media::Encrypted(_) => {
+            let media = image.thumbnail.unwrap_or(image.media);
+            return download(client, cache, media).await;
+        }
+    };
+    let key = cache::MediaCache::key(&url, Some(size));
+    let (server, path) = parse_mxc(&url)?;
+    let fetch = async move {
+        let mut request =
+            get_content_thumbnail::Request::new(&path, &*server, size.0.into(), size.1.into());
+        request.method = Some(get_content_thumbnail::Method::Scale);
+        Ok(client.send(request, None).await?.file)
+    };
+    cache::cached(cache, key, fetch).await
+}
+
+/// Download a piece of media, decrypting it if needed. The media cache is checked first, and
+/// encrypted media is cached as it was downloaded so it's never stored in plaintext.
+pub async fn download(
+    client: Client,
+    cache: cache::SharedCache,
+    media: Media,
+) -> Result<Vec<u8>, Error> {
+    let key = cache::MediaCache::key(media.url(), None);
+    let (server, path) = parse_mxc(media.url())?;
+    let fetch = async move {
+        let request = get_content::Request::new(&path, &*server);
+        Ok(client.send(request, None).await?.file)
+    };
+    let data = cache::cached(cache, key, fetch).await?;
+    match media {
+        Media::Plain(_) => Ok(data),
+        Media::Encrypted(file) => attachment::decrypt(&data, &file),
+    }
+}
+
 pub trait AnyRoomEventExt {
     /// Gets the event id of the underlying event
     fn event_id(&self) -> &EventId;
+    /// Gets the sender of the underlying event
+    fn sender(&self) -> &UserId;
     /// Gets the ´origin_server_ts` member of the underlying event
     fn origin_server_ts(&self) -> SystemTime;
-    /// Gets the mxc url in a message event if there is noe
-    fn image_url(&self) -> Option<String>;
+    /// Gets the image to show for a message event: the image of an image message or sticker, or
+    /// the thumbnail of a video
+    fn image(&self) -> Option<ImageSource>;
+    /// Gets the transaction ID of a message event we sent from this device
+    fn transaction_id(&self) -> Option<&str>;
 }
 
 impl AnyRoomEventExt for AnyRoomEvent {
@@ -274,6 +712,14 @@ impl AnyRoomEventExt for AnyRoomEvent {
             AnyRoomEvent::RedactedState(e) => e.event_id(),
         }
     }
+    fn sender(&self) -> &UserId {
+        match self {
+            AnyRoomEvent::Message(e) => e.sender(),
+            AnyRoomEvent::State(e) => e.sender(),
+            AnyRoomEvent::RedactedMessage(e) => e.sender(),
+            AnyRoomEvent::RedactedState(e) => e.sender(),
+        }
+    }
     fn origin_server_ts(&self) -> SystemTime {
         match self {
             AnyRoomEvent::Message(e) => e.origin_server_ts(),
@@ -283,29 +729,37 @@ impl AnyRoomEventExt for AnyRoomEvent {
         }
         .to_owned()
     }
-    fn image_url(&self) -> Option<String> {
+    fn image(&self) -> Option<ImageSource> {
         match self {
-            AnyRoomEvent::Message(message) => message.image_url(),
+            AnyRoomEvent::Message(message) => message.image(),
             _ => None,
         }
     }
+    fn transaction_id(&self) -> Option<&str> {
+        let unsigned = match self {
+            AnyRoomEvent::Message(AnyMessageEvent::RoomMessage(e)) => &e.unsigned,
+            AnyRoomEvent::Message(AnyMessageEvent::RoomEncrypted(e)) => &e.unsigned,
+            AnyRoomEvent::Message(AnyMessageEvent::Sticker(e)) => &e.unsigned,
+            AnyRoomEvent::Message(AnyMessageEvent::Reaction(e)) => &e.unsigned,
+            _ => return None,
+        };
+        unsigned.transaction_id.as_deref()
+    }
 }
 
 pub trait AnyMessageEventExt {
-    fn image_url(&self) -> Option<String>;
+    fn image(&self) -> Option<ImageSource>;
 }
 
 impl AnyMessageEventExt for AnyMessageEvent {
-    fn image_url(&self) -> Option<String> {
+    fn image(&self) -> Option<ImageSource> {
         match self {
-            AnyMessageEvent::RoomMessage(MessageEvent {
-                content:
-                    MessageEventContent {
-                        msgtype: MessageType::Image(ref image),
-                        ..
-                    },
-                ..
-            }) => image.url.clone(),
+            AnyMessageEvent::RoomMessage(MessageEvent { content, .. }) => {
+                message_image(&content.msgtype).or_else(|| {
+                    file::message_attachment(&content.msgtype).and_then(|a| a.thumbnail)
+                })
+            }
+            AnyMessageEvent::Sticker(sticker) => Some(sticker_image(&sticker.content)),
             _ => None,
         }
     }
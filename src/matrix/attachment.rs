@@ -0,0 +1,208 @@
+//! Encryption of attachments in end-to-end encrypted rooms.
+//!
+//! Files are encrypted with AES-256 in CTR mode, and the SHA-256 hash of the ciphertext is sent
+//! along with the key so the download can be verified before it's decrypted.
+
+use aes::Aes256;
+use ctr::{
+    cipher::{NewCipher, StreamCipher},
+    Ctr128BE,
+};
+use matrix_sdk::events::room::EncryptedFile;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+use super::Error;
+
+type Aes256Ctr = Ctr128BE<Aes256>;
+
+/// Version of the attachment encryption scheme we support
+const VERSION: &str = "v2";
+
+/// Decrypt an attachment, checking its hash first
+pub fn decrypt(data: &[u8], file: &EncryptedFile) -> Result<Vec<u8>, Error> {
+    // Go through JSON so we only depend on the wire format of the file info
+    let file = serde_json::to_value(file)?;
+    let field = |value: &Value, name: &str| -> Result<String, Error> {
+        value
+            .get(name)
+            .and_then(Value::as_str)
+            .map(String::from)
+            .ok_or_else(|| anyhow::anyhow!("Encrypted file is missing `{}`", name))
+    };
+    anyhow::ensure!(
+        field(&file, "v")? == VERSION,
+        "Unsupported attachment encryption version"
+    );
+    let key = file
+        .get("key")
+        .ok_or_else(|| anyhow::anyhow!("Encrypted file is missing `key`"))?;
+    anyhow::ensure!(
+        field(key, "alg")? == "A256CTR",
+        "Unsupported attachment encryption algorithm"
+    );
+    let expected = file
+        .get("hashes")
+        .map(|hashes| field(hashes, "sha256"))
+        .transpose()?
+        .ok_or_else(|| anyhow::anyhow!("Encrypted file has no SHA-256 hash"))?;
+
+    let hash = Sha256::digest(data);
+    anyhow::ensure!(
+        decode(&expected, base64::STANDARD_NO_PAD)? == hash.as_slice(),
+        "Attachment doesn't match its hash"
+    );
+
+    let key = decode(&field(key, "k")?, base64::URL_SAFE_NO_PAD)?;
+    let iv = decode(&field(&file, "iv")?, base64::STANDARD_NO_PAD)?;
+    anyhow::ensure!(key.len() == 32 && iv.len() == 16, "Invalid attachment key");
+
+    let mut plaintext = data.to_vec();
+    Aes256Ctr::new(key.as_slice().into(), iv.as_slice().into()).apply_keystream(&mut plaintext);
+    Ok(plaintext)
+}
+
+/// Encrypt an attachment with a new key. Returns the ciphertext to upload, and a function to
+/// build the file info once the URL of the upload is known.
+pub fn encrypt(data: &[u8]) -> Result<(Vec<u8>, impl FnOnce(String) -> EncryptedFile), Error> {
+    let mut key = [0u8; 32];
+    // The second half of the IV is the counter, which starts at 0
+    let mut iv = [0u8; 16];
+    getrandom::getrandom(&mut key)?;
+    getrandom::getrandom(&mut iv[..8])?;
+
+    let mut ciphertext = data.to_vec();
+    Aes256Ctr::new(&key.into(), &iv.into()).apply_keystream(&mut ciphertext);
+    let hash = Sha256::digest(&ciphertext);
+
+    let info = json!({
+        "v": VERSION,
+        "key": {
+            "kty": "oct",
+            "key_ops": ["encrypt", "decrypt"],
+            "alg": "A256CTR",
+            "k": base64::encode_config(key, base64::URL_SAFE_NO_PAD),
+            "ext": true,
+        },
+        "iv": base64::encode_config(iv, base64::STANDARD_NO_PAD),
+        "hashes": { "sha256": base64::encode_config(hash, base64::STANDARD_NO_PAD) },
+    });
+    let file = move |url: String| {
+        let mut info = info;
+        info["url"] = json!(url);
+        serde_json::from_value(info).expect("Encrypted file info is valid")
+    };
+    Ok((ciphertext, file))
+}
+
+/// Decode base64 whether or not it's padded
+fn decode(text: &str, config: base64::Config) -> Result<Vec<u8>, Error> {
+    Ok(base64::decode_config(text.trim_end_matches('='), config)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Encrypt some data, with file info as it would be sent in a message
+    fn encrypt_file(data: &[u8]) -> (Vec<u8>, EncryptedFile) {
+        let (ciphertext, file) = encrypt(data).unwrap();
+        (ciphertext, file(String::from("mxc://example.org/abcdef")))
+    }
+
+    /// Change the file info through its JSON
+    fn modify(file: &EncryptedFile, change: impl FnOnce(&mut Value)) -> EncryptedFile {
+        let mut json = serde_json::to_value(file).unwrap();
+        change(&mut json);
+        serde_json::from_value(json).unwrap()
+    }
+
+    /// Add the padding some clients send
+    fn pad(value: &mut Value) {
+        let text = value.as_str().unwrap();
+        let padding = "=".repeat((4 - text.len() % 4) % 4);
+        *value = json!(format!("{}{}", text, padding));
+    }
+
+    #[test]
+    fn round_trip() {
+        let data = b"The quick brown fox jumps over the lazy dog".repeat(100);
+        let (ciphertext, file) = encrypt_file(&data);
+        assert_eq!(ciphertext.len(), data.len());
+        assert_ne!(ciphertext, data);
+        assert_eq!(decrypt(&ciphertext, &file).unwrap(), data);
+
+        // Empty files work too
+        let (ciphertext, file) = encrypt_file(b"");
+        assert_eq!(decrypt(&ciphertext, &file).unwrap(), b"");
+    }
+
+    #[test]
+    fn new_key_every_time() {
+        let (first, first_file) = encrypt_file(b"hello");
+        let (second, second_file) = encrypt_file(b"hello");
+        assert_ne!(first, second);
+        assert!(decrypt(&first, &second_file).is_err());
+        assert_eq!(decrypt(&first, &first_file).unwrap(), b"hello");
+    }
+
+    #[test]
+    fn file_info() {
+        let (_, file) = encrypt_file(b"hello");
+        let json = serde_json::to_value(&file).unwrap();
+        assert_eq!(json["v"], "v2");
+        assert_eq!(json["url"], "mxc://example.org/abcdef");
+        assert_eq!(json["key"]["alg"], "A256CTR");
+        // We send base64 without padding, and the counter half of the IV starts at 0
+        let iv = json["iv"].as_str().unwrap();
+        assert!(!iv.ends_with('='));
+        assert_eq!(decode(iv, base64::STANDARD_NO_PAD).unwrap()[8..], [0; 8]);
+        assert!(!json["key"]["k"].as_str().unwrap().ends_with('='));
+        assert!(!json["hashes"]["sha256"].as_str().unwrap().ends_with('='));
+    }
+
+    #[test]
+    fn tampered() {
+        let (mut ciphertext, file) = encrypt_file(b"hello");
+        ciphertext[0] ^= 1;
+        let error = decrypt(&ciphertext, &file).unwrap_err();
+        assert_eq!(error.to_string(), "Attachment doesn't match its hash");
+
+        // A hash that doesn't belong to the file
+        let (ciphertext, file) = encrypt_file(b"hello");
+        let (_, other) = encrypt_file(b"goodbye");
+        let file = modify(&file, |json| {
+            json["hashes"] = serde_json::to_value(&other).unwrap()["hashes"].clone()
+        });
+        assert!(decrypt(&ciphertext, &file).is_err());
+
+        // No hash at all
+        let (ciphertext, file) = encrypt_file(b"hello");
+        let file = modify(&file, |json| json["hashes"] = json!({}));
+        assert!(decrypt(&ciphertext, &file).is_err());
+    }
+
+    #[test]
+    fn padded_base64() {
+        let (ciphertext, file) = encrypt_file(b"hello");
+        let file = modify(&file, |json| {
+            pad(&mut json["key"]["k"]);
+            pad(&mut json["iv"]);
+            pad(&mut json["hashes"]["sha256"]);
+        });
+        let json = serde_json::to_value(&file).unwrap();
+        assert!(json["iv"].as_str().unwrap().ends_with("=="));
+        assert_eq!(decrypt(&ciphertext, &file).unwrap(), b"hello");
+    }
+
+    #[test]
+    fn unsupported() {
+        let (ciphertext, file) = encrypt_file(b"hello");
+        let old = modify(&file, |json| json["v"] = json!("v1"));
+        assert!(decrypt(&ciphertext, &old).is_err());
+        let algorithm = modify(&file, |json| json["key"]["alg"] = json!("A128CTR"));
+        assert!(decrypt(&ciphertext, &algorithm).is_err());
+        let short_iv = modify(&file, |json| json["iv"] = json!("AAAAAAAAAAA"));
+        assert!(decrypt(&ciphertext, &short_iv).is_err());
+    }
+}
@@ -0,0 +1,371 @@
+//! On-disk cache of downloaded media.
+//!
+//! Files are stored under the SHA-256 hash of their contents, and an index maps each mxc URI
+//! (and thumbnail size) to the hash. The least recently used entries are evicted when the cache
+//! grows past its size limit.
+
+use std::{
+    collections::BTreeMap,
+    future::Future,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use super::Error;
+
+/// Size limit of a new cache, 500 MiB
+pub const DEFAULT_LIMIT: u64 = 500 * 1024 * 1024;
+
+/// A cache that can be shared between tasks
+pub type SharedCache = Arc<Mutex<MediaCache>>;
+
+/// A cached file
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct Entry {
+    /// Hex encoded SHA-256 hash of the contents, which is also the file name
+    hash: String,
+    /// Size in bytes
+    size: u64,
+    /// When the entry was last used, in seconds since the unix epoch
+    last_used: u64,
+}
+
+/// What's in the cache, stored next to the cached files
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+struct Index {
+    /// Maximum total size of the cached files in bytes
+    limit: u64,
+    /// Cached files by key
+    entries: BTreeMap<String, Entry>,
+}
+
+impl Index {
+    /// Total size of the files in the index. Files shared by several keys count once.
+    fn size(&self) -> u64 {
+        let mut files = BTreeMap::new();
+        for entry in self.entries.values() {
+            files.insert(&entry.hash, entry.size);
+        }
+        files.values().sum()
+    }
+}
+
+#[derive(Debug)]
+pub struct MediaCache {
+    /// Directory the files are stored in
+    dir: PathBuf,
+    index: Index,
+    /// Total size of the cached files in bytes, kept up to date as files come and go
+    size: u64,
+}
+
+impl MediaCache {
+    /// Open the cache in the user's cache directory, creating it if needed
+    pub fn open() -> Self {
+        let dir = dirs::cache_dir()
+            .unwrap_or_else(std::env::temp_dir)
+            .join("retrix")
+            .join("media");
+        Self::open_in(dir)
+    }
+
+    /// Open the cache stored in a directory, creating it if needed
+    pub fn open_in(dir: impl AsRef<Path>) -> Self {
+        let dir = dir.as_ref().to_owned();
+        // A broken index just means starting over
+        let index: Index = std::fs::read(dir.join("index.json"))
+            .ok()
+            .and_then(|index| serde_json::from_slice(&index).ok())
+            .unwrap_or_else(|| Index {
+                limit: DEFAULT_LIMIT,
+                ..Index::default()
+            });
+        let size = index.size();
+        Self { dir, index, size }
+    }
+
+    /// Open the cache so it can be shared between tasks
+    pub fn shared() -> SharedCache {
+        Arc::new(Mutex::new(Self::open()))
+    }
+
+    /// The key for a piece of media, or a thumbnail of it if a size is given
+    pub fn key(url: &str, size: Option<(u32, u32)>) -> String {
+        match size {
+            Some((width, height)) => format!("{}#{}x{}", url, width, height),
+            None => url.to_owned(),
+        }
+    }
+
+    /// Get a file from the cache, if it's there and intact
+    pub fn get(&mut self, key: &str) -> Option<Vec<u8>> {
+        let entry = self.index.entries.get_mut(key)?;
+        let data = std::fs::read(self.dir.join(&entry.hash)).ok();
+        match data {
+            Some(data) if hash(&data) == entry.hash => {
+                entry.last_used = now();
+                Some(data)
+            }
+            // Missing or corrupted
+            _ => {
+                self.remove(key);
+                self.save().ok();
+                None
+            }
+        }
+    }
+
+    /// Add a file to the cache, evicting old files if it gets too big
+    pub fn put(&mut self, key: String, data: &[u8]) -> Result<(), Error> {
+        let hash = hash(data);
+        self.remove(&key);
+        std::fs::create_dir_all(&self.dir)?;
+        let path = self.dir.join(&hash);
+        if !path.exists() {
+            // Write to a temporary file first so a crash can't leave a partial file behind
+            let temp = self.dir.join(format!("{}.tmp", hash));
+            std::fs::write(&temp, data)?;
+            std::fs::rename(temp, path)?;
+        }
+        if !self.index.entries.values().any(|e| e.hash == hash) {
+            self.size += data.len() as u64;
+        }
+        let entry = Entry {
+            hash,
+            size: data.len() as u64,
+            last_used: now(),
+        };
+        self.index.entries.insert(key, entry);
+        self.evict();
+        self.save()
+    }
+
+    /// Total size of the cached files in bytes. Files shared by several keys count once.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Maximum size of the cache in bytes
+    pub fn limit(&self) -> u64 {
+        self.index.limit
+    }
+
+    /// Change the maximum size of the cache, evicting files if needed
+    pub fn set_limit(&mut self, limit: u64) -> Result<(), Error> {
+        self.index.limit = limit;
+        self.evict();
+        self.save()
+    }
+
+    /// Delete everything in the cache
+    pub fn clear(&mut self) -> Result<(), Error> {
+        let keys: Vec<String> = self.index.entries.keys().cloned().collect();
+        for key in keys {
+            self.remove(&key);
+        }
+        self.save()
+    }
+
+    /// Remove the least recently used files until the cache fits in its limit
+    fn evict(&mut self) {
+        let mut entries: Vec<(String, u64)> = self
+            .index
+            .entries
+            .iter()
+            .map(|(key, entry)| (key.clone(), entry.last_used))
+            .collect();
+        entries.sort_unstable_by_key(|(_, last_used)| *last_used);
+        let mut entries = entries.into_iter();
+        while self.size > self.index.limit {
+            match entries.next() {
+                Some((key, _)) => self.remove(&key),
+                None => break,
+            }
+        }
+    }
+
+    /// Remove an entry, deleting its file unless another entry has the same contents
+    fn remove(&mut self, key: &str) {
+        if let Some(entry) = self.index.entries.remove(key) {
+            let shared = self.index.entries.values().any(|e| e.hash == entry.hash);
+            if !shared {
+                std::fs::remove_file(self.dir.join(&entry.hash)).ok();
+                self.size -= entry.size;
+            }
+        }
+    }
+
+    /// Write the index to disk
+    fn save(&self) -> Result<(), Error> {
+        std::fs::create_dir_all(&self.dir)?;
+        std::fs::write(
+            self.dir.join("index.json"),
+            serde_json::to_vec(&self.index)?,
+        )?;
+        Ok(())
+    }
+}
+
+/// Use a shared cache on a blocking thread, since everything it does touches the disk
+async fn with_cache<T, F>(cache: SharedCache, f: F) -> Result<T, Error>
+where
+    T: Send + 'static,
+    F: FnOnce(&mut MediaCache) -> T + Send + 'static,
+{
+    Ok(tokio::task::spawn_blocking(move || f(&mut cache.lock().unwrap())).await?)
+}
+
+/// Get a file from the cache, or fetch it and add it to the cache if it's not there
+pub async fn cached<F>(cache: SharedCache, key: String, fetch: F) -> Result<Vec<u8>, Error>
+where
+    F: Future<Output = Result<Vec<u8>, Error>>,
+{
+    // Don't hold the lock while fetching
+    let hit = {
+        let key = key.clone();
+        with_cache(cache.clone(), move |cache| cache.get(&key)).await?
+    };
+    if let Some(data) = hit {
+        return Ok(data);
+    }
+    let data = fetch.await?;
+    // Failing to cache something isn't a reason to not show it
+    with_cache(cache, move |cache| {
+        cache.put(key, &data).ok();
+        data
+    })
+    .await
+}
+
+/// Change the size limit of a shared cache, evicting files if needed
+pub async fn set_limit(cache: SharedCache, limit: u64) -> Result<(), Error> {
+    with_cache(cache, move |cache| cache.set_limit(limit)).await?
+}
+
+/// Delete everything in a shared cache
+pub async fn clear(cache: SharedCache) -> Result<(), Error> {
+    with_cache(cache, MediaCache::clear).await?
+}
+
+/// Hex encoded SHA-256 hash of some data
+fn hash(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// The current time in seconds since the unix epoch
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An empty cache in a directory of its own
+    fn cache(name: &str) -> MediaCache {
+        let dir = std::env::temp_dir()
+            .join(format!("retrix-cache-test-{}", std::process::id()))
+            .join(name);
+        std::fs::remove_dir_all(&dir).ok();
+        MediaCache::open_in(dir)
+    }
+
+    /// Make an entry look like it was last used at `time`
+    fn touch(cache: &mut MediaCache, key: &str, time: u64) {
+        cache.index.entries.get_mut(key).unwrap().last_used = time;
+    }
+
+    #[test]
+    fn put_and_get() {
+        let mut cache = cache("put_and_get");
+        cache.put("a".into(), b"first").unwrap();
+        assert_eq!(cache.get("a").as_deref(), Some(&b"first"[..]));
+        assert_eq!(cache.get("b"), None);
+        assert_eq!(cache.size(), 5);
+        // The index is saved along with the files
+        let reopened = MediaCache::open_in(&cache.dir);
+        assert_eq!(reopened.size(), 5);
+        assert!(reopened.index.entries.contains_key("a"));
+        // Replacing an entry replaces its file
+        cache.put("a".into(), b"second!").unwrap();
+        assert_eq!(cache.get("a").as_deref(), Some(&b"second!"[..]));
+        assert_eq!(cache.size(), 7);
+        assert!(!cache.dir.join(hash(b"first")).exists());
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut cache = cache("evicts_least_recently_used");
+        cache.set_limit(10).unwrap();
+        cache.put("a".into(), b"aaaa").unwrap();
+        touch(&mut cache, "a", 2);
+        cache.put("b".into(), b"bbbb").unwrap();
+        touch(&mut cache, "b", 1);
+        cache.put("c".into(), b"cccc").unwrap();
+        assert!(cache.index.entries.contains_key("a"));
+        assert!(!cache.index.entries.contains_key("b"));
+        assert!(cache.index.entries.contains_key("c"));
+        assert!(!cache.dir.join(hash(b"bbbb")).exists());
+        assert_eq!(cache.size(), 8);
+    }
+
+    #[test]
+    fn drops_broken_files() {
+        let mut cache = cache("drops_broken_files");
+        cache.put("corrupted".into(), b"good data").unwrap();
+        cache.put("missing".into(), b"other data").unwrap();
+        std::fs::write(cache.dir.join(hash(b"good data")), b"bad data").unwrap();
+        std::fs::remove_file(cache.dir.join(hash(b"other data"))).unwrap();
+        assert_eq!(cache.get("corrupted"), None);
+        assert_eq!(cache.get("missing"), None);
+        assert!(cache.index.entries.is_empty());
+        assert_eq!(cache.size(), 0);
+        let reopened = MediaCache::open_in(&cache.dir);
+        assert!(reopened.index.entries.is_empty());
+    }
+
+    #[test]
+    fn shared_files() {
+        let mut cache = cache("shared_files");
+        cache.put("a".into(), b"same").unwrap();
+        cache.put("b".into(), b"same").unwrap();
+        // Counted once, and kept until nothing uses it
+        assert_eq!(cache.size(), 4);
+        cache.remove("a");
+        assert_eq!(cache.get("b").as_deref(), Some(&b"same"[..]));
+        assert_eq!(cache.size(), 4);
+        cache.remove("b");
+        assert!(!cache.dir.join(hash(b"same")).exists());
+        assert_eq!(cache.size(), 0);
+    }
+
+    #[test]
+    fn set_limit() {
+        let mut cache = cache("set_limit");
+        for (time, key) in ["a", "b", "c"].iter().enumerate() {
+            cache
+                .put(key.to_string(), key.repeat(4).as_bytes())
+                .unwrap();
+            touch(&mut cache, key, time as u64);
+        }
+        assert_eq!(cache.size(), 12);
+        cache.set_limit(8).unwrap();
+        assert_eq!(cache.limit(), 8);
+        assert_eq!(cache.size(), 8);
+        assert!(!cache.index.entries.contains_key("a"));
+        cache.set_limit(0).unwrap();
+        assert!(cache.index.entries.is_empty());
+        assert_eq!(cache.size(), 0);
+        assert_eq!(MediaCache::open_in(&cache.dir).limit(), 0);
+    }
+}
@@ -0,0 +1,736 @@
+//! Slash commands typed in the composer, like `/me` and `/join`.
+//!
+//! Commands that change room state need to change the current content of the state event, so
+//! they go through the client-server API directly, like the membership commands.
+
+use std::convert::TryFrom;
+
+use matrix_sdk::{
+    events::room::message::MessageEventContent,
+    identifiers::{RoomId, RoomIdOrAliasId, UserId},
+    reqwest::{self, Method, StatusCode},
+};
+use serde_json::{json, Value};
+
+use super::{api_url, markdown, Error, Session};
+
+/// Power level `/op` gives when no level is given, which makes users moderators
+const DEFAULT_OP_LEVEL: i64 = 50;
+/// Prefixed to the message by `/shrug`
+const SHRUG: &str = "¯\\_(ツ)_/¯";
+
+/// Description of a command for help and completion
+#[derive(Clone, Copy, Debug)]
+pub struct Info {
+    /// Name of the command, without the slash
+    pub name: &'static str,
+    /// Arguments it takes, with optional ones in brackets
+    pub args: &'static str,
+    pub description: &'static str,
+}
+
+impl Info {
+    /// Usage line, like `/kick <user> [reason]`
+    pub fn usage(&self) -> String {
+        match self.args {
+            "" => format!("/{}", self.name),
+            args => format!("/{} {}", self.name, args),
+        }
+    }
+}
+
+/// All the commands, in the order they're listed in help
+pub const COMMANDS: &[Info] = &[
+    Info {
+        name: "me",
+        args: "<action>",
+        description: "Send an action, like \"* Alice waves\"",
+    },
+    Info {
+        name: "shrug",
+        args: "[message]",
+        description: "Send a message with ¯\\_(ツ)_/¯ in front",
+    },
+    Info {
+        name: "plain",
+        args: "<message>",
+        description: "Send a message without markdown formatting",
+    },
+    Info {
+        name: "html",
+        args: "<html>",
+        description: "Send a message formatted with HTML",
+    },
+    Info {
+        name: "join",
+        args: "<room id or alias>",
+        description: "Join a room",
+    },
+    Info {
+        name: "leave",
+        args: "[reason]",
+        description: "Leave the room",
+    },
+    Info {
+        name: "invite",
+        args: "<user>",
+        description: "Invite a user to the room",
+    },
+    Info {
+        name: "kick",
+        args: "<user> [reason]",
+        description: "Remove a user from the room",
+    },
+    Info {
+        name: "ban",
+        args: "<user> [reason]",
+        description: "Ban a user from the room",
+    },
+    Info {
+        name: "op",
+        args: "<user> [power level]",
+        description: "Set the power level of a user, 50 by default",
+    },
+    Info {
+        name: "nick",
+        args: "<name>",
+        description: "Change your display name",
+    },
+    Info {
+        name: "myroomnick",
+        args: "<name>",
+        description: "Change your display name in this room only",
+    },
+    Info {
+        name: "topic",
+        args: "<topic>",
+        description: "Set the topic of the room",
+    },
+    Info {
+        name: "devtools",
+        args: "",
+        description: "Show developer tools for the room",
+    },
+    Info {
+        name: "help",
+        args: "[command]",
+        description: "List the commands, or show how to use one",
+    },
+];
+
+/// A parsed command
+#[derive(Clone, Debug)]
+pub enum SlashCommand {
+    /// A message that starts with a slash, escaped by doubling it
+    Text(String),
+    Me(String),
+    Shrug(String),
+    Plain(String),
+    Html(String),
+    Join(RoomIdOrAliasId),
+    Leave(Option<String>),
+    Invite(UserId),
+    Kick(UserId, Option<String>),
+    Ban(UserId, Option<String>),
+    Op(UserId, i64),
+    Nick(String),
+    RoomNick(String),
+    Topic(String),
+    DevTools,
+    Help(Option<String>),
+}
+
+/// Parse a composed message as a command. Returns `None` if it isn't one and should be sent as
+/// it is.
+pub fn parse(input: &str) -> Option<Result<SlashCommand, Error>> {
+    let input = input.strip_prefix('/')?;
+    if input.starts_with('/') {
+        return Some(Ok(SlashCommand::Text(input.to_owned())));
+    }
+    let (name, args) = match input.find(char::is_whitespace) {
+        Some(idx) => (&input[..idx], input[idx..].trim()),
+        None => (input, ""),
+    };
+    Some(parse_command(name, args))
+}
+
+fn parse_command(name: &str, args: &str) -> Result<SlashCommand, Error> {
+    let info = COMMANDS
+        .iter()
+        .find(|info| info.name == name)
+        .ok_or_else(|| {
+            anyhow::anyhow!(
+                "Unknown command /{}. Type /help for a list of commands, or start the message \
+                 with // to send it as text",
+                name
+            )
+        })?;
+    let usage = || anyhow::anyhow!("Usage: {}", info.usage());
+    let required = || {
+        Some(args.to_owned())
+            .filter(|a| !a.is_empty())
+            .ok_or_else(usage)
+    };
+    let optional = |args: &str| Some(args.to_owned()).filter(|a| !a.is_empty());
+    let user = || split_user(args).unwrap_or_else(|| Err(usage()));
+
+    Ok(match name {
+        "me" => SlashCommand::Me(required()?),
+        "shrug" => SlashCommand::Shrug(args.to_owned()),
+        "plain" => SlashCommand::Plain(required()?),
+        "html" => SlashCommand::Html(required()?),
+        "join" => {
+            let target = required()?;
+            SlashCommand::Join(RoomIdOrAliasId::try_from(target.as_str()).map_err(|_| {
+                anyhow::anyhow!(
+                    "{} isn't a room ID or alias, like #room:example.org",
+                    target
+                )
+            })?)
+        }
+        "leave" => SlashCommand::Leave(optional(args)),
+        "invite" => match user()? {
+            (user, "") => SlashCommand::Invite(user),
+            _ => return Err(usage()),
+        },
+        "kick" => {
+            let (user, reason) = user()?;
+            SlashCommand::Kick(user, optional(reason))
+        }
+        "ban" => {
+            let (user, reason) = user()?;
+            SlashCommand::Ban(user, optional(reason))
+        }
+        "op" => {
+            let (user, level) = user()?;
+            let level = match level {
+                "" => DEFAULT_OP_LEVEL,
+                level => level
+                    .parse()
+                    .map_err(|_| anyhow::anyhow!("{} isn't a power level", level))?,
+            };
+            SlashCommand::Op(user, level)
+        }
+        "nick" => SlashCommand::Nick(required()?),
+        "myroomnick" => SlashCommand::RoomNick(required()?),
+        "topic" => SlashCommand::Topic(required()?),
+        "devtools" => SlashCommand::DevTools,
+        "help" => SlashCommand::Help(optional(args.trim_start_matches('/'))),
+        _ => unreachable!("every command is parsed"),
+    })
+}
+
+/// Split arguments into a user and the rest of the arguments. Returns `None` if there are no
+/// arguments.
+fn split_user(args: &str) -> Option<Result<(UserId, &str), Error>> {
+    let mut args = args.splitn(2, char::is_whitespace);
+    let user = args.next().filter(|u| !u.is_empty())?;
+    let rest = args.next().unwrap_or_default().trim();
+    Some(
+        UserId::try_from(user)
+            .map(|user| (user, rest))
+            .map_err(|_| anyhow::anyhow!("{} isn't a user ID, like @alice:example.org", user)),
+    )
+}
+
+impl SlashCommand {
+    /// Content of the message the command sends, if it sends one
+    pub fn content(&self, markdown: bool) -> Option<MessageEventContent> {
+        Some(match self {
+            SlashCommand::Text(text) => markdown::text_content(text.clone(), markdown),
+            SlashCommand::Me(action) => markdown::emote_content(action.clone(), markdown),
+            SlashCommand::Shrug(text) if text.is_empty() => MessageEventContent::text_plain(SHRUG),
+            SlashCommand::Shrug(text) => {
+                MessageEventContent::text_plain(format!("{} {}", SHRUG, text))
+            }
+            SlashCommand::Plain(text) => MessageEventContent::text_plain(text.clone()),
+            SlashCommand::Html(html) => MessageEventContent::text_html(strip_tags(html), html),
+            _ => return None,
+        })
+    }
+}
+
+/// Commands that start with what's been typed, while the command name is being typed
+pub fn suggestions(input: &str) -> Vec<&'static Info> {
+    match input.strip_prefix('/') {
+        Some(prefix) if !prefix.starts_with('/') && !prefix.contains(char::is_whitespace) => {
+            COMMANDS
+                .iter()
+                .filter(|info| info.name.starts_with(prefix))
+                .collect()
+        }
+        _ => Vec::new(),
+    }
+}
+
+/// Complete the name of the command being typed, as far as it's the same for every command it
+/// could be
+pub fn complete(input: &str) -> Option<String> {
+    let suggestions = suggestions(input);
+    let (first, rest) = suggestions.split_first()?;
+    if rest.is_empty() {
+        return Some(format!("/{} ", first.name));
+    }
+    let common = common_prefix(first.name, rest.iter().map(|info| info.name));
+    Some(format!("/{}", common))
+}
+
+/// The longest start all the names have in common
+fn common_prefix<'a>(first: &'a str, rest: impl Iterator<Item = &'a str>) -> &'a str {
+    rest.fold(first, |common, name| {
+        let len = common
+            .chars()
+            .zip(name.chars())
+            .take_while(|(a, b)| a == b)
+            .map(|(a, _)| a.len_utf8())
+            .sum();
+        &common[..len]
+    })
+}
+
+/// Help text for one command, or all of them
+pub fn help(command: Option<&str>) -> Result<String, Error> {
+    let line = |info: &Info| format!("{}: {}", info.usage(), info.description);
+    match command {
+        Some(name) => COMMANDS
+            .iter()
+            .find(|info| info.name == name)
+            .map(line)
+            .ok_or_else(|| anyhow::anyhow!("Unknown command /{}", name)),
+        None => {
+            let mut help = String::from("Start a message with // to send it without a command.");
+            for info in COMMANDS {
+                help.push('\n');
+                help.push_str(&line(info));
+            }
+            Ok(help)
+        }
+    }
+}
+
+/// Plain text version of an HTML message, for clients that don't show HTML
+fn strip_tags(html: &str) -> String {
+    let mut text = String::with_capacity(html.len());
+    let mut in_tag = false;
+    for c in html.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' if in_tag => in_tag = false,
+            c if !in_tag => text.push(c),
+            _ => (),
+        }
+    }
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&")
+}
+
+/// Call an endpoint of the client-server API, returning the status and the JSON response
+async fn send(
+    session: &Session,
+    method: Method,
+    segments: &[&str],
+    body: &Value,
+) -> Result<(StatusCode, Value), Error> {
+    let mut request = reqwest::Client::new()
+        .request(method.clone(), api_url(session, segments)?)
+        .bearer_auth(&session.access_token);
+    if method != Method::GET {
+        request = request
+            .header("Content-Type", "application/json")
+            .body(body.to_string());
+    }
+    let response = request.send().await?;
+    let status = response.status();
+    let response = serde_json::from_str(&response.text().await?).unwrap_or_default();
+    Ok((status, response))
+}
+
+/// Turn a failed response into an error, using the error message from the server
+fn check((status, response): (StatusCode, Value)) -> Result<Value, Error> {
+    if status.is_success() {
+        return Ok(response);
+    }
+    let error = response.get("error").and_then(Value::as_str);
+    Err(anyhow::anyhow!(
+        "{}",
+        error.unwrap_or_else(|| status.canonical_reason().unwrap_or("Request failed"))
+    ))
+}
+
+/// Call an endpoint of the client-server API that changes something
+async fn request(
+    session: &Session,
+    method: Method,
+    segments: &[&str],
+    body: &Value,
+) -> Result<Value, Error> {
+    check(send(session, method, segments, body).await?)
+}
+
+/// Join a room by ID or alias
+pub async fn join(session: Session, room: RoomIdOrAliasId) -> Result<(), Error> {
+    request(
+        &session,
+        Method::POST,
+        &["r0", "join", room.as_str()],
+        &json!({}),
+    )
+    .await?;
+    Ok(())
+}
+
+/// Leave a room
+pub async fn leave(session: Session, room_id: RoomId, reason: Option<String>) -> Result<(), Error> {
+    let mut body = json!({});
+    if let Some(reason) = reason {
+        body["reason"] = json!(reason);
+    }
+    request(
+        &session,
+        Method::POST,
+        &["r0", "rooms", room_id.as_str(), "leave"],
+        &body,
+    )
+    .await?;
+    Ok(())
+}
+
+/// Change the membership of another user, where `action` is `invite`, `kick` or `ban`
+pub async fn membership(
+    session: Session,
+    room_id: RoomId,
+    action: &'static str,
+    user_id: UserId,
+    reason: Option<String>,
+) -> Result<(), Error> {
+    let mut body = json!({ "user_id": user_id });
+    if let Some(reason) = reason {
+        body["reason"] = json!(reason);
+    }
+    request(
+        &session,
+        Method::POST,
+        &["r0", "rooms", room_id.as_str(), action],
+        &body,
+    )
+    .await?;
+    Ok(())
+}
+
+/// Change some fields of a state event, keeping the rest of its content. If the event doesn't
+/// exist yet, `default` is changed instead, or it's an error without one.
+async fn update_state(
+    session: &Session,
+    room_id: &RoomId,
+    event_type: &str,
+    state_key: &str,
+    default: Option<Value>,
+    update: impl FnOnce(&mut Value),
+) -> Result<(), Error> {
+    let segments = [
+        "r0",
+        "rooms",
+        room_id.as_str(),
+        "state",
+        event_type,
+        state_key,
+    ];
+    let mut content = match send(session, Method::GET, &segments, &Value::Null).await? {
+        (StatusCode::NOT_FOUND, _) => default
+            .ok_or_else(|| anyhow::anyhow!("The room doesn't have an {} event", event_type))?,
+        response => check(response)?,
+    };
+    anyhow::ensure!(content.is_object(), "Invalid {} event", event_type);
+    update(&mut content);
+    request(session, Method::PUT, &segments, &content).await?;
+    Ok(())
+}
+
+/// Set our display name in one room
+pub async fn set_room_nick(session: Session, room_id: RoomId, name: String) -> Result<(), Error> {
+    let user_id = session.user_id.to_string();
+    update_state(
+        &session,
+        &room_id,
+        "m.room.member",
+        &user_id,
+        None,
+        |content| {
+            content["displayname"] = json!(name);
+        },
+    )
+    .await
+}
+
+/// Set the topic of a room
+pub async fn set_topic(session: Session, room_id: RoomId, topic: String) -> Result<(), Error> {
+    update_state(
+        &session,
+        &room_id,
+        "m.room.topic",
+        "",
+        Some(json!({})),
+        |content| {
+            content["topic"] = json!(topic);
+        },
+    )
+    .await
+}
+
+/// Set the power level of a user in a room
+pub async fn set_power_level(
+    session: Session,
+    room_id: RoomId,
+    user_id: UserId,
+    level: i64,
+) -> Result<(), Error> {
+    // Without power levels the room creator has all the power, which we'd take away
+    update_state(
+        &session,
+        &room_id,
+        "m.room.power_levels",
+        "",
+        None,
+        |content| {
+            if !content["users"].is_object() {
+                content["users"] = json!({});
+            }
+            content["users"][user_id.as_str()] = json!(level);
+        },
+    )
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::matrix::message_body;
+
+    fn parsed(input: &str) -> SlashCommand {
+        parse(input).expect("is a command").expect("parses")
+    }
+
+    fn error(input: &str) -> String {
+        parse(input)
+            .expect("is a command")
+            .expect_err("doesn't parse")
+            .to_string()
+    }
+
+    fn alice() -> UserId {
+        UserId::try_from("@alice:example.org").unwrap()
+    }
+
+    #[test]
+    fn not_commands() {
+        assert!(parse("hello").is_none());
+        assert!(parse(" /me waves").is_none());
+        assert!(parse("").is_none());
+    }
+
+    #[test]
+    fn escaped() {
+        assert!(matches!(parsed("//me waves"), SlashCommand::Text(t) if t == "/me waves"));
+        assert!(matches!(parsed("///"), SlashCommand::Text(t) if t == "//"));
+        assert!(matches!(
+            parsed("//usr/bin is a directory"),
+            SlashCommand::Text(t) if t == "/usr/bin is a directory"
+        ));
+        // Only the first slash is taken off, the rest of the message is left alone
+        let content = parsed("//  *spaced*").content(false).unwrap();
+        assert_eq!(message_body(&content.msgtype), "/  *spaced*");
+    }
+
+    #[test]
+    fn messages() {
+        assert!(matches!(parsed("/me  waves  "), SlashCommand::Me(a) if a == "waves"));
+        assert!(matches!(parsed("/me\twaves"), SlashCommand::Me(a) if a == "waves"));
+        assert!(matches!(parsed("/shrug"), SlashCommand::Shrug(t) if t.is_empty()));
+        assert!(matches!(parsed("/shrug oh well"), SlashCommand::Shrug(t) if t == "oh well"));
+        assert!(matches!(parsed("/plain **bold**"), SlashCommand::Plain(t) if t == "**bold**"));
+        assert_eq!(error("/me"), "Usage: /me <action>");
+        assert_eq!(error("/html   "), "Usage: /html <html>");
+    }
+
+    #[test]
+    fn content() {
+        let shrug = parsed("/shrug").content(false).unwrap();
+        assert_eq!(message_body(&shrug.msgtype), SHRUG);
+        let shrug = parsed("/shrug oh well").content(false).unwrap();
+        assert_eq!(message_body(&shrug.msgtype), "¯\\_(ツ)_/¯ oh well");
+        let html = parsed("/html <b>bold</b> &amp; <i>brave</i>")
+            .content(false)
+            .unwrap();
+        assert_eq!(message_body(&html.msgtype), "bold & brave");
+        assert!(parsed("/devtools").content(true).is_none());
+    }
+
+    #[test]
+    fn unknown() {
+        assert!(error("/frobnicate now").starts_with("Unknown command /frobnicate."));
+        // Names are matched exactly
+        assert!(error("/ME waves").starts_with("Unknown command /ME."));
+        assert!(error("/").starts_with("Unknown command /."));
+    }
+
+    #[test]
+    fn rooms() {
+        assert!(matches!(
+            parsed("/join #room:example.org"),
+            SlashCommand::Join(room) if room.as_str() == "#room:example.org"
+        ));
+        assert!(matches!(
+            parsed("/join !abc:example.org"),
+            SlashCommand::Join(room) if room.as_str() == "!abc:example.org"
+        ));
+        assert_eq!(
+            error("/join room"),
+            "room isn't a room ID or alias, like #room:example.org"
+        );
+        assert!(matches!(parsed("/leave"), SlashCommand::Leave(None)));
+        assert!(matches!(
+            parsed("/leave  bye all "),
+            SlashCommand::Leave(Some(reason)) if reason == "bye all"
+        ));
+    }
+
+    #[test]
+    fn users() {
+        assert!(matches!(
+            parsed("/invite @alice:example.org"),
+            SlashCommand::Invite(user) if user == alice()
+        ));
+        assert_eq!(
+            error("/invite @alice:example.org please"),
+            "Usage: /invite <user>"
+        );
+        assert_eq!(
+            error("/invite alice"),
+            "alice isn't a user ID, like @alice:example.org"
+        );
+        assert!(matches!(
+            parsed("/kick @alice:example.org"),
+            SlashCommand::Kick(user, None) if user == alice()
+        ));
+        assert!(matches!(
+            parsed("/ban @alice:example.org  spam and  more "),
+            SlashCommand::Ban(user, Some(reason)) if user == alice() && reason == "spam and  more"
+        ));
+        assert_eq!(error("/kick"), "Usage: /kick <user> [reason]");
+    }
+
+    #[test]
+    fn op() {
+        assert!(matches!(
+            parsed("/op @alice:example.org"),
+            SlashCommand::Op(user, DEFAULT_OP_LEVEL) if user == alice()
+        ));
+        assert!(matches!(
+            parsed("/op @alice:example.org 100"),
+            SlashCommand::Op(_, 100)
+        ));
+        assert!(matches!(
+            parsed("/op @alice:example.org -1"),
+            SlashCommand::Op(_, -1)
+        ));
+        assert_eq!(
+            error("/op @alice:example.org admin"),
+            "admin isn't a power level"
+        );
+        assert_eq!(
+            error("/op @alice:example.org 50 now"),
+            "50 now isn't a power level"
+        );
+        assert_eq!(error("/op"), "Usage: /op <user> [power level]");
+    }
+
+    #[test]
+    fn split() {
+        assert!(split_user("").is_none());
+        let (user, rest) = split_user("@alice:example.org").unwrap().unwrap();
+        assert_eq!(user, alice());
+        assert_eq!(rest, "");
+        let (_, rest) = split_user("@alice:example.org \t being rude ")
+            .unwrap()
+            .unwrap();
+        assert_eq!(rest, "being rude");
+        assert!(split_user("alice being rude").unwrap().is_err());
+    }
+
+    #[test]
+    fn help_command() {
+        assert!(matches!(parsed("/help"), SlashCommand::Help(None)));
+        assert!(matches!(parsed("/help /kick"), SlashCommand::Help(Some(c)) if c == "kick"));
+        assert_eq!(
+            help(Some("kick")).unwrap(),
+            "/kick <user> [reason]: Remove a user from the room"
+        );
+        assert!(help(Some("frobnicate")).is_err());
+        let all = help(None).unwrap();
+        assert_eq!(all.lines().count(), COMMANDS.len() + 1);
+        assert!(all.contains("/devtools: Show developer tools for the room"));
+    }
+
+    #[test]
+    fn suggest() {
+        let names = |input| {
+            suggestions(input)
+                .iter()
+                .map(|info| info.name)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(names("/m"), ["me", "myroomnick"]);
+        assert_eq!(names("/to"), ["topic"]);
+        assert_eq!(names("/").len(), COMMANDS.len());
+        assert!(names("/x").is_empty());
+        // Not while typing the arguments, escaped messages or other messages
+        assert!(names("/me ").is_empty());
+        assert!(names("//m").is_empty());
+        assert!(names("m").is_empty());
+    }
+
+    #[test]
+    fn completion() {
+        // Only one command left, so it's completed with room for the arguments
+        assert_eq!(complete("/to").as_deref(), Some("/topic "));
+        assert_eq!(complete("/myr").as_deref(), Some("/myroomnick "));
+        // Several commands, so only as far as they agree
+        assert_eq!(complete("/m").as_deref(), Some("/m"));
+        assert_eq!(complete("/b").as_deref(), Some("/ban "));
+        assert_eq!(complete("/").as_deref(), Some("/"));
+        assert_eq!(complete("/x"), None);
+        assert_eq!(complete("//m"), None);
+    }
+
+    #[test]
+    fn prefix() {
+        let names = vec!["myroomavatar", "myroomnick", "myrooms"];
+        assert_eq!(common_prefix("myroomname", names.into_iter()), "myroom");
+        assert_eq!(common_prefix("me", std::iter::once("myroomnick")), "m");
+        assert_eq!(common_prefix("nick", std::iter::once("myroomnick")), "");
+        assert_eq!(common_prefix("topic", std::iter::empty()), "topic");
+        // Not cut in the middle of a character
+        assert_eq!(common_prefix("né", std::iter::once("nè")), "n");
+    }
+
+    #[test]
+    fn strip() {
+        assert_eq!(strip_tags("<b>bold</b> text"), "bold text");
+        assert_eq!(
+            strip_tags("<a href=\"https://example.org\">link</a>"),
+            "link"
+        );
+        assert_eq!(strip_tags("a &lt;b&gt; c"), "a <b> c");
+        assert_eq!(
+            strip_tags("&quot;quoted&quot; &#39;too&#39;"),
+            "\"quoted\" 'too'"
+        );
+        // Decoded once, so escaped entities stay entities
+        assert_eq!(strip_tags("&amp;lt;"), "&lt;");
+        assert_eq!(strip_tags("1 > 0"), "1 > 0");
+        assert_eq!(strip_tags("unclosed <b"), "unclosed ");
+    }
+}
@@ -0,0 +1,64 @@
+//! Message edits, which replace the content of an earlier message.
+
+use matrix_sdk::{
+    events::{
+        room::message::{MessageEvent, MessageEventContent, Relation, Replacement},
+        AnyMessageEvent, AnyRoomEvent,
+    },
+    identifiers::EventId,
+};
+
+use super::message_body_mut;
+
+/// Gets the id of the event a message is an edit of
+pub fn replaces(event: &AnyRoomEvent) -> Option<&EventId> {
+    match event {
+        AnyRoomEvent::Message(AnyMessageEvent::RoomMessage(MessageEvent {
+            content:
+                MessageEventContent {
+                    relates_to: Some(Relation::Replacement(replacement)),
+                    ..
+                },
+            ..
+        })) => Some(&replacement.event_id),
+        _ => None,
+    }
+}
+
+/// Gets the new content of an edit. Falls back to the content of the edit event itself,
+/// minus the leading `* `, for clients that left out `m.new_content`.
+pub fn new_content(edit: &MessageEvent) -> MessageEventContent {
+    match edit.content.new_content {
+        Some(ref content) => (**content).clone(),
+        None => {
+            let mut content = edit.content.clone();
+            content.relates_to = None;
+            if let Some((body, formatted)) = message_body_mut(&mut content.msgtype) {
+                if let Some(stripped) = body.strip_prefix("* ") {
+                    *body = stripped.to_owned();
+                }
+                if let Some(formatted) = formatted {
+                    if let Some(stripped) = formatted.body.strip_prefix("* ") {
+                        formatted.body = stripped.to_owned();
+                    }
+                }
+            }
+            content
+        }
+    }
+}
+
+/// Turn `content` into an edit of the message with id `original`. The edit's own body is the
+/// new body prefixed with `*`, the usual fallback for clients that don't support edits.
+pub fn make_edit(original: EventId, content: MessageEventContent) -> MessageEventContent {
+    let mut edit = content.clone();
+    if let Some((body, formatted)) = message_body_mut(&mut edit.msgtype) {
+        *body = format!("* {}", body);
+        if let Some(formatted) = formatted {
+            formatted.body = format!("* {}", formatted.body);
+        }
+    }
+    edit.relates_to = Some(Relation::Replacement(Replacement::new(original)));
+    edit.new_content = Some(Box::new(content));
+    edit
+}
@@ -0,0 +1,261 @@
+//! Attachments of file, audio and video messages, and saving media to disk.
+
+use std::{convert::TryFrom, ffi::OsStr, path::PathBuf};
+
+use matrix_sdk::{events::room::message::MessageType, Client};
+
+use super::{cache::SharedCache, download, message_media, Error, ImageSource, Media};
+
+/// A file attached to a message
+#[derive(Clone, Debug)]
+pub struct Attachment {
+    /// File name, from the message body
+    pub name: String,
+    /// Size in bytes, if the sender included it
+    pub size: Option<u64>,
+    /// MIME type, if the sender included it
+    pub mimetype: Option<String>,
+    pub media: Media,
+    /// Thumbnail of a video
+    pub thumbnail: Option<ImageSource>,
+}
+
+/// Gets the attachment of a file, audio or video message
+pub fn message_attachment(msgtype: &MessageType) -> Option<Attachment> {
+    let media = message_media(msgtype)?;
+    let size = |size: Option<_>| size.map(u64::from);
+    let (name, size, mimetype, thumbnail) = match msgtype {
+        MessageType::File(file) => {
+            let info = file.info.as_deref();
+            (
+                &file.body,
+                size(info.and_then(|i| i.size)),
+                info.and_then(|i| i.mimetype.clone()),
+                None,
+            )
+        }
+        MessageType::Audio(audio) => {
+            let info = audio.info.as_deref();
+            (
+                &audio.body,
+                size(info.and_then(|i| i.size)),
+                info.and_then(|i| i.mimetype.clone()),
+                None,
+            )
+        }
+        MessageType::Video(video) => {
+            let info = video.info.as_deref();
+            let thumbnail = info.and_then(|info| {
+                let media = match (&info.thumbnail_url, &info.thumbnail_file) {
+                    (_, Some(file)) => Media::Encrypted(file.clone()),
+                    (Some(url), None) => Media::Plain(url.clone()),
+                    (None, None) => return None,
+                };
+                let thumbnail_info = info.thumbnail_info.as_deref();
+                let dimension = |d: Option<_>| d.and_then(|d| u32::try_from(u64::from(d)).ok());
+                Some(ImageSource {
+                    media,
+                    dimensions: thumbnail_info
+                        .and_then(|i| Some((dimension(i.width)?, dimension(i.height)?))),
+                    mimetype: thumbnail_info.and_then(|i| i.mimetype.clone()),
+                    thumbnail: None,
+                })
+            });
+            (
+                &video.body,
+                size(info.and_then(|i| i.size)),
+                info.and_then(|i| i.mimetype.clone()),
+                thumbnail,
+            )
+        }
+        _ => return None,
+    };
+    Some(Attachment {
+        name: name.clone(),
+        size,
+        mimetype,
+        media,
+        thumbnail,
+    })
+}
+
+/// Longest file name we save under, in characters
+const MAX_NAME_LENGTH: usize = 200;
+/// Device names Windows reserves in every directory, whatever the extension
+const RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Characters that change the direction of text, which can make a name look like it has
+/// another extension
+fn is_direction_control(c: char) -> bool {
+    matches!(
+        c,
+        '\u{200e}' | '\u{200f}' | '\u{202a}'..='\u{202e}' | '\u{2066}'..='\u{2069}'
+    )
+}
+
+/// Make a file name picked by the sender safe to save under. Path separators and characters
+/// that aren't allowed on some systems are replaced, so the file can't end up outside the
+/// download directory, and names that would be hidden, reserved or shown misleadingly (with
+/// bidirectional text overrides hiding the real extension) are changed.
+fn sanitize_name(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .filter(|&c| !is_direction_control(c))
+        .map(|c| match c {
+            '/' | '\\' | '<' | '>' | ':' | '"' | '|' | '?' | '*' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    // Leading dots would hide the file, and Windows drops trailing dots and spaces
+    let mut name = name
+        .trim_matches(|c: char| c == '.' || c.is_whitespace())
+        .to_owned();
+    if name.chars().count() > MAX_NAME_LENGTH {
+        // Keep the extension, which decides what opens the file
+        let (stem, extension) = match name.rfind('.') {
+            Some(idx) if name.len() - idx <= 16 => name.split_at(idx),
+            _ => (name.as_str(), ""),
+        };
+        let stem: String = stem
+            .chars()
+            .take(MAX_NAME_LENGTH - extension.chars().count())
+            .collect();
+        name = format!("{}{}", stem.trim_end(), extension);
+    }
+    let stem = name.split('.').next().unwrap_or_default();
+    if RESERVED_NAMES.iter().any(|r| r.eq_ignore_ascii_case(stem)) {
+        name.insert(0, '_');
+    }
+    if name.is_empty() {
+        name.push_str("download");
+    }
+    name
+}
+
+/// Where to save a file by default: the download directory, with a number added to the name if
+/// there's already a file with that name
+pub fn default_path(name: &str) -> PathBuf {
+    let dir = dirs::download_dir()
+        .or_else(dirs::home_dir)
+        .unwrap_or_default();
+    let name = sanitize_name(name);
+    let path = dir.join(&name);
+    if !path.exists() {
+        return path;
+    }
+    let (stem, extension) = match name.rfind('.') {
+        Some(idx) if idx > 0 => name.split_at(idx),
+        _ => (name.as_str(), ""),
+    };
+    (1..)
+        .map(|n| dir.join(format!("{} ({}){}", stem, n, extension)))
+        .find(|path| !path.exists())
+        .unwrap()
+}
+
+/// Download media, decrypting it if needed, and save it to `path`
+pub async fn save(
+    client: Client,
+    cache: SharedCache,
+    media: Media,
+    path: PathBuf,
+) -> Result<PathBuf, Error> {
+    let data = download(client, cache, media).await?;
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(&path, data)?;
+    Ok(path)
+}
+
+/// Open a file or URL with the default application for it
+#[cfg(target_os = "windows")]
+pub fn open(target: impl AsRef<OsStr>) -> Result<(), Error> {
+    use std::{iter, os::windows::ffi::OsStrExt, ptr};
+    use winapi::um::{shellapi::ShellExecuteW, winuser::SW_SHOWNORMAL};
+
+    let wide = |s: &OsStr| -> Vec<u16> { s.encode_wide().chain(iter::once(0)).collect() };
+    let target = target.as_ref();
+    let operation = wide(OsStr::new("open"));
+    let file = wide(target);
+    // Unlike `start`, this doesn't go through a shell that would interpret characters like `&`
+    // in file names and URLs
+    let result = unsafe {
+        ShellExecuteW(
+            ptr::null_mut(),
+            operation.as_ptr(),
+            file.as_ptr(),
+            ptr::null(),
+            ptr::null(),
+            SW_SHOWNORMAL,
+        )
+    };
+    // Anything above 32 means it worked
+    anyhow::ensure!(
+        result as isize > 32,
+        "Couldn't open {}",
+        target.to_string_lossy()
+    );
+    Ok(())
+}
+
+/// Open a file or URL with the default application for it
+#[cfg(not(target_os = "windows"))]
+pub fn open(target: impl AsRef<OsStr>) -> Result<(), Error> {
+    let program = if cfg!(target_os = "macos") {
+        "open"
+    } else {
+        "xdg-open"
+    };
+    std::process::Command::new(program).arg(target).spawn()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sanitize() {
+        assert_eq!(sanitize_name("report.pdf"), "report.pdf");
+        assert_eq!(
+            sanitize_name("Holiday photo (1).jpg"),
+            "Holiday photo (1).jpg"
+        );
+        // Paths stay in the download directory
+        assert_eq!(sanitize_name("../../.bashrc"), "_.._.bashrc");
+        assert_eq!(sanitize_name("/etc/passwd"), "_etc_passwd");
+        assert_eq!(sanitize_name("..\\..\\evil.exe"), "_.._evil.exe");
+        assert_eq!(sanitize_name("C:\\Windows\\win.ini"), "C__Windows_win.ini");
+        // Nothing hidden, and nothing Windows would change
+        assert_eq!(sanitize_name(".hidden"), "hidden");
+        assert_eq!(sanitize_name("name. . "), "name");
+        assert_eq!(sanitize_name("a&calc.exe"), "a&calc.exe");
+        assert_eq!(sanitize_name("what?.txt"), "what_.txt");
+        assert_eq!(sanitize_name("line\nbreak"), "line_break");
+        // Bidirectional overrides can't disguise the extension
+        assert_eq!(sanitize_name("invoice\u{202e}fdp.exe"), "invoicefdp.exe");
+        // Reserved names on Windows
+        assert_eq!(sanitize_name("CON"), "_CON");
+        assert_eq!(sanitize_name("nul.txt"), "_nul.txt");
+        assert_eq!(sanitize_name("console.txt"), "console.txt");
+        // Something is always left
+        assert_eq!(sanitize_name(""), "download");
+        assert_eq!(sanitize_name(".."), "download");
+        assert_eq!(sanitize_name(" . "), "download");
+    }
+
+    #[test]
+    fn sanitize_long_names() {
+        let name = format!("{}.tar.gz", "a".repeat(300));
+        let sanitized = sanitize_name(&name);
+        assert_eq!(sanitized.chars().count(), MAX_NAME_LENGTH);
+        assert!(sanitized.ends_with("a.gz"));
+        let name = "ü".repeat(300);
+        assert_eq!(sanitize_name(&name).chars().count(), MAX_NAME_LENGTH);
+    }
+}
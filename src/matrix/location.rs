@@ -0,0 +1,281 @@
+//! Location messages, with `geo:` URIs as in RFC 5870 and the extra fields of MSC3488.
+
+use std::{
+    fmt,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use matrix_sdk::events::AnyMessageEventContent;
+use serde_json::json;
+
+use super::{custom_content, Error};
+
+/// A point on earth, with the uncertainty of the position if known
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Location {
+    /// Degrees north
+    pub latitude: f64,
+    /// Degrees east
+    pub longitude: f64,
+    /// Metres above sea level
+    pub altitude: Option<f64>,
+    /// Radius of the area the location is in, in metres
+    pub uncertainty: Option<f64>,
+}
+
+impl Location {
+    /// Parse a `geo:` URI, like `geo:51.5008,0.1247;u=35`
+    pub fn parse(uri: &str) -> Result<Self, Error> {
+        let rest = match uri.get(..4) {
+            Some(scheme) if scheme.eq_ignore_ascii_case("geo:") => &uri[4..],
+            _ => return Err(anyhow::anyhow!("Not a geo: URI")),
+        };
+        let mut parts = rest.split(';');
+        let coordinates: Vec<&str> = parts.next().unwrap_or_default().split(',').collect();
+        let number = |n: &str| -> Result<f64, Error> {
+            n.trim()
+                .parse::<f64>()
+                .ok()
+                .filter(|n| n.is_finite())
+                .ok_or_else(|| anyhow::anyhow!("Invalid coordinate `{}`", n))
+        };
+        let (latitude, longitude, altitude) = match coordinates[..] {
+            [lat, lon] => (number(lat)?, number(lon)?, None),
+            [lat, lon, alt] => (number(lat)?, number(lon)?, Some(number(alt)?)),
+            _ => {
+                return Err(anyhow::anyhow!(
+                    "A location needs a latitude and a longitude"
+                ))
+            }
+        };
+        let mut uncertainty = None;
+        for param in parts {
+            let (name, value) = match param.find('=') {
+                Some(idx) => (&param[..idx], &param[idx + 1..]),
+                None => (param, ""),
+            };
+            match name.to_ascii_lowercase().as_str() {
+                "crs" if !value.eq_ignore_ascii_case("wgs84") => {
+                    return Err(anyhow::anyhow!(
+                        "Unsupported coordinate reference system `{}`",
+                        value
+                    ))
+                }
+                "u" => uncertainty = Some(number(value)?).filter(|u| *u >= 0.0),
+                // Other parameters don't change where the location is
+                _ => (),
+            }
+        }
+        Self::new(latitude, longitude, altitude, uncertainty)
+    }
+
+    /// Parse a location the user typed, either as a `geo:` URI or as `latitude, longitude`
+    pub fn parse_input(input: &str) -> Result<Self, Error> {
+        let input = input.trim();
+        match input.get(..4) {
+            Some(scheme) if scheme.eq_ignore_ascii_case("geo:") => Self::parse(input),
+            _ => Self::parse(&format!("geo:{}", input.replace(' ', ""))),
+        }
+    }
+
+    fn new(
+        latitude: f64,
+        longitude: f64,
+        altitude: Option<f64>,
+        uncertainty: Option<f64>,
+    ) -> Result<Self, Error> {
+        anyhow::ensure!(
+            (-90.0..=90.0).contains(&latitude),
+            "Latitude must be between -90 and 90"
+        );
+        anyhow::ensure!(
+            (-180.0..=180.0).contains(&longitude),
+            "Longitude must be between -180 and 180"
+        );
+        Ok(Self {
+            latitude,
+            longitude,
+            altitude,
+            uncertainty,
+        })
+    }
+
+    /// The `geo:` URI of the location
+    pub fn uri(&self) -> String {
+        let mut uri = format!("geo:{},{}", self.latitude, self.longitude);
+        if let Some(altitude) = self.altitude {
+            uri.push_str(&format!(",{}", altitude));
+        }
+        if let Some(uncertainty) = self.uncertainty {
+            uri.push_str(&format!(";u={}", uncertainty));
+        }
+        uri
+    }
+
+    /// Link to the location on OpenStreetMap, zoomed to fit the uncertainty
+    pub fn map_url(&self) -> String {
+        let zoom = match self.uncertainty {
+            Some(u) if u > 5000.0 => 10,
+            Some(u) if u > 500.0 => 13,
+            Some(u) if u > 50.0 => 16,
+            _ => 18,
+        };
+        format!(
+            "https://www.openstreetmap.org/?mlat={lat}&mlon={lon}#map={zoom}/{lat}/{lon}",
+            lat = self.latitude,
+            lon = self.longitude,
+            zoom = zoom
+        )
+    }
+}
+
+impl fmt::Display for Location {
+    /// Formats the coordinates like `51.50080° N, 0.12470° E (±35 m)`
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let north = if self.latitude < 0.0 { 'S' } else { 'N' };
+        let east = if self.longitude < 0.0 { 'W' } else { 'E' };
+        write!(
+            f,
+            "{:.5}° {}, {:.5}° {}",
+            self.latitude.abs(),
+            north,
+            self.longitude.abs(),
+            east
+        )?;
+        if let Some(altitude) = self.altitude {
+            write!(f, ", {} m", altitude)?;
+        }
+        if let Some(uncertainty) = self.uncertainty {
+            write!(f, " (±{} m)", uncertainty)?;
+        }
+        Ok(())
+    }
+}
+
+/// Text used as the body of a location message. Clients that don't know about locations show
+/// this instead.
+fn body(location: &Location, description: Option<&str>) -> String {
+    match description {
+        Some(description) => format!("{} ({})", description, location.uri()),
+        None => format!("Location {}", location.uri()),
+    }
+}
+
+/// Content of a location message, with the MSC3488 fields as well as the ones the SDK knows
+/// about, which clients without MSC3488 support use
+pub fn content(location: &Location, description: Option<&str>) -> AnyMessageEventContent {
+    let body = body(location, description);
+    let mut content = json!({
+        "msgtype": "m.location",
+        "body": body,
+        "geo_uri": location.uri(),
+        "org.matrix.msc1767.text": body,
+        "org.matrix.msc3488.location": { "uri": location.uri() },
+        // Sharing a static location the user entered, not where they are
+        "org.matrix.msc3488.asset": { "type": "m.pin" },
+        "org.matrix.msc3488.ts": SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or_default(),
+    });
+    if let Some(description) = description {
+        content["org.matrix.msc3488.location"]["description"] = json!(description);
+    }
+    custom_content("m.room.message", content)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn location(latitude: f64, longitude: f64) -> Location {
+        Location {
+            latitude,
+            longitude,
+            altitude: None,
+            uncertainty: None,
+        }
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(
+            Location::parse("geo:51.5008,0.1247").unwrap(),
+            location(51.5008, 0.1247)
+        );
+        assert_eq!(
+            Location::parse("GEO:-33.8688,151.2093").unwrap(),
+            location(-33.8688, 151.2093)
+        );
+        let with_extras = Location::parse("geo:48.198634,16.371648,183;u=40").unwrap();
+        assert_eq!(with_extras.altitude, Some(183.0));
+        assert_eq!(with_extras.uncertainty, Some(40.0));
+        // Parameters are case insensitive, and unknown ones are ignored
+        let params = Location::parse("geo:1,2;CRS=WGS84;U=5;foo=bar;flag").unwrap();
+        assert_eq!(params.uncertainty, Some(5.0));
+        assert_eq!(params.latitude, 1.0);
+        // A negative uncertainty doesn't mean anything
+        assert_eq!(Location::parse("geo:1,2;u=-1").unwrap().uncertainty, None);
+    }
+
+    #[test]
+    fn parse_errors() {
+        for uri in &[
+            "",
+            "geo",
+            "geo:",
+            "geo:51.5",
+            "geo:1,2,3,4",
+            "geo:north,east",
+            "geo:91,0",
+            "geo:0,-181",
+            "geo:NaN,0",
+            "geo:inf,0",
+            "geo:1,2;u=wide",
+            "geo:1,2;crs=moon",
+            "https://example.org/geo:1,2",
+        ] {
+            assert!(Location::parse(uri).is_err(), "{} should be rejected", uri);
+        }
+        // The limits themselves are fine
+        assert!(Location::parse("geo:90,-180").is_ok());
+        assert!(Location::parse("geo:-90,180").is_ok());
+    }
+
+    #[test]
+    fn parse_input() {
+        let expected = location(51.5008, 0.1247);
+        assert_eq!(Location::parse_input("51.5008, 0.1247").unwrap(), expected);
+        assert_eq!(Location::parse_input(" 51.5008,0.1247 ").unwrap(), expected);
+        assert_eq!(
+            Location::parse_input("geo:51.5008,0.1247").unwrap(),
+            expected
+        );
+        assert!(Location::parse_input("London").is_err());
+    }
+
+    #[test]
+    fn round_trip() {
+        let location = Location {
+            latitude: 48.198634,
+            longitude: 16.371648,
+            altitude: Some(183.0),
+            uncertainty: Some(40.0),
+        };
+        assert_eq!(location.uri(), "geo:48.198634,16.371648,183;u=40");
+        assert_eq!(Location::parse(&location.uri()).unwrap(), location);
+    }
+
+    #[test]
+    fn display() {
+        assert_eq!(
+            location(51.5008, -0.1247).to_string(),
+            "51.50080° N, 0.12470° W"
+        );
+        let location = Location {
+            uncertainty: Some(35.0),
+            ..location(-33.8688, 151.2093)
+        };
+        assert_eq!(location.to_string(), "33.86880° S, 151.20930° E (±35 m)");
+    }
+}
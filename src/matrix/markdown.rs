@@ -0,0 +1,172 @@
+//! Rendering of composed messages from CommonMark to the HTML subset matrix allows.
+
+use matrix_sdk::events::room::message::{
+    EmoteMessageEventContent, MessageEventContent, MessageType,
+};
+use pulldown_cmark::{html, Event, Options, Parser, Tag};
+
+/// URL schemes links are allowed to have in formatted messages
+const ALLOWED_SCHEMES: &[&str] = &["http", "https", "ftp", "mailto", "magnet"];
+
+/// Create the content for a text message, with a formatted body if the text contains markdown
+/// and `markdown` is enabled. The text itself is always used as the plain body.
+pub fn text_content(text: String, markdown: bool) -> MessageEventContent {
+    match markdown.then(|| render(&text)).flatten() {
+        Some(html) => MessageEventContent::text_html(text, html),
+        None => MessageEventContent::text_plain(text),
+    }
+}
+
+/// Create the content for an emote, formatted the same way as [`text_content`]
+pub fn emote_content(text: String, markdown: bool) -> MessageEventContent {
+    let emote = match markdown.then(|| render(&text)).flatten() {
+        Some(html) => EmoteMessageEventContent::html(text, html),
+        None => EmoteMessageEventContent::plain(text),
+    };
+    MessageEventContent::new(MessageType::Emote(emote))
+}
+
+/// Render markdown to sanitized HTML. Returns `None` if the text has no formatting, in which
+/// case it should be sent as plain text.
+pub fn render(text: &str) -> Option<String> {
+    let options = Options::ENABLE_STRIKETHROUGH | Options::ENABLE_TABLES;
+    // Whether we're inside a link or image we've dropped
+    let mut dropped = false;
+    let events = Parser::new_ext(text, options).filter_map(|event| match event {
+        // Raw HTML isn't trusted, so show it as written
+        Event::Html(html) => Some(Event::Text(html)),
+        // Chat users expect line breaks to be kept
+        Event::SoftBreak => Some(Event::HardBreak),
+        Event::Start(Tag::Link(_, ref url, _)) if !allowed_link(url) => {
+            dropped = true;
+            None
+        }
+        // Only images hosted on matrix can be shown
+        Event::Start(Tag::Image(_, ref url, _)) if !url.starts_with("mxc://") => {
+            dropped = true;
+            None
+        }
+        Event::End(Tag::Link(..)) | Event::End(Tag::Image(..)) if dropped => {
+            dropped = false;
+            None
+        }
+        event => Some(event),
+    });
+    let mut html = String::new();
+    html::push_html(&mut html, events);
+    let html = html.trim_end();
+
+    // A single paragraph with nothing special in it is just plain text
+    let inner = html
+        .strip_prefix("<p>")
+        .and_then(|h| h.strip_suffix("</p>"))
+        .filter(|h| !h.contains("<p>"));
+    match inner {
+        Some(inner) if inner.trim() == escape(text.trim()) => None,
+        Some(inner) => Some(inner.to_owned()),
+        None => Some(html.to_owned()),
+    }
+}
+
+/// Whether a link destination uses a scheme that's allowed in formatted messages
+pub fn allowed_link(url: &str) -> bool {
+    match url.split_once(':') {
+        Some((scheme, _)) => ALLOWED_SCHEMES
+            .iter()
+            .any(|allowed| scheme.eq_ignore_ascii_case(allowed)),
+        // Relative links don't make sense in a message
+        None => false,
+    }
+}
+
+/// Escape text for inclusion in HTML, the same way the rendered markdown is escaped
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plain_text_has_no_formatting() {
+        assert_eq!(render("hello"), None);
+        assert_eq!(render("  hello  "), None);
+        assert_eq!(render("fish & chips"), None);
+        assert_eq!(render("1 < 2 > 0"), None);
+        assert_eq!(render("say \"hi\""), None);
+        // Raw HTML is shown as written
+        assert_eq!(render("<b>not bold</b>"), None);
+    }
+
+    #[test]
+    fn single_paragraph_is_unwrapped() {
+        assert_eq!(render("*hi*").as_deref(), Some("<em>hi</em>"));
+        assert_eq!(
+            render("**bold** & <i>").as_deref(),
+            Some("<strong>bold</strong> &amp; &lt;i&gt;")
+        );
+        assert_eq!(render("a\nb").as_deref(), Some("a<br />\nb"));
+        assert_eq!(render("~~gone~~").as_deref(), Some("<del>gone</del>"));
+    }
+
+    #[test]
+    fn blocks_are_kept() {
+        assert_eq!(
+            render("one\n\ntwo").as_deref(),
+            Some("<p>one</p>\n<p>two</p>")
+        );
+        assert_eq!(
+            render("```\nlet a = 1 < 2;\n```").as_deref(),
+            Some("<pre><code>let a = 1 &lt; 2;\n</code></pre>")
+        );
+        assert_eq!(
+            render("> quoted").as_deref(),
+            Some("<blockquote>\n<p>quoted</p>\n</blockquote>")
+        );
+        assert_eq!(
+            render("- a\n- b").as_deref(),
+            Some("<ul>\n<li>a</li>\n<li>b</li>\n</ul>")
+        );
+    }
+
+    #[test]
+    fn links_are_filtered() {
+        assert_eq!(
+            render("[site](https://example.org)").as_deref(),
+            Some("<a href=\"https://example.org\">site</a>")
+        );
+        assert_eq!(
+            render("[click](javascript:alert(1))").as_deref(),
+            Some("click")
+        );
+        assert_eq!(render("[here](/relative)").as_deref(), Some("here"));
+        assert_eq!(
+            render("![cat](https://example.org/cat.png)").as_deref(),
+            Some("cat")
+        );
+        assert_eq!(
+            render("![cat](mxc://example.org/cat)").as_deref(),
+            Some("<img src=\"mxc://example.org/cat\" alt=\"cat\" />")
+        );
+    }
+
+    #[test]
+    fn escaping() {
+        assert_eq!(escape("plain"), "plain");
+        assert_eq!(
+            escape("<a href=\"x\">&</a>"),
+            "&lt;a href=&quot;x&quot;&gt;&amp;&lt;/a&gt;"
+        );
+    }
+}
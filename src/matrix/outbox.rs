@@ -0,0 +1,150 @@
+//! Messages waiting to be sent, with the transaction IDs that match them to their remote echo.
+//!
+//! The queue is kept on disk, so messages written while offline are still sent after a restart.
+
+use std::{path::PathBuf, time::SystemTime};
+
+use matrix_sdk::{
+    events::{AnyMessageEventContent, EventContent},
+    identifiers::{EventId, RoomId},
+    uuid::Uuid,
+    Client,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::value::{to_raw_value, RawValue};
+
+use super::Error;
+
+/// How far a message in the queue has come
+#[derive(Clone, Debug, PartialEq)]
+pub enum State {
+    /// Waiting until we're online
+    Queued,
+    /// Being sent
+    Sending,
+    /// Accepted by the server, waiting for it to come back through sync
+    Sent(EventId),
+    /// Couldn't be sent, waiting for the user to retry or delete it
+    Failed(String),
+}
+
+/// A message we're sending
+#[derive(Clone, Debug)]
+pub struct Outgoing {
+    pub room_id: RoomId,
+    /// Transaction ID, which the server includes in the remote echo
+    pub txn_id: String,
+    pub content: AnyMessageEventContent,
+    /// When the message was written
+    pub created: SystemTime,
+    pub state: State,
+}
+
+impl Outgoing {
+    pub fn new(room_id: RoomId, content: AnyMessageEventContent) -> Self {
+        Self {
+            room_id,
+            txn_id: Uuid::new_v4().to_string(),
+            content,
+            created: SystemTime::now(),
+            state: State::Queued,
+        }
+    }
+}
+
+/// How a message in the queue is stored
+#[derive(Serialize, Deserialize)]
+struct Stored {
+    room_id: RoomId,
+    txn_id: String,
+    event_type: String,
+    content: Box<RawValue>,
+    created: SystemTime,
+    /// Why sending failed, for messages that are waiting to be retried
+    error: Option<String>,
+    /// Whether the content has fields the SDK doesn't know about, which parsing it would drop
+    #[serde(default)]
+    custom: bool,
+}
+
+/// File the queue is stored in
+fn path() -> PathBuf {
+    dirs::config_dir()
+        .unwrap()
+        .join("retrix")
+        .join("outbox.json")
+}
+
+/// Read the queue. Messages that were being sent when we quit are queued to be sent again,
+/// since we don't know whether they arrived.
+pub fn load() -> Result<Vec<Outgoing>, Error> {
+    let path = path();
+    if !path.is_file() {
+        return Ok(Vec::new());
+    }
+    let stored: Vec<Stored> = serde_json::from_slice(&std::fs::read(path)?)?;
+    Ok(stored
+        .into_iter()
+        .filter_map(|stored| {
+            let content = match stored.custom {
+                true => super::custom_content(
+                    &stored.event_type,
+                    serde_json::from_str(stored.content.get()).ok()?,
+                ),
+                false => {
+                    AnyMessageEventContent::from_parts(&stored.event_type, stored.content).ok()?
+                }
+            };
+            Some(Outgoing {
+                room_id: stored.room_id,
+                txn_id: stored.txn_id,
+                content,
+                created: stored.created,
+                state: match stored.error {
+                    Some(error) => State::Failed(error),
+                    None => State::Queued,
+                },
+            })
+        })
+        .collect())
+}
+
+/// Write the queue. Messages the server has accepted are left out.
+pub fn save<'a>(messages: impl Iterator<Item = &'a Outgoing>) -> Result<(), Error> {
+    let stored = messages
+        .filter(|message| !matches!(message.state, State::Sent(_)))
+        .map(|message| {
+            Ok(Stored {
+                room_id: message.room_id.clone(),
+                txn_id: message.txn_id.clone(),
+                event_type: message.content.event_type().to_owned(),
+                content: to_raw_value(&message.content)?,
+                created: message.created,
+                error: match message.state {
+                    State::Failed(ref error) => Some(error.clone()),
+                    _ => None,
+                },
+                custom: matches!(message.content, AnyMessageEventContent::Custom(_)),
+            })
+        })
+        .collect::<Result<Vec<_>, Error>>()?;
+    let path = path();
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(path, serde_json::to_vec(&stored)?)?;
+
+    Ok(())
+}
+
+/// Send a message with its transaction ID, so sending it twice only sends it once
+pub async fn send(
+    client: Client,
+    room_id: RoomId,
+    txn_id: String,
+    content: AnyMessageEventContent,
+) -> Result<EventId, Error> {
+    let txn_id = Uuid::parse_str(&txn_id)?;
+    let response = client.room_send(&room_id, content, Some(txn_id)).await?;
+    Ok(response.event_id)
+}
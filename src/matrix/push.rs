@@ -0,0 +1,1424 @@
+//! Client-side evaluation of push rules.
+//!
+//! The homeserver evaluates `m.push_rules` to decide what to send to push gateways, but it
+//! doesn't tell clients the outcome for events received through sync, so we have to evaluate
+//! the rules ourselves to know what counts as a notification or a mention.
+
+use std::{collections::BTreeMap, convert::TryFrom};
+
+use matrix_sdk::{
+    api::r0::push::{
+        delete_pushrule, get_pushrules_all, set_pushrule, set_pushrule_enabled,
+        RuleKind as ApiRuleKind, RuleScope,
+    },
+    events::AnyRoomEvent,
+    identifiers::{RoomId, UserId},
+    Client,
+};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::Value as JsonValue;
+
+use super::Error;
+
+/// Power level required to trigger a room-wide notification if none is set.
+const DEFAULT_NOTIFICATION_LEVEL: i64 = 50;
+
+/// The `global` push rule set of an account.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Ruleset {
+    /// Rules that override all other rules
+    #[serde(rename = "override", default)]
+    pub override_: Vec<PushRule>,
+    /// Rules matching the body of messages
+    #[serde(default)]
+    pub content: Vec<PushRule>,
+    /// Rules for all messages in a given room
+    #[serde(default)]
+    pub room: Vec<PushRule>,
+    /// Rules for all messages from a given user
+    #[serde(default)]
+    pub sender: Vec<PushRule>,
+    /// Fallback rules, evaluated last
+    #[serde(default)]
+    pub underride: Vec<PushRule>,
+}
+
+/// The kind of a push rule, which determines how it's matched and its precedence.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RuleKind {
+    Override,
+    Content,
+    Room,
+    Sender,
+    Underride,
+}
+
+impl RuleKind {
+    /// All kinds, in the order they're evaluated.
+    pub const ALL: [RuleKind; 5] = [
+        RuleKind::Override,
+        RuleKind::Content,
+        RuleKind::Room,
+        RuleKind::Sender,
+        RuleKind::Underride,
+    ];
+
+    /// The name of the rule kind as used in the client-server API.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            RuleKind::Override => "override",
+            RuleKind::Content => "content",
+            RuleKind::Room => "room",
+            RuleKind::Sender => "sender",
+            RuleKind::Underride => "underride",
+        }
+    }
+
+    /// Convert to the kind used by matrix-sdk requests
+    fn to_api(self) -> ApiRuleKind {
+        match self {
+            RuleKind::Override => ApiRuleKind::Override,
+            RuleKind::Content => ApiRuleKind::Content,
+            RuleKind::Room => ApiRuleKind::Room,
+            RuleKind::Sender => ApiRuleKind::Sender,
+            RuleKind::Underride => ApiRuleKind::Underride,
+        }
+    }
+}
+
+/// How much a room should notify, expressed through push rules the same way other clients do.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RoomMode {
+    /// Use the account defaults, i.e. no rules for the room
+    AllMessages,
+    /// A room rule that doesn't notify, so only mentions and keywords do
+    MentionsAndKeywords,
+    /// An override rule that doesn't notify for anything
+    Mute,
+}
+
+impl Default for RoomMode {
+    fn default() -> Self {
+        RoomMode::AllMessages
+    }
+}
+
+/// A single push rule.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PushRule {
+    /// Identifier of the rule. For room and sender rules, this is the room or user id.
+    pub rule_id: String,
+    /// Whether this is a server-default rule
+    #[serde(default)]
+    pub default: bool,
+    /// Whether the rule is enabled
+    pub enabled: bool,
+    /// What to do when the rule matches
+    #[serde(default)]
+    pub actions: Vec<Action>,
+    /// Conditions that all must hold for override and underride rules
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub conditions: Option<Vec<Condition>>,
+    /// Glob pattern to match the body against for content rules
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pattern: Option<String>,
+}
+
+/// A condition for a push rule.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Condition {
+    /// A glob pattern matched against a dot-separated field of the event
+    EventMatch { key: String, pattern: String },
+    /// The body contains our display name
+    ContainsDisplayName,
+    /// The number of joined members compared to a value, e.g. `"2"`, `">=10"`
+    RoomMemberCount { is: String },
+    /// The sender has the power level needed for the given notification kind
+    SenderNotificationPermission { key: String },
+    /// A condition we don't understand, which never matches
+    #[serde(other)]
+    Unknown,
+}
+
+/// An action to take when a rule matches.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Action {
+    Notify,
+    DontNotify,
+    Coalesce,
+    SetTweak(Tweak),
+    /// An action we don't know, which is ignored
+    Unknown(String),
+}
+
+/// Changes to how a notification is presented.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Tweak {
+    /// Sound to play
+    Sound(String),
+    /// Whether the event should be highlighted
+    Highlight(bool),
+    /// A tweak we don't know the meaning of
+    Custom(String, JsonValue),
+}
+
+impl<'de> Deserialize<'de> for Action {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        struct TweakObject {
+            set_tweak: String,
+            value: Option<JsonValue>,
+        }
+
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Repr {
+            Name(String),
+            Tweak(TweakObject),
+        }
+
+        match Repr::deserialize(deserializer)? {
+            Repr::Name(name) => match name.as_str() {
+                "notify" => Ok(Action::Notify),
+                "dont_notify" => Ok(Action::DontNotify),
+                "coalesce" => Ok(Action::Coalesce),
+                _ => Ok(Action::Unknown(name)),
+            },
+            Repr::Tweak(TweakObject { set_tweak, value }) => {
+                let tweak = match (set_tweak.as_str(), value) {
+                    ("sound", Some(JsonValue::String(sound))) => Tweak::Sound(sound),
+                    ("highlight", None) => Tweak::Highlight(true),
+                    ("highlight", Some(JsonValue::Bool(highlight))) => Tweak::Highlight(highlight),
+                    (_, value) => Tweak::Custom(set_tweak, value.unwrap_or(JsonValue::Null)),
+                };
+                Ok(Action::SetTweak(tweak))
+            }
+        }
+    }
+}
+
+impl Serialize for Action {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let value = match self {
+            Action::Notify => JsonValue::from("notify"),
+            Action::DontNotify => JsonValue::from("dont_notify"),
+            Action::Coalesce => JsonValue::from("coalesce"),
+            Action::Unknown(name) => JsonValue::from(name.as_str()),
+            Action::SetTweak(Tweak::Sound(sound)) => {
+                serde_json::json!({ "set_tweak": "sound", "value": sound })
+            }
+            Action::SetTweak(Tweak::Highlight(highlight)) => {
+                serde_json::json!({ "set_tweak": "highlight", "value": highlight })
+            }
+            Action::SetTweak(Tweak::Custom(name, value)) => {
+                serde_json::json!({ "set_tweak": name, "value": value })
+            }
+        };
+        value.serialize(serializer)
+    }
+}
+
+/// Information about the room and our user needed to evaluate rules.
+#[derive(Clone, Debug)]
+pub struct PushContext {
+    /// Our own user id
+    pub user_id: UserId,
+    /// Our display name in the room
+    pub display_name: Option<String>,
+    /// Number of joined members in the room
+    pub member_count: u64,
+    /// Power levels of users in the room
+    pub users: BTreeMap<UserId, i64>,
+    /// Power level of users not in `users`
+    pub users_default: i64,
+    /// Power levels required for notification kinds, e.g. `room`
+    pub notifications: BTreeMap<String, i64>,
+}
+
+impl PushContext {
+    /// Create a context with no power levels set.
+    pub fn new(user_id: UserId, display_name: Option<String>, member_count: u64) -> Self {
+        Self {
+            user_id,
+            display_name,
+            member_count,
+            users: BTreeMap::new(),
+            users_default: 0,
+            notifications: BTreeMap::new(),
+        }
+    }
+
+    /// Build the context from the state of a joined room.
+    pub async fn from_room(room: &matrix_sdk::room::Joined, user_id: &UserId) -> Self {
+        let display_name = match room.get_member(user_id).await {
+            Ok(Some(member)) => Some(member.name().to_owned()),
+            _ => None,
+        };
+        let mut context = Self::new(user_id.clone(), display_name, room.joined_members_count());
+        if let Some(levels) = room.power_levels() {
+            context.users = levels
+                .users
+                .into_iter()
+                .map(|(user, level)| (user, i64::from(level)))
+                .collect();
+            context.users_default = levels.users_default.into();
+            context
+                .notifications
+                .insert("room".to_owned(), levels.notifications.room.into());
+        }
+        context
+    }
+
+    /// Power level of the given user
+    fn power_level(&self, user: &UserId) -> i64 {
+        self.users.get(user).copied().unwrap_or(self.users_default)
+    }
+}
+
+/// The result of evaluating the rules against an event.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Evaluation {
+    /// Id of the rule that matched, if any
+    pub rule_id: Option<String>,
+    /// The actions of the matching rule
+    pub actions: Vec<Action>,
+}
+
+impl Evaluation {
+    /// Whether the event should cause a notification
+    pub fn notify(&self) -> bool {
+        self.actions
+            .iter()
+            .any(|a| matches!(a, Action::Notify | Action::Coalesce))
+    }
+
+    /// Whether the event should be highlighted, i.e. is a mention
+    pub fn highlight(&self) -> bool {
+        self.actions
+            .iter()
+            .any(|a| a == &Action::SetTweak(Tweak::Highlight(true)))
+    }
+
+    /// The sound to play for the notification, if any
+    pub fn sound(&self) -> Option<&str> {
+        self.actions.iter().find_map(|a| match a {
+            Action::SetTweak(Tweak::Sound(sound)) => Some(sound.as_str()),
+            _ => None,
+        })
+    }
+}
+
+impl Ruleset {
+    /// Parse the content of an `m.push_rules` account data event.
+    pub fn from_account_data(content: &JsonValue) -> Result<Self, serde_json::Error> {
+        match content.get("global") {
+            Some(global) => Self::deserialize(global),
+            None => Ok(Self::default()),
+        }
+    }
+
+    /// The server-default rules as defined by the spec, for use until the real ones are known.
+    pub fn server_default(user_id: &UserId) -> Self {
+        let json = serde_json::json!({
+            "override": [
+                {
+                    "rule_id": ".m.rule.master",
+                    "default": true,
+                    "enabled": false,
+                    "conditions": [],
+                    "actions": ["dont_notify"]
+                },
+                {
+                    "rule_id": ".m.rule.suppress_notices",
+                    "default": true,
+                    "enabled": true,
+                    "conditions": [
+                        { "kind": "event_match", "key": "content.msgtype", "pattern": "m.notice" }
+                    ],
+                    "actions": ["dont_notify"]
+                },
+                {
+                    "rule_id": ".m.rule.invite_for_me",
+                    "default": true,
+                    "enabled": true,
+                    "conditions": [
+                        { "kind": "event_match", "key": "type", "pattern": "m.room.member" },
+                        { "kind": "event_match", "key": "content.membership", "pattern": "invite" },
+                        { "kind": "event_match", "key": "state_key", "pattern": user_id.as_str() }
+                    ],
+                    "actions": [
+                        "notify",
+                        { "set_tweak": "sound", "value": "default" },
+                        { "set_tweak": "highlight", "value": false }
+                    ]
+                },
+                {
+                    "rule_id": ".m.rule.member_event",
+                    "default": true,
+                    "enabled": true,
+                    "conditions": [
+                        { "kind": "event_match", "key": "type", "pattern": "m.room.member" }
+                    ],
+                    "actions": ["dont_notify"]
+                },
+                {
+                    "rule_id": ".m.rule.contains_display_name",
+                    "default": true,
+                    "enabled": true,
+                    "conditions": [{ "kind": "contains_display_name" }],
+                    "actions": [
+                        "notify",
+                        { "set_tweak": "sound", "value": "default" },
+                        { "set_tweak": "highlight" }
+                    ]
+                },
+                {
+                    "rule_id": ".m.rule.tombstone",
+                    "default": true,
+                    "enabled": true,
+                    "conditions": [
+                        { "kind": "event_match", "key": "type", "pattern": "m.room.tombstone" },
+                        { "kind": "event_match", "key": "state_key", "pattern": "" }
+                    ],
+                    "actions": ["notify", { "set_tweak": "highlight" }]
+                },
+                {
+                    "rule_id": ".m.rule.roomnotif",
+                    "default": true,
+                    "enabled": true,
+                    "conditions": [
+                        { "kind": "event_match", "key": "content.body", "pattern": "@room" },
+                        { "kind": "sender_notification_permission", "key": "room" }
+                    ],
+                    "actions": ["notify", { "set_tweak": "highlight" }]
+                }
+            ],
+            "content": [
+                {
+                    "rule_id": ".m.rule.contains_user_name",
+                    "default": true,
+                    "enabled": true,
+                    "pattern": user_id.localpart(),
+                    "actions": [
+                        "notify",
+                        { "set_tweak": "sound", "value": "default" },
+                        { "set_tweak": "highlight" }
+                    ]
+                }
+            ],
+            "underride": [
+                {
+                    "rule_id": ".m.rule.call",
+                    "default": true,
+                    "enabled": true,
+                    "conditions": [
+                        { "kind": "event_match", "key": "type", "pattern": "m.call.invite" }
+                    ],
+                    "actions": [
+                        "notify",
+                        { "set_tweak": "sound", "value": "ring" },
+                        { "set_tweak": "highlight", "value": false }
+                    ]
+                },
+                {
+                    "rule_id": ".m.rule.encrypted_room_one_to_one",
+                    "default": true,
+                    "enabled": true,
+                    "conditions": [
+                        { "kind": "room_member_count", "is": "2" },
+                        { "kind": "event_match", "key": "type", "pattern": "m.room.encrypted" }
+                    ],
+                    "actions": [
+                        "notify",
+                        { "set_tweak": "sound", "value": "default" },
+                        { "set_tweak": "highlight", "value": false }
+                    ]
+                },
+                {
+                    "rule_id": ".m.rule.room_one_to_one",
+                    "default": true,
+                    "enabled": true,
+                    "conditions": [
+                        { "kind": "room_member_count", "is": "2" },
+                        { "kind": "event_match", "key": "type", "pattern": "m.room.message" }
+                    ],
+                    "actions": [
+                        "notify",
+                        { "set_tweak": "sound", "value": "default" },
+                        { "set_tweak": "highlight", "value": false }
+                    ]
+                },
+                {
+                    "rule_id": ".m.rule.message",
+                    "default": true,
+                    "enabled": true,
+                    "conditions": [
+                        { "kind": "event_match", "key": "type", "pattern": "m.room.message" }
+                    ],
+                    "actions": ["notify", { "set_tweak": "highlight", "value": false }]
+                },
+                {
+                    "rule_id": ".m.rule.encrypted",
+                    "default": true,
+                    "enabled": true,
+                    "conditions": [
+                        { "kind": "event_match", "key": "type", "pattern": "m.room.encrypted" }
+                    ],
+                    "actions": ["notify", { "set_tweak": "highlight", "value": false }]
+                }
+            ]
+        });
+        Self::deserialize(json).expect("default push rules are valid")
+    }
+
+    /// The rules of the given kind
+    pub fn rules(&self, kind: RuleKind) -> &Vec<PushRule> {
+        match kind {
+            RuleKind::Override => &self.override_,
+            RuleKind::Content => &self.content,
+            RuleKind::Room => &self.room,
+            RuleKind::Sender => &self.sender,
+            RuleKind::Underride => &self.underride,
+        }
+    }
+
+    /// Mutable access to the rules of the given kind
+    pub fn rules_mut(&mut self, kind: RuleKind) -> &mut Vec<PushRule> {
+        match kind {
+            RuleKind::Override => &mut self.override_,
+            RuleKind::Content => &mut self.content,
+            RuleKind::Room => &mut self.room,
+            RuleKind::Sender => &mut self.sender,
+            RuleKind::Underride => &mut self.underride,
+        }
+    }
+
+    /// Find a rule by kind and id
+    pub fn get(&self, kind: RuleKind, rule_id: &str) -> Option<&PushRule> {
+        self.rules(kind).iter().find(|r| r.rule_id == rule_id)
+    }
+
+    /// Whether the rule exists and is enabled
+    pub fn is_enabled(&self, kind: RuleKind, rule_id: &str) -> bool {
+        self.get(kind, rule_id).map_or(false, |r| r.enabled)
+    }
+
+    /// The user-defined keyword rules
+    pub fn keywords(&self) -> Vec<&PushRule> {
+        self.content
+            .iter()
+            .filter(|r| !r.default && r.pattern.is_some())
+            .collect()
+    }
+
+    /// The notification mode of the given room
+    pub fn room_mode(&self, room_id: &RoomId) -> RoomMode {
+        let id = room_id.as_str();
+        let muted = self.override_.iter().any(|r| {
+            r.enabled && r.rule_id == id && r.actions.iter().all(|a| a == &Action::DontNotify)
+        });
+        if muted {
+            return RoomMode::Mute;
+        }
+        match self.get(RuleKind::Room, id) {
+            Some(rule) if rule.enabled && !rule.actions.contains(&Action::Notify) => {
+                RoomMode::MentionsAndKeywords
+            }
+            _ => RoomMode::AllMessages,
+        }
+    }
+
+    /// Kinds of the rules that set the mode of the given room
+    pub fn room_rule_kinds(&self, room_id: &RoomId) -> Vec<RuleKind> {
+        [RuleKind::Override, RuleKind::Room]
+            .iter()
+            .copied()
+            .filter(|&kind| self.get(kind, room_id.as_str()).is_some())
+            .collect()
+    }
+
+    /// Locally apply a room mode, replacing any existing rules for the room
+    pub fn set_room_mode(&mut self, room_id: &RoomId, mode: RoomMode) {
+        for kind in [RuleKind::Override, RuleKind::Room].iter().copied() {
+            self.rules_mut(kind)
+                .retain(|r| r.rule_id != room_id.as_str());
+        }
+        if let Some((kind, rule)) = PushRule::for_room_mode(room_id, mode) {
+            // User-defined rules take precedence over default ones, except for the master rule
+            // which the server always keeps first
+            let rules = self.rules_mut(kind);
+            let index = rules
+                .iter()
+                .position(|r| r.rule_id == ".m.rule.master")
+                .map_or(0, |i| i + 1);
+            rules.insert(index, rule);
+        }
+    }
+
+    /// Evaluate the rules against an event, returning the actions of the first matching rule.
+    pub fn evaluate(&self, event: &AnyRoomEvent, context: &PushContext) -> Evaluation {
+        let json = match serde_json::to_value(event) {
+            Ok(json) => json,
+            Err(_) => return Evaluation::default(),
+        };
+        self.evaluate_json(&json, context)
+    }
+
+    /// Evaluate the rules against the JSON representation of an event.
+    pub fn evaluate_json(&self, event: &JsonValue, context: &PushContext) -> Evaluation {
+        for kind in RuleKind::ALL.iter().copied() {
+            for rule in self.rules(kind).iter().filter(|r| r.enabled) {
+                if rule.matches(kind, event, context) {
+                    return Evaluation {
+                        rule_id: Some(rule.rule_id.clone()),
+                        actions: rule.actions.clone(),
+                    };
+                }
+            }
+        }
+        Evaluation::default()
+    }
+}
+
+impl PushRule {
+    /// Whether the rule applies to the event
+    pub fn matches(&self, kind: RuleKind, event: &JsonValue, context: &PushContext) -> bool {
+        match kind {
+            RuleKind::Override | RuleKind::Underride => self
+                .conditions
+                .iter()
+                .flatten()
+                .all(|c| c.applies(event, context)),
+            RuleKind::Content => match (&self.pattern, lookup(event, "content.body")) {
+                (Some(pattern), Some(JsonValue::String(body))) => glob_matches_words(pattern, body),
+                _ => false,
+            },
+            RuleKind::Room => lookup(event, "room_id")
+                .and_then(JsonValue::as_str)
+                .map_or(false, |id| id == self.rule_id),
+            RuleKind::Sender => lookup(event, "sender")
+                .and_then(JsonValue::as_str)
+                .map_or(false, |id| id == self.rule_id),
+        }
+    }
+
+    /// Create an enabled rule for a room
+    pub fn for_room(room_id: &RoomId, actions: Vec<Action>) -> Self {
+        Self {
+            rule_id: room_id.to_string(),
+            default: false,
+            enabled: true,
+            actions,
+            conditions: None,
+            pattern: None,
+        }
+    }
+
+    /// The rule implementing a room mode, if one is needed
+    pub fn for_room_mode(room_id: &RoomId, mode: RoomMode) -> Option<(RuleKind, Self)> {
+        match mode {
+            RoomMode::AllMessages => None,
+            RoomMode::MentionsAndKeywords => Some((
+                RuleKind::Room,
+                Self::for_room(room_id, vec![Action::DontNotify]),
+            )),
+            RoomMode::Mute => {
+                let mut rule = Self::for_room(room_id, vec![Action::DontNotify]);
+                rule.conditions = Some(vec![Condition::EventMatch {
+                    key: "room_id".to_owned(),
+                    pattern: room_id.to_string(),
+                }]);
+                Some((RuleKind::Override, rule))
+            }
+        }
+    }
+
+    /// Create a content rule that highlights messages containing a keyword
+    pub fn keyword(keyword: &str) -> Self {
+        Self {
+            rule_id: keyword.to_owned(),
+            default: false,
+            enabled: true,
+            actions: vec![
+                Action::Notify,
+                Action::SetTweak(Tweak::Sound("default".to_owned())),
+                Action::SetTweak(Tweak::Highlight(true)),
+            ],
+            conditions: None,
+            pattern: Some(keyword.to_owned()),
+        }
+    }
+}
+
+impl Condition {
+    /// Whether the condition holds for the event
+    pub fn applies(&self, event: &JsonValue, context: &PushContext) -> bool {
+        match self {
+            Condition::EventMatch { key, pattern } => match lookup(event, key) {
+                Some(JsonValue::String(value)) if key == "content.body" => {
+                    glob_matches_words(pattern, value)
+                }
+                Some(JsonValue::String(value)) => glob_matches(pattern, value),
+                _ => false,
+            },
+            Condition::ContainsDisplayName => {
+                let name = match context.display_name {
+                    Some(ref name) if !name.is_empty() => name,
+                    _ => return false,
+                };
+                // Our own events shouldn't mention ourselves
+                if lookup(event, "sender").and_then(JsonValue::as_str)
+                    == Some(context.user_id.as_str())
+                {
+                    return false;
+                }
+                match lookup(event, "content.body") {
+                    Some(JsonValue::String(body)) => contains_word(body, name),
+                    _ => false,
+                }
+            }
+            Condition::RoomMemberCount { is } => member_count_matches(is, context.member_count),
+            Condition::SenderNotificationPermission { key } => {
+                let sender = match lookup(event, "sender")
+                    .and_then(JsonValue::as_str)
+                    .and_then(|s| UserId::try_from(s).ok())
+                {
+                    Some(sender) => sender,
+                    None => return false,
+                };
+                let required = context
+                    .notifications
+                    .get(key)
+                    .copied()
+                    .unwrap_or(DEFAULT_NOTIFICATION_LEVEL);
+                context.power_level(&sender) >= required
+            }
+            Condition::Unknown => false,
+        }
+    }
+}
+
+/// Fetch the current push rules of the account
+pub async fn get_rules(client: Client) -> Result<Ruleset, Error> {
+    let response = client.send(get_pushrules_all::Request::new(), None).await?;
+    let global = serde_json::to_value(&response.global)?;
+    Ok(Ruleset::deserialize(global)?)
+}
+
+/// Create or replace a push rule
+pub async fn set_rule(client: &Client, kind: RuleKind, rule: &PushRule) -> Result<(), Error> {
+    let actions = serde_json::from_value(serde_json::to_value(&rule.actions)?)?;
+    let mut request =
+        set_pushrule::Request::new(RuleScope::Global, kind.to_api(), &rule.rule_id, actions);
+    if let Some(ref conditions) = rule.conditions {
+        request.conditions = serde_json::from_value(serde_json::to_value(conditions)?)?;
+    }
+    request.pattern = rule.pattern.as_deref();
+    client.send(request, None).await?;
+    Ok(())
+}
+
+/// Delete a push rule
+pub async fn delete_rule(client: &Client, kind: RuleKind, rule_id: &str) -> Result<(), Error> {
+    let request = delete_pushrule::Request::new(RuleScope::Global, kind.to_api(), rule_id);
+    client.send(request, None).await?;
+    Ok(())
+}
+
+/// Enable or disable a push rule
+pub async fn set_rule_enabled(
+    client: &Client,
+    kind: RuleKind,
+    rule_id: &str,
+    enabled: bool,
+) -> Result<(), Error> {
+    let request =
+        set_pushrule_enabled::Request::new(RuleScope::Global, kind.to_api(), rule_id, enabled);
+    client.send(request, None).await?;
+    Ok(())
+}
+
+/// Change the notification mode of a room on the server.
+///
+/// `stale` are the kinds of the rules currently setting the mode of the room, which are
+/// removed before the new rule is added.
+pub async fn set_room_mode(
+    client: &Client,
+    room_id: &RoomId,
+    stale: Vec<RuleKind>,
+    mode: RoomMode,
+) -> Result<(), Error> {
+    for kind in stale {
+        delete_rule(client, kind, room_id.as_str()).await?;
+    }
+    if let Some((kind, rule)) = PushRule::for_room_mode(room_id, mode) {
+        set_rule(client, kind, &rule).await?;
+    }
+    Ok(())
+}
+
+/// Look up a dot-separated key like `content.body` in an event.
+fn lookup<'a>(event: &'a JsonValue, key: &str) -> Option<&'a JsonValue> {
+    key.split('.')
+        .try_fold(event, |value, part| value.get(part))
+}
+
+/// Compare a room member count against a condition like `">=2"`
+fn member_count_matches(is: &str, count: u64) -> bool {
+    let split = is
+        .find(|c: char| c.is_ascii_digit())
+        .unwrap_or_else(|| is.len());
+    let (op, number) = is.split_at(split);
+    let number: u64 = match number.parse() {
+        Ok(number) => number,
+        Err(_) => return false,
+    };
+    match op {
+        "" | "==" => count == number,
+        "<" => count < number,
+        ">" => count > number,
+        "<=" => count <= number,
+        ">=" => count >= number,
+        _ => false,
+    }
+}
+
+/// Case-insensitively match a whole value against a glob with `*` and `?` wildcards.
+fn glob_matches(pattern: &str, value: &str) -> bool {
+    let pattern: Vec<char> = pattern.to_lowercase().chars().collect();
+    let value: Vec<char> = value.to_lowercase().chars().collect();
+    glob_match_chars(&pattern, &value)
+}
+
+/// Case-insensitively match a glob against any run of whole words in the value.
+fn glob_matches_words(pattern: &str, value: &str) -> bool {
+    if !pattern.contains(|c| c == '*' || c == '?') {
+        return contains_word(value, pattern);
+    }
+    let pattern: Vec<char> = pattern.to_lowercase().chars().collect();
+    let value: Vec<char> = value.to_lowercase().chars().collect();
+    let boundary = |i: usize| {
+        i == 0 || i == value.len() || !is_word_char(value[i - 1]) || !is_word_char(value[i])
+    };
+    // Run the pattern as a state machine over the value in a single pass, starting a new match
+    // at every word boundary. `states[p]` is whether the first `p` pattern characters match
+    // the text since some boundary.
+    let mut states = vec![false; pattern.len() + 1];
+    for i in 0..=value.len() {
+        if boundary(i) {
+            states[0] = true;
+        }
+        // A `*` can match nothing
+        for p in 0..pattern.len() {
+            if states[p] && pattern[p] == '*' {
+                states[p + 1] = true;
+            }
+        }
+        if states[pattern.len()] && boundary(i) {
+            return true;
+        }
+        if i == value.len() {
+            break;
+        }
+        let mut next = vec![false; pattern.len() + 1];
+        for (p, &c) in pattern.iter().enumerate().filter(|&(p, _)| states[p]) {
+            match c {
+                '*' => next[p] = true,
+                '?' => next[p + 1] = true,
+                c if c == value[i] => next[p + 1] = true,
+                _ => (),
+            }
+        }
+        states = next;
+    }
+    false
+}
+
+/// Glob matching on lowercased characters.
+fn glob_match_chars(pattern: &[char], value: &[char]) -> bool {
+    // Iterative wildcard matching with backtracking to the last `*`
+    let (mut p, mut v) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while v < value.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, v));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == value[v] => {
+                p += 1;
+                v += 1;
+            }
+            _ => match star {
+                Some((star_p, star_v)) => {
+                    p = star_p + 1;
+                    v = star_v + 1;
+                    star = Some((star_p, star_v + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Whether `needle` occurs in `haystack` surrounded by word boundaries, ignoring case.
+fn contains_word(haystack: &str, needle: &str) -> bool {
+    let haystack: Vec<char> = haystack.to_lowercase().chars().collect();
+    let needle: Vec<char> = needle.to_lowercase().chars().collect();
+    if needle.is_empty() || needle.len() > haystack.len() {
+        return false;
+    }
+    (0..=haystack.len() - needle.len()).any(|start| {
+        let end = start + needle.len();
+        haystack[start..end] == needle[..]
+            && (start == 0 || !is_word_char(haystack[start - 1]))
+            && (end == haystack.len() || !is_word_char(haystack[end]))
+    })
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn user(id: &str) -> UserId {
+        UserId::try_from(id).unwrap()
+    }
+
+    fn room() -> RoomId {
+        RoomId::try_from("!room:example.org").unwrap()
+    }
+
+    /// Context for `@alice:example.org`, display name `Alice`, in a room with `members` members
+    fn context(members: u64) -> PushContext {
+        PushContext::new(
+            user("@alice:example.org"),
+            Some("Alice".to_owned()),
+            members,
+        )
+    }
+
+    fn message(sender: &str, msgtype: &str, body: &str) -> JsonValue {
+        json!({
+            "type": "m.room.message",
+            "room_id": room().as_str(),
+            "sender": sender,
+            "event_id": "$event:example.org",
+            "content": { "msgtype": msgtype, "body": body },
+        })
+    }
+
+    fn text(sender: &str, body: &str) -> JsonValue {
+        message(sender, "m.text", body)
+    }
+
+    /// Id of the rule that decides what happens with the event
+    fn matching(rules: &Ruleset, event: &JsonValue, context: &PushContext) -> Option<String> {
+        rules.evaluate_json(event, context).rule_id
+    }
+
+    fn ids(rules: &[PushRule]) -> Vec<&str> {
+        rules.iter().map(|r| r.rule_id.as_str()).collect()
+    }
+
+    #[test]
+    fn server_default_order() {
+        let rules = Ruleset::server_default(&user("@alice:example.org"));
+        assert_eq!(
+            ids(&rules.override_),
+            [
+                ".m.rule.master",
+                ".m.rule.suppress_notices",
+                ".m.rule.invite_for_me",
+                ".m.rule.member_event",
+                ".m.rule.contains_display_name",
+                ".m.rule.tombstone",
+                ".m.rule.roomnotif",
+            ]
+        );
+        assert_eq!(ids(&rules.content), [".m.rule.contains_user_name"]);
+        assert!(rules.room.is_empty());
+        assert!(rules.sender.is_empty());
+        assert_eq!(
+            ids(&rules.underride),
+            [
+                ".m.rule.call",
+                ".m.rule.encrypted_room_one_to_one",
+                ".m.rule.room_one_to_one",
+                ".m.rule.message",
+                ".m.rule.encrypted",
+            ]
+        );
+        assert!(!rules.override_[0].enabled, "the master rule is disabled");
+        assert!(rules.rules(RuleKind::Override).iter().all(|r| r.default));
+        assert_eq!(
+            rules.content[0].pattern.as_deref(),
+            Some("alice"),
+            "the user name rule matches the localpart"
+        );
+    }
+
+    #[test]
+    fn server_default_round_trips() {
+        let rules = Ruleset::server_default(&user("@alice:example.org"));
+        let json = json!({ "global": serde_json::to_value(&rules).unwrap() });
+        let parsed = Ruleset::from_account_data(&json).unwrap();
+        for kind in RuleKind::ALL.iter().copied() {
+            assert_eq!(ids(rules.rules(kind)), ids(parsed.rules(kind)));
+        }
+        assert_eq!(
+            parsed.underride[0].actions,
+            [
+                Action::Notify,
+                Action::SetTweak(Tweak::Sound("ring".to_owned())),
+                Action::SetTweak(Tweak::Highlight(false)),
+            ]
+        );
+    }
+
+    #[test]
+    fn unknown_actions_are_ignored() {
+        let rules = Ruleset::server_default(&user("@alice:example.org"));
+        let mut json = json!({ "global": serde_json::to_value(&rules).unwrap() });
+        for rule in json["global"]["override"].as_array_mut().unwrap() {
+            if rule["rule_id"] == ".m.rule.contains_display_name" {
+                rule["actions"]
+                    .as_array_mut()
+                    .unwrap()
+                    .push(json!("new_action"));
+            }
+        }
+        let parsed = Ruleset::from_account_data(&json).unwrap();
+        let mention = parsed.evaluate_json(&text("@bob:example.org", "hi Alice"), &context(5));
+        assert!(mention.notify());
+        assert!(mention.highlight());
+        assert!(mention
+            .actions
+            .contains(&Action::Unknown("new_action".to_owned())));
+    }
+
+    #[test]
+    fn event_match_globs() {
+        let condition = |key: &str, pattern: &str| Condition::EventMatch {
+            key: key.to_owned(),
+            pattern: pattern.to_owned(),
+        };
+        let event = text("@bob:example.org", "Lunch at the CAFE today?");
+        let context = context(5);
+        assert!(condition("type", "m.room.message").applies(&event, &context));
+        assert!(condition("type", "m.room.*").applies(&event, &context));
+        assert!(condition("type", "m.?oom.message").applies(&event, &context));
+        assert!(condition("type", "M.ROOM.MESSAGE").applies(&event, &context));
+        // Other keys have to match the whole value
+        assert!(!condition("type", "m.room").applies(&event, &context));
+        assert!(!condition("type", "room.message").applies(&event, &context));
+        assert!(!condition("content.missing", "*").applies(&event, &context));
+        // The body is matched against whole words
+        assert!(condition("content.body", "cafe").applies(&event, &context));
+        assert!(condition("content.body", "lunch at").applies(&event, &context));
+        assert!(condition("content.body", "caf*").applies(&event, &context));
+        assert!(condition("content.body", "t?day").applies(&event, &context));
+        assert!(!condition("content.body", "caf").applies(&event, &context));
+        assert!(!condition("content.body", "unch").applies(&event, &context));
+    }
+
+    #[test]
+    fn glob_matching() {
+        assert!(glob_matches("*", ""));
+        assert!(glob_matches("a*c", "abbbc"));
+        assert!(glob_matches("a*b*c", "aXbYc"));
+        assert!(!glob_matches("a*c", "abcd"));
+        assert!(!glob_matches("a?c", "ac"));
+        assert!(glob_matches_words("foo*", "hello foobar there"));
+        assert!(!glob_matches_words("foo", "hello foobar there"));
+        assert!(!glob_matches_words("bar*", "hello foobar there"));
+        assert!(glob_matches_words("*bar", "hello foobar there"));
+        assert!(glob_matches_words("hello * there", "hello foobar there"));
+        assert!(glob_matches_words("f?o", "a foo."));
+        assert!(!glob_matches_words("f?o", "a fooo."));
+        assert!(!glob_matches_words("a*z", &"a".repeat(5000)));
+    }
+
+    #[test]
+    fn contains_display_name_word_boundaries() {
+        let condition = Condition::ContainsDisplayName;
+        let context = context(5);
+        let applies = |body: &str| condition.applies(&text("@bob:example.org", body), &context);
+        assert!(applies("Alice"));
+        assert!(applies("hi alice!"));
+        assert!(applies("(ALICE) can you look at this"));
+        assert!(applies("thanks, Alice."));
+        assert!(!applies("malice"));
+        assert!(!applies("alice_"));
+        assert!(!applies("alice2"));
+        assert!(!applies("ali ce"));
+        // Not by our own messages
+        assert!(!condition.applies(&text("@alice:example.org", "I'm Alice"), &context));
+        // Not without a display name
+        let mut nameless = context.clone();
+        nameless.display_name = None;
+        assert!(!condition.applies(&text("@bob:example.org", "alice"), &nameless));
+        nameless.display_name = Some(String::new());
+        assert!(!condition.applies(&text("@bob:example.org", ""), &nameless));
+    }
+
+    #[test]
+    fn room_member_count() {
+        let applies = |is: &str, members: u64| {
+            Condition::RoomMemberCount { is: is.to_owned() }
+                .applies(&text("@bob:example.org", "hi"), &context(members))
+        };
+        assert!(applies("2", 2));
+        assert!(!applies("2", 3));
+        assert!(applies("==2", 2));
+        assert!(!applies("==2", 1));
+        assert!(applies("<10", 9));
+        assert!(!applies("<10", 10));
+        assert!(applies(">=10", 10));
+        assert!(applies(">=10", 11));
+        assert!(!applies(">=10", 9));
+        assert!(applies(">1", 2));
+        assert!(applies("<=1", 1));
+        assert!(!applies("two", 2));
+        assert!(!applies("=>2", 2));
+    }
+
+    #[test]
+    fn sender_notification_permission() {
+        let condition = Condition::SenderNotificationPermission {
+            key: "room".to_owned(),
+        };
+        let mut context = context(5);
+        context.users.insert(user("@mod:example.org"), 50);
+        context.users.insert(user("@admin:example.org"), 100);
+        let applies = |sender: &str, context: &PushContext| {
+            condition.applies(&text(sender, "@room hello"), context)
+        };
+        // The default level needed is 50
+        assert!(applies("@mod:example.org", &context));
+        assert!(applies("@admin:example.org", &context));
+        assert!(!applies("@bob:example.org", &context));
+        context.notifications.insert("room".to_owned(), 100);
+        assert!(!applies("@mod:example.org", &context));
+        assert!(applies("@admin:example.org", &context));
+        context.users_default = 100;
+        assert!(applies("@bob:example.org", &context));
+        // Senders that aren't user ids never have permission
+        assert!(!applies("not a user", &context));
+    }
+
+    #[test]
+    fn default_rules() {
+        let rules = Ruleset::server_default(&user("@alice:example.org"));
+        let group = context(5);
+        let direct = context(2);
+        let bob = "@bob:example.org";
+        assert_eq!(
+            matching(&rules, &text(bob, "hello"), &group).as_deref(),
+            Some(".m.rule.message")
+        );
+        assert_eq!(
+            matching(&rules, &text(bob, "hello"), &direct).as_deref(),
+            Some(".m.rule.room_one_to_one")
+        );
+        let encrypted = json!({
+            "type": "m.room.encrypted",
+            "room_id": room().as_str(),
+            "sender": bob,
+            "content": { "algorithm": "m.megolm.v1.aes-sha2" },
+        });
+        assert_eq!(
+            matching(&rules, &encrypted, &direct).as_deref(),
+            Some(".m.rule.encrypted_room_one_to_one")
+        );
+        assert_eq!(
+            matching(&rules, &encrypted, &group).as_deref(),
+            Some(".m.rule.encrypted")
+        );
+        let invite = json!({
+            "type": "m.room.member",
+            "room_id": room().as_str(),
+            "sender": bob,
+            "state_key": "@alice:example.org",
+            "content": { "membership": "invite" },
+        });
+        assert_eq!(
+            matching(&rules, &invite, &group).as_deref(),
+            Some(".m.rule.invite_for_me")
+        );
+        let mut join = invite.clone();
+        join["content"]["membership"] = json!("join");
+        assert_eq!(
+            matching(&rules, &join, &group).as_deref(),
+            Some(".m.rule.member_event")
+        );
+        let tombstone = json!({
+            "type": "m.room.tombstone",
+            "room_id": room().as_str(),
+            "sender": bob,
+            "state_key": "",
+            "content": { "body": "moved", "replacement_room": "!new:example.org" },
+        });
+        assert_eq!(
+            matching(&rules, &tombstone, &group).as_deref(),
+            Some(".m.rule.tombstone")
+        );
+        let call = json!({
+            "type": "m.call.invite",
+            "room_id": room().as_str(),
+            "sender": bob,
+            "content": {},
+        });
+        assert_eq!(
+            matching(&rules, &call, &group).as_deref(),
+            Some(".m.rule.call")
+        );
+        // Only senders allowed to notify the room trigger @room
+        assert_eq!(
+            matching(&rules, &text(bob, "@room lunch"), &group).as_deref(),
+            Some(".m.rule.message")
+        );
+        let mut moderated = group.clone();
+        moderated.users.insert(user(bob), 50);
+        assert_eq!(
+            matching(&rules, &text(bob, "@room lunch"), &moderated).as_deref(),
+            Some(".m.rule.roomnotif")
+        );
+        // Other events don't match anything
+        let reaction = json!({
+            "type": "m.reaction",
+            "room_id": room().as_str(),
+            "sender": bob,
+            "content": {},
+        });
+        assert_eq!(matching(&rules, &reaction, &group), None);
+        assert!(!rules.evaluate_json(&reaction, &group).notify());
+    }
+
+    #[test]
+    fn evaluation_actions() {
+        let rules = Ruleset::server_default(&user("@alice:example.org"));
+        let context = context(5);
+        let plain = rules.evaluate_json(&text("@bob:example.org", "hello"), &context);
+        assert!(plain.notify());
+        assert!(!plain.highlight());
+        assert_eq!(plain.sound(), None);
+        let direct = PushContext {
+            member_count: 2,
+            ..context.clone()
+        };
+        let direct = rules.evaluate_json(&text("@bob:example.org", "hello"), &direct);
+        assert_eq!(direct.sound(), Some("default"));
+        let mention = rules.evaluate_json(&text("@bob:example.org", "hi Alice"), &context);
+        assert!(mention.notify());
+        assert!(mention.highlight());
+        let user_name = rules.evaluate_json(&text("@bob:example.org", "ping alice"), &context);
+        assert!(user_name.highlight());
+        let notice = rules.evaluate_json(
+            &message("@bob:example.org", "m.notice", "hi Alice"),
+            &context,
+        );
+        assert!(!notice.notify());
+        assert!(!notice.highlight());
+    }
+
+    #[test]
+    fn override_takes_precedence() {
+        let mut rules = Ruleset::server_default(&user("@alice:example.org"));
+        let context = context(5);
+        // suppress_notices comes before the display name and user name rules
+        let notice = message("@bob:example.org", "m.notice", "alice");
+        assert_eq!(
+            matching(&rules, &notice, &context).as_deref(),
+            Some(".m.rule.suppress_notices")
+        );
+        // The master rule silences everything once enabled
+        rules.override_[0].enabled = true;
+        assert_eq!(
+            matching(&rules, &text("@bob:example.org", "hi Alice"), &context).as_deref(),
+            Some(".m.rule.master")
+        );
+        assert!(!rules
+            .evaluate_json(&text("@bob:example.org", "hi Alice"), &context)
+            .notify());
+    }
+
+    #[test]
+    fn content_before_room_before_sender_before_underride() {
+        let mut rules = Ruleset::server_default(&user("@alice:example.org"));
+        let context = context(5);
+        let bob = "@bob:example.org";
+        // A sender rule beats the underride rules
+        let mut sender_rule = PushRule::for_room(&room(), vec![Action::DontNotify]);
+        sender_rule.rule_id = bob.to_owned();
+        rules.sender.push(sender_rule);
+        assert_eq!(
+            matching(&rules, &text(bob, "hello"), &context).as_deref(),
+            Some(bob)
+        );
+        assert_eq!(
+            matching(&rules, &text("@carol:example.org", "hello"), &context).as_deref(),
+            Some(".m.rule.message")
+        );
+        // A room rule beats the sender rule
+        rules
+            .room
+            .push(PushRule::for_room(&room(), vec![Action::DontNotify]));
+        assert_eq!(
+            matching(&rules, &text(bob, "hello"), &context).as_deref(),
+            Some(room().as_str())
+        );
+        assert!(!rules.evaluate_json(&text(bob, "hello"), &context).notify());
+        // Content rules beat the room rule
+        let mut keyword = PushRule::for_room(&room(), vec![Action::Notify]);
+        keyword.rule_id = "deploy".to_owned();
+        keyword.pattern = Some("deploy".to_owned());
+        rules.content.insert(0, keyword);
+        assert_eq!(
+            matching(&rules, &text(bob, "deploy is done"), &context).as_deref(),
+            Some("deploy")
+        );
+        // Override rules beat the content rules, in their own order
+        let mut muted = PushRule::for_room(&room(), vec![Action::DontNotify]);
+        muted.conditions = Some(vec![Condition::EventMatch {
+            key: "room_id".to_owned(),
+            pattern: room().to_string(),
+        }]);
+        rules.override_.push(muted);
+        assert_eq!(
+            matching(&rules, &text(bob, "deploy is done"), &context).as_deref(),
+            Some(room().as_str())
+        );
+        assert_eq!(
+            matching(&rules, &message(bob, "m.notice", "deploy"), &context).as_deref(),
+            Some(".m.rule.suppress_notices")
+        );
+    }
+
+    #[test]
+    fn room_modes() {
+        let mut rules = Ruleset::server_default(&user("@alice:example.org"));
+        let context = context(5);
+        let bob = "@bob:example.org";
+        assert_eq!(rules.room_mode(&room()), RoomMode::AllMessages);
+        assert!(rules.room_rule_kinds(&room()).is_empty());
+        rules.set_room_mode(&room(), RoomMode::MentionsAndKeywords);
+        assert_eq!(rules.room_mode(&room()), RoomMode::MentionsAndKeywords);
+        assert_eq!(rules.room_rule_kinds(&room()), [RuleKind::Room]);
+        assert!(!rules.evaluate_json(&text(bob, "hello"), &context).notify());
+        assert!(rules
+            .evaluate_json(&text(bob, "hi Alice"), &context)
+            .highlight());
+        // Keywords still get through
+        rules.content.insert(0, PushRule::keyword("deploy"));
+        assert_eq!(
+            matching(&rules, &text(bob, "deploy is done"), &context).as_deref(),
+            Some("deploy")
+        );
+        // Muting replaces the room rule with an override rule, which beats the content rules
+        rules.set_room_mode(&room(), RoomMode::Mute);
+        assert_eq!(rules.room_mode(&room()), RoomMode::Mute);
+        assert_eq!(rules.room_rule_kinds(&room()), [RuleKind::Override]);
+        assert_eq!(
+            matching(&rules, &text(bob, "deploy is done"), &context).as_deref(),
+            Some(room().as_str())
+        );
+        // It goes right after the master rule, so it also beats the other default overrides
+        assert_eq!(rules.override_[0].rule_id, ".m.rule.master");
+        assert_eq!(rules.override_[1].rule_id, room().as_str());
+        assert_eq!(
+            matching(&rules, &message(bob, "m.notice", "deploy"), &context).as_deref(),
+            Some(room().as_str())
+        );
+        // The master rule still silences everything first
+        rules.override_[0].enabled = true;
+        assert_eq!(
+            matching(&rules, &text(bob, "hello"), &context).as_deref(),
+            Some(".m.rule.master")
+        );
+        rules.set_room_mode(&room(), RoomMode::AllMessages);
+        assert_eq!(rules.room_mode(&room()), RoomMode::AllMessages);
+        assert!(rules.room_rule_kinds(&room()).is_empty());
+    }
+
+    #[test]
+    fn keywords_from_other_clients() {
+        let mut rules = Ruleset::server_default(&user("@alice:example.org"));
+        let mut keyword = PushRule::keyword("deploy*");
+        keyword.rule_id = "keyword-1234".to_owned();
+        rules.content.insert(0, keyword);
+        rules.content.insert(0, PushRule::keyword("lunch"));
+        let keywords: Vec<_> = rules
+            .keywords()
+            .into_iter()
+            .map(|r| (r.rule_id.as_str(), r.pattern.as_deref()))
+            .collect();
+        assert_eq!(
+            keywords,
+            [("lunch", Some("lunch")), ("keyword-1234", Some("deploy*"))]
+        );
+        assert_eq!(
+            matching(&rules, &text("@bob:example.org", "deployed"), &context(5)).as_deref(),
+            Some("keyword-1234")
+        );
+    }
+
+    #[test]
+    fn disabled_rules_are_skipped() {
+        let mut rules = Ruleset::server_default(&user("@alice:example.org"));
+        let context = context(5);
+        for rule in rules.override_.iter_mut() {
+            if rule.rule_id == ".m.rule.contains_display_name" {
+                rule.enabled = false;
+            }
+        }
+        assert_eq!(
+            matching(&rules, &text("@bob:example.org", "hi Alice"), &context).as_deref(),
+            Some(".m.rule.contains_user_name")
+        );
+        rules.content[0].enabled = false;
+        assert_eq!(
+            matching(&rules, &text("@bob:example.org", "hi Alice"), &context).as_deref(),
+            Some(".m.rule.message")
+        );
+    }
+
+    #[test]
+    fn actions_parse() {
+        let actions: Vec<Action> = serde_json::from_value(json!([
+            "notify",
+            "dont_notify",
+            "coalesce",
+            { "set_tweak": "sound", "value": "default" },
+            { "set_tweak": "highlight" },
+            { "set_tweak": "highlight", "value": false },
+            { "set_tweak": "custom", "value": 3 },
+        ]))
+        .unwrap();
+        assert_eq!(
+            actions,
+            [
+                Action::Notify,
+                Action::DontNotify,
+                Action::Coalesce,
+                Action::SetTweak(Tweak::Sound("default".to_owned())),
+                Action::SetTweak(Tweak::Highlight(true)),
+                Action::SetTweak(Tweak::Highlight(false)),
+                Action::SetTweak(Tweak::Custom("custom".to_owned(), json!(3))),
+            ]
+        );
+        // Actions from newer versions of the spec are kept, but don't do anything
+        let unknown: Action = serde_json::from_value(json!("new_action")).unwrap();
+        assert_eq!(unknown, Action::Unknown("new_action".to_owned()));
+        assert_eq!(serde_json::to_value(&unknown).unwrap(), json!("new_action"));
+        let unknown: Condition = serde_json::from_value(json!({ "kind": "new_kind" })).unwrap();
+        assert!(!unknown.applies(&text("@bob:example.org", "hi"), &context(5)));
+    }
+}
@@ -0,0 +1,27 @@
+//! Reactions, which annotate an event with a key, usually an emoji.
+
+use matrix_sdk::{
+    events::{
+        reaction::{ReactionEvent, ReactionEventContent, Relation},
+        AnyMessageEvent, AnyMessageEventContent, AnyRoomEvent,
+    },
+    identifiers::EventId,
+};
+
+/// Emojis offered in the reaction picker
+pub const QUICK_REACTIONS: &[&str] = &[
+    "👍", "👎", "😄", "🎉", "😕", "❤️", "🚀", "👀", "😂", "😮", "😢", "🙏",
+];
+
+/// Gets the reaction if the event is one
+pub fn as_reaction(event: &AnyRoomEvent) -> Option<&ReactionEvent> {
+    match event {
+        AnyRoomEvent::Message(AnyMessageEvent::Reaction(reaction)) => Some(reaction),
+        _ => None,
+    }
+}
+
+/// Content for reacting to an event with `key`
+pub fn content(target: EventId, key: String) -> AnyMessageEventContent {
+    AnyMessageEventContent::Reaction(ReactionEventContent::new(Relation::new(target, key)))
+}
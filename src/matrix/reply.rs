@@ -0,0 +1,147 @@
+//! Rich replies and their fallbacks.
+
+use matrix_sdk::{
+    events::{
+        room::message::{FormattedBody, InReplyTo, MessageEvent, MessageEventContent, Relation},
+        AnyMessageEvent, AnyRoomEvent,
+    },
+    identifiers::EventId,
+};
+
+use super::{markdown::escape, message_body, message_body_mut, message_formatted_body};
+
+/// Gets the id of the event a message is a reply to
+pub fn in_reply_to(event: &AnyRoomEvent) -> Option<&EventId> {
+    match event {
+        AnyRoomEvent::Message(AnyMessageEvent::RoomMessage(MessageEvent {
+            content:
+                MessageEventContent {
+                    relates_to: Some(Relation::Reply { in_reply_to }),
+                    ..
+                },
+            ..
+        })) => Some(&in_reply_to.event_id),
+        _ => None,
+    }
+}
+
+/// Turn a message into a reply to `parent`, adding the relation and fallbacks for clients
+/// that don't support replies.
+pub fn make_reply(mut content: MessageEventContent, parent: &MessageEvent) -> MessageEventContent {
+    let parent_body = plain_body(&parent.content, &parent.content);
+    let parent_html = match message_formatted_body(&parent.content.msgtype) {
+        Some(html) => strip_html_fallback(html),
+        None => escape(parent_body).replace('\n', "<br />"),
+    };
+
+    // Plain text fallback, with each line of the parent quoted
+    let mut quote = String::new();
+    for (idx, line) in parent_body.lines().enumerate() {
+        match idx {
+            0 => quote.push_str(&format!("> <{}> {}\n", parent.sender, line)),
+            _ => quote.push_str(&format!("> {}\n", line)),
+        }
+    }
+    let html_quote = format!(
+        "<mx-reply><blockquote><a href=\"https://matrix.to/#/{room}/{event}\">In reply to</a> \
+         <a href=\"https://matrix.to/#/{sender}\">{sender}</a><br />{body}</blockquote></mx-reply>",
+        room = parent.room_id,
+        event = parent.event_id,
+        sender = parent.sender,
+        body = parent_html,
+    );
+
+    if let Some((body, formatted)) = message_body_mut(&mut content.msgtype) {
+        let html = match formatted.take() {
+            Some(formatted) => formatted.body,
+            None => escape(body).replace('\n', "<br />"),
+        };
+        *body = format!("{}\n{}", quote, body);
+        *formatted = Some(FormattedBody::html(format!("{}{}", html_quote, html)));
+    }
+    content.relates_to = Some(Relation::Reply {
+        in_reply_to: InReplyTo::new(parent.event_id.clone()),
+    });
+    content
+}
+
+/// The plain body of `content`, without the reply fallback if the message is a reply.
+/// `original` is the content the message was sent with, since edits don't repeat the relation.
+pub fn plain_body<'a>(original: &MessageEventContent, content: &'a MessageEventContent) -> &'a str {
+    let body = message_body(&content.msgtype);
+    match original.relates_to {
+        Some(Relation::Reply { .. }) => strip_fallback(body),
+        _ => body,
+    }
+}
+
+/// Remove the quoted fallback from the plain body of a reply. Messages that aren't replies can
+/// start with a quote too, so this should only be used on replies.
+pub fn strip_fallback(body: &str) -> &str {
+    if !body.starts_with("> ") {
+        return body;
+    }
+    // The fallback is all the quoted lines at the start, followed by an empty line
+    let mut rest = body;
+    while rest.starts_with('>') {
+        rest = match rest.find('\n') {
+            Some(newline) => &rest[newline + 1..],
+            None => "",
+        };
+    }
+    rest.strip_prefix('\n').unwrap_or(rest)
+}
+
+/// Remove the `<mx-reply>` fallback from the formatted body of a reply.
+pub fn strip_html_fallback(html: &str) -> String {
+    match (html.find("<mx-reply>"), html.find("</mx-reply>")) {
+        (Some(start), Some(end)) if start < end => {
+            format!("{}{}", &html[..start], &html[end + "</mx-reply>".len()..])
+        }
+        _ => html.to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use super::*;
+
+    #[test]
+    fn fallback_is_only_stripped_from_replies() {
+        let body = "> <@bob:example.org> original\n> more\n\nanswer";
+        let mut reply = MessageEventContent::text_plain(body);
+        reply.relates_to = Some(Relation::Reply {
+            in_reply_to: InReplyTo::new(EventId::try_from("$parent:example.org").unwrap()),
+        });
+        assert_eq!(plain_body(&reply, &reply), "answer");
+        // A message that starts with a quote keeps it
+        let quote = MessageEventContent::text_plain(body);
+        assert_eq!(plain_body(&quote, &quote), body);
+        // Edits don't have the relation, so the original decides
+        let edit = MessageEventContent::text_plain("> still a quote\n\nnew answer");
+        assert_eq!(plain_body(&reply, &edit), "new answer");
+        assert_eq!(plain_body(&quote, &edit), "> still a quote\n\nnew answer");
+    }
+
+    #[test]
+    fn strip_fallback_lines() {
+        assert_eq!(strip_fallback("> a\n> b\n\nc\n> d"), "c\n> d");
+        assert_eq!(strip_fallback("> a\nb"), "b");
+        assert_eq!(strip_fallback("> only a quote"), "");
+        assert_eq!(strip_fallback("no quote"), "no quote");
+    }
+
+    #[test]
+    fn strip_html_fallbacks() {
+        assert_eq!(
+            strip_html_fallback("<mx-reply><blockquote>q</blockquote></mx-reply>answer"),
+            "answer"
+        );
+        assert_eq!(
+            strip_html_fallback("</mx-reply>x<mx-reply>"),
+            "</mx-reply>x<mx-reply>"
+        );
+    }
+}
@@ -0,0 +1,167 @@
+//! Stickers, and sticker packs in the image pack format of MSC2545.
+//!
+//! Packs come from the user's own `im.ponies.user_emotes` account data, from the rooms the user
+//! has enabled in `im.ponies.emote_rooms`, and from the room the sticker is being sent to. The SDK
+//! doesn't know these event types, so they're fetched through the client-server API directly.
+
+use std::convert::TryInto;
+
+use matrix_sdk::{
+    events::{room::ImageInfo, sticker::StickerEventContent, AnyMessageEventContent},
+    identifiers::RoomId,
+    reqwest::{self, StatusCode},
+};
+use serde_json::Value;
+
+use super::{api_url, Error, Session};
+
+/// Account data with the user's own pack
+const USER_PACK: &str = "im.ponies.user_emotes";
+/// Account data listing rooms whose packs the user has enabled everywhere
+const PACK_ROOMS: &str = "im.ponies.emote_rooms";
+/// State event with a pack belonging to a room
+const ROOM_PACK: &str = "im.ponies.room_emotes";
+
+/// A sticker in a pack
+#[derive(Clone, Debug)]
+pub struct Sticker {
+    /// Short name used to refer to the sticker
+    pub shortcode: String,
+    /// Description of the sticker, sent as its body
+    pub body: String,
+    /// mxc url of the image
+    pub url: String,
+    /// Width and height, if the pack has them
+    pub dimensions: Option<(u32, u32)>,
+    /// MIME type, if the pack has it
+    pub mimetype: Option<String>,
+}
+
+/// A set of stickers
+#[derive(Clone, Debug)]
+pub struct Pack {
+    /// Name of the pack
+    pub name: String,
+    pub stickers: Vec<Sticker>,
+}
+
+/// Fetch all the sticker packs the user can use in a room
+pub async fn packs(session: Session, room_id: RoomId) -> Result<Vec<Pack>, Error> {
+    let user_id = session.user_id.to_string();
+    let mut packs = Vec::new();
+    let user_pack = get(
+        &session,
+        &["r0", "user", &user_id, "account_data", USER_PACK],
+    )
+    .await?;
+    if let Some(pack) = user_pack.and_then(|pack| parse_pack(&pack, "Personal")) {
+        packs.push(pack);
+    }
+    // Packs of the room, and of the rooms enabled everywhere, as (room, state key)
+    let mut sources = vec![(room_id.to_string(), String::new())];
+    let rooms = get(
+        &session,
+        &["r0", "user", &user_id, "account_data", PACK_ROOMS],
+    )
+    .await?;
+    if let Some(Value::Object(rooms)) = rooms.as_ref().and_then(|r| r.get("rooms")) {
+        for (room, state_keys) in rooms {
+            for state_key in state_keys.as_object().into_iter().flat_map(|s| s.keys()) {
+                let source = (room.clone(), state_key.clone());
+                if !sources.contains(&source) {
+                    sources.push(source);
+                }
+            }
+        }
+    }
+    for (room, state_key) in sources {
+        // Packs in rooms we can't see any more aren't worth failing over
+        let pack = get(
+            &session,
+            &["r0", "rooms", &room, "state", ROOM_PACK, &state_key],
+        )
+        .await
+        .ok()
+        .flatten();
+        if let Some(pack) = pack.and_then(|pack| parse_pack(&pack, "Room stickers")) {
+            packs.push(pack);
+        }
+    }
+    Ok(packs)
+}
+
+/// GET an endpoint that returns JSON, returning `None` if it doesn't exist
+async fn get(session: &Session, segments: &[&str]) -> Result<Option<Value>, Error> {
+    let response = reqwest::Client::new()
+        .get(api_url(session, segments)?)
+        .bearer_auth(&session.access_token)
+        .send()
+        .await?;
+    if response.status() == StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+    let response = response.error_for_status()?.text().await?;
+    Ok(Some(serde_json::from_str(&response)?))
+}
+
+/// Whether an image or pack is meant to be used as a sticker. Without a usage it's both an
+/// emoticon and a sticker.
+fn is_sticker(usage: Option<&Value>) -> Option<bool> {
+    let usage = usage?.as_array()?;
+    Some(usage.iter().any(|u| u.as_str() == Some("sticker")))
+}
+
+/// Read the stickers from the content of a pack event. Packs without stickers are skipped.
+fn parse_pack(content: &Value, default_name: &str) -> Option<Pack> {
+    let pack = content.get("pack");
+    let name = pack
+        .and_then(|p| p.get("display_name"))
+        .and_then(Value::as_str)
+        .unwrap_or(default_name)
+        .to_owned();
+    let pack_usage = is_sticker(pack.and_then(|p| p.get("usage"))).unwrap_or(true);
+
+    let mut stickers = Vec::new();
+    for (shortcode, image) in content.get("images")?.as_object()? {
+        let usage = is_sticker(image.get("usage")).unwrap_or(pack_usage);
+        let url = match image.get("url").and_then(Value::as_str) {
+            Some(url) if usage => url.to_owned(),
+            _ => continue,
+        };
+        let info = image.get("info");
+        let size = |name: &str| -> Option<u32> { info?.get(name)?.as_u64()?.try_into().ok() };
+        stickers.push(Sticker {
+            shortcode: shortcode.clone(),
+            body: image
+                .get("body")
+                .and_then(Value::as_str)
+                .unwrap_or(shortcode)
+                .to_owned(),
+            url,
+            dimensions: size("w").zip(size("h")),
+            mimetype: info
+                .and_then(|i| i.get("mimetype"))
+                .and_then(Value::as_str)
+                .map(String::from),
+        });
+    }
+    match stickers.is_empty() {
+        true => None,
+        false => Some(Pack { name, stickers }),
+    }
+}
+
+/// Content of a message sending a sticker
+pub fn content(sticker: &Sticker) -> AnyMessageEventContent {
+    let mut info = ImageInfo::new();
+    if let Some((width, height)) = sticker.dimensions {
+        info.width = Some(width.into());
+        info.height = Some(height.into());
+    }
+    info.mimetype = sticker.mimetype.clone();
+    AnyMessageEventContent::Sticker(StickerEventContent::new(
+        sticker.body.clone(),
+        info,
+        sticker.url.clone(),
+    ))
+}
@@ -0,0 +1,180 @@
+//! Threads, where replies to a root event are kept out of the main timeline.
+//!
+//! The SDK doesn't know about thread relations yet, so they're read from the raw JSON of events,
+//! replies are sent as custom content and threads are paginated through the client-server API
+//! directly.
+
+use std::{collections::BTreeMap, convert::TryFrom, sync::Mutex};
+
+use matrix_sdk::{
+    events::{
+        room::message::MessageEventContent, AnyMessageEventContent, AnyRoomEvent, EventContent,
+    },
+    identifiers::{EventId, RoomId},
+    reqwest, Raw,
+};
+use serde_json::{json, Value};
+
+use super::{api_url, custom_content, Error, Session};
+
+/// Relation types used for threads, the stable one first
+pub const REL_TYPES: &[&str] = &["m.thread", "io.element.thread"];
+
+/// Number of thread replies to request per page
+const PAGE_SIZE: u32 = 50;
+
+/// Thread roots of events in sync responses, by event id, kept until the events are handled
+static SYNCED_ROOTS: Mutex<BTreeMap<EventId, EventId>> = Mutex::new(BTreeMap::new());
+
+/// Summary of a thread bundled with its root by the server
+#[derive(Clone, Debug)]
+pub struct Summary {
+    /// Number of replies in the thread
+    pub count: u64,
+    /// The most recent reply
+    pub latest: Option<AnyRoomEvent>,
+}
+
+/// A page of replies to a thread
+#[derive(Clone, Debug)]
+pub struct Page {
+    /// The replies, newest first
+    pub events: Vec<AnyRoomEvent>,
+    /// Token for the next (older) page, if there is one
+    pub next_batch: Option<String>,
+}
+
+/// Gets the root of the thread an event is part of, from its raw JSON
+pub fn root(json: &Value) -> Option<EventId> {
+    let relation = json.get("content")?.get("m.relates_to")?;
+    let rel_type = relation.get("rel_type")?.as_str()?;
+    if !REL_TYPES.contains(&rel_type) {
+        return None;
+    }
+    EventId::try_from(relation.get("event_id")?.as_str()?).ok()
+}
+
+/// Gets the summary the server bundled with a thread root, from its raw JSON
+pub fn summary(json: &Value) -> Option<Summary> {
+    let relations = json.get("unsigned")?.get("m.relations")?;
+    let thread = REL_TYPES.iter().find_map(|t| relations.get(t))?;
+    Some(Summary {
+        count: thread
+            .get("count")
+            .and_then(Value::as_u64)
+            .unwrap_or_default(),
+        latest: thread
+            .get("latest_event")
+            .and_then(|e| serde_json::from_value(e.clone()).ok()),
+    })
+}
+
+/// Deserializes a raw event, also returning its thread root and bundled thread summary
+pub fn deserialize(
+    raw: &Raw<AnyRoomEvent>,
+) -> Option<(AnyRoomEvent, Option<EventId>, Option<Summary>)> {
+    let event = raw.deserialize().ok()?;
+    let json: Value = serde_json::from_str(raw.json().get()).ok()?;
+    Some((event, root(&json), summary(&json)))
+}
+
+/// Notes the thread roots of the timeline events in the body of a sync response, since the SDK
+/// drops thread relations when it deserializes them
+pub fn note_synced(body: &[u8]) {
+    let json: Value = match serde_json::from_slice(body) {
+        Ok(json) => json,
+        Err(_) => return,
+    };
+    let rooms = match json.pointer("/rooms/join").and_then(Value::as_object) {
+        Some(rooms) => rooms,
+        None => return,
+    };
+    let mut roots = SYNCED_ROOTS.lock().unwrap();
+    for room in rooms.values() {
+        let events = room.pointer("/timeline/events").and_then(Value::as_array);
+        for event in events.into_iter().flatten() {
+            let id = event
+                .get("event_id")
+                .and_then(Value::as_str)
+                .and_then(|id| EventId::try_from(id).ok());
+            if let (Some(id), Some(root)) = (id, root(event)) {
+                roots.insert(id, root);
+            }
+        }
+    }
+}
+
+/// Takes the thread root noted for a synced event, if it's part of a thread
+pub fn take_synced(id: &EventId) -> Option<EventId> {
+    SYNCED_ROOTS.lock().unwrap().remove(id)
+}
+
+/// Fetches a page of replies in a thread, starting at `from`, or the most recent ones if `None`
+pub async fn replies(
+    session: Session,
+    room_id: RoomId,
+    root: EventId,
+    from: Option<String>,
+) -> Result<Page, Error> {
+    let url = api_url(
+        &session,
+        &[
+            "v1",
+            "rooms",
+            room_id.as_str(),
+            "relations",
+            root.as_str(),
+            REL_TYPES[0],
+        ],
+    )?;
+    let mut request = reqwest::Client::new()
+        .get(url)
+        .bearer_auth(&session.access_token)
+        .query(&[("limit", PAGE_SIZE.to_string())]);
+    if let Some(from) = from {
+        request = request.query(&[("from", from)]);
+    }
+    let response = request.send().await?.error_for_status()?.text().await?;
+    let response: Value = serde_json::from_str(&response)?;
+
+    let events = match response.get("chunk").and_then(Value::as_array) {
+        Some(chunk) => chunk
+            .iter()
+            .filter_map(|e| {
+                let mut e = e.clone();
+                // Events from this endpoint may leave out the room id
+                if let Some(object) = e.as_object_mut() {
+                    object
+                        .entry("room_id")
+                        .or_insert_with(|| json!(room_id.as_str()));
+                }
+                serde_json::from_value(e).ok()
+            })
+            .collect(),
+        None => Vec::new(),
+    };
+    Ok(Page {
+        events,
+        next_batch: response
+            .get("next_batch")
+            .and_then(Value::as_str)
+            .map(String::from),
+    })
+}
+
+/// Content of a reply in a thread. `latest` is the most recent event in the thread, which
+/// clients without thread support show the message as a reply to.
+pub fn content(
+    root: EventId,
+    latest: EventId,
+    content: MessageEventContent,
+) -> Result<AnyMessageEventContent, Error> {
+    let mut json = serde_json::to_value(&content)?;
+    json["m.relates_to"] = json!({
+        "rel_type": REL_TYPES[0],
+        "event_id": root,
+        "is_falling_back": true,
+        "m.in_reply_to": { "event_id": latest },
+    });
+    Ok(custom_content(content.event_type(), json))
+}
@@ -0,0 +1,297 @@
+//! Uploading files and sending them as messages.
+
+use std::{
+    convert::TryInto,
+    io::Cursor,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use futures::StreamExt;
+use image::{GenericImageView, ImageOutputFormat};
+use matrix_sdk::{
+    api::r0::media::get_media_config,
+    events::room::{
+        message::{
+            AudioInfo, AudioMessageEventContent, FileInfo, FileMessageEventContent,
+            ImageMessageEventContent, MessageEventContent, MessageType, VideoInfo,
+            VideoMessageEventContent,
+        },
+        EncryptedFile, ImageInfo, ThumbnailInfo,
+    },
+    reqwest::{self, header},
+    Client,
+};
+use mime_guess::mime::{self, Mime};
+use serde_json::Value;
+
+use super::{attachment, media_url, Error, Media, Session};
+
+/// Thumbnails are generated for images larger than this
+const THUMBNAIL_SIZE: (u32, u32) = (800, 600);
+
+/// Files are handed to the connection in pieces of this size, counting them as they go
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// A thumbnail of an image that's being uploaded
+#[derive(Clone, Debug)]
+pub struct Thumbnail {
+    pub data: Vec<u8>,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// A file that has been read and is ready to be uploaded
+#[derive(Clone, Debug)]
+pub struct Prepared {
+    /// File name, used as the message body
+    pub name: String,
+    /// Detected MIME type
+    pub mime: Mime,
+    /// Contents of the file
+    pub data: Vec<u8>,
+    /// Width and height if the file is an image
+    pub dimensions: Option<(u32, u32)>,
+    /// Thumbnail if the file is a large image
+    pub thumbnail: Option<Thumbnail>,
+}
+
+impl Prepared {
+    /// Size of the file in bytes
+    pub fn size(&self) -> u64 {
+        self.data.len() as u64
+    }
+
+    /// Number of bytes to upload, counting the thumbnail
+    pub fn upload_size(&self) -> u64 {
+        let thumbnail = self.thumbnail.as_ref().map_or(0, |t| t.data.len());
+        self.size() + thumbnail as u64
+    }
+}
+
+/// Get the largest upload the server accepts, if it has a limit
+pub async fn upload_limit(client: Client) -> Result<Option<u64>, Error> {
+    let response = client.send(get_media_config::Request::new(), None).await?;
+    Ok(response.upload_size.map(u64::from))
+}
+
+/// Read a file and work out how to send it, generating a thumbnail for large images. Reading
+/// and decoding happen on a blocking thread, so they don't hold up the interface.
+pub async fn prepare(path: PathBuf, limit: Option<u64>) -> Result<Prepared, Error> {
+    tokio::task::spawn_blocking(move || read(&path, limit)).await?
+}
+
+fn read(path: &Path, limit: Option<u64>) -> Result<Prepared, Error> {
+    let size = std::fs::metadata(path)?.len();
+    if let Some(limit) = limit {
+        if size > limit {
+            return Err(anyhow::anyhow!(
+                "File is too large to upload ({}, the server allows {})",
+                format_size(size),
+                format_size(limit)
+            ));
+        }
+    }
+    let name = path
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_else(|| String::from("file"));
+    let mime = mime_guess::from_path(path).first_or_octet_stream();
+    let data = std::fs::read(path)?;
+
+    let mut dimensions = None;
+    let mut thumbnail = None;
+    if mime.type_() == mime::IMAGE {
+        // Images we can't decode are still sent, just without the extra info
+        if let Ok(image) = image::load_from_memory(&data) {
+            let (width, height) = image.dimensions();
+            dimensions = Some((width, height));
+            if width > THUMBNAIL_SIZE.0 || height > THUMBNAIL_SIZE.1 {
+                let small = image.thumbnail(THUMBNAIL_SIZE.0, THUMBNAIL_SIZE.1);
+                let mut data = Vec::new();
+                small.write_to(&mut Cursor::new(&mut data), ImageOutputFormat::Jpeg(85))?;
+                thumbnail = Some(Thumbnail {
+                    width: small.width(),
+                    height: small.height(),
+                    data,
+                });
+            }
+        }
+    }
+
+    Ok(Prepared {
+        name,
+        mime,
+        data,
+        dimensions,
+        thumbnail,
+    })
+}
+
+/// Upload some data to the media repository, adding the number of bytes sent to `sent` as it
+/// goes, and return its mxc url. The SDK can't report progress, so this uses the API directly.
+async fn upload_bytes(
+    session: &Session,
+    mime: &Mime,
+    data: Vec<u8>,
+    sent: &Arc<AtomicU64>,
+) -> Result<String, Error> {
+    let url = media_url(session, &["r0", "upload"])?;
+    let len = data.len();
+    let chunks: Vec<Result<Vec<u8>, std::io::Error>> =
+        data.chunks(CHUNK_SIZE).map(|c| Ok(c.to_vec())).collect();
+    let sent = Arc::clone(sent);
+    let body = futures::stream::iter(chunks).inspect(move |chunk| {
+        if let Ok(chunk) = chunk {
+            sent.fetch_add(chunk.len() as u64, Ordering::Relaxed);
+        }
+    });
+    let response = reqwest::Client::new()
+        .post(url)
+        .bearer_auth(&session.access_token)
+        .header(header::CONTENT_TYPE, mime.as_ref())
+        .header(header::CONTENT_LENGTH, len)
+        .body(reqwest::Body::wrap_stream(body))
+        .send()
+        .await?
+        .error_for_status()?
+        .text()
+        .await?;
+    let response: Value = serde_json::from_str(&response)?;
+    response
+        .get("content_uri")
+        .and_then(Value::as_str)
+        .map(String::from)
+        .ok_or_else(|| anyhow::anyhow!("The server didn't say where the file was uploaded"))
+}
+
+/// Upload some data, encrypting it first if `encrypt` is set
+async fn upload_data(
+    session: &Session,
+    mime: &Mime,
+    data: &[u8],
+    encrypt: bool,
+    sent: &Arc<AtomicU64>,
+) -> Result<Media, Error> {
+    if !encrypt {
+        let url = upload_bytes(session, mime, data.to_vec(), sent).await?;
+        return Ok(Media::Plain(url));
+    }
+    // The server only sees the ciphertext, so the real type goes in the message
+    let (ciphertext, file) = attachment::encrypt(data)?;
+    let url = upload_bytes(session, &mime::APPLICATION_OCTET_STREAM, ciphertext, sent).await?;
+    Ok(Media::Encrypted(Box::new(file(url))))
+}
+
+/// Split media into the `url` and `file` fields of message content
+fn media_fields(media: Media) -> (Option<String>, Option<Box<EncryptedFile>>) {
+    match media {
+        Media::Plain(url) => (Some(url), None),
+        Media::Encrypted(file) => (None, Some(file)),
+    }
+}
+
+/// Upload a prepared file and its thumbnail, returning the content of the message to send.
+/// In encrypted rooms, `encrypt` should be set so the server can't read the file. The number
+/// of bytes sent so far is kept in `sent`, out of [`Prepared::upload_size`].
+pub async fn upload(
+    session: Session,
+    prepared: Prepared,
+    encrypt: bool,
+    sent: Arc<AtomicU64>,
+) -> Result<MessageEventContent, Error> {
+    let size = prepared.size();
+    let media = upload_data(&session, &prepared.mime, &prepared.data, encrypt, &sent).await?;
+    let (url, file) = media_fields(media);
+    let body = prepared.name;
+    let mimetype = Some(prepared.mime.essence_str().to_owned());
+
+    let msgtype = match prepared.mime.type_() {
+        mime::IMAGE => {
+            let mut info = ImageInfo::new();
+            info.mimetype = mimetype;
+            info.size = size.try_into().ok();
+            if let Some((width, height)) = prepared.dimensions {
+                info.width = Some(width.into());
+                info.height = Some(height.into());
+            }
+            if let Some(thumbnail) = prepared.thumbnail {
+                let thumbnail_size = thumbnail.data.len() as u64;
+                let media =
+                    upload_data(&session, &mime::IMAGE_JPEG, &thumbnail.data, encrypt, &sent)
+                        .await?;
+                let mut thumbnail_info = ThumbnailInfo::new();
+                thumbnail_info.mimetype = Some(mime::IMAGE_JPEG.to_string());
+                thumbnail_info.size = thumbnail_size.try_into().ok();
+                thumbnail_info.width = Some(thumbnail.width.into());
+                thumbnail_info.height = Some(thumbnail.height.into());
+                let (thumbnail_url, thumbnail_file) = media_fields(media);
+                info.thumbnail_url = thumbnail_url;
+                info.thumbnail_file = thumbnail_file;
+                info.thumbnail_info = Some(Box::new(thumbnail_info));
+            }
+            let mut content =
+                ImageMessageEventContent::plain(body, String::new(), Some(Box::new(info)));
+            content.url = url;
+            content.file = file;
+            MessageType::Image(content)
+        }
+        mime::AUDIO => {
+            let mut info = AudioInfo::new();
+            info.mimetype = mimetype;
+            info.size = size.try_into().ok();
+            let mut content =
+                AudioMessageEventContent::plain(body, String::new(), Some(Box::new(info)));
+            content.url = url;
+            content.file = file;
+            MessageType::Audio(content)
+        }
+        mime::VIDEO => {
+            let mut info = VideoInfo::new();
+            info.mimetype = mimetype;
+            info.size = size.try_into().ok();
+            let mut content =
+                VideoMessageEventContent::plain(body, String::new(), Some(Box::new(info)));
+            content.url = url;
+            content.file = file;
+            MessageType::Video(content)
+        }
+        _ => {
+            let mut info = FileInfo::new();
+            info.mimetype = mimetype;
+            info.size = size.try_into().ok();
+            let mut content =
+                FileMessageEventContent::plain(body, String::new(), Some(Box::new(info)));
+            content.url = url;
+            content.file = file;
+            MessageType::File(content)
+        }
+    };
+    Ok(MessageEventContent::new(msgtype))
+}
+
+/// Format a number of bytes for display
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: &[&str] = &["B", "KiB", "MiB", "GiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    match unit {
+        0 => format!("{} {}", bytes, UNITS[0]),
+        _ => format!("{:.1} {}", size, UNITS[unit]),
+    }
+}
+
+/// Expand a leading `~` in a path the user typed
+pub fn expand_path(path: &str) -> PathBuf {
+    match (path.strip_prefix("~/"), dirs::home_dir()) {
+        (Some(rest), Some(home)) => home.join(rest),
+        _ => PathBuf::from(path),
+    }
+}
@@ -1,39 +1,112 @@
 use std::{
-    collections::{BTreeMap, HashSet},
-    time::SystemTime,
+    collections::{BTreeMap, BTreeSet, HashSet},
+    convert::TryFrom,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime},
 };
 
-use futures::executor::block_on;
+use futures::{
+    executor::block_on,
+    future::{abortable, AbortHandle},
+};
 use iced::{
-    Align, Application, Button, Column, Command, Container, Element, Image, Length, Row, Rule,
-    Scrollable, Subscription, Text, TextInput,
+    tooltip, Align, Application, Button, Column, Command, Container, Element, Image, Length,
+    ProgressBar, Radio, Rectangle, Row, Rule, Scrollable, Subscription, Text, TextInput, Tooltip,
 };
 use matrix_sdk::{
     api::r0::{
-        media::get_content::Request as ImageRequest,
         message::get_message_events::{Request as MessageRequest, Response as MessageResponse},
+        room::get_room_event::Request as EventRequest,
     },
     events::{
         key::verification::cancel::CancelCode as VerificationCancelCode,
+        presence::PresenceEvent,
+        reaction::ReactionEvent,
         room::{
             member::MembershipState,
-            message::{MessageEventContent, MessageType, Relation},
+            message::{
+                FormattedBody, MessageEvent, MessageEventContent, MessageFormat, MessageType,
+            },
         },
-        AnyMessageEvent, AnyMessageEventContent, AnyRoomEvent, AnyStateEvent, AnyToDeviceEvent,
+        AnyBasicEvent, AnyMessageEvent, AnyMessageEventContent, AnyRoomEvent, AnyStateEvent,
+        AnyToDeviceEvent,
     },
     identifiers::{EventId, RoomAliasId, RoomId, UserId},
+    presence::PresenceState,
 };
 
-use crate::matrix::{self, AnyMessageEventExt, AnyRoomEventExt};
+use crate::matrix::{
+    self,
+    cache::{self, MediaCache, SharedCache},
+    command::{self, SlashCommand},
+    edit,
+    location::{self, Location},
+    outbox::{self, Outgoing},
+    push::{self, PushContext, PushRule, RoomMode, RuleKind, Ruleset},
+    reaction, reply, sticker, thread, AnyMessageEventExt, AnyRoomEventExt,
+};
 
+pub mod animation;
+pub mod html;
+pub mod notice;
 pub mod prompt;
 pub mod settings;
 pub mod theme;
+pub mod timeline;
+pub mod viewer;
 
+use animation::Animation;
 use prompt::{PromptAction, PromptView};
 use settings::SettingsView;
+use timeline::{Chunks, Position, Timeline};
+use viewer::ImageViewer;
 
 const THUMBNAIL_SIZE: u32 = 48;
+/// Largest size images are shown at in the timeline
+const MAX_IMAGE_SIZE: (u32, u32) = (640, 480);
+/// Largest size of video thumbnails on attachment cards
+const ATTACHMENT_THUMBNAIL: (u32, u32) = (160, 120);
+/// Size of stickers that don't say how big they are
+const STICKER_SIZE: (u32, u32) = (256, 256);
+/// Size of stickers in the sticker picker
+const STICKER_PICKER_SIZE: (u32, u32) = (64, 64);
+/// Stickers per row in the sticker picker
+const STICKERS_PER_ROW: usize = 6;
+/// Thumbnail sizes servers are likely to have pregenerated, from smallest to largest
+const THUMBNAIL_SIZES: &[(u32, u32)] = &[(320, 240), (640, 480), (800, 600)];
+/// Bytes in a mebibyte, the unit of the media cache size setting
+const MIB: u64 = 1024 * 1024;
+/// How long without interaction before we're automatically marked as away
+const IDLE_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+/// How often to try connecting to the homeserver while offline
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(15);
+/// How often to show how much of an upload has been sent
+const UPLOAD_PROGRESS_INTERVAL: Duration = Duration::from_millis(250);
+/// How many of the most recent events in a room to keep for offline use
+const HISTORY_CACHE_SIZE: usize = 50;
+/// Space between the widgets in the timeline
+const TIMELINE_SPACING: u16 = 4;
+/// Height of a line of text in the default size, used to estimate the height of events that
+/// are scrolled out of view
+const TEXT_HEIGHT: f32 = 24.0;
+/// Height of a line of the smaller text used for notices and buttons
+const SMALL_TEXT_HEIGHT: f32 = 17.0;
+/// Height of a button with text in the default size
+const BUTTON_HEIGHT: f32 = 34.0;
+/// Height of a button with the smaller text
+const SMALL_BUTTON_HEIGHT: f32 = 27.0;
+/// Height of the quote of the message a message replies to
+const QUOTE_HEIGHT: f32 = 25.0;
+/// Average width of a character in the default text size, to estimate where messages wrap
+const CHAR_WIDTH: f32 = 10.0;
+/// Width taken by the time and buttons next to a message
+const MESSAGE_CONTROLS_WIDTH: u32 = 300;
+/// How many events outside the visible part of the timeline get widgets anyway
+const TIMELINE_OVERSCAN: usize = 20;
 
 /// What order to sort rooms in in the room list.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -42,6 +115,112 @@ pub enum RoomSorting {
     Alphabetic,
 }
 
+/// Progress of saving an attachment to disk
+#[derive(Debug, Clone)]
+pub enum Download {
+    /// Downloading and saving the file
+    InProgress,
+    /// The file was saved at this path
+    Saved(String),
+    /// Saving the file failed
+    Failed(String),
+}
+
+/// A file being uploaded
+#[derive(Debug, Clone)]
+pub struct Upload {
+    /// Room the file will be sent to
+    room: RoomId,
+    /// Name of the file
+    name: String,
+    /// Number of bytes to upload, once the file has been read
+    size: Option<u64>,
+    /// Number of bytes sent so far
+    sent: Arc<AtomicU64>,
+    /// The file, if it was read while offline and is waiting for us to connect
+    waiting: Option<matrix::upload::Prepared>,
+    /// Handle to cancel the upload
+    abort: AbortHandle,
+    /// Button to cancel the upload
+    cancel_button: iced::button::State,
+}
+
+/// Presence state the user can choose for themselves.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PresenceSetting {
+    Online,
+    Unavailable,
+    Offline,
+}
+
+impl Default for PresenceSetting {
+    fn default() -> Self {
+        PresenceSetting::Online
+    }
+}
+
+impl PresenceSetting {
+    fn state(self) -> PresenceState {
+        match self {
+            PresenceSetting::Online => PresenceState::Online,
+            PresenceSetting::Unavailable => PresenceState::Unavailable,
+            PresenceSetting::Offline => PresenceState::Offline,
+        }
+    }
+}
+
+/// Last known presence of a user
+#[derive(Debug, Clone)]
+pub struct Presence {
+    /// Online status
+    pub state: PresenceState,
+    /// Whether the user is actively using their client
+    pub currently_active: bool,
+    /// When the user was last active
+    pub last_active: Option<SystemTime>,
+    /// Custom status message
+    pub status: Option<String>,
+}
+
+impl Presence {
+    pub fn from_event(event: &PresenceEvent) -> Self {
+        let content = &event.content;
+        Self {
+            state: content.presence.clone(),
+            currently_active: content.currently_active.unwrap_or(false),
+            last_active: content
+                .last_active_ago
+                .map(|ago| SystemTime::now() - Duration::from_millis(ago.into())),
+            status: content.status_msg.clone().filter(|s| !s.is_empty()),
+        }
+    }
+
+    /// Coloured dot indicating the presence state
+    pub fn indicator(&self) -> Text {
+        let color = match self.state {
+            PresenceState::Online => [0.0, 0.7, 0.0],
+            PresenceState::Unavailable => [0.9, 0.6, 0.0],
+            _ => [0.5, 0.5, 0.5],
+        };
+        Text::new("\u{25CF}").color(color)
+    }
+
+    /// Description of when the user was last active
+    pub fn activity(&self) -> String {
+        if self.currently_active {
+            return String::from("Active now");
+        }
+        match self.last_active.and_then(|t| t.elapsed().ok()) {
+            Some(ago) => format!("Last active {} ago", format_duration(ago)),
+            None => match self.state {
+                PresenceState::Online => String::from("Online"),
+                PresenceState::Unavailable => String::from("Away"),
+                _ => String::from("Offline"),
+            },
+        }
+    }
+}
+
 /// Data for en entry in the room list
 #[derive(Clone, Debug, Default)]
 pub struct RoomEntry {
@@ -57,8 +236,18 @@ pub struct RoomEntry {
     pub avatar: Option<String>,
     /// Person we're in a direct message with
     pub direct: Option<UserId>,
+    /// Display names of joined members, if loaded
+    pub members: Option<BTreeMap<UserId, String>>,
     /// Cache of messages
     pub messages: MessageBuffer,
+    /// Threads in the room, by the id of their root
+    pub threads: BTreeMap<EventId, Thread>,
+    /// Messages since the room was last opened that the push rules say notify
+    pub notifications: u64,
+    /// Messages since the room was last opened that the push rules say mention us
+    pub highlights: u64,
+    /// What the push rules need to know about the room, until its state changes
+    push_context: Option<PushContext>,
 }
 
 impl RoomEntry {
@@ -74,15 +263,48 @@ impl RoomEntry {
     }
 }
 
-// Alternate storage strategies: HashMap<EventId, Event>+Vec<EventId>,
-// HashSet<EventId>+BTreemap<Event + Ord(origin_server_ts)>
+/// Replies in a thread, which are kept out of the main timeline
+#[derive(Clone, Debug, Default)]
+pub struct Thread {
+    /// Number of replies according to the server
+    count: u64,
+    /// The replies we have
+    pub messages: MessageBuffer,
+    /// Token for fetching older replies, if there are any
+    next_batch: Option<String>,
+    /// Whether we've fetched replies from the server
+    loaded: bool,
+}
+
+impl Thread {
+    /// Number of replies in the thread
+    pub fn count(&self) -> u64 {
+        self.count.max(self.messages.messages.len() as u64)
+    }
+
+    /// The most recent reply
+    pub fn latest(&self) -> Option<&AnyRoomEvent> {
+        self.messages.messages.last()
+    }
+}
+
 /// Message history/event cache for a given room.
 #[derive(Clone, Debug)]
 pub struct MessageBuffer {
-    /// The messages we have stored
-    messages: Vec<AnyRoomEvent>,
-    /// Set of event id's we have
+    /// The messages we have stored, in the order the server sent them
+    messages: Timeline<Position, EventId, AnyRoomEvent>,
+    /// Runs of messages with nothing missing between them, and the gaps between those
+    chunks: Chunks,
+    /// Set of event id's we have, including edits and reactions
     known_ids: HashSet<EventId>,
+    /// Edits of messages, by the id of the message they edit, oldest first
+    edits: BTreeMap<EventId, Vec<AnyRoomEvent>>,
+    /// Reactions to messages, by the id of the message they react to
+    reactions: BTreeMap<EventId, Vec<AnyRoomEvent>>,
+    /// Messages that have been redacted, or that we're in the process of redacting
+    redacted: HashSet<EventId>,
+    /// Whether we have the room creation event
+    beginning: bool,
     /// Token for the start of the messages we have
     start: Option<String>,
     /// Token for the end of the messages we have
@@ -91,15 +313,16 @@ pub struct MessageBuffer {
     updated: std::time::SystemTime,
     /// Whether we're awaiting for backfill to be received
     loading: bool,
+    /// Whether there are new events that aren't in the history cache
+    dirty: bool,
+    /// Changes whenever something that's shown in the timeline does, so the layout of the
+    /// timeline only has to be estimated again then
+    version: u64,
+    /// Messages we're sending, shown after the timeline until their remote echo arrives
+    pending: Vec<Outgoing>,
 }
 
 impl MessageBuffer {
-    /// Sorts the messages by send time
-    fn sort(&mut self) {
-        self.messages
-            .sort_unstable_by_key(|msg| msg.origin_server_ts())
-    }
-
     /// Gets the send time of the most recently sent message
     fn update_time(&mut self) {
         self.updated = match self.messages.last() {
@@ -108,68 +331,285 @@ impl MessageBuffer {
         };
     }
 
-    fn remove(&mut self, id: &EventId) {
-        self.messages.retain(|e| e.event_id() != id);
-        self.known_ids.remove(&id);
+    /// Mark a message as redacted. Redacted edits and reactions are ignored, so a message
+    /// goes back to its previous version when an edit is redacted.
+    pub fn redact(&mut self, id: &EventId) {
+        self.redacted.insert(id.clone());
+        self.version += 1;
     }
 
-    /// Add a message to the buffer.
-    pub fn push(&mut self, event: AnyRoomEvent) {
-        self.known_ids.insert(event.event_id().clone());
-        if let AnyRoomEvent::Message(AnyMessageEvent::RoomMessage(
-            matrix_sdk::events::MessageEvent {
-                content:
-                    MessageEventContent {
-                        relates_to: Some(Relation::Replacement(ref replacement)),
-                        ..
-                    },
-                ..
-            },
-        )) = event
-        {
-            self.remove(&replacement.event_id);
+    /// Take an event out of the timeline, for events that turn out to belong elsewhere. The
+    /// event stays known, so it isn't added back when it's synced or paginated again.
+    pub fn remove_from_timeline(&mut self, id: &EventId) {
+        self.messages.remove(id);
+        self.version += 1;
+    }
+
+    /// Undo a local redaction that failed to be sent
+    pub fn unredact(&mut self, id: &EventId) {
+        self.redacted.remove(id);
+        self.version += 1;
+    }
+
+    /// Whether a message has been redacted
+    pub fn is_redacted(&self, id: &EventId) -> bool {
+        self.redacted.contains(id)
+    }
+
+    /// Drop the pending message an event is the remote echo of
+    fn match_echo(&mut self, event: &AnyRoomEvent) {
+        let txn_id = event.transaction_id();
+        let sent = outbox::State::Sent(event.event_id().clone());
+        self.pending
+            .retain(|message| Some(message.txn_id.as_str()) != txn_id && message.state != sent);
+    }
+
+    /// Add a message we're sending
+    pub fn push_pending(&mut self, message: Outgoing) {
+        self.pending.push(message);
+    }
+
+    /// Messages we're sending, oldest first
+    pub fn pending(&self) -> &[Outgoing] {
+        &self.pending
+    }
+
+    /// Gets a message we're sending by its transaction ID
+    pub fn pending_mut(&mut self, txn_id: &str) -> Option<&mut Outgoing> {
+        self.pending.iter_mut().find(|m| m.txn_id == txn_id)
+    }
+
+    /// Stop showing a message we're sending
+    pub fn remove_pending(&mut self, txn_id: &str) {
+        self.pending.retain(|m| m.txn_id != txn_id);
+    }
+
+    /// Mark a message as accepted by the server, dropping it if the remote echo came first
+    pub fn pending_sent(&mut self, txn_id: &str, id: EventId) {
+        if self.known_ids.contains(&id) {
+            self.remove_pending(txn_id);
+        } else if let Some(message) = self.pending_mut(txn_id) {
+            message.state = outbox::State::Sent(id);
         }
+    }
+
+    /// Handles relations of a new event. Returns the event if it should be shown in the
+    /// timeline, or `None` if it was aggregated onto another event.
+    fn aggregate(&mut self, event: AnyRoomEvent) -> Option<AnyRoomEvent> {
         if let AnyRoomEvent::Message(AnyMessageEvent::RoomRedaction(ref redaction)) = event {
-            self.remove(&redaction.redacts);
+            self.redact(&redaction.redacts);
         }
-        self.messages.push(event);
-        self.sort();
-        self.update_time();
+        if let Some(original) = edit::replaces(&event) {
+            let edits = self.edits.entry(original.clone()).or_default();
+            edits.push(event);
+            edits.sort_by_key(|e| e.origin_server_ts());
+            return None;
+        }
+        if let Some(reaction) = reaction::as_reaction(&event) {
+            let target = reaction.content.relation.event_id.clone();
+            self.reactions.entry(target).or_default().push(event);
+            return None;
+        }
+        Some(event)
     }
 
-    /// Adds several messages to the buffer
-    pub fn append(&mut self, mut events: Vec<AnyRoomEvent>) {
-        events.retain(|e| !self.known_ids.contains(e.event_id()));
-        for event in events.iter() {
-            // Handle replacement
-            if let AnyRoomEvent::Message(AnyMessageEvent::RoomMessage(
-                matrix_sdk::events::MessageEvent {
-                    content:
-                        MessageEventContent {
-                            relates_to: Some(Relation::Replacement(replacement)),
-                            ..
-                        },
-                    ..
-                },
-            )) = event
-            {
-                self.remove(&replacement.event_id);
+    /// Add an event we don't have yet, putting it in the timeline at the position `position`
+    /// hands out. Returns whether the event was new.
+    fn add(&mut self, event: AnyRoomEvent, position: impl FnOnce(&mut Chunks) -> Position) -> bool {
+        if !self.known_ids.insert(event.event_id().clone()) {
+            return false;
+        }
+        self.version += 1;
+        self.match_echo(&event);
+        if let Some(event) = self.aggregate(event) {
+            if let AnyRoomEvent::State(AnyStateEvent::RoomCreate(_)) = event {
+                self.beginning = true;
             }
-            if let AnyRoomEvent::Message(AnyMessageEvent::RoomRedaction(redaction)) = event {
-                self.remove(&redaction.redacts);
+            let id = event.event_id().clone();
+            self.messages.insert(position(&mut self.chunks), id, event);
+        }
+        true
+    }
+
+    /// Add a message after the ones we have, like one from sync.
+    pub fn push(&mut self, event: AnyRoomEvent) {
+        self.add(event, Chunks::after);
+        self.update_time();
+        self.dirty = true;
+    }
+
+    /// Adds several messages after the ones we have, oldest first
+    pub fn append(&mut self, events: Vec<AnyRoomEvent>) {
+        for event in events {
+            self.add(event, Chunks::after);
+        }
+        self.update_time();
+        self.dirty = true;
+    }
+
+    /// Adds messages from paginating back from the start of what we have, newest first
+    pub fn prepend(&mut self, events: Vec<AnyRoomEvent>) {
+        for event in events {
+            self.add(event, |chunks| chunks.before(0));
+        }
+        self.update_time();
+        self.dirty = true;
+    }
+
+    /// Note that sync skipped some messages, so the next ones start a new chunk. The skipped
+    /// messages can be fetched by paginating back from `token`.
+    pub fn gap(&mut self, token: String) {
+        self.chunks.gap(token);
+        self.version += 1;
+    }
+
+    /// The first chunk with messages missing before it, if there is one
+    pub fn first_gap(&self) -> Option<usize> {
+        self.chunks.first_gap()
+    }
+
+    /// Adds messages from paginating back from the gap before `chunk`, newest first. Once we
+    /// reach a message we have, the gap is closed and the rest of the messages are ones we have
+    /// or belong before the previous chunk, so they're left out. Otherwise the gap now starts at
+    /// `token`.
+    pub fn fill_gap(&mut self, chunk: usize, events: Vec<AnyRoomEvent>, token: Option<String>) {
+        let mut closed = token.is_none();
+        for event in events {
+            if !self.add(event, |chunks| chunks.before(chunk)) {
+                closed = true;
+                break;
             }
-            self.known_ids.insert(event.event_id().clone());
         }
-        self.messages.append(&mut events);
-        self.sort();
+        if let Some(chunk) = self.chunks.get_mut(chunk) {
+            chunk.filling = false;
+            chunk.gap = if closed { None } else { token };
+        }
+        self.version += 1;
         self.update_time();
+        self.dirty = true;
+    }
+
+    /// Adds the newest messages from paginating back from the present, newest first. Messages
+    /// older than the ones we have are left out, since we can't tell where they belong.
+    pub fn catch_up(&mut self, events: Vec<AnyRoomEvent>) {
+        let mut new: Vec<AnyRoomEvent> = events
+            .into_iter()
+            .take_while(|event| !self.known_ids.contains(event.event_id()))
+            .collect();
+        new.reverse();
+        self.append(new);
+    }
+
+    /// The most recent events to cache, at most `count` of them along with their edits and
+    /// reactions, in the chunks they're in
+    pub fn recent(&self, count: usize) -> matrix::History {
+        let len = self.messages.len();
+        let start = len.saturating_sub(count);
+        let first_chunk = match self.messages.window(start..len).next() {
+            Some((position, _)) => position.chunk,
+            None => return matrix::History::default(),
+        };
+        // Chunks without events are kept too, for the gaps before them
+        let mut chunks: Vec<matrix::HistoryChunk> = (first_chunk..self.chunks.len())
+            .map(|chunk| matrix::HistoryChunk {
+                gap: self.chunks.get(chunk).and_then(|c| c.gap.clone()),
+                events: Vec::new(),
+            })
+            .collect();
+        for (position, event) in self.messages.window(start..len) {
+            let events = &mut chunks[position.chunk - first_chunk].events;
+            events.push(event.clone());
+            let id = event.event_id();
+            for related in self.edits.get(id).into_iter().chain(self.reactions.get(id)) {
+                events.extend(related.iter().cloned());
+            }
+        }
+        // Nothing can be missing before the oldest chunk once it's loaded, so the token for the
+        // gap before it is where to continue loading older events from instead. That only holds
+        // if none of its events were left out, otherwise we don't have a token for its oldest
+        // cached event.
+        let before = start
+            .checked_sub(1)
+            .and_then(|previous| self.messages.window(previous..start).next())
+            .map(|(position, _)| position.chunk);
+        let gap = chunks[0].gap.take();
+        let end = match before {
+            None => self.end.clone(),
+            Some(chunk) if chunk == first_chunk => None,
+            Some(_) => gap,
+        };
+        matrix::History { chunks, end }
+    }
+
+    /// Add the events from the history cache to an empty buffer
+    pub fn restore(&mut self, history: matrix::History) {
+        for chunk in history.chunks {
+            if let Some(token) = chunk.gap {
+                self.gap(token);
+            }
+            self.append(chunk.events);
+        }
+        self.end = history.end;
+    }
+
+    /// Edits of a message made by its sender, oldest first
+    pub fn edits<'a>(
+        &'a self,
+        message: &'a MessageEvent,
+    ) -> impl Iterator<Item = &'a MessageEvent> {
+        self.edits
+            .get(&message.event_id)
+            .into_iter()
+            .flatten()
+            .filter(move |e| !self.redacted.contains(e.event_id()))
+            .filter_map(|e| match e {
+                AnyRoomEvent::Message(AnyMessageEvent::RoomMessage(edit)) => Some(edit),
+                _ => None,
+            })
+            .filter(move |edit| edit.sender == message.sender)
+    }
+
+    /// Reactions to a message grouped by key, in the order each key was first used. Each user
+    /// is only counted once per key.
+    pub fn reactions(&self, id: &EventId) -> Vec<(&str, Vec<&ReactionEvent>)> {
+        let mut groups: Vec<(&str, Vec<&ReactionEvent>)> = Vec::new();
+        let reactions = self
+            .reactions
+            .get(id)
+            .into_iter()
+            .flatten()
+            .filter(|e| !self.redacted.contains(e.event_id()))
+            .filter_map(reaction::as_reaction);
+        for reaction in reactions {
+            let key = reaction.content.relation.emoji.as_str();
+            match groups.iter_mut().find(|(k, _)| *k == key) {
+                Some((_, group)) => {
+                    if group.iter().all(|r| r.sender != reaction.sender) {
+                        group.push(reaction);
+                    }
+                }
+                None => groups.push((key, vec![reaction])),
+            }
+        }
+        groups
+    }
+
+    /// The current content of a message, taking edits into account
+    pub fn latest_content(&self, message: &MessageEvent) -> MessageEventContent {
+        match self.edits(message).last() {
+            Some(edit) => edit::new_content(edit),
+            None => message.content.clone(),
+        }
+    }
+
+    /// Gets an event by its id
+    pub fn get(&self, id: &EventId) -> Option<&AnyRoomEvent> {
+        self.messages.get(id)
     }
 
     /// Whather the message buffer has the room creation event
     pub fn has_beginning(&self) -> bool {
-        self.messages
-            .iter()
-            .any(|e| matches!(e, AnyRoomEvent::State(AnyStateEvent::RoomCreate(_))))
+        self.beginning
     }
 }
 
@@ -177,39 +617,178 @@ impl Default for MessageBuffer {
     fn default() -> Self {
         Self {
             messages: Default::default(),
+            chunks: Default::default(),
             known_ids: Default::default(),
+            edits: Default::default(),
+            reactions: Default::default(),
+            redacted: Default::default(),
+            beginning: false,
             start: None,
             end: None,
             updated: SystemTime::UNIX_EPOCH,
             loading: false,
+            dirty: false,
+            version: 0,
+            pending: Vec::new(),
         }
     }
 }
 
+/// Buttons for acting on a message in the timeline
+#[derive(Debug, Clone, Default)]
+pub struct MessageButtons {
+    /// Button to reply to the message
+    reply: iced::button::State,
+    /// Button to edit the message
+    edit: iced::button::State,
+    /// Button to view the edit history of the message
+    edited: iced::button::State,
+    /// Button to delete the message
+    delete: iced::button::State,
+    /// Button to open the reaction picker
+    react: iced::button::State,
+    /// Reaction chips under the message
+    reactions: Vec<iced::button::State>,
+    /// Button to open the thread the message is the root of
+    thread: iced::button::State,
+    /// Button to open an image in the viewer
+    image: iced::button::State,
+    /// Button to play or pause an animated image
+    play: iced::button::State,
+    /// Button to save an attachment
+    download: iced::button::State,
+    /// Button to save and open an attachment
+    open: iced::button::State,
+    /// Button to open a location on a map
+    map: iced::button::State,
+}
+
 /// Main view after successful login
 #[derive(Debug, Clone)]
 pub struct MainView {
     /// Settings view, if open
     settings_view: Option<SettingsView>,
+    /// Image viewer, if open
+    image_viewer: Option<ImageViewer>,
     /// The matrix-sdk client
     client: matrix_sdk::Client,
     /// Sync token to use for backfill calls
     sync_token: String,
+    /// Whether we've connected to the homeserver. While offline, cached data is shown.
+    online: bool,
+    /// Whether a connection attempt is in progress
+    connecting: bool,
+    /// Why the last connection attempt failed
+    connection_error: Option<String>,
+    /// Rooms that are being loaded from the store
+    loading_rooms: BTreeSet<RoomId>,
+    /// Events from catching up after connecting for rooms that are still being loaded
+    unloaded_events: BTreeMap<RoomId, Vec<matrix::Event>>,
+    /// Unsent messages from the outbox on disk for rooms that haven't been loaded yet
+    unloaded_outbox: BTreeMap<RoomId, Vec<Outgoing>>,
     session: matrix::Session,
     /// Draft of message to send
     draft: String,
+    /// Event the message being composed is a reply to
+    reply_to: Option<EventId>,
+    /// Own message the message being composed is an edit of
+    editing: Option<EventId>,
+    /// Message whose edit history is being viewed
+    edit_history: Option<EventId>,
+    /// Message we're asking for confirmation to delete
+    redacting: Option<EventId>,
+    /// Reason for deleting the message
+    redact_reason: String,
+    /// Root of the thread open in the side panel
+    open_thread: Option<EventId>,
+    /// Draft of a reply in the open thread
+    thread_draft: String,
+    /// Whether the input for attaching a file is shown
+    attaching: bool,
+    /// Path of the file to attach
+    attach_path: String,
+    /// File currently being uploaded
+    upload: Option<Upload>,
+    /// Largest file the server accepts, if it told us
+    upload_limit: Option<u64>,
+    /// Whether the sticker picker is shown
+    picking_sticker: bool,
+    /// Sticker packs usable in a room, once they've been fetched
+    sticker_packs: Option<(RoomId, Vec<sticker::Pack>)>,
+    /// Why the last command couldn't be run
+    command_error: Option<String>,
+    /// Commands run while offline, which are run once we're connected
+    held_commands: Vec<(RoomId, SlashCommand)>,
+    /// Messages deleted while offline, which are deleted on the server once we're connected
+    held_redactions: Vec<(RoomId, EventId, Option<String>)>,
+    /// Output of `/help`
+    command_help: Option<String>,
+    /// Whether the developer tools panel is shown
+    devtools: bool,
+    /// ID of the event whose source is shown in the developer tools
+    devtools_event: String,
+    /// Whether the input for sharing a location is shown
+    sharing_location: bool,
+    /// Coordinates or `geo:` URI of the location to share
+    location_input: String,
+    /// Description of the location to share
+    location_description: String,
+    /// Why the location to share couldn't be parsed
+    location_error: Option<String>,
+    /// Attachment being saved, and the path to save it to
+    saving_attachment: Option<(EventId, String)>,
+    /// Attachments that have been saved or are being saved
+    downloads: BTreeMap<EventId, Download>,
+    /// Message the reaction picker is open for
+    reacting_to: Option<EventId>,
+    /// Custom reaction in the reaction picker
+    reaction_input: String,
+    /// Events fetched individually because they're not in the timeline, like reply parents
+    fetched_events: BTreeMap<EventId, AnyRoomEvent>,
+    /// Events being fetched individually
+    fetching_events: HashSet<EventId>,
     /// Potential error message
     error: Option<(String, iced::button::State)>,
+    /// Downloaded media stored on disk
+    media_cache: SharedCache,
     /// Selected room
     selected: Option<RoomId>,
     /// Potential verification flow
     sas: Option<matrix_sdk::Sas>,
     /// Whether to sort rooms alphabetically or by activity
     sorting: RoomSorting,
+    /// Whether to format sent messages as markdown
+    markdown: bool,
+    /// Whether animated images play without being started
+    autoplay: bool,
+    /// Whether to hide members joining and leaving from the timeline
+    hide_joins: bool,
+    /// The account's push rules
+    push_rules: Ruleset,
+    /// Presence of other users
+    presence: BTreeMap<UserId, Presence>,
+    /// Presence we've chosen for ourselves
+    own_presence: PresenceSetting,
+    /// Our status message
+    status_msg: String,
+    /// Time of last user interaction
+    last_activity: Instant,
+    /// Whether we've been automatically marked as away
+    idle: bool,
+    /// Whether to show the member list
+    show_members: bool,
     /// Room state
     rooms: BTreeMap<RoomId, RoomEntry>,
-    /// A map of mxc urls to image data
+    /// A map of mxc urls to images scaled down for the timeline
     images: BTreeMap<String, iced::image::Handle>,
+    /// A map of mxc urls to full resolution images
+    originals: BTreeMap<String, iced::image::Handle>,
+    /// Full resolution images being downloaded
+    fetching_originals: HashSet<String>,
+    /// A map of mxc urls to animated images
+    animations: BTreeMap<String, Animation>,
+    /// Size of the window, used to fit images in the timeline
+    window_size: (u32, u32),
     /// A map of mxc urls to image thumbnails
     thumbnails: BTreeMap<String, iced::image::Handle>,
 
@@ -221,6 +800,12 @@ pub struct MainView {
     room_scroll: iced::scrollable::State,
     /// Message view scrollbar state
     message_scroll: iced::scrollable::State,
+    /// Estimated layout of the timeline that was last shown, and what it was estimated from
+    timeline_layout: Option<(TimelineLayout, timeline::Heights)>,
+    /// Member list scrollbar state
+    member_scroll: iced::scrollable::State,
+    /// Button to toggle member list
+    members_button: iced::button::State,
     /// Backfill fetch button state
     backfill_button: iced::button::State,
     /// Button to go the room a tombstone points to
@@ -229,40 +814,208 @@ pub struct MainView {
     message_input: iced::text_input::State,
     /// Button to send drafted message
     send_button: iced::button::State,
+    /// Buttons for each message in the timeline
+    message_buttons: Vec<MessageButtons>,
+    /// Button to stop replying or editing
+    compose_cancel_button: iced::button::State,
+    /// Scroll position of the edit history
+    edit_history_scroll: iced::scrollable::State,
+    /// Button to close the edit history
+    edit_history_close: iced::button::State,
+    /// Text input for the reason for deleting a message
+    redact_reason_input: iced::text_input::State,
+    /// Button to confirm deleting a message
+    redact_confirm_button: iced::button::State,
+    /// Button to cancel deleting a message
+    redact_cancel_button: iced::button::State,
+    /// Text input for the path to save an attachment to
+    attachment_path_input: iced::text_input::State,
+    /// Button to save an attachment
+    attachment_save_button: iced::button::State,
+    /// Button to cancel saving an attachment
+    attachment_cancel_button: iced::button::State,
+    /// Buttons for the reactions in the reaction picker
+    reaction_buttons: Vec<iced::button::State>,
+    /// Text input for a custom reaction
+    reaction_input_state: iced::text_input::State,
+    /// Button to close the reaction picker
+    reaction_cancel_button: iced::button::State,
+    /// Scroll position of the thread panel
+    thread_scroll: iced::scrollable::State,
+    /// Button to load older replies in the thread panel
+    thread_backfill_button: iced::button::State,
+    /// Text input for replying in a thread
+    thread_input: iced::text_input::State,
+    /// Button to send a reply in a thread
+    thread_send_button: iced::button::State,
+    /// Button to close the thread panel
+    thread_close_button: iced::button::State,
+    /// Button to show the input for attaching a file
+    attach_button: iced::button::State,
+    /// Text input for the path of a file to attach
+    attach_input: iced::text_input::State,
+    /// Button to upload the attached file
+    upload_button: iced::button::State,
+    /// Button to show the sticker picker
+    sticker_button: iced::button::State,
+    /// Stickers in the sticker picker
+    sticker_buttons: Vec<iced::button::State>,
+    /// Scrollbar of the sticker picker
+    sticker_scroll: iced::scrollable::State,
+    /// Button to close the output of `/help`
+    command_help_close: iced::button::State,
+    /// Scroll position of the developer tools panel
+    devtools_scroll: iced::scrollable::State,
+    /// Button to close the developer tools panel
+    devtools_close: iced::button::State,
+    /// Text input for the event to show the source of in the developer tools
+    devtools_event_input: iced::text_input::State,
+    /// Button to show the input for sharing a location
+    location_button: iced::button::State,
+    /// Text input for the location to share
+    location_input_state: iced::text_input::State,
+    /// Text input for the description of the location to share
+    location_description_input: iced::text_input::State,
+    /// Button to share the location
+    location_share_button: iced::button::State,
     /// Button to open settings menu
     settings_button: iced::button::State,
     /// Button for accepting/continuing verification
     sas_accept_button: iced::button::State,
     /// Button for cancelling verification
     sas_deny_button: iced::button::State,
+    /// Button to retry connecting while offline
+    reconnect_button: iced::button::State,
+    /// Buttons to retry and delete messages we're sending
+    pending_buttons: Vec<(iced::button::State, iced::button::State)>,
+    /// Buttons for loading messages missing from the timeline, by chunk
+    gap_buttons: Vec<iced::button::State>,
 }
 
 impl MainView {
     pub fn new(client: matrix_sdk::Client, session: matrix::Session) -> Self {
+        // Messages that weren't sent before we quit
+        let mut unloaded_outbox: BTreeMap<RoomId, Vec<Outgoing>> = BTreeMap::new();
+        for message in matrix::outbox::load().unwrap_or_default() {
+            unloaded_outbox
+                .entry(message.room_id.clone())
+                .or_default()
+                .push(message);
+        }
         Self {
+            push_rules: Ruleset::server_default(&session.user_id),
+            presence: Default::default(),
+            own_presence: PresenceSetting::Online,
+            status_msg: String::new(),
+            last_activity: Instant::now(),
+            idle: false,
+            show_members: false,
             client,
             session,
             sync_token: String::new(),
+            online: false,
+            connecting: false,
+            loading_rooms: Default::default(),
+            unloaded_events: Default::default(),
+            unloaded_outbox,
+            connection_error: None,
             settings_view: None,
+            image_viewer: None,
             settings_button: Default::default(),
             error: None,
+            media_cache: MediaCache::shared(),
             sas: None,
             rooms: Default::default(),
             selected: None,
             images: Default::default(),
             thumbnails: Default::default(),
+            originals: Default::default(),
+            fetching_originals: HashSet::new(),
+            animations: BTreeMap::new(),
+            // iced's default window size
+            window_size: (1024, 768),
             room_scroll: Default::default(),
             message_scroll: Default::default(),
+            timeline_layout: None,
+            member_scroll: Default::default(),
+            members_button: Default::default(),
             backfill_button: Default::default(),
             tombstone_button: Default::default(),
             message_input: Default::default(),
             dm_buttons: Vec::new(),
             group_buttons: Vec::new(),
             draft: String::new(),
+            reply_to: None,
+            editing: None,
+            edit_history: None,
+            redacting: None,
+            redact_reason: String::new(),
+            open_thread: None,
+            thread_draft: String::new(),
+            attaching: false,
+            attach_path: String::new(),
+            upload: None,
+            upload_limit: None,
+            picking_sticker: false,
+            sticker_packs: None,
+            command_error: None,
+            held_commands: Vec::new(),
+            held_redactions: Vec::new(),
+            command_help: None,
+            devtools: false,
+            devtools_event: String::new(),
+            sharing_location: false,
+            location_input: String::new(),
+            location_description: String::new(),
+            location_error: None,
+            saving_attachment: None,
+            downloads: BTreeMap::new(),
+            reacting_to: None,
+            reaction_input: String::new(),
+            fetched_events: Default::default(),
+            fetching_events: Default::default(),
             send_button: Default::default(),
+            message_buttons: Vec::new(),
+            compose_cancel_button: Default::default(),
+            edit_history_scroll: Default::default(),
+            edit_history_close: Default::default(),
+            redact_reason_input: Default::default(),
+            redact_confirm_button: Default::default(),
+            redact_cancel_button: Default::default(),
+            attachment_path_input: Default::default(),
+            attachment_save_button: Default::default(),
+            attachment_cancel_button: Default::default(),
+            reaction_buttons: Vec::new(),
+            reaction_input_state: Default::default(),
+            reaction_cancel_button: Default::default(),
+            thread_scroll: Default::default(),
+            thread_backfill_button: Default::default(),
+            thread_input: Default::default(),
+            thread_send_button: Default::default(),
+            thread_close_button: Default::default(),
+            attach_button: Default::default(),
+            attach_input: Default::default(),
+            upload_button: Default::default(),
+            sticker_button: Default::default(),
+            sticker_buttons: Vec::new(),
+            sticker_scroll: Default::default(),
+            command_help_close: Default::default(),
+            devtools_scroll: Default::default(),
+            devtools_close: Default::default(),
+            devtools_event_input: Default::default(),
+            location_button: Default::default(),
+            location_input_state: Default::default(),
+            location_description_input: Default::default(),
+            location_share_button: Default::default(),
             sorting: RoomSorting::Alphabetic,
+            markdown: true,
+            autoplay: true,
+            hide_joins: false,
             sas_accept_button: Default::default(),
             sas_deny_button: Default::default(),
+            reconnect_button: Default::default(),
+            pending_buttons: Vec::new(),
+            gap_buttons: Vec::new(),
         }
     }
 
@@ -283,13 +1036,496 @@ impl MainView {
         }
     }
 
+    /// Create room entries for joined rooms we don't have yet, filled in from the cache.
+    fn load_rooms(&mut self) -> Command<Message> {
+        let mut commands: Vec<Command<Message>> = Vec::new();
+        for room in self.client.joined_rooms().into_iter() {
+            if self.rooms.contains_key(room.room_id())
+                || !self.loading_rooms.insert(room.room_id().clone())
+            {
+                continue;
+            }
+            let r = room.clone();
+            let user_id = self.session.user_id.clone();
+            let command: Command<_> = async move {
+                let mut entry = RoomEntry::from_sdk(&r).await;
+                entry.push_context = Some(PushContext::from_room(&r, &user_id).await);
+                entry
+                    .messages
+                    .restore(matrix::read_history(r.room_id()).unwrap_or_default());
+                entry.messages.dirty = false;
+                Message::ResetRoom(r.room_id().to_owned(), entry)
+            }
+            .into();
+            commands.push(command);
+            // Cached avatars can be shown even while offline
+            commands.push(self.fetch_avatar(room));
+        }
+        Command::batch(commands)
+    }
+
+    /// The box images in the timeline are fitted into, which depends on the window size
+    fn image_bounds(&self) -> (u32, u32) {
+        let width = self.timeline_width();
+        (width.min(MAX_IMAGE_SIZE.0), MAX_IMAGE_SIZE.1)
+    }
+
+    /// Width of the timeline
+    fn timeline_width(&self) -> u32 {
+        // Room list, padding, and the side panel if one is open
+        let mut used = 340;
+        if self.show_members
+            || self.edit_history.is_some()
+            || self.open_thread.is_some()
+            || self.devtools
+        {
+            used += 350;
+        }
+        self.window_size.0.saturating_sub(used).max(100)
+    }
+
+    /// Fetch an image scaled down to fit in the timeline
+    fn fetch_image(&self, image: matrix::ImageSource) -> Command<Message> {
+        let client = self.client.clone();
+        let cache = self.media_cache.clone();
+        let url = image.media.url().to_owned();
+        let paused = !self.autoplay;
+        let animated = image
+            .mimetype
+            .as_deref()
+            .map_or(false, animation::may_be_animated);
+        let (width, height) = fit_image(image.dimensions, self.image_bounds());
+        // Ask for a size the server probably has already, so it doesn't have to scale it
+        let size = THUMBNAIL_SIZES
+            .iter()
+            .copied()
+            .find(|&(w, h)| w >= width && h >= height)
+            .unwrap_or(THUMBNAIL_SIZES[THUMBNAIL_SIZES.len() - 1]);
+        let fetch = async move {
+            // Server thumbnails are still images, so possible animations need the original
+            if animated {
+                let data = matrix::download(client, cache, image.media).await?;
+                // Decoding every frame takes a while, so it's kept off the executor
+                let (animation, data) =
+                    tokio::task::spawn_blocking(move || (Animation::decode(&data, paused), data))
+                        .await?;
+                return Ok(match animation {
+                    Some(animation) => Message::FetchedAnimation(url, animation),
+                    None => Message::FetchedImage(url, iced::image::Handle::from_memory(data)),
+                });
+            }
+            let data = matrix::download_thumbnail(client, cache, image, size).await?;
+            Ok(Message::FetchedImage(
+                url,
+                iced::image::Handle::from_memory(data),
+            ))
+        };
+        Command::perform(fetch, |result: Result<_, matrix::Error>| {
+            result.unwrap_or_else(|e| Message::ErrorMessage(e.to_string()))
+        })
+    }
+
+    /// Gets the attachment of a message in the selected room
+    fn attachment(&self, id: &EventId) -> Option<matrix::file::Attachment> {
+        let selected = self.selected.as_ref()?;
+        let message = match self.find_event(selected, id)? {
+            AnyRoomEvent::Message(AnyMessageEvent::RoomMessage(message)) => message,
+            _ => return None,
+        };
+        let content = match self.rooms.get(selected) {
+            Some(room) => room.messages.latest_content(message),
+            None => message.content.clone(),
+        };
+        matrix::file::message_attachment(&content.msgtype)
+    }
+
+    /// Save the attachment of a message to `path`, and open it if `open` is set
+    fn save_attachment(&mut self, id: EventId, path: PathBuf, open: bool) -> Command<Message> {
+        let attachment = match self.attachment(&id) {
+            Some(attachment) => attachment,
+            None => return Command::none(),
+        };
+        if let Some(Download::InProgress) = self.downloads.get(&id) {
+            return Command::none();
+        }
+        self.downloads.insert(id.clone(), Download::InProgress);
+        let client = self.client.clone();
+        let cache = self.media_cache.clone();
+        let save = async move {
+            let path = matrix::file::save(client, cache, attachment.media, path).await?;
+            if open {
+                matrix::file::open(&path)?;
+            }
+            Ok::<_, matrix::Error>(path)
+        };
+        Command::perform(save, |result| {
+            Message::AttachmentSaved(
+                id,
+                result
+                    .map(|path| path.display().to_string())
+                    .map_err(|e| e.to_string()),
+            )
+        })
+    }
+
+    /// Fetch the avatar thumbnail of a room if it has one, from the media cache if possible
+    fn fetch_avatar(&self, room: matrix_sdk::room::Joined) -> Command<Message> {
+        let url = match room.avatar_url() {
+            Some(url) => url,
+            None => return Command::none(),
+        };
+        let key = MediaCache::key(&url, Some((THUMBNAIL_SIZE, THUMBNAIL_SIZE)));
+        let fetch = async move {
+            room.avatar(Some(THUMBNAIL_SIZE), Some(THUMBNAIL_SIZE))
+                .await?
+                .ok_or_else(|| anyhow::anyhow!("Room has no avatar"))
+        };
+        let cache = self.media_cache.clone();
+        Command::perform(cache::cached(cache, key, fetch), |result| match result {
+            Ok(data) => Message::FetchedThumbnail(url, iced::image::Handle::from_memory(data)),
+            // Not worth bothering the user about, the room just won't have an avatar
+            Err(_) => Message::Noop,
+        })
+    }
+
+    /// Try to connect to the homeserver
+    fn connect(&mut self) -> Command<Message> {
+        if self.online || self.connecting {
+            return Command::none();
+        }
+        self.connecting = true;
+        Command::perform(
+            matrix::connect(self.client.clone()),
+            |result| match result {
+                Ok((token, events)) => Message::Connected(token, events),
+                Err(e) => Message::ConnectionFailed(e.to_string()),
+            },
+        )
+    }
+
+    /// Handle an event from the sync done when connecting, holding it back if its room is
+    /// still being loaded so it doesn't get lost when the loaded room replaces ours
+    fn catch_up(&mut self, event: matrix::Event) -> Command<Message> {
+        let room_id = match event {
+            matrix::Event::Joined(_, ref joined) => joined.room_id().clone(),
+            matrix::Event::Gap(ref room_id, _) | matrix::Event::Thread(ref room_id, _, _) => {
+                room_id.clone()
+            }
+            event => return self.update(Message::Sync(event)),
+        };
+        if self.loading_rooms.contains(&room_id) {
+            self.unloaded_events.entry(room_id).or_default().push(event);
+            return Command::none();
+        }
+        self.update(Message::Sync(event))
+    }
+
+    /// Send a message, or queue it if we're offline
+    fn send(&mut self, room: RoomId, content: AnyMessageEventContent) -> Command<Message> {
+        let message = Outgoing::new(room.clone(), content);
+        let txn_id = message.txn_id.clone();
+        let buffer = &mut self.rooms.entry(room.clone()).or_default().messages;
+        buffer.push_pending(message);
+        let command = self.send_pending(&room, &txn_id);
+        self.save_outbox();
+        command
+    }
+
+    /// Send a message from the outgoing queue, or leave it queued if we're offline
+    fn send_pending(&mut self, room: &RoomId, txn_id: &str) -> Command<Message> {
+        let online = self.online;
+        let message = match self.rooms.get_mut(room) {
+            Some(entry) => match entry.messages.pending_mut(txn_id) {
+                Some(message) => message,
+                None => return Command::none(),
+            },
+            None => return Command::none(),
+        };
+        if !online {
+            message.state = outbox::State::Queued;
+            return Command::none();
+        }
+        message.state = outbox::State::Sending;
+        let client = self.client.clone();
+        let (room, txn_id) = (room.clone(), txn_id.to_owned());
+        Command::perform(
+            outbox::send(
+                client,
+                room.clone(),
+                txn_id.clone(),
+                message.content.clone(),
+            ),
+            move |result| Message::PendingSent(room, txn_id, result.map_err(|e| e.to_string())),
+        )
+    }
+
+    /// Save the outgoing queue, so messages are still sent after a restart
+    fn save_outbox(&mut self) {
+        let messages = self
+            .rooms
+            .values()
+            .flat_map(|room| room.messages.pending())
+            .chain(self.unloaded_outbox.values().flatten());
+        if let Err(e) = outbox::save(messages) {
+            self.error = Some((
+                format!("Couldn't save unsent messages: {}", e),
+                Default::default(),
+            ));
+        }
+    }
+
+    /// Run a command that doesn't send a message
+    fn run_command(&mut self, room: RoomId, command: SlashCommand) -> Command<Message> {
+        match command {
+            SlashCommand::Help(name) => {
+                match command::help(name.as_deref()) {
+                    Ok(help) => {
+                        self.command_help = Some(help);
+                        self.draft.clear();
+                    }
+                    Err(e) => self.command_error = Some(e.to_string()),
+                }
+                return Command::none();
+            }
+            SlashCommand::DevTools => {
+                self.show_members = false;
+                self.edit_history = None;
+                self.open_thread = None;
+                self.devtools = true;
+                self.draft.clear();
+                return Command::none();
+            }
+            _ => (),
+        }
+        self.draft.clear();
+        if !self.online {
+            self.held_commands.push((room, command));
+            return Command::none();
+        }
+        self.perform_command(room, command)
+    }
+
+    /// Run a command against the server
+    fn perform_command(&self, room: RoomId, command: SlashCommand) -> Command<Message> {
+        let session = self.session.clone();
+        match command {
+            SlashCommand::Join(target) => {
+                Command::perform(command::join(session, target), command_result)
+            }
+            SlashCommand::Leave(reason) => {
+                Command::perform(command::leave(session, room, reason), command_result)
+            }
+            SlashCommand::Invite(user) => Command::perform(
+                command::membership(session, room, "invite", user, None),
+                command_result,
+            ),
+            SlashCommand::Kick(user, reason) => Command::perform(
+                command::membership(session, room, "kick", user, reason),
+                command_result,
+            ),
+            SlashCommand::Ban(user, reason) => Command::perform(
+                command::membership(session, room, "ban", user, reason),
+                command_result,
+            ),
+            SlashCommand::Op(user, level) => Command::perform(
+                command::set_power_level(session, room, user, level),
+                command_result,
+            ),
+            SlashCommand::Nick(name) => {
+                let client = self.client.clone();
+                Command::perform(
+                    async move {
+                        client.set_display_name(Some(&name)).await?;
+                        Ok::<_, matrix::Error>(())
+                    },
+                    command_result,
+                )
+            }
+            SlashCommand::RoomNick(name) => {
+                Command::perform(command::set_room_nick(session, room, name), command_result)
+            }
+            SlashCommand::Topic(topic) => {
+                Command::perform(command::set_topic(session, room, topic), command_result)
+            }
+            // Commands that send messages are sent by the caller
+            _ => Command::none(),
+        }
+    }
+
+    /// Redact an event, showing it as redacted until the server tells us otherwise. While
+    /// offline, the redaction is sent once we're connected.
+    fn redact(&mut self, room: RoomId, id: EventId, reason: Option<String>) -> Command<Message> {
+        if let Some(buffer) = self.rooms.get_mut(&room) {
+            buffer.messages.redact(&id);
+        }
+        if !self.online {
+            self.held_redactions.push((room, id, reason));
+            return Command::none();
+        }
+        let client = self.client.clone();
+        Command::perform(
+            matrix::redact(client, room.clone(), id.clone(), reason),
+            move |result| match result {
+                Ok(()) => Message::Noop,
+                Err(e) => Message::RedactFailed(room, id, e.to_string()),
+            },
+        )
+    }
+
+    /// Find an event in a room, whether it's in the timeline or was fetched separately
+    fn find_event(&self, room: &RoomId, id: &EventId) -> Option<&AnyRoomEvent> {
+        self.rooms
+            .get(room)
+            .and_then(|r| r.messages.get(id))
+            .or_else(|| self.fetched_events.get(id))
+    }
+
+    /// Count a message from sync towards the notifications of its room, as decided by the push
+    /// rules. Messages in the open room and our own messages don't count. If what the push rules
+    /// need to know about the room isn't ready, it's gathered from the store first.
+    fn count_notification(
+        &mut self,
+        event: &AnyRoomEvent,
+        joined: &matrix_sdk::room::Joined,
+    ) -> Command<Message> {
+        let room_id = joined.room_id();
+        if event.sender() == &self.session.user_id || self.selected.as_ref() == Some(room_id) {
+            return Command::none();
+        }
+        let room = match self.rooms.get_mut(room_id) {
+            Some(room) => room,
+            None => return Command::none(),
+        };
+        let context = match room.push_context {
+            Some(ref context) => context,
+            None => {
+                let (joined, event) = (joined.clone(), event.clone());
+                let user_id = self.session.user_id.clone();
+                return async move {
+                    let context = PushContext::from_room(&joined, &user_id).await;
+                    Message::CountNotification(joined.room_id().clone(), event, context)
+                }
+                .into();
+            }
+        };
+        let evaluation = self.push_rules.evaluate(event, context);
+        if evaluation.notify() {
+            room.notifications += 1;
+        }
+        if evaluation.highlight() {
+            room.highlights += 1;
+        }
+        Command::none()
+    }
+
+    /// Fetch the parents of replies that we don't have
+    fn fetch_reply_parents<'a>(
+        &self,
+        room: &RoomId,
+        events: impl Iterator<Item = &'a AnyRoomEvent>,
+    ) -> Command<Message> {
+        let commands: Vec<Command<Message>> = events
+            .filter_map(reply::in_reply_to)
+            .filter(|parent| {
+                self.find_event(room, parent).is_none() && !self.fetching_events.contains(*parent)
+            })
+            .map(|parent| {
+                let message = Message::FetchEvent(room.clone(), parent.clone());
+                async move { message }.into()
+            })
+            .collect();
+        Command::batch(commands)
+    }
+
+    /// Fetch the joined members of a room
+    fn load_members(&self, id: RoomId) -> Command<Message> {
+        let room = match self.client.get_joined_room(&id) {
+            Some(room) => room,
+            None => return Command::none(),
+        };
+        async move {
+            match room.joined_members().await {
+                Ok(members) => Message::MembersLoaded(
+                    id,
+                    members
+                        .iter()
+                        .map(|m| (m.user_id().clone(), m.name().to_owned()))
+                        .collect(),
+                ),
+                Err(e) => Message::ErrorMessage(e.to_string()),
+            }
+        }
+        .into()
+    }
+
+    /// Set our presence on the server, along with our status message
+    fn send_presence(&self, presence: PresenceSetting) -> Command<Message> {
+        let client = self.client.clone();
+        let user_id = self.session.user_id.clone();
+        let status = Some(self.status_msg.clone()).filter(|s| !s.is_empty());
+        Command::perform(
+            async move { matrix::set_presence(client, user_id, presence.state(), status).await },
+            |result| match result {
+                Ok(()) => Message::PresenceSaved,
+                Err(e) => Message::PresenceFailed(e.to_string()),
+            },
+        )
+    }
+
+    /// Note that the user interacted with the client, coming back from being idle if needed
+    fn register_activity(&mut self, message: &Message) -> Command<Message> {
+        if !message.is_interaction() {
+            return Command::none();
+        }
+        self.last_activity = Instant::now();
+        if self.idle {
+            self.idle = false;
+            return self.send_presence(self.own_presence);
+        }
+        Command::none()
+    }
+
     pub fn view(&mut self) -> Element<Message> {
         // If settings view is open, display that instead
         if let Some(ref mut settings) = self.settings_view {
-            return settings.view(self.sorting);
+            let cache_size = self.media_cache.lock().unwrap().size();
+            return settings.view(
+                self.sorting,
+                self.markdown,
+                self.autoplay,
+                self.hide_joins,
+                &self.push_rules,
+                cache_size,
+            );
+        }
+        // Same for the image viewer
+        if let Some(ref mut viewer) = self.image_viewer {
+            let url = viewer.media.url().to_owned();
+            let handle = self
+                .animations
+                .get(&url)
+                .map(Animation::handle)
+                .or_else(|| self.originals.get(&url))
+                .or_else(|| self.images.get(&url));
+            return viewer.view(handle, self.fetching_originals.contains(&url));
         }
         let mut root_row = Row::new().width(Length::Fill).height(Length::Fill);
 
+        // Message being replied to
+        let reply_text = match (&self.selected, &self.reply_to) {
+            (Some(room), Some(id)) => Some(match self.find_event(room, id) {
+                Some(parent) => {
+                    format!("Replying to {}: {}", parent.sender(), reply_preview(parent))
+                }
+                None => String::from("Replying to a message"),
+            }),
+            _ if self.editing.is_some() => Some(String::from("Editing message")),
+            _ => None,
+        };
+        // Taken before widgets start borrowing parts of the view
+        let timeline_width = self.timeline_width();
+        let image_bounds = self.image_bounds();
+
         let mut room_scroll = Scrollable::new(&mut self.room_scroll)
             .width(300.into())
             .height(Length::Fill)
@@ -332,6 +1568,7 @@ impl MainView {
         // Create buttons
         let thumbnails = &self.thumbnails;
         let images = &self.images;
+        let presence = &self.presence;
         let dm_buttons: Vec<Button<_>> = self
             .dm_buttons
             .iter_mut()
@@ -354,7 +1591,11 @@ impl MainView {
                         );
                     }
                 }
-                Button::new(button, row.push(Text::new(name)))
+                if let Some(presence) = room.direct.as_ref().and_then(|d| presence.get(d)) {
+                    row = row.push(presence.indicator());
+                }
+                let row = row.push(Text::new(name)).push(unread_badge(room));
+                Button::new(button, row)
                     .width(300.into())
                     .on_press(Message::SelectRoom(id.to_owned().to_owned()))
             })
@@ -380,7 +1621,8 @@ impl MainView {
                         );
                     }
                 }
-                Button::new(button, row.push(Text::new(name)))
+                let row = row.push(Text::new(name)).push(unread_badge(room));
+                Button::new(button, row)
                     .width(300.into())
                     .on_press(Message::SelectRoom(id.to_owned().to_owned()))
             })
@@ -404,6 +1646,7 @@ impl MainView {
         root_row = root_row.push(room_col);
 
         let mut message_col = Column::new().spacing(5).padding(5);
+        let mut member_col: Option<Element<_>> = None;
         let selected_room = match self.selected {
             Some(ref selected) => match (
                 self.rooms.get(selected),
@@ -415,6 +1658,11 @@ impl MainView {
             None => None,
         };
         if let Some((room, joined)) = selected_room {
+            let member_name =
+                |user: &UserId| match block_on(async { joined.get_member(user).await }) {
+                    Ok(Some(member)) => member.name().to_owned(),
+                    _ => user.to_string(),
+                };
             // Include user id or canonical alias in title when appropriate
             let title = if let Some(ref direct) = room.direct {
                 format!("{} ({})", &room.name, direct)
@@ -432,12 +1680,324 @@ impl MainView {
                         .height(24.into()),
                 );
             }
+            // Notification mode selection
+            let mode = self.push_rules.room_mode(self.selected.as_ref().unwrap());
+            let mut mode_row = Row::new().spacing(10).align_items(Align::Center);
+            for &(value, label) in [
+                (RoomMode::AllMessages, "All messages"),
+                (RoomMode::MentionsAndKeywords, "Mentions & keywords"),
+                (RoomMode::Mute, "Mute"),
+            ]
+            .iter()
+            {
+                mode_row =
+                    mode_row.push(Radio::new(value, label, Some(mode), Message::SetRoomMode));
+            }
             message_col = message_col
-                .push(title_row.push(Text::new(title).size(25)))
-                .push(Rule::horizontal(2));
+                .push(
+                    title_row
+                        .push(Text::new(title).size(25).width(Length::Fill))
+                        .push(mode_row)
+                        .push(
+                            Button::new(&mut self.members_button, Text::new("Members"))
+                                .on_press(Message::ToggleMembers),
+                        ),
+                )
+                .push(Rule::horizontal(2));
+            // Member list
+            if self.show_members {
+                let mut scroll = Scrollable::new(&mut self.member_scroll)
+                    .width(250.into())
+                    .height(Length::Fill)
+                    .spacing(4)
+                    .padding(5)
+                    .scrollbar_width(2);
+                match room.members {
+                    Some(ref members) => {
+                        let mut members: Vec<_> = members.iter().collect();
+                        members.sort_unstable_by_key(|(_, name)| name.to_uppercase());
+                        for (user, name) in members {
+                            let mut row = Row::new().spacing(5).align_items(Align::Center);
+                            let mut col = Column::new().push(Text::new(name));
+                            if let Some(presence) = self.presence.get(user) {
+                                row = row.push(presence.indicator());
+                                if let Some(ref status) = presence.status {
+                                    col = col.push(Text::new(status).size(14));
+                                }
+                                col = col.push(
+                                    Text::new(presence.activity())
+                                        .size(14)
+                                        .color([0.3, 0.3, 0.3]),
+                                );
+                            }
+                            scroll = scroll.push(row.push(col));
+                        }
+                    }
+                    None => scroll = scroll.push(Text::new("Loading members...")),
+                }
+                member_col = Some(scroll.into());
+            }
+            // Thread
+            let thread = self
+                .open_thread
+                .as_ref()
+                .and_then(|root| Some((root, room.threads.get(root)?)));
+            if let Some((root, thread)) = thread {
+                let mut col = Column::new()
+                    .width(350.into())
+                    .height(Length::Fill)
+                    .spacing(5)
+                    .padding(5)
+                    .push(
+                        Row::new()
+                            .align_items(Align::Center)
+                            .push(Text::new("Thread").size(20).width(Length::Fill))
+                            .push(
+                                Button::new(&mut self.thread_close_button, Text::new("Close"))
+                                    .on_press(Message::CloseThread),
+                            ),
+                    );
+                if let Some(root) = room.messages.get(root) {
+                    col = col.push(
+                        Container::new(
+                            Text::new(format!(
+                                "{}: {}",
+                                member_name(root.sender()),
+                                reply_preview(root)
+                            ))
+                            .size(16),
+                        )
+                        .padding(3)
+                        .style(theme::Quote),
+                    );
+                }
+                let mut scroll = Scrollable::new(&mut self.thread_scroll)
+                    .height(Length::Fill)
+                    .spacing(4)
+                    .scrollbar_width(2);
+                // Older replies
+                if thread.messages.loading {
+                    scroll = scroll.push(Text::new("Loading..."));
+                } else if thread.next_batch.is_some() || !thread.loaded {
+                    scroll = scroll.push(
+                        Button::new(&mut self.thread_backfill_button, Text::new("Load more"))
+                            .on_press(Message::ThreadBackFill(root.clone(), thread.loaded)),
+                    );
+                }
+                for event in thread.messages.messages.iter() {
+                    match event {
+                        AnyRoomEvent::Message(_)
+                            if thread.messages.is_redacted(event.event_id()) =>
+                        {
+                            scroll =
+                                scroll.push(Text::new("Deleted message").color([0.3, 0.3, 0.3]));
+                        }
+                        AnyRoomEvent::Message(AnyMessageEvent::RoomMessage(message)) => {
+                            let content = thread.messages.latest_content(message);
+                            let body = reply::plain_body(&message.content, &content);
+                            let formatted = match content.msgtype {
+                                MessageType::Text(ref text) => text.formatted.as_ref(),
+                                MessageType::Notice(ref notice) => notice.formatted.as_ref(),
+                                MessageType::Emote(ref emote) => emote.formatted.as_ref(),
+                                _ => None,
+                            };
+                            scroll = scroll.push(
+                                Column::new()
+                                    .push(
+                                        Row::new()
+                                            .spacing(5)
+                                            .push(
+                                                Text::new(member_name(&message.sender))
+                                                    .color([0.0, 0.0, 1.0]),
+                                            )
+                                            .push(
+                                                Text::new(format_systime(message.origin_server_ts))
+                                                    .size(14),
+                                            ),
+                                    )
+                                    .push(view_body(body, formatted)),
+                            );
+                        }
+                        AnyRoomEvent::Message(AnyMessageEvent::RoomEncrypted(_)) => {
+                            scroll =
+                                scroll.push(Text::new("Encrypted event").color([0.3, 0.3, 0.3]));
+                        }
+                        _ => (),
+                    }
+                }
+                col = col.push(scroll);
+                col = col.push(
+                    Row::new()
+                        .push(
+                            TextInput::new(
+                                &mut self.thread_input,
+                                "Reply in thread...",
+                                &self.thread_draft,
+                                Message::SetThreadDraft,
+                            )
+                            .width(Length::Fill)
+                            .padding(5)
+                            .on_submit(Message::SendThreadReply),
+                        )
+                        .push(
+                            Button::new(&mut self.thread_send_button, Text::new("Send"))
+                                .on_press(Message::SendThreadReply),
+                        ),
+                );
+                member_col = Some(col.into());
+            }
+            // Edit history
+            let history = self
+                .edit_history
+                .as_ref()
+                .and_then(|id| room.messages.get(id));
+            if let Some(AnyRoomEvent::Message(AnyMessageEvent::RoomMessage(original))) = history {
+                let mut scroll = Scrollable::new(&mut self.edit_history_scroll)
+                    .width(300.into())
+                    .height(Length::Fill)
+                    .spacing(8)
+                    .padding(5)
+                    .scrollbar_width(2)
+                    .push(
+                        Row::new()
+                            .align_items(Align::Center)
+                            .push(Text::new("Edit history").size(20).width(Length::Fill))
+                            .push(
+                                Button::new(&mut self.edit_history_close, Text::new("Close"))
+                                    .on_press(Message::CloseEdits),
+                            ),
+                    );
+                let versions =
+                    std::iter::once((original.origin_server_ts, original.content.clone())).chain(
+                        room.messages
+                            .edits(original)
+                            .map(|edit| (edit.origin_server_ts, edit::new_content(edit))),
+                    );
+                for (time, content) in versions {
+                    let body = reply::plain_body(&original.content, &content);
+                    let formatted = match content.msgtype {
+                        MessageType::Text(ref text) => text.formatted.as_ref(),
+                        MessageType::Notice(ref notice) => notice.formatted.as_ref(),
+                        MessageType::Emote(ref emote) => emote.formatted.as_ref(),
+                        _ => None,
+                    };
+                    scroll = scroll.push(
+                        Column::new()
+                            .push(
+                                Text::new(format_systime(time))
+                                    .size(14)
+                                    .color([0.3, 0.3, 0.3]),
+                            )
+                            .push(view_body(body, formatted)),
+                    );
+                }
+                member_col = Some(scroll.into());
+            }
+            // Developer tools
+            if self.devtools {
+                let creation = joined.create_content().unwrap();
+                let mut scroll = Scrollable::new(&mut self.devtools_scroll)
+                    .width(350.into())
+                    .height(Length::Fill)
+                    .spacing(5)
+                    .padding(5)
+                    .scrollbar_width(2)
+                    .push(
+                        Row::new()
+                            .align_items(Align::Center)
+                            .push(Text::new("Developer tools").size(20).width(Length::Fill))
+                            .push(
+                                Button::new(&mut self.devtools_close, Text::new("Close"))
+                                    .on_press(Message::CloseDevTools),
+                            ),
+                    )
+                    .push(Text::new(format!("Room ID: {}", joined.room_id())).size(14))
+                    .push(Text::new(format!("Room version: {}", creation.room_version)).size(14))
+                    .push(
+                        Text::new(format!(
+                            "Encrypted: {}",
+                            if joined.is_encrypted() { "yes" } else { "no" }
+                        ))
+                        .size(14),
+                    )
+                    .push(
+                        Text::new(format!("Loaded events: {}", room.messages.messages.len()))
+                            .size(14),
+                    )
+                    .push(
+                        Text::new(format!(
+                            "Signed in as {} on device {}",
+                            self.session.user_id, self.session.device_id
+                        ))
+                        .size(14),
+                    )
+                    .push(
+                        TextInput::new(
+                            &mut self.devtools_event_input,
+                            "Event ID to show the source of",
+                            &self.devtools_event,
+                            Message::SetDevToolsEvent,
+                        )
+                        .padding(5),
+                    );
+                let fetched_events = &self.fetched_events;
+                let event_id = self.devtools_event.trim();
+                if !event_id.is_empty() {
+                    let event = EventId::try_from(event_id)
+                        .ok()
+                        .and_then(|id| room.messages.get(&id).or_else(|| fetched_events.get(&id)));
+                    let source = match event {
+                        Some(event) => serde_json::to_string_pretty(event)
+                            .unwrap_or_else(|e| format!("Couldn't show the event: {}", e)),
+                        None => String::from("The event isn't loaded"),
+                    };
+                    scroll = scroll.push(Text::new(source).size(12));
+                }
+                member_col = Some(scroll.into());
+            }
+            // Only events around the visible part of the timeline get widgets. The rest are
+            // replaced by space of their estimated height, so the scrollbar stays usable.
+            // Estimating goes through every event, so it's only done again when something
+            // that changes the layout does.
+            let layout = TimelineLayout {
+                room: joined.room_id().clone(),
+                version: room.messages.version,
+                threads: room.threads.len(),
+                width: timeline_width,
+                image_bounds,
+                hide_joins: self.hide_joins,
+                animations: self.animations.len(),
+            };
+            if self.timeline_layout.as_ref().map(|(l, _)| l) != Some(&layout) {
+                let heights = estimate_heights(room, &layout, &self.animations);
+                self.timeline_layout = Some((layout, heights));
+            }
+            let heights = &self.timeline_layout.as_ref().unwrap().1;
+            let spacing = TIMELINE_SPACING as f32;
+            // The backfill button goes above the events, and the messages we're sending and
+            // the tombstone below them
+            let above = BUTTON_HEIGHT + spacing;
+            let mut below = room.messages.pending().len() as f32 * (SMALL_BUTTON_HEIGHT + spacing);
+            if !room.messages.pending().is_empty() {
+                below += 2.0 * spacing + 4.0 + TEXT_HEIGHT;
+            }
+            if joined.tombstone().is_some() {
+                below += TEXT_HEIGHT + BUTTON_HEIGHT + spacing;
+            }
+            let event_count = room.messages.messages.len();
+            let viewport_height = self.window_size.1 as f32;
+            let offset = self.message_scroll.offset(
+                Rectangle::new(iced::Point::ORIGIN, iced::Size::new(1.0, viewport_height)),
+                Rectangle::new(
+                    iced::Point::ORIGIN,
+                    iced::Size::new(1.0, above + heights.total() + below),
+                ),
+            );
+            let window =
+                heights.visible_range(offset as f32 - above, viewport_height, TIMELINE_OVERSCAN);
             let mut scroll = Scrollable::new(&mut self.message_scroll)
                 .scrollbar_width(2)
-                .spacing(4)
+                .spacing(TIMELINE_SPACING)
                 .height(Length::Fill);
             // Backfill button or loading message
             let backfill: Element<_> = if room.messages.loading {
@@ -464,79 +2024,311 @@ impl MainView {
             // Rendered display name of most recent sender
             let mut sender = String::from("Unknown sender");
             // Messages
-            for event in room.messages.messages.iter() {
-                #[allow(clippy::single_match)]
+            // The space is followed by spacing like every other widget
+            if window.start > 0 {
+                scroll = push_space(scroll, heights.top(window.start) - spacing);
+            }
+            self.message_buttons
+                .resize_with(window.len(), Default::default);
+            // Markers where messages are missing, one for each chunk that has a gap before it
+            self.gap_buttons
+                .resize_with(room.messages.chunks.len(), Default::default);
+            let mut previous_chunk = room
+                .messages
+                .messages
+                .window(window.start.saturating_sub(1)..window.start)
+                .next()
+                .map(|(position, _)| position.chunk);
+            let fetched_events = &self.fetched_events;
+            let own_id = &self.session.user_id;
+            let can_redact = matrix::can_redact_others(&joined, own_id);
+            let hide_joins = self.hide_joins;
+            let mut gap_buttons: Vec<Option<&mut iced::button::State>> =
+                self.gap_buttons.iter_mut().map(Some).collect();
+            // Consecutive membership changes, which are shown as one line
+            let mut membership_run: Vec<(notice::Notice, SystemTime)> = Vec::new();
+            for ((position, event), buttons) in room
+                .messages
+                .messages
+                .window(window.clone())
+                .zip(self.message_buttons.iter_mut())
+            {
+                if previous_chunk != Some(position.chunk) {
+                    previous_chunk = Some(position.chunk);
+                    let chunk = room.messages.chunks.get(position.chunk);
+                    let button = gap_buttons.get_mut(position.chunk).and_then(Option::take);
+                    if let (Some(chunk), Some(button)) = (chunk, button) {
+                        if chunk.gap.is_some() {
+                            scroll = notice::push_run(scroll, &mut membership_run);
+                            last_sender = None;
+                            scroll = scroll.push(view_gap(
+                                button,
+                                joined.room_id(),
+                                position.chunk,
+                                chunk.filling,
+                            ));
+                        }
+                    }
+                }
+                let state_notice = match event {
+                    AnyRoomEvent::State(state) => notice::describe(state, &member_name),
+                    _ => None,
+                };
+                if let Some(notice) = state_notice.as_ref() {
+                    if let Some((change, _)) = notice.membership {
+                        if !(hide_joins && change.is_join_or_leave()) {
+                            membership_run.push((notice.clone(), event.origin_server_ts()));
+                            last_sender = None;
+                        }
+                        continue;
+                    }
+                }
+                scroll = notice::push_run(scroll, &mut membership_run);
                 match event {
+                    AnyRoomEvent::Message(_) if room.messages.is_redacted(event.event_id()) => {
+                        scroll = scroll.push(Text::new("Deleted message").color([0.3, 0.3, 0.3]));
+                    }
                     AnyRoomEvent::Message(AnyMessageEvent::RoomMessage(message)) => {
                         // Display sender if message is from new sender
                         if last_sender.as_ref() != Some(&message.sender) {
                             last_sender = Some(message.sender.clone());
-                            sender = match block_on(async {
-                                joined.get_member(&message.sender).await
-                            }) {
-                                Ok(Some(member)) => member.name().to_owned(),
-                                _ => message.sender.to_string(),
-                            };
+                            sender = member_name(&message.sender);
                             scroll = scroll
                                 .push(iced::Space::with_height(4.into()))
                                 .push(Text::new(&sender).color([0.0, 0.0, 1.0]));
                         }
-                        let content: Element<_> = match &message.content.msgtype {
-                            MessageType::Audio(audio) => {
-                                Text::new(format!("Audio message: {}", audio.body))
-                                    .color([0.2, 0.2, 0.2])
-                                    .width(Length::Fill)
-                                    .into()
-                            }
-                            MessageType::Emote(emote) => {
-                                Text::new(format!("* {} {}", sender, emote.body))
-                                    .width(Length::Fill)
-                                    .into()
+                        // Quote the message this is a reply to
+                        let parent_id = reply::in_reply_to(event);
+                        if let Some(parent_id) = parent_id {
+                            let parent = room
+                                .messages
+                                .get(parent_id)
+                                .or_else(|| fetched_events.get(parent_id));
+                            let preview = match parent {
+                                Some(parent) => format!(
+                                    "{}: {}",
+                                    member_name(parent.sender()),
+                                    reply_preview(parent)
+                                ),
+                                None => String::from("Loading replied message..."),
+                            };
+                            scroll = scroll.push(
+                                Container::new(Text::new(preview).size(16))
+                                    .padding(3)
+                                    .style(theme::Quote),
+                            );
+                        }
+                        // Remove the reply fallback from the body
+                        let body = |body: &'_ str| -> String {
+                            match parent_id {
+                                Some(_) => reply::strip_fallback(body).to_owned(),
+                                None => body.to_owned(),
                             }
-                            MessageType::File(file) => Text::new(format!("File '{}'", file.body))
-                                .color([0.2, 0.2, 0.2])
-                                .width(Length::Fill)
-                                .into(),
-                            MessageType::Image(image) => {
-                                if let Some(ref url) = image.url {
-                                    match self.images.get(url) {
-                                        Some(handle) => Container::new(
-                                            Image::new(handle.to_owned())
-                                                .width(800.into())
-                                                .height(1200.into()),
+                        };
+                        // Show the newest version of edited messages
+                        let edited = room.messages.edits(message).next().is_some();
+                        let latest = room.messages.latest_content(message);
+                        let content: Element<_> = match &latest.msgtype {
+                            MessageType::Audio(_)
+                            | MessageType::File(_)
+                            | MessageType::Video(_) => {
+                                match matrix::file::message_attachment(&latest.msgtype) {
+                                    Some(attachment) => {
+                                        let thumbnail = attachment.thumbnail.as_ref().map(|t| {
+                                            let size =
+                                                fit_image(t.dimensions, ATTACHMENT_THUMBNAIL);
+                                            (self.images.get(t.media.url()), size)
+                                        });
+                                        view_attachment(
+                                            &latest.msgtype,
+                                            &attachment,
+                                            thumbnail,
+                                            self.downloads.get(&message.event_id),
+                                            (&mut buttons.download, &mut buttons.open),
+                                            &message.event_id,
                                         )
-                                        .width(Length::Fill)
-                                        .into(),
-                                        None => {
-                                            Text::new("Image not loaded").width(Length::Fill).into()
-                                        }
                                     }
-                                } else {
-                                    Text::new("Encrypted images not supported yet")
+                                    None => Text::new("Attachment is missing")
                                         .width(Length::Fill)
-                                        .into()
+                                        .into(),
                                 }
                             }
+                            MessageType::Emote(emote) => Row::new()
+                                .spacing(5)
+                                .push(Text::new(format!("* {}", sender)))
+                                .push(view_body(&body(&emote.body), emote.formatted.as_ref()))
+                                .into(),
+                            MessageType::Image(_) => match matrix::message_image(&latest.msgtype) {
+                                Some(source) => {
+                                    let url = source.media.url();
+                                    let (width, height) =
+                                        fit_image(source.dimensions, image_bounds);
+                                    let animation = self.animations.get(url);
+                                    let image = match animation {
+                                        Some(animation) => animation.view(width, height),
+                                        None => view_image(
+                                            self.originals
+                                                .get(url)
+                                                .or_else(|| self.images.get(url)),
+                                            (width, height),
+                                            "Loading image...",
+                                        ),
+                                    };
+                                    let mut column = Column::new().spacing(2).push(
+                                        Button::new(&mut buttons.image, image)
+                                            .padding(0)
+                                            .style(theme::Borderless)
+                                            .on_press(Message::OpenImage(message.event_id.clone())),
+                                    );
+                                    if let Some(animation) = animation {
+                                        let label = match animation.paused {
+                                            true => "Play",
+                                            false => "Pause",
+                                        };
+                                        column = column.push(
+                                            Button::new(
+                                                &mut buttons.play,
+                                                Text::new(label).size(14),
+                                            )
+                                            .on_press(Message::ToggleAnimation(url.to_owned())),
+                                        );
+                                    }
+                                    Container::new(column).width(Length::Fill).into()
+                                }
+                                None => Text::new("Image is missing").width(Length::Fill).into(),
+                            },
+                            MessageType::Location(location) => view_location(
+                                &location.geo_uri,
+                                &body(&location.body),
+                                &mut buttons.map,
+                            ),
                             MessageType::Notice(notice) => {
-                                Text::new(&notice.body).width(Length::Fill).into()
+                                view_body(&body(&notice.body), notice.formatted.as_ref())
                             }
                             MessageType::ServerNotice(notice) => {
                                 Text::new(&notice.body).width(Length::Fill).into()
                             }
                             MessageType::Text(text) => {
-                                Text::new(&text.body).width(Length::Fill).into()
-                            }
-                            MessageType::Video(video) => {
-                                Text::new(format!("Video: {}", video.body))
-                                    .color([0.2, 0.2, 0.2])
-                                    .into()
+                                view_body(&body(&text.body), text.formatted.as_ref())
                             }
                             _ => Text::new("Unknown message type").into(),
                         };
-                        let row = Row::new()
+                        let mut row = Row::new()
                             .spacing(5)
                             .push(content)
                             .push(Text::new(format_systime(message.origin_server_ts)));
+                        if edited {
+                            row = row.push(
+                                Button::new(&mut buttons.edited, Text::new("(edited)").size(14))
+                                    .on_press(Message::ShowEdits(message.event_id.clone())),
+                            );
+                        }
+                        row = row.push(
+                            Button::new(&mut buttons.reply, Text::new("Reply").size(14))
+                                .on_press(Message::Reply(message.event_id.clone())),
+                        );
+                        // Only text can be edited
+                        let editable = matches!(
+                            latest.msgtype,
+                            MessageType::Text(_) | MessageType::Emote(_) | MessageType::Notice(_)
+                        );
+                        if editable && &message.sender == own_id {
+                            row = row.push(
+                                Button::new(&mut buttons.edit, Text::new("Edit").size(14))
+                                    .on_press(Message::Edit(message.event_id.clone())),
+                            );
+                        }
+                        row = row.push(
+                            Button::new(&mut buttons.react, Text::new("React").size(14))
+                                .on_press(Message::React(message.event_id.clone())),
+                        );
+                        if &message.sender == own_id || can_redact {
+                            row = row.push(
+                                Button::new(&mut buttons.delete, Text::new("Delete").size(14))
+                                    .on_press(Message::Redact(message.event_id.clone())),
+                            );
+                        }
+                        scroll = scroll.push(row);
+                        // Reactions, with who reacted in a tooltip
+                        let reactions = room.messages.reactions(&message.event_id);
+                        if !reactions.is_empty() {
+                            buttons
+                                .reactions
+                                .resize_with(reactions.len(), Default::default);
+                            let mut chips = Row::new().spacing(4);
+                            for ((key, reactions), state) in
+                                reactions.into_iter().zip(buttons.reactions.iter_mut())
+                            {
+                                let selected = reactions.iter().any(|r| &r.sender == own_id);
+                                let names: Vec<String> =
+                                    reactions.iter().map(|r| member_name(&r.sender)).collect();
+                                let label = format!("{} {}", key, reactions.len());
+                                let chip = Button::new(state, Text::new(label).size(14))
+                                    .style(theme::Reaction { selected })
+                                    .on_press(Message::ToggleReaction(
+                                        message.event_id.clone(),
+                                        key.to_owned(),
+                                    ));
+                                chips = chips.push(Tooltip::new(
+                                    chip,
+                                    names.join(", "),
+                                    tooltip::Position::Top,
+                                ));
+                            }
+                            scroll = scroll.push(chips);
+                        }
+                        // Thread summary
+                        if let Some(thread) = room.threads.get(&message.event_id) {
+                            let mut summary = match thread.count() {
+                                1 => String::from("1 reply"),
+                                n => format!("{} replies", n),
+                            };
+                            if let Some(latest) = thread.latest() {
+                                summary.push_str(&format!(
+                                    ", latest from {}: {}",
+                                    member_name(latest.sender()),
+                                    reply_preview(latest)
+                                ));
+                            }
+                            scroll = scroll.push(
+                                Button::new(&mut buttons.thread, Text::new(summary).size(14))
+                                    .style(theme::Reaction { selected: false })
+                                    .on_press(Message::OpenThread(message.event_id.clone())),
+                            );
+                        }
+                    }
+                    AnyRoomEvent::Message(AnyMessageEvent::Sticker(sticker)) => {
+                        if last_sender.as_ref() != Some(&sticker.sender) {
+                            last_sender = Some(sticker.sender.clone());
+                            sender = member_name(&sticker.sender);
+                            scroll = scroll
+                                .push(iced::Space::with_height(4.into()))
+                                .push(Text::new(&sender).color([0.0, 0.0, 1.0]));
+                        }
+                        let source = matrix::sticker_image(&sticker.content);
+                        let url = source.media.url();
+                        let (width, height) = match source.dimensions {
+                            Some(_) => fit_image(source.dimensions, image_bounds),
+                            None => STICKER_SIZE,
+                        };
+                        let body = &sticker.content.body;
+                        let image = match self.animations.get(url) {
+                            Some(animation) => animation.view(width, height),
+                            None => view_image(self.images.get(url), (width, height), body),
+                        };
+                        let mut row = Row::new()
+                            .spacing(5)
+                            .push(
+                                Container::new(Tooltip::new(image, body, tooltip::Position::Top))
+                                    .width(Length::Fill),
+                            )
+                            .push(Text::new(format_systime(sticker.origin_server_ts)));
+                        if &sticker.sender == own_id || can_redact {
+                            row = row.push(
+                                Button::new(&mut buttons.delete, Text::new("Delete").size(14))
+                                    .on_press(Message::Redact(sticker.event_id.clone())),
+                            );
+                        }
                         scroll = scroll.push(row);
                     }
                     AnyRoomEvent::Message(AnyMessageEvent::RoomEncrypted(_encrypted)) => {
@@ -545,9 +2337,65 @@ impl MainView {
                     AnyRoomEvent::RedactedMessage(_) => {
                         scroll = scroll.push(Text::new("Deleted message").color([0.3, 0.3, 0.3]));
                     }
+                    AnyRoomEvent::State(_) => {
+                        if let Some(state_notice) = state_notice {
+                            let time = format_systime(event.origin_server_ts());
+                            scroll = scroll.push(notice::view(state_notice.text, Some(time)));
+                            last_sender = None;
+                        }
+                    }
                     _ => (),
                 }
             }
+            scroll = notice::push_run(scroll, &mut membership_run);
+            if window.end < event_count {
+                scroll = push_space(scroll, heights.total() - heights.top(window.end) - spacing);
+            }
+            // Messages we're sending
+            let pending = room.messages.pending();
+            self.pending_buttons
+                .resize_with(pending.len(), Default::default);
+            for (message, (retry, delete)) in pending.iter().zip(self.pending_buttons.iter_mut()) {
+                if last_sender.as_ref() != Some(own_id) {
+                    last_sender = Some(own_id.clone());
+                    scroll = scroll
+                        .push(iced::Space::with_height(4.into()))
+                        .push(Text::new(member_name(own_id)).color([0.0, 0.0, 1.0]));
+                }
+                let status = match message.state {
+                    outbox::State::Queued => String::from("Waiting to send"),
+                    outbox::State::Sending => String::from("Sending..."),
+                    outbox::State::Sent(_) => String::from("Sent"),
+                    outbox::State::Failed(ref e) => format!("Failed to send: {}", e),
+                };
+                let failed = matches!(message.state, outbox::State::Failed(_));
+                let mut row = Row::new()
+                    .spacing(5)
+                    .align_items(Align::Center)
+                    .push(
+                        Text::new(pending_preview(&message.content))
+                            .width(Length::Fill)
+                            .color([0.5, 0.5, 0.5]),
+                    )
+                    .push(Text::new(status).size(14).color(match failed {
+                        true => [0.8, 0.0, 0.0],
+                        false => [0.5, 0.5, 0.5],
+                    }));
+                if failed {
+                    row = row.push(Button::new(retry, Text::new("Retry").size(14)).on_press(
+                        Message::RetryPending(message.room_id.clone(), message.txn_id.clone()),
+                    ));
+                }
+                if matches!(
+                    message.state,
+                    outbox::State::Queued | outbox::State::Failed(_)
+                ) {
+                    row = row.push(Button::new(delete, Text::new("Delete").size(14)).on_press(
+                        Message::DeletePending(message.room_id.clone(), message.txn_id.clone()),
+                    ));
+                }
+                scroll = scroll.push(row);
+            }
             // Tombstone
             if let Some(tombstone) = joined.tombstone() {
                 let text = Text::new(format!(
@@ -632,6 +2480,32 @@ impl MainView {
             };
             message_col = message_col.push(sas_row);
         }
+        // Offline notice
+        if !self.online {
+            let mut text = match self.connection_error {
+                Some(ref e) => format!("Offline ({}), showing cached messages.", e),
+                None => String::from("Connecting, showing cached messages."),
+            };
+            let queued = self
+                .rooms
+                .values()
+                .flat_map(|room| room.messages.pending())
+                .filter(|message| message.state == outbox::State::Queued)
+                .count();
+            if queued > 0 {
+                text.push_str(&format!(" {} message(s) will be sent when online.", queued));
+            }
+            let button = Button::new(&mut self.reconnect_button, Text::new("Reconnect"));
+            message_col = message_col.push(
+                Row::new()
+                    .align_items(Align::Center)
+                    .push(Text::new(text).width(Length::Fill).color([0.5, 0.5, 0.5]))
+                    .push(match self.connecting {
+                        true => button,
+                        false => button.on_press(Message::Connect),
+                    }),
+            );
+        }
         // Potential error message
         if let Some((ref error, ref mut button)) = self.error {
             message_col = message_col.push(
@@ -640,9 +2514,309 @@ impl MainView {
                     .push(Button::new(button, Text::new("Close")).on_press(Message::ClearError)),
             );
         }
+        if let Some(text) = reply_text {
+            message_col = message_col.push(
+                Row::new()
+                    .align_items(Align::Center)
+                    .push(Text::new(text).width(Length::Fill).size(16))
+                    .push(
+                        Button::new(&mut self.compose_cancel_button, Text::new("Cancel"))
+                            .on_press(Message::CancelReply),
+                    ),
+            );
+        }
+        // Reaction picker
+        if self.reacting_to.is_some() {
+            self.reaction_buttons
+                .resize_with(reaction::QUICK_REACTIONS.len(), Default::default);
+            let mut row = Row::new()
+                .spacing(4)
+                .align_items(Align::Center)
+                .push(Text::new("React with"));
+            for (key, state) in reaction::QUICK_REACTIONS
+                .iter()
+                .zip(self.reaction_buttons.iter_mut())
+            {
+                row = row.push(
+                    Button::new(state, Text::new(*key))
+                        .style(theme::Reaction { selected: false })
+                        .on_press(Message::SendReaction(key.to_string())),
+                );
+            }
+            let custom = self.reaction_input.clone();
+            row = row
+                .push(
+                    TextInput::new(
+                        &mut self.reaction_input_state,
+                        "Other",
+                        &self.reaction_input,
+                        Message::SetReactionInput,
+                    )
+                    .width(100.into())
+                    .padding(5)
+                    .on_submit(Message::SendReaction(custom)),
+                )
+                .push(
+                    Button::new(&mut self.reaction_cancel_button, Text::new("Cancel"))
+                        .on_press(Message::CancelReaction),
+                );
+            message_col = message_col.push(row);
+        }
+        // Where to save an attachment
+        if let Some((_, ref path)) = self.saving_attachment {
+            message_col = message_col.push(
+                Row::new()
+                    .spacing(5)
+                    .align_items(Align::Center)
+                    .push(Text::new("Save to"))
+                    .push(
+                        TextInput::new(
+                            &mut self.attachment_path_input,
+                            "Path",
+                            path,
+                            Message::SetAttachmentPath,
+                        )
+                        .width(Length::Fill)
+                        .padding(5)
+                        .on_submit(Message::SaveAttachment),
+                    )
+                    .push(
+                        Button::new(&mut self.attachment_save_button, Text::new("Save"))
+                            .on_press(Message::SaveAttachment),
+                    )
+                    .push(
+                        Button::new(&mut self.attachment_cancel_button, Text::new("Cancel"))
+                            .on_press(Message::CancelSaveAttachment),
+                    ),
+            );
+        }
+        // Confirmation for deleting a message
+        if self.redacting.is_some() {
+            message_col = message_col.push(
+                Row::new()
+                    .spacing(5)
+                    .align_items(Align::Center)
+                    .push(Text::new("Delete message?"))
+                    .push(
+                        TextInput::new(
+                            &mut self.redact_reason_input,
+                            "Reason (optional)",
+                            &self.redact_reason,
+                            Message::SetRedactReason,
+                        )
+                        .width(Length::Fill)
+                        .padding(5)
+                        .on_submit(Message::ConfirmRedact),
+                    )
+                    .push(
+                        Button::new(&mut self.redact_confirm_button, Text::new("Delete"))
+                            .on_press(Message::ConfirmRedact),
+                    )
+                    .push(
+                        Button::new(&mut self.redact_cancel_button, Text::new("Cancel"))
+                            .on_press(Message::CancelRedact),
+                    ),
+            );
+        }
+        // Upload progress
+        if let Some(ref mut upload) = self.upload {
+            let sent = upload.sent.load(Ordering::Relaxed);
+            let (status, progress) = match upload.size {
+                _ if upload.waiting.is_some() => (
+                    format!("{} will be uploaded once connected", upload.name),
+                    None,
+                ),
+                None => (format!("Reading {}...", upload.name), None),
+                Some(size) => (
+                    format!(
+                        "Uploading {} ({} of {})...",
+                        upload.name,
+                        matrix::upload::format_size(sent.min(size)),
+                        matrix::upload::format_size(size)
+                    ),
+                    Some(ProgressBar::new(0.0..=size as f32, sent as f32).width(150.into())),
+                ),
+            };
+            let mut row = Row::new()
+                .spacing(5)
+                .align_items(Align::Center)
+                .push(Text::new(status).width(Length::Fill));
+            if let Some(progress) = progress {
+                row = row.push(progress);
+            }
+            message_col = message_col.push(
+                row.push(
+                    Button::new(&mut upload.cancel_button, Text::new("Cancel"))
+                        .on_press(Message::CancelUpload),
+                ),
+            );
+        }
+        // Sticker picker
+        if self.picking_sticker {
+            let packs = match (&self.sticker_packs, &self.selected) {
+                (Some((room, packs)), Some(selected)) if room == selected => Some(packs),
+                _ => None,
+            };
+            let mut picker = Scrollable::new(&mut self.sticker_scroll)
+                .height(200.into())
+                .spacing(5)
+                .scrollbar_width(2);
+            match packs {
+                None => picker = picker.push(Text::new("Loading stickers...")),
+                Some(packs) if packs.is_empty() => picker = picker.push(Text::new(
+                    "You don't have any stickers. Sticker packs can be added with another client.",
+                )),
+                Some(packs) => {
+                    let count = packs.iter().map(|p| p.stickers.len()).sum();
+                    self.sticker_buttons.resize_with(count, Default::default);
+                    let mut buttons = self.sticker_buttons.iter_mut();
+                    for (pack_idx, pack) in packs.iter().enumerate() {
+                        picker = picker.push(Text::new(&pack.name).size(18));
+                        let stickers: Vec<_> = pack.stickers.iter().enumerate().collect();
+                        for chunk in stickers.chunks(STICKERS_PER_ROW) {
+                            let mut row = Row::new().spacing(5);
+                            for (idx, sticker) in chunk {
+                                let size = fit_image(sticker.dimensions, STICKER_PICKER_SIZE);
+                                let image = match self.animations.get(&sticker.url) {
+                                    Some(animation) => animation.view(size.0, size.1),
+                                    None => view_image(
+                                        self.images.get(&sticker.url),
+                                        size,
+                                        &sticker.shortcode,
+                                    ),
+                                };
+                                let button = Button::new(buttons.next().unwrap(), image)
+                                    .style(theme::Borderless)
+                                    .on_press(Message::SendSticker(pack_idx, *idx));
+                                row = row.push(Tooltip::new(
+                                    button,
+                                    &sticker.body,
+                                    tooltip::Position::Top,
+                                ));
+                            }
+                            picker = picker.push(row);
+                        }
+                    }
+                }
+            }
+            message_col = message_col.push(picker);
+        }
+        // Location to share
+        if self.sharing_location {
+            let mut row = Row::new()
+                .spacing(5)
+                .align_items(Align::Center)
+                .push(
+                    TextInput::new(
+                        &mut self.location_input_state,
+                        "Latitude, longitude or geo: URI",
+                        &self.location_input,
+                        Message::SetLocationInput,
+                    )
+                    .width(Length::FillPortion(1))
+                    .padding(5)
+                    .on_submit(Message::ShareLocation),
+                )
+                .push(
+                    TextInput::new(
+                        &mut self.location_description_input,
+                        "Description (optional)",
+                        &self.location_description,
+                        Message::SetLocationDescription,
+                    )
+                    .width(Length::FillPortion(1))
+                    .padding(5)
+                    .on_submit(Message::ShareLocation),
+                )
+                .push(
+                    Button::new(&mut self.location_share_button, Text::new("Share"))
+                        .on_press(Message::ShareLocation),
+                );
+            if let Some(ref error) = self.location_error {
+                row = row.push(Text::new(error).size(14).color([0.8, 0.0, 0.0]));
+            }
+            message_col = message_col.push(row);
+        }
+        // File to attach
+        if self.attaching {
+            let mut row = Row::new().spacing(5).align_items(Align::Center).push(
+                TextInput::new(
+                    &mut self.attach_input,
+                    "Path of file to send",
+                    &self.attach_path,
+                    Message::SetAttachPath,
+                )
+                .width(Length::Fill)
+                .padding(5)
+                .on_submit(Message::Upload),
+            );
+            let mut upload_button = Button::new(&mut self.upload_button, Text::new("Upload"));
+            if self.upload.is_none() {
+                upload_button = upload_button.on_press(Message::Upload);
+            }
+            row = row.push(upload_button);
+            if let Some(limit) = self.upload_limit {
+                row = row.push(
+                    Text::new(format!("Max {}", matrix::upload::format_size(limit))).size(14),
+                );
+            }
+            message_col = message_col.push(row);
+        }
+        // Command errors, help and suggestions
+        if let Some(ref error) = self.command_error {
+            message_col = message_col.push(Text::new(error).size(14).color([0.8, 0.0, 0.0]));
+        }
+        if !self.held_commands.is_empty() {
+            let text = match self.held_commands.len() {
+                1 => String::from("1 command will be run once connected"),
+                n => format!("{} commands will be run once connected", n),
+            };
+            message_col = message_col.push(Text::new(text).size(14).color([0.5, 0.5, 0.5]));
+        }
+        if let Some(ref help) = self.command_help {
+            message_col = message_col.push(
+                Container::new(
+                    Row::new()
+                        .spacing(5)
+                        .push(Text::new(help).size(14).width(Length::Fill))
+                        .push(
+                            Button::new(&mut self.command_help_close, Text::new("Close").size(14))
+                                .on_press(Message::CloseCommandHelp),
+                        ),
+                )
+                .padding(5)
+                .width(Length::Fill)
+                .style(theme::Quote),
+            );
+        }
+        let suggestions = command::suggestions(&self.draft);
+        if !suggestions.is_empty() {
+            let mut col = Column::new().spacing(2);
+            for info in suggestions {
+                col = col.push(
+                    Row::new()
+                        .spacing(10)
+                        .push(Text::new(info.usage()).size(14))
+                        .push(Text::new(info.description).size(14).color([0.4, 0.4, 0.4])),
+                );
+            }
+            message_col = message_col.push(col);
+        }
         // Compose box
         message_col = message_col.push(
             Row::new()
+                .push(
+                    Button::new(&mut self.attach_button, Text::new("Attach"))
+                        .on_press(Message::ToggleAttach),
+                )
+                .push(
+                    Button::new(&mut self.sticker_button, Text::new("Stickers"))
+                        .on_press(Message::ToggleStickers),
+                )
+                .push(
+                    Button::new(&mut self.location_button, Text::new("Location"))
+                        .on_press(Message::ToggleLocation),
+                )
                 .push(
                     TextInput::new(
                         &mut self.message_input,
@@ -660,35 +2834,122 @@ impl MainView {
                 ),
         );
         root_row = root_row.push(message_col);
+        if let Some(member_col) = member_col {
+            root_row = root_row.push(member_col);
+        }
 
         root_row.into()
     }
 
     fn update(&mut self, message: Message) -> Command<Message> {
         let view = self;
+        // What the push rules know about a room depends on its state
+        if let Message::Sync(matrix::Event::Joined(AnyRoomEvent::State(_), ref joined)) = message {
+            if let Some(room) = view.rooms.get_mut(joined.room_id()) {
+                room.push_context = None;
+            }
+        }
         match message {
             Message::ErrorMessage(e) => view.error = Some((e, Default::default())),
             Message::ClearError => view.error = None,
             Message::SetSort(s) => view.sorting = s,
-            Message::ResetRoom(id, room) => {
+            Message::SetMarkdown(markdown) => view.markdown = markdown,
+            Message::SetHideJoins(hide) => view.hide_joins = hide,
+            Message::SetAutoplay(autoplay) => {
+                view.autoplay = autoplay;
+                for animation in view.animations.values_mut() {
+                    animation.paused = !autoplay;
+                }
+            }
+            Message::ResetRoom(id, mut room) => {
+                // Keep the messages we're sending
+                if let Some(old) = view.rooms.get_mut(&id) {
+                    room.messages.pending = std::mem::take(&mut old.messages.pending);
+                    room.notifications = old.notifications;
+                    room.highlights = old.highlights;
+                }
+                // Messages from the outbox on disk are queued once the room is loaded
+                let unsent = view.unloaded_outbox.remove(&id).unwrap_or_default();
+                let queued: Vec<String> = unsent
+                    .iter()
+                    .filter(|message| message.state == outbox::State::Queued)
+                    .map(|message| message.txn_id.clone())
+                    .collect();
+                for message in unsent {
+                    room.messages.push_pending(message);
+                }
+                let empty = room.messages.messages.is_empty();
                 view.rooms.insert(id.clone(), room);
-                return async move { Message::BackFill(id) }.into();
+                view.loading_rooms.remove(&id);
+                let mut commands = Vec::new();
+                for txn_id in queued {
+                    commands.push(view.send_pending(&id, &txn_id));
+                }
+                for event in view.unloaded_events.remove(&id).unwrap_or_default() {
+                    commands.push(view.update(Message::Sync(event)));
+                }
+                if view.online && empty {
+                    commands.push(async move { Message::BackFill(id) }.into());
+                }
+                return Command::batch(commands);
             }
             Message::SelectRoom(r) => {
                 view.selected = Some(r.clone());
-                if view.rooms.get(&r).unwrap().messages.messages.is_empty() {
-                    return async move { Message::BackFill(r) }.into();
+                if let Some(room) = view.rooms.get_mut(&r) {
+                    room.notifications = 0;
+                    room.highlights = 0;
+                }
+                view.reply_to = None;
+                view.edit_history = None;
+                view.redacting = None;
+                view.reacting_to = None;
+                view.open_thread = None;
+                view.picking_sticker = false;
+                if view.editing.take().is_some() {
+                    view.draft.clear();
+                }
+                let room = view.rooms.get(&r).unwrap();
+                let mut commands = Vec::new();
+                if view.show_members && room.members.is_none() {
+                    commands.push(view.load_members(r.clone()));
+                }
+                if view.online && room.messages.messages.is_empty() {
+                    commands.push(async move { Message::BackFill(r) }.into());
+                }
+                return Command::batch(commands);
+            }
+            Message::ToggleMembers => {
+                view.show_members = !view.show_members;
+                view.edit_history = None;
+                view.open_thread = None;
+                view.devtools = false;
+                if let Some(ref selected) = view.selected {
+                    let loaded = view.rooms.get(selected).map(|r| r.members.is_some());
+                    if view.show_members && loaded == Some(false) {
+                        return view.load_members(selected.clone());
+                    }
+                }
+            }
+            Message::MembersLoaded(id, members) => {
+                if let Some(room) = view.rooms.get_mut(&id) {
+                    room.members = Some(members);
                 }
             }
             Message::Sync(event) => match event {
                 matrix::Event::Joined(event, joined) => match event {
                     AnyRoomEvent::Message(event) => {
-                        let room = view.rooms.entry(event.room_id().clone()).or_default();
-                        room.messages.push(AnyRoomEvent::Message(event.clone()));
-                        let mut commands = Vec::new();
+                        let room_id = event.room_id().clone();
+                        let room_event = AnyRoomEvent::Message(event.clone());
+                        let count = view.count_notification(&room_event, &joined);
+                        let room = view.rooms.entry(room_id.clone()).or_default();
+                        room.messages.push(room_event.clone());
+                        let mut commands = vec![
+                            count,
+                            view.fetch_reply_parents(&room_id, std::iter::once(&room_event)),
+                        ];
                         // Add fetch image command if the message has an image
-                        let img_cmd = match event.image_url() {
-                            Some(url) => async { Message::FetchImage(url) }.into(),
+                        let img_cmd = match event.image() {
+                            Some(image) => async { Message::FetchImage(image) }.into(),
                             None => Command::none(),
                         };
                         commands.push(img_cmd);
@@ -789,6 +3050,23 @@ impl MainView {
                                     _ => (),
                                 }
                             }
+                            // Keep member list up to date
+                            if let Some(ref mut members) = room.members {
+                                let user = member.state_key.clone();
+                                match member.content.membership {
+                                    MembershipState::Join => {
+                                        let name = member
+                                            .content
+                                            .displayname
+                                            .clone()
+                                            .unwrap_or_else(|| user.to_string());
+                                        members.insert(user, name);
+                                    }
+                                    _ => {
+                                        members.remove(&user);
+                                    }
+                                }
+                            }
                             room.messages.push(AnyRoomEvent::State(event));
                         }
                         ref any => {
@@ -820,83 +3098,587 @@ impl MainView {
                     }
                     _ => (),
                 },
+                matrix::Event::AccountData(AnyBasicEvent::PushRules(rules)) => {
+                    let rules = serde_json::to_value(&rules.content)
+                        .and_then(|json| Ruleset::from_account_data(&json));
+                    match rules {
+                        Ok(rules) => view.push_rules = rules,
+                        Err(e) => view.error = Some((e.to_string(), Default::default())),
+                    }
+                }
+                matrix::Event::Presence(event) => {
+                    view.presence
+                        .insert(event.sender.clone(), Presence::from_event(&event));
+                }
+                matrix::Event::Gap(room_id, token) => {
+                    if let Some(room) = view.rooms.get_mut(&room_id) {
+                        room.messages.gap(token);
+                    }
+                }
+                matrix::Event::Thread(room_id, root, id) => {
+                    let room = match view.rooms.get_mut(&room_id) {
+                        Some(room) => room,
+                        None => return Command::none(),
+                    };
+                    // Move the event we got through sync, since it's already decrypted
+                    let event = match room.messages.get(&id) {
+                        Some(event) => event.clone(),
+                        None => return Command::none(),
+                    };
+                    room.messages.remove_from_timeline(&id);
+                    let thread = room.threads.entry(root).or_default();
+                    if thread.messages.get(&id).is_none() {
+                        thread.count += 1;
+                        thread.messages.push(event);
+                    }
+                }
                 matrix::Event::Token(token) => {
                     view.sync_token = token;
                 }
                 _ => (),
             },
             Message::BackFill(id) => {
+                if !view.online {
+                    return Command::none();
+                }
                 let entry = view.rooms.entry(id.clone()).or_default();
-                entry.messages.loading = true;
                 let client = view.client.clone();
                 let room = client.get_joined_room(&id).unwrap();
                 let token = match entry.messages.end.clone() {
                     Some(end) => end,
-                    None => room
-                        .last_prev_batch()
-                        .unwrap_or_else(|| view.sync_token.clone()),
+                    None => {
+                        // Without a token for the oldest messages, we can only paginate back
+                        // from the latest sync, which is where the newest gap starts. Fill the
+                        // gaps first, so nothing lands in the wrong chunk.
+                        if let Some(chunk) = entry.messages.first_gap() {
+                            return async move { Message::FillGap(id, chunk) }.into();
+                        }
+                        room.last_prev_batch()
+                            .unwrap_or_else(|| view.sync_token.clone())
+                    }
                 };
+                entry.messages.loading = true;
                 return async move {
                     let mut request = MessageRequest::backward(&id, &token);
                     request.limit = matrix_sdk::uint!(30);
                     match room.messages(request).await {
-                        Ok(response) => Message::BackFilled(id, response),
+                        Ok(response) => Message::BackFilled(id, None, response),
                         Err(e) => Message::ErrorMessage(e.to_string()),
                     }
                 }
                 .into();
             }
-            Message::BackFilled(id, response) => {
+            Message::FillGap(id, chunk) => {
+                if !view.online {
+                    return Command::none();
+                }
+                let gap = match view
+                    .rooms
+                    .get_mut(&id)
+                    .and_then(|r| r.messages.chunks.get_mut(chunk))
+                {
+                    Some(gap) if !gap.filling => gap,
+                    _ => return Command::none(),
+                };
+                let token = match gap.gap.clone() {
+                    Some(token) => token,
+                    None => return Command::none(),
+                };
+                gap.filling = true;
+                let room = view.client.get_joined_room(&id).unwrap();
+                return async move {
+                    let mut request = MessageRequest::backward(&id, &token);
+                    request.limit = matrix_sdk::uint!(30);
+                    match room.messages(request).await {
+                        Ok(response) => Message::BackFilled(id, Some(chunk), response),
+                        Err(e) => Message::GapFailed(id, chunk, e.to_string()),
+                    }
+                }
+                .into();
+            }
+            Message::GapFailed(id, chunk, error) => {
+                if let Some(gap) = view
+                    .rooms
+                    .get_mut(&id)
+                    .and_then(|r| r.messages.chunks.get_mut(chunk))
+                {
+                    gap.filling = false;
+                }
+                view.error = Some((error, Default::default()));
+            }
+            Message::BackFilled(id, gap, response) => {
                 let room = view.rooms.get_mut(&id).unwrap();
-                room.messages.loading = false;
-                let events: Vec<AnyRoomEvent> = response
-                    .chunk
-                    .into_iter()
-                    .filter_map(|e| e.deserialize().ok())
-                    .chain(
-                        response
-                            .state
-                            .into_iter()
-                            .filter_map(|e| e.deserialize().ok().map(AnyRoomEvent::State)),
-                    )
+                // Thread replies go to their thread instead of the main timeline
+                let mut events = Vec::new();
+                let mut replies: BTreeMap<EventId, Vec<AnyRoomEvent>> = BTreeMap::new();
+                for (event, root, summary) in response.chunk.iter().filter_map(thread::deserialize)
+                {
+                    if let Some(summary) = summary {
+                        let thread = room.threads.entry(event.event_id().clone()).or_default();
+                        thread.count = summary.count;
+                        if let Some(latest) = summary.latest {
+                            thread.messages.push(latest);
+                        }
+                    }
+                    match root {
+                        Some(root) => replies.entry(root).or_default().push(event),
+                        None => events.push(event),
+                    }
+                }
+                for (root, replies) in replies {
+                    room.threads
+                        .entry(root)
+                        .or_default()
+                        .messages
+                        .prepend(replies);
+                }
+                let mut commands: Vec<Command<_>> = events
+                    .iter()
+                    .filter_map(|e| e.image())
+                    .map(|image| async { Message::FetchImage(image) }.into())
                     .collect();
+                commands.push(view.fetch_reply_parents(&id, events.iter()));
+                let room = view.rooms.get_mut(&id).unwrap();
+                match gap {
+                    // Lazy loaded members aren't part of the gap, so they're left out
+                    Some(chunk) => room.messages.fill_gap(chunk, events, response.end),
+                    None => {
+                        // Members lazy loaded for the page are older than anything in it
+                        events.extend(
+                            response
+                                .state
+                                .into_iter()
+                                .filter_map(|e| e.deserialize().ok().map(AnyRoomEvent::State)),
+                        );
+                        room.messages.loading = false;
+                        if let Some(start) = response.start {
+                            room.messages.start = Some(start);
+                        }
+                        if let Some(end) = response.end {
+                            room.messages.end = Some(end);
+                        }
+                        room.messages.prepend(events);
+                    }
+                }
+                return Command::batch(commands);
+            }
 
-                if let Some(start) = response.start {
-                    room.messages.start = Some(start);
+            Message::OpenThread(root) => {
+                view.show_members = false;
+                view.edit_history = None;
+                view.devtools = false;
+                view.open_thread = Some(root.clone());
+                let loaded = view
+                    .selected
+                    .as_ref()
+                    .and_then(|id| view.rooms.get(id))
+                    .and_then(|room| room.threads.get(&root))
+                    .map_or(false, |thread| thread.loaded);
+                if !loaded {
+                    return view.update(Message::ThreadBackFill(root, false));
+                }
+            }
+            Message::CloseThread => view.open_thread = None,
+            Message::ThreadBackFill(root, older) => {
+                let selected = match view.selected.clone() {
+                    Some(selected) if view.online => selected,
+                    _ => return Command::none(),
+                };
+                let thread = view
+                    .rooms
+                    .entry(selected.clone())
+                    .or_default()
+                    .threads
+                    .entry(root.clone())
+                    .or_default();
+                let from = match older {
+                    true => thread.next_batch.clone(),
+                    false => None,
+                };
+                thread.messages.loading = true;
+                let session = view.session.clone();
+                return Command::perform(
+                    matrix::thread::replies(session, selected.clone(), root.clone(), from),
+                    move |result| match result {
+                        Ok(page) => Message::ThreadBackFilled(selected, root, older, page),
+                        Err(e) => Message::ErrorMessage(e.to_string()),
+                    },
+                );
+            }
+            Message::ThreadBackFilled(room_id, root, older, page) => {
+                let room = view.rooms.entry(room_id).or_default();
+                // Replies we got through sync don't belong in the main timeline
+                for event in page.events.iter() {
+                    room.messages.remove_from_timeline(event.event_id());
                 }
-                if let Some(end) = response.end {
-                    room.messages.end = Some(end);
+                let thread = room.threads.entry(root).or_default();
+                thread.messages.loading = false;
+                // Only the oldest page we've fetched tells us where to continue from
+                if older || !thread.loaded {
+                    thread.next_batch = page.next_batch;
+                }
+                if older {
+                    thread.messages.prepend(page.events);
+                } else if thread.loaded {
+                    thread.messages.catch_up(page.events);
+                } else {
+                    // What we had came from summaries and the timeline, so it may be out of
+                    // order. The pages we fetch have all of it anyway.
+                    thread.messages = MessageBuffer::default();
+                    thread.messages.prepend(page.events);
                 }
-                let commands: Vec<Command<_>> = events
+                thread.loaded = true;
+            }
+            Message::SendThreadReply => {
+                let (selected, root) = match (view.selected.clone(), view.open_thread.clone()) {
+                    (Some(selected), Some(root)) if !view.thread_draft.is_empty() => {
+                        (selected, root)
+                    }
+                    _ => return Command::none(),
+                };
+                let latest = view
+                    .rooms
+                    .get(&selected)
+                    .and_then(|room| room.threads.get(&root))
+                    .and_then(Thread::latest)
+                    .map_or_else(|| root.clone(), |e| e.event_id().clone());
+                let content = matrix::markdown::text_content(
+                    std::mem::take(&mut view.thread_draft),
+                    view.markdown,
+                );
+                return match thread::content(root, latest, content) {
+                    Ok(content) => view.send(selected, content),
+                    Err(e) => {
+                        view.error = Some((e.to_string(), Default::default()));
+                        Command::none()
+                    }
+                };
+            }
+            Message::SetThreadDraft(draft) => view.thread_draft = draft,
+            Message::ToggleAttach => view.attaching = !view.attaching,
+            Message::ToggleStickers => {
+                view.picking_sticker = !view.picking_sticker;
+                let room = match view.selected.clone() {
+                    Some(room) if view.picking_sticker => room,
+                    _ => return Command::none(),
+                };
+                let loaded = matches!(view.sticker_packs, Some((ref r, _)) if r == &room);
+                if loaded || !view.online {
+                    return Command::none();
+                }
+                let session = view.session.clone();
+                return Command::perform(sticker::packs(session, room.clone()), move |result| {
+                    match result {
+                        Ok(packs) => Message::StickerPacks(room, packs),
+                        Err(e) => Message::ErrorMessage(e.to_string()),
+                    }
+                });
+            }
+            Message::StickerPacks(room, packs) => {
+                let commands: Vec<Command<_>> = packs
                     .iter()
-                    .filter_map(|e| e.image_url())
-                    .map(|url| async { Message::FetchImage(url) }.into())
+                    .flat_map(|pack| pack.stickers.iter())
+                    .map(|sticker| {
+                        let image = matrix::ImageSource {
+                            media: matrix::Media::Plain(sticker.url.clone()),
+                            dimensions: sticker.dimensions,
+                            mimetype: sticker.mimetype.clone(),
+                            thumbnail: None,
+                        };
+                        view.fetch_image(image)
+                    })
                     .collect();
-                room.messages.append(events);
+                view.sticker_packs = Some((room, packs));
                 return Command::batch(commands);
             }
-
-            Message::FetchImage(url) => {
-                let (server, path) = match matrix::parse_mxc(&url) {
-                    Ok((server, path)) => (server, path),
-                    Err(e) => return async move { Message::ErrorMessage(e.to_string()) }.into(),
+            Message::SendSticker(pack, idx) => {
+                let sticker = match view.sticker_packs {
+                    Some((_, ref packs)) => packs.get(pack).and_then(|p| p.stickers.get(idx)),
+                    None => None,
+                };
+                if let (Some(sticker), Some(room)) = (sticker, view.selected.clone()) {
+                    let content = sticker::content(sticker);
+                    view.picking_sticker = false;
+                    return view.send(room, content);
+                }
+            }
+            Message::ToggleLocation => {
+                view.sharing_location = !view.sharing_location;
+                view.location_error = None;
+            }
+            Message::SetLocationInput(input) => {
+                view.location_input = input;
+                view.location_error = None;
+            }
+            Message::SetLocationDescription(description) => view.location_description = description,
+            Message::ShareLocation => {
+                let room = match view.selected.clone() {
+                    Some(room) => room,
+                    None => return Command::none(),
+                };
+                let location = match Location::parse_input(&view.location_input) {
+                    Ok(location) => location,
+                    Err(e) => {
+                        view.location_error = Some(e.to_string());
+                        return Command::none();
+                    }
+                };
+                let description = Some(view.location_description.trim())
+                    .filter(|d| !d.is_empty())
+                    .map(String::from);
+                view.sharing_location = false;
+                view.location_input.clear();
+                view.location_description.clear();
+                let content = location::content(&location, description.as_deref());
+                return view.send(room, content);
+            }
+            Message::OpenMap(url) => {
+                if let Err(e) = matrix::file::open(&url) {
+                    view.error = Some((e.to_string(), Default::default()));
+                }
+            }
+            Message::SetAttachPath(path) => view.attach_path = path,
+            Message::UploadLimit(limit) => view.upload_limit = limit,
+            Message::Upload => {
+                let room = match view.selected.clone() {
+                    Some(room) if view.upload.is_none() && !view.attach_path.is_empty() => room,
+                    _ => return Command::none(),
+                };
+                let path = matrix::upload::expand_path(&view.attach_path);
+                let limit = view.upload_limit;
+                let (future, abort) = abortable(matrix::upload::prepare(path, limit));
+                view.upload = Some(Upload {
+                    room,
+                    name: view.attach_path.clone(),
+                    size: None,
+                    sent: Default::default(),
+                    waiting: None,
+                    abort,
+                    cancel_button: Default::default(),
+                });
+                return Command::perform(future, |result| match result {
+                    Ok(Ok(prepared)) => Message::UploadPrepared(prepared),
+                    Ok(Err(e)) => Message::UploadFailed(e.to_string()),
+                    Err(_) => Message::UploadCancelled,
+                });
+            }
+            Message::UploadPrepared(prepared) => {
+                let upload = match view.upload {
+                    Some(ref mut upload) => upload,
+                    None => return Command::none(),
                 };
+                upload.name = prepared.name.clone();
+                upload.size = Some(prepared.upload_size());
+                if !view.online {
+                    upload.waiting = Some(prepared);
+                    return Command::none();
+                }
+                let encrypt = view
+                    .client
+                    .get_joined_room(&upload.room)
+                    .map_or(false, |room| room.is_encrypted());
+                upload.sent.store(0, Ordering::Relaxed);
+                let sent = Arc::clone(&upload.sent);
+                let session = view.session.clone();
+                let (future, abort) =
+                    abortable(matrix::upload::upload(session, prepared, encrypt, sent));
+                upload.abort = abort;
+                return Command::perform(future, |result| match result {
+                    Ok(Ok(content)) => Message::UploadFinished(content),
+                    Ok(Err(e)) => Message::UploadFailed(e.to_string()),
+                    Err(_) => Message::UploadCancelled,
+                });
+            }
+            Message::UploadFinished(content) => {
+                if let Some(upload) = view.upload.take() {
+                    view.attaching = false;
+                    view.attach_path.clear();
+                    let content = AnyMessageEventContent::RoomMessage(content);
+                    return view.send(upload.room, content);
+                }
+            }
+            Message::UploadFailed(e) => {
+                view.upload = None;
+                view.error = Some((format!("Upload failed: {}", e), Default::default()));
+            }
+            Message::CancelUpload => {
+                if let Some(ref upload) = view.upload {
+                    upload.abort.abort();
+                    // Uploads waiting for us to connect have nothing running to cancel
+                    if upload.waiting.is_some() {
+                        view.upload = None;
+                    }
+                }
+            }
+            Message::UploadCancelled => view.upload = None,
+            // Nothing to do, the view reads the progress when it's redrawn
+            Message::UploadTick => (),
+            Message::DownloadAttachment(id) => {
+                if let Some(attachment) = view.attachment(&id) {
+                    let path = matrix::file::default_path(&attachment.name);
+                    view.saving_attachment = Some((id, path.to_string_lossy().into_owned()));
+                }
+            }
+            Message::SetAttachmentPath(path) => {
+                if let Some((_, ref mut old)) = view.saving_attachment {
+                    *old = path;
+                }
+            }
+            Message::SaveAttachment => {
+                if let Some((id, path)) = view.saving_attachment.take() {
+                    let path = matrix::upload::expand_path(&path);
+                    return view.save_attachment(id, path, false);
+                }
+            }
+            Message::CancelSaveAttachment => view.saving_attachment = None,
+            Message::OpenAttachment(id) => {
+                if let Some(attachment) = view.attachment(&id) {
+                    let path = matrix::file::default_path(&attachment.name);
+                    return view.save_attachment(id, path, true);
+                }
+            }
+            Message::AttachmentSaved(id, result) => {
+                let download = match result {
+                    Ok(path) => Download::Saved(path),
+                    Err(e) => Download::Failed(e),
+                };
+                view.downloads.insert(id, download);
+            }
+            Message::FetchImage(image) => return view.fetch_image(image),
+            Message::FetchedImage(url, handle) => {
+                view.images.insert(url, handle);
+            }
+            Message::FetchedAnimation(url, animation) => {
+                view.animations.insert(url, animation);
+            }
+            Message::ToggleAnimation(url) => {
+                if let Some(animation) = view.animations.get_mut(&url) {
+                    animation.paused = !animation.paused;
+                }
+            }
+            Message::AnimationTick(now) => {
+                for animation in view.animations.values_mut() {
+                    animation.tick(now);
+                }
+            }
+            Message::FetchOriginal(media) => {
+                let url = media.url().to_owned();
+                if view.originals.contains_key(&url) || !view.fetching_originals.insert(url.clone())
+                {
+                    return Command::none();
+                }
                 let client = view.client.clone();
-                return async move {
-                    let request = ImageRequest::new(&path, &*server);
-                    let response = client.send(request, None).await;
-                    match response {
-                        Ok(response) => Message::FetchedImage(
+                let cache = view.media_cache.clone();
+                return Command::perform(matrix::download(client, cache, media), |result| {
+                    match result {
+                        Ok(data) => Message::FetchedOriginal(
                             url,
-                            iced::image::Handle::from_memory(response.file),
+                            Ok(iced::image::Handle::from_memory(data)),
                         ),
-                        Err(e) => Message::ErrorMessage(e.to_string()),
+                        Err(e) => Message::FetchedOriginal(url, Err(e.to_string())),
                     }
+                });
+            }
+            Message::FetchedOriginal(url, result) => {
+                view.fetching_originals.remove(&url);
+                match result {
+                    Ok(handle) => {
+                        view.originals.insert(url, handle);
+                    }
+                    Err(e) => view.error = Some((e, Default::default())),
                 }
-                .into();
             }
-            Message::FetchedImage(url, handle) => {
-                view.images.insert(url, handle);
+            Message::WindowResized(width, height) => view.window_size = (width, height),
+            Message::OpenImage(id) => {
+                let selected = match view.selected.clone() {
+                    Some(selected) => selected,
+                    None => return Command::none(),
+                };
+                let message = match view.find_event(&selected, &id) {
+                    Some(AnyRoomEvent::Message(AnyMessageEvent::RoomMessage(message))) => message,
+                    _ => return Command::none(),
+                };
+                let content = match view.rooms.get(&selected) {
+                    Some(room) => room.messages.latest_content(message),
+                    None => message.content.clone(),
+                };
+                let (name, source) = match content.msgtype {
+                    MessageType::Image(ref image) => {
+                        match matrix::message_image(&content.msgtype) {
+                            Some(source) => (image.body.clone(), source),
+                            None => return Command::none(),
+                        }
+                    }
+                    _ => return Command::none(),
+                };
+                let sender = view
+                    .client
+                    .get_joined_room(&selected)
+                    .and_then(|joined| block_on(joined.get_member(&message.sender)).ok())
+                    .flatten()
+                    .map(|member| member.name().to_owned())
+                    .unwrap_or_else(|| message.sender.to_string());
+                let media = source.media;
+                view.image_viewer = Some(ImageViewer::new(
+                    sender,
+                    message.origin_server_ts,
+                    name,
+                    media.clone(),
+                ));
+                return async { Message::FetchOriginal(media) }.into();
+            }
+            Message::CloseImage => view.image_viewer = None,
+            Message::ZoomImage(factor) => {
+                if let Some(ref mut viewer) = view.image_viewer {
+                    viewer.zoom(factor);
+                }
+            }
+            Message::PanImage(x, y) => {
+                if let Some(ref mut viewer) = view.image_viewer {
+                    viewer.pan(x, y);
+                }
+            }
+            Message::ResetZoom => {
+                if let Some(ref mut viewer) = view.image_viewer {
+                    viewer.reset();
+                }
+            }
+            Message::SetImageSavePath(path) => {
+                if let Some(ref mut viewer) = view.image_viewer {
+                    viewer.save_path = path;
+                }
+            }
+            Message::SaveImage => {
+                let viewer = match view.image_viewer {
+                    Some(ref mut viewer) if !viewer.saving => viewer,
+                    _ => return Command::none(),
+                };
+                viewer.saving = true;
+                viewer.status = None;
+                let path = matrix::upload::expand_path(&viewer.save_path);
+                let media = viewer.media.clone();
+                let client = view.client.clone();
+                let cache = view.media_cache.clone();
+                // Comes from the disk cache if the viewer already loaded it
+                return Command::perform(
+                    matrix::file::save(client, cache, media, path),
+                    |result| {
+                        Message::ImageSaved(
+                            result
+                                .map(|path| path.display().to_string())
+                                .map_err(|e| e.to_string()),
+                        )
+                    },
+                );
+            }
+            Message::ImageSaved(result) => {
+                if let Some(ref mut viewer) = view.image_viewer {
+                    viewer.saving = false;
+                    viewer.status = Some(match result {
+                        Ok(path) => format!("Saved to {}", path),
+                        Err(e) => format!("Couldn't save image: {}", e),
+                    });
+                }
             }
             Message::FetchedThumbnail(url, handle) => {
                 view.thumbnails.insert(url, handle);
@@ -906,6 +3688,18 @@ impl MainView {
                     room.name = name;
                 }
             }
+            Message::CountNotification(id, event, context) => {
+                if let Some(room) = view.rooms.get_mut(&id) {
+                    let context = room.push_context.get_or_insert(context);
+                    let evaluation = view.push_rules.evaluate(&event, context);
+                    if evaluation.notify() {
+                        room.notifications += 1;
+                    }
+                    if evaluation.highlight() {
+                        room.highlights += 1;
+                    }
+                }
+            }
             Message::SetVerification(v) => view.sas = v,
             Message::VerificationAccept => {
                 let sas = match &view.sas {
@@ -933,52 +3727,309 @@ impl MainView {
                     },
                 );
             }
-            Message::VerificationCancel => {
-                let sas = match &view.sas {
-                    Some(sas) => sas.clone(),
+            Message::VerificationCancel => {
+                let sas = match &view.sas {
+                    Some(sas) => sas.clone(),
+                    None => return Command::none(),
+                };
+                return Command::perform(
+                    async move { sas.cancel().await },
+                    |result| match result {
+                        Ok(()) => Message::VerificationCancelled(VerificationCancelCode::User),
+                        Err(e) => Message::ErrorMessage(e.to_string()),
+                    },
+                );
+            }
+            Message::VerificationCancelled(code) => {
+                view.sas = None;
+                return async move { Message::ErrorMessage(code.as_str().to_owned()) }.into();
+            }
+            Message::VerificationClose => view.sas = None,
+            Message::SetMessage(m) => {
+                view.draft = m;
+                view.command_error = None;
+            }
+            Message::PendingSent(room, txn_id, result) => {
+                if let Some(entry) = view.rooms.get_mut(&room) {
+                    match result {
+                        Ok(id) => entry.messages.pending_sent(&txn_id, id),
+                        Err(e) => {
+                            if let Some(message) = entry.messages.pending_mut(&txn_id) {
+                                message.state = outbox::State::Failed(e);
+                            }
+                        }
+                    }
+                }
+                view.save_outbox();
+            }
+            Message::RetryPending(room, txn_id) => {
+                let command = view.send_pending(&room, &txn_id);
+                view.save_outbox();
+                return command;
+            }
+            Message::DeletePending(room, txn_id) => {
+                if let Some(entry) = view.rooms.get_mut(&room) {
+                    entry.messages.remove_pending(&txn_id);
+                }
+                view.save_outbox();
+            }
+            Message::CompleteCommand => {
+                if let Some(completed) = command::complete(&view.draft) {
+                    view.draft = completed;
+                    view.message_input.move_cursor_to_end();
+                }
+            }
+            Message::CommandFailed(e) => view.command_error = Some(e),
+            Message::CloseCommandHelp => view.command_help = None,
+            Message::SetDevToolsEvent(id) => view.devtools_event = id,
+            Message::CloseDevTools => view.devtools = false,
+            Message::SendMessage => {
+                let selected = match view.selected.clone() {
+                    Some(selected) => selected,
+                    None => return Command::none(),
+                };
+                // Commands either give the content of the message they send, or run here
+                view.command_error = None;
+                let content = match command::parse(&view.draft) {
+                    None => matrix::markdown::text_content(view.draft.clone(), view.markdown),
+                    Some(Err(e)) => {
+                        view.command_error = Some(e.to_string());
+                        return Command::none();
+                    }
+                    Some(Ok(command)) => match command.content(view.markdown) {
+                        Some(content) => content,
+                        None => return view.run_command(selected, command),
+                    },
+                };
+                // Add reply relation and fallback
+                let parent = view
+                    .reply_to
+                    .take()
+                    .and_then(|id| view.find_event(&selected, &id));
+                let content = match parent {
+                    Some(AnyRoomEvent::Message(AnyMessageEvent::RoomMessage(parent))) => {
+                        reply::make_reply(content, parent)
+                    }
+                    _ => content,
+                };
+                // Turn it into an edit if we're editing
+                let content = match view.editing.take() {
+                    Some(original) => edit::make_edit(original, content),
+                    None => content,
+                };
+                let content = AnyMessageEventContent::RoomMessage(content);
+                view.draft.clear();
+                return view.send(selected, content);
+            }
+            Message::Reply(id) => {
+                if view.editing.take().is_some() {
+                    view.draft.clear();
+                }
+                view.reply_to = Some(id);
+            }
+            Message::CancelReply => {
+                view.reply_to = None;
+                if view.editing.take().is_some() {
+                    view.draft.clear();
+                }
+            }
+            Message::Edit(id) => {
+                let selected = match view.selected {
+                    Some(ref selected) => selected,
                     None => return Command::none(),
                 };
-                return Command::perform(
-                    async move { sas.cancel().await },
-                    |result| match result {
-                        Ok(()) => Message::VerificationCancelled(VerificationCancelCode::User),
-                        Err(e) => Message::ErrorMessage(e.to_string()),
-                    },
-                );
+                let room = match view.rooms.get(selected) {
+                    Some(room) => room,
+                    None => return Command::none(),
+                };
+                if let Some(AnyRoomEvent::Message(AnyMessageEvent::RoomMessage(message))) =
+                    room.messages.get(&id)
+                {
+                    let content = room.messages.latest_content(message);
+                    view.draft = reply::plain_body(&message.content, &content).to_owned();
+                    view.reply_to = None;
+                    view.editing = Some(id);
+                }
             }
-            Message::VerificationCancelled(code) => {
-                view.sas = None;
-                return async move { Message::ErrorMessage(code.as_str().to_owned()) }.into();
+            Message::ShowEdits(id) => {
+                view.show_members = false;
+                view.open_thread = None;
+                view.devtools = false;
+                view.edit_history = Some(id);
             }
-            Message::VerificationClose => view.sas = None,
-            Message::SetMessage(m) => view.draft = m,
-            Message::SendMessage => {
+            Message::CloseEdits => view.edit_history = None,
+            Message::Redact(id) => {
+                view.redacting = Some(id);
+                view.redact_reason.clear();
+            }
+            Message::SetRedactReason(reason) => view.redact_reason = reason,
+            Message::CancelRedact => view.redacting = None,
+            Message::ConfirmRedact => {
+                let (selected, id) = match (view.selected.clone(), view.redacting.take()) {
+                    (Some(selected), Some(id)) => (selected, id),
+                    _ => return Command::none(),
+                };
+                let reason =
+                    Some(std::mem::take(&mut view.redact_reason)).filter(|r| !r.is_empty());
+                return view.redact(selected, id, reason);
+            }
+            Message::React(id) => {
+                view.reacting_to = Some(id);
+                view.reaction_input.clear();
+            }
+            Message::SetReactionInput(input) => view.reaction_input = input,
+            Message::CancelReaction => view.reacting_to = None,
+            Message::SendReaction(key) => {
+                let key = key.trim().to_owned();
+                view.reaction_input.clear();
+                match view.reacting_to.take() {
+                    Some(target) if !key.is_empty() => {
+                        return view.update(Message::ToggleReaction(target, key))
+                    }
+                    _ => (),
+                }
+            }
+            Message::ToggleReaction(target, key) => {
                 let selected = match view.selected.clone() {
                     Some(selected) => selected,
                     None => return Command::none(),
                 };
-                let draft = view.draft.clone();
+                let own = view.rooms.get(&selected).and_then(|room| {
+                    room.messages
+                        .reactions(&target)
+                        .into_iter()
+                        .filter(|(k, _)| *k == key)
+                        .flat_map(|(_, reactions)| reactions)
+                        .find(|r| r.sender == view.session.user_id)
+                        .map(|r| r.event_id.clone())
+                });
+                // Remove our reaction if we've already reacted with this key
+                return match own {
+                    Some(reaction) => view.redact(selected, reaction, None),
+                    None => view.send(selected, reaction::content(target, key)),
+                };
+            }
+            Message::RedactFailed(room_id, id, error) => {
+                if let Some(room) = view.rooms.get_mut(&room_id) {
+                    room.messages.unredact(&id);
+                }
+                view.error = Some((
+                    format!("Couldn't delete message: {}", error),
+                    Default::default(),
+                ));
+            }
+            Message::FetchEvent(room_id, event_id) => {
+                if !view.online || !view.fetching_events.insert(event_id.clone()) {
+                    return Command::none();
+                }
+                let client = view.client.clone();
+                return async move {
+                    let request = EventRequest::new(&room_id, &event_id);
+                    match client.send(request, None).await {
+                        Ok(response) => match response.event.deserialize() {
+                            Ok(event) => Message::FetchedEvent(event),
+                            Err(e) => Message::ErrorMessage(e.to_string()),
+                        },
+                        Err(e) => Message::ErrorMessage(e.to_string()),
+                    }
+                }
+                .into();
+            }
+            Message::FetchedEvent(event) => {
+                view.fetching_events.remove(event.event_id());
+                view.fetched_events.insert(event.event_id().clone(), event);
+            }
+            Message::Connect => return view.connect(),
+            Message::Connected(token, events) => {
+                view.online = true;
+                view.connecting = false;
+                view.connection_error = None;
+                view.sync_token = token;
+                let mut commands =
+                    vec![view.load_rooms(), async { Message::FetchPushRules }.into()];
+                // Catch up on what happened while we were offline
+                for event in events {
+                    commands.push(view.catch_up(event));
+                }
+                // Rooms loaded from cache still need their avatars
+                for room in view.client.joined_rooms().into_iter() {
+                    if !view.rooms.contains_key(room.room_id()) {
+                        continue;
+                    }
+                    commands.push(view.fetch_avatar(room));
+                }
+                let queued: Vec<(RoomId, String)> = view
+                    .rooms
+                    .iter()
+                    .flat_map(|(id, room)| {
+                        room.messages
+                            .pending()
+                            .iter()
+                            .filter(|message| message.state == outbox::State::Queued)
+                            .map(move |message| (id.clone(), message.txn_id.clone()))
+                    })
+                    .collect();
+                for (room, txn_id) in queued {
+                    commands.push(view.send_pending(&room, &txn_id));
+                }
+                // Other things done while offline
+                for (room, id, reason) in std::mem::take(&mut view.held_redactions) {
+                    commands.push(view.redact(room, id, reason));
+                }
+                for (room, command) in std::mem::take(&mut view.held_commands) {
+                    commands.push(view.perform_command(room, command));
+                }
+                let waiting = view.upload.as_mut().and_then(|u| u.waiting.take());
+                if let Some(prepared) = waiting {
+                    commands.push(view.update(Message::UploadPrepared(prepared)));
+                }
                 let client = view.client.clone();
+                commands.push(Command::perform(
+                    matrix::upload::upload_limit(client),
+                    |result| match result {
+                        Ok(limit) => Message::UploadLimit(limit),
+                        Err(_) => Message::Noop,
+                    },
+                ));
+                return Command::batch(commands);
+            }
+            Message::ConnectionFailed(e) => {
+                view.connecting = false;
+                view.connection_error = Some(e);
+            }
+            Message::SaveHistory => {
+                let rooms: Vec<(RoomId, matrix::History)> = view
+                    .rooms
+                    .iter_mut()
+                    .filter(|(_, room)| room.messages.dirty)
+                    .map(|(id, room)| {
+                        room.messages.dirty = false;
+                        (id.clone(), room.messages.recent(HISTORY_CACHE_SIZE))
+                    })
+                    .collect();
+                if rooms.is_empty() {
+                    return Command::none();
+                }
                 return Command::perform(
                     async move {
-                        client
-                            .room_send(
-                                &selected,
-                                AnyMessageEventContent::RoomMessage(
-                                    MessageEventContent::text_plain(draft),
-                                ),
-                                None,
-                            )
-                            .await
+                        for (id, history) in rooms {
+                            matrix::write_history(&id, &history)?;
+                        }
+                        Ok(())
                     },
-                    |result| match result {
-                        Ok(_) => Message::SetMessage(String::new()),
+                    |result: Result<(), matrix::Error>| match result {
+                        Ok(()) => Message::Noop,
                         Err(e) => Message::ErrorMessage(e.to_string()),
                     },
                 );
             }
             Message::OpenSettings => {
-                view.settings_view = Some(SettingsView::new());
+                let mut settings = SettingsView::new();
+                settings.presence = view.own_presence;
+                settings.status = view.status_msg.clone();
+                let limit = view.media_cache.lock().unwrap().limit();
+                settings.cache_limit = (limit / MIB).to_string();
+                view.settings_view = Some(settings);
                 let client = view.client.clone();
                 return Command::perform(
                     async move {
@@ -1042,6 +4093,169 @@ impl MainView {
                 }
             }
             Message::CloseSettings => view.settings_view = None,
+            Message::SetCacheLimitInput(limit) => {
+                if let Some(ref mut settings) = view.settings_view {
+                    settings.cache_limit = limit;
+                }
+            }
+            Message::SaveCacheLimit => {
+                if let Some(ref settings) = view.settings_view {
+                    match settings.cache_limit.trim().parse::<u64>() {
+                        Ok(limit) => {
+                            let cache = view.media_cache.clone();
+                            let limit = limit.saturating_mul(MIB);
+                            return Command::perform(cache::set_limit(cache, limit), |result| {
+                                match result {
+                                    Ok(()) => Message::Noop,
+                                    Err(e) => Message::ErrorMessage(e.to_string()),
+                                }
+                            });
+                        }
+                        Err(_) => {
+                            view.error = Some((
+                                String::from("Cache size must be a number of MiB"),
+                                Default::default(),
+                            ));
+                        }
+                    }
+                }
+            }
+            Message::ClearCache => {
+                let cache = view.media_cache.clone();
+                return Command::perform(cache::clear(cache), |result| match result {
+                    Ok(()) => Message::Noop,
+                    Err(e) => {
+                        Message::ErrorMessage(format!("Couldn't clear the media cache: {}", e))
+                    }
+                });
+            }
+            Message::SetPresenceInput(presence) => {
+                if let Some(ref mut settings) = view.settings_view {
+                    settings.presence = presence;
+                }
+            }
+            Message::SetStatusInput(status) => {
+                if let Some(ref mut settings) = view.settings_view {
+                    settings.status = status;
+                }
+            }
+            Message::SavePresence => {
+                if let Some(ref mut settings) = view.settings_view {
+                    settings.saving_presence = true;
+                    view.own_presence = settings.presence;
+                    view.status_msg = settings.status.clone();
+                    view.idle = false;
+                    return view.send_presence(view.own_presence);
+                }
+            }
+            Message::PresenceSaved => {
+                if let Some(ref mut settings) = view.settings_view {
+                    settings.saving_presence = false;
+                }
+            }
+            Message::PresenceFailed(e) => {
+                if let Some(ref mut settings) = view.settings_view {
+                    settings.saving_presence = false;
+                }
+                view.error = Some((e, Default::default()));
+            }
+            Message::IdleTick => {
+                let idle = view.last_activity.elapsed() > IDLE_TIMEOUT;
+                if idle && !view.idle && view.own_presence == PresenceSetting::Online {
+                    view.idle = true;
+                    return view.send_presence(PresenceSetting::Unavailable);
+                }
+            }
+            Message::FetchPushRules => {
+                return Command::perform(
+                    push::get_rules(view.client.clone()),
+                    |result| match result {
+                        Ok(rules) => Message::PushRules(rules),
+                        Err(e) => Message::ErrorMessage(e.to_string()),
+                    },
+                );
+            }
+            Message::PushRules(rules) => view.push_rules = rules,
+            Message::PushRulesFailed(e) => {
+                view.error = Some((e, Default::default()));
+                return async { Message::FetchPushRules }.into();
+            }
+            Message::SetRoomMode(mode) => {
+                let room_id = match view.selected.clone() {
+                    Some(selected) => selected,
+                    None => return Command::none(),
+                };
+                let stale = view.push_rules.room_rule_kinds(&room_id);
+                view.push_rules.set_room_mode(&room_id, mode);
+                let client = view.client.clone();
+                return Command::perform(
+                    async move { push::set_room_mode(&client, &room_id, stale, mode).await },
+                    push_result,
+                );
+            }
+            Message::SetRulesEnabled(kind, ids, enabled) => {
+                for id in ids.iter() {
+                    if let Some(rule) = view
+                        .push_rules
+                        .rules_mut(kind)
+                        .iter_mut()
+                        .find(|r| r.rule_id == *id)
+                    {
+                        rule.enabled = enabled;
+                    }
+                }
+                let client = view.client.clone();
+                return Command::perform(
+                    async move {
+                        for id in ids.iter() {
+                            push::set_rule_enabled(&client, kind, id, enabled).await?;
+                        }
+                        Ok(())
+                    },
+                    push_result,
+                );
+            }
+            Message::SetKeywordInput(keyword) => {
+                if let Some(ref mut settings) = view.settings_view {
+                    settings.keyword = keyword;
+                }
+            }
+            Message::AddKeyword => {
+                let settings = match view.settings_view {
+                    Some(ref mut settings) => settings,
+                    None => return Command::none(),
+                };
+                let keyword = std::mem::take(&mut settings.keyword);
+                let keyword = keyword.trim();
+                let exists = view
+                    .push_rules
+                    .keywords()
+                    .iter()
+                    .any(|r| r.pattern.as_deref() == Some(keyword));
+                if keyword.is_empty()
+                    || exists
+                    || view.push_rules.get(RuleKind::Content, keyword).is_some()
+                {
+                    return Command::none();
+                }
+                let rule = PushRule::keyword(keyword);
+                view.push_rules.content.insert(0, rule.clone());
+                let client = view.client.clone();
+                return Command::perform(
+                    async move { push::set_rule(&client, RuleKind::Content, &rule).await },
+                    push_result,
+                );
+            }
+            Message::RemoveKeyword(rule_id) => {
+                view.push_rules
+                    .content
+                    .retain(|r| r.default || r.rule_id != rule_id);
+                let client = view.client.clone();
+                return Command::perform(
+                    async move { push::delete_rule(&client, RuleKind::Content, &rule_id).await },
+                    push_result,
+                );
+            }
             _ => (),
         };
         Command::none()
@@ -1076,14 +4290,49 @@ pub enum Message {
     /// Reset state for room
     ResetRoom(RoomId, RoomEntry),
     RoomName(RoomId, String),
+    /// Count a message towards the notifications of a room, with what the push rules need to
+    /// know about the room
+    CountNotification(RoomId, AnyRoomEvent, PushContext),
     /// Get backfill for given room
     BackFill(RoomId),
-    /// Received backfill
-    BackFilled(RoomId, MessageResponse),
+    /// Received backfill. For pages that fill a gap, the chunk after the gap.
+    BackFilled(RoomId, Option<usize>, MessageResponse),
+    /// Fetch the messages missing before a chunk of the timeline
+    FillGap(RoomId, usize),
+    /// Couldn't fetch the messages missing before a chunk
+    GapFailed(RoomId, usize, String),
     /// Fetched a thumbnail
     FetchedThumbnail(String, iced::image::Handle),
-    /// Fetch an image pointed to by an mxc url
-    FetchImage(String),
+    /// Fetch an image, decrypting it if needed
+    FetchImage(matrix::ImageSource),
+    /// Download the full resolution version of an image
+    FetchOriginal(matrix::Media),
+    /// An animated image was downloaded and decoded
+    FetchedAnimation(String, Animation),
+    /// Play or pause an animated image
+    ToggleAnimation(String),
+    /// Time to advance playing animations
+    AnimationTick(Instant),
+    /// A full resolution image finished downloading
+    FetchedOriginal(String, Result<iced::image::Handle, String>),
+    /// The window was resized to the given width and height
+    WindowResized(u32, u32),
+    /// Open the image in a message in the image viewer
+    OpenImage(EventId),
+    /// Close the image viewer
+    CloseImage,
+    /// Zoom the image viewer in or out by a factor
+    ZoomImage(f32),
+    /// Move the image in the viewer
+    PanImage(f32, f32),
+    /// Fit the image in the viewer to the window
+    ResetZoom,
+    /// Set the path to save the viewed image to
+    SetImageSavePath(String),
+    /// Save the viewed image
+    SaveImage,
+    /// The viewed image was saved to the given path, or saving it failed
+    ImageSaved(Result<String, String>),
     /// Fetched an image
     FetchedImage(String, iced::image::Handle),
     /// View messages from this room
@@ -1094,6 +4343,18 @@ pub enum Message {
     ClearError,
     /// Set how the room list is sorted
     SetSort(RoomSorting),
+    /// Set whether to send messages as markdown
+    SetMarkdown(bool),
+    /// Set whether animated images play automatically
+    SetAutoplay(bool),
+    /// Set whether members joining and leaving are hidden from the timeline
+    SetHideJoins(bool),
+    /// Set the media cache size limit input
+    SetCacheLimitInput(String),
+    /// Apply the media cache size limit
+    SaveCacheLimit,
+    /// Delete everything in the media cache
+    ClearCache,
     /// Set verification flow
     SetVerification(Option<matrix_sdk::Sas>),
     /// Accept verification flow
@@ -1118,6 +4379,116 @@ pub enum Message {
     SetMessage(String),
     /// Send the contents of the compose box to the selected room
     SendMessage,
+    /// Reply to the given message
+    Reply(EventId),
+    /// Stop replying to or editing a message
+    CancelReply,
+    /// Edit one of our own messages
+    Edit(EventId),
+    /// Show the previous versions of an edited message
+    ShowEdits(EventId),
+    /// Close the edit history
+    CloseEdits,
+    /// Ask for confirmation to delete a message
+    Redact(EventId),
+    /// Set the reason for deleting a message
+    SetRedactReason(String),
+    /// Delete the message we asked for confirmation for
+    ConfirmRedact,
+    /// Don't delete the message after all
+    CancelRedact,
+    /// Deleting a message failed, so it should be shown again
+    RedactFailed(RoomId, EventId, String),
+    /// Open the reaction picker for a message
+    React(EventId),
+    /// Set the custom reaction in the reaction picker
+    SetReactionInput(String),
+    /// React to the message the reaction picker is open for
+    SendReaction(String),
+    /// Close the reaction picker
+    CancelReaction,
+    /// Add or remove our reaction to a message
+    ToggleReaction(EventId, String),
+    /// Open the side panel for a thread
+    OpenThread(EventId),
+    /// Close the thread side panel
+    CloseThread,
+    /// Fetch replies in a thread, older ones if `true`, otherwise the newest ones
+    ThreadBackFill(EventId, bool),
+    /// Received replies in a thread
+    ThreadBackFilled(RoomId, EventId, bool, matrix::thread::Page),
+    /// Set the draft of a reply in the open thread
+    SetThreadDraft(String),
+    /// Send the draft to the open thread
+    SendThreadReply,
+    /// Show or hide the input for attaching a file
+    ToggleAttach,
+    /// Show or hide the sticker picker
+    ToggleStickers,
+    /// The sticker packs usable in a room
+    StickerPacks(RoomId, Vec<sticker::Pack>),
+    /// Send a sticker, by index of its pack and its index in the pack
+    SendSticker(usize, usize),
+    /// A message from the outgoing queue was sent, or failed to be, by room and transaction ID
+    PendingSent(RoomId, String, Result<EventId, String>),
+    /// Try sending a message that failed again
+    RetryPending(RoomId, String),
+    /// Remove a message from the outgoing queue without sending it
+    DeletePending(RoomId, String),
+    /// Complete the name of the command being typed
+    CompleteCommand,
+    /// Running a command failed
+    CommandFailed(String),
+    /// Close the output of `/help`
+    CloseCommandHelp,
+    /// Set the event to show the source of in the developer tools
+    SetDevToolsEvent(String),
+    /// Close the developer tools panel
+    CloseDevTools,
+    /// Show or hide the input for sharing a location
+    ToggleLocation,
+    /// Set the coordinates or `geo:` URI of the location to share
+    SetLocationInput(String),
+    /// Set the description of the location to share
+    SetLocationDescription(String),
+    /// Send the location to the selected room
+    ShareLocation,
+    /// Open a map in the browser
+    OpenMap(String),
+    /// Set the path of the file to attach
+    SetAttachPath(String),
+    /// Upload the attached file and send it to the selected room
+    Upload,
+    /// The file to upload has been read
+    UploadPrepared(matrix::upload::Prepared),
+    /// Time to show how much of the upload has been sent
+    UploadTick,
+    /// The file has been uploaded, and the message can be sent
+    UploadFinished(MessageEventContent),
+    /// Uploading a file failed
+    UploadFailed(String),
+    /// Stop uploading the file
+    CancelUpload,
+    /// The upload was cancelled
+    UploadCancelled,
+    /// The largest file the server accepts
+    UploadLimit(Option<u64>),
+    /// Ask where to save the attachment of a message
+    DownloadAttachment(EventId),
+    /// Set the path to save an attachment to
+    SetAttachmentPath(String),
+    /// Save the attachment to the chosen path
+    SaveAttachment,
+    /// Don't save the attachment after all
+    CancelSaveAttachment,
+    /// Save the attachment of a message to the download directory and open it
+    OpenAttachment(EventId),
+    /// An attachment was saved to the given path, or saving it failed
+    AttachmentSaved(EventId, Result<String, String>),
+    /// Fetch an event that's not in the timeline
+    FetchEvent(RoomId, EventId),
+    /// Fetched an event
+    FetchedEvent(AnyRoomEvent),
 
     // Settings messages
     /// Open settings menu
@@ -1136,6 +4507,144 @@ pub enum Message {
     SetKeyPassword(String),
     /// Import encryption keys
     ImportKeys,
+
+    // Notification messages
+    /// Fetch push rules from the server
+    FetchPushRules,
+    /// Received push rules
+    PushRules(Ruleset),
+    /// Changing push rules failed, the local copy should be refreshed
+    PushRulesFailed(String),
+    /// Set notification mode of the selected room
+    SetRoomMode(RoomMode),
+    /// Enable or disable the given push rules
+    SetRulesEnabled(RuleKind, &'static [&'static str], bool),
+    /// Set keyword input field
+    SetKeywordInput(String),
+    /// Add a notification keyword
+    AddKeyword,
+    /// Remove the notification keyword rule with the given id
+    RemoveKeyword(String),
+
+    // Presence messages
+    /// Show or hide the member list
+    ToggleMembers,
+    /// Loaded the member list of a room
+    MembersLoaded(RoomId, BTreeMap<UserId, String>),
+    /// Set presence selection in settings
+    SetPresenceInput(PresenceSetting),
+    /// Set status message input field
+    SetStatusInput(String),
+    /// Save presence and status message
+    SavePresence,
+    /// Presence was set successfully
+    PresenceSaved,
+    /// Setting presence failed
+    PresenceFailed(String),
+    /// Periodic check of whether the user is idle
+    IdleTick,
+
+    // Connection messages
+    /// Try to connect to the homeserver
+    Connect,
+    /// Connected to the homeserver, with the token to continue syncing from and the events
+    /// since the last sync
+    Connected(String, Vec<matrix::Event>),
+    /// Failed to connect to the homeserver
+    ConnectionFailed(String),
+    /// Write recent history to the cache
+    SaveHistory,
+}
+
+impl Message {
+    /// Whether the message is a result of the user interacting with the client, as opposed to
+    /// syncing or the completion of a background task. Messages that are also sent in the
+    /// background, like `BackFill`, don't count.
+    fn is_interaction(&self) -> bool {
+        matches!(
+            self,
+            Message::ToggleAnimation(_)
+                | Message::OpenImage(_)
+                | Message::CloseImage
+                | Message::ZoomImage(_)
+                | Message::PanImage(..)
+                | Message::ResetZoom
+                | Message::SetImageSavePath(_)
+                | Message::SaveImage
+                | Message::SelectRoom(_)
+                | Message::ClearError
+                | Message::SetSort(_)
+                | Message::SetMarkdown(_)
+                | Message::SetAutoplay(_)
+                | Message::SetHideJoins(_)
+                | Message::SetCacheLimitInput(_)
+                | Message::SaveCacheLimit
+                | Message::ClearCache
+                | Message::VerificationAccept
+                | Message::VerificationConfirm
+                | Message::VerificationCancel
+                | Message::VerificationClose
+                | Message::SetMessage(_)
+                | Message::SendMessage
+                | Message::Reply(_)
+                | Message::CancelReply
+                | Message::Edit(_)
+                | Message::ShowEdits(_)
+                | Message::CloseEdits
+                | Message::Redact(_)
+                | Message::SetRedactReason(_)
+                | Message::ConfirmRedact
+                | Message::CancelRedact
+                | Message::React(_)
+                | Message::SetReactionInput(_)
+                | Message::SendReaction(_)
+                | Message::CancelReaction
+                | Message::ToggleReaction(..)
+                | Message::OpenThread(_)
+                | Message::CloseThread
+                | Message::ThreadBackFill(_, true)
+                | Message::SetThreadDraft(_)
+                | Message::SendThreadReply
+                | Message::ToggleAttach
+                | Message::ToggleStickers
+                | Message::SendSticker(..)
+                | Message::RetryPending(..)
+                | Message::DeletePending(..)
+                | Message::CompleteCommand
+                | Message::CloseCommandHelp
+                | Message::SetDevToolsEvent(_)
+                | Message::CloseDevTools
+                | Message::ToggleLocation
+                | Message::SetLocationInput(_)
+                | Message::SetLocationDescription(_)
+                | Message::ShareLocation
+                | Message::OpenMap(_)
+                | Message::SetAttachPath(_)
+                | Message::Upload
+                | Message::CancelUpload
+                | Message::DownloadAttachment(_)
+                | Message::SetAttachmentPath(_)
+                | Message::SaveAttachment
+                | Message::CancelSaveAttachment
+                | Message::OpenAttachment(_)
+                | Message::OpenSettings
+                | Message::CloseSettings
+                | Message::SetDisplayNameInput(_)
+                | Message::SaveDisplayName
+                | Message::SetKeyPath(_)
+                | Message::SetKeyPassword(_)
+                | Message::ImportKeys
+                | Message::SetRoomMode(_)
+                | Message::SetRulesEnabled(..)
+                | Message::SetKeywordInput(_)
+                | Message::AddKeyword
+                | Message::RemoveKeyword(_)
+                | Message::ToggleMembers
+                | Message::SetPresenceInput(_)
+                | Message::SetStatusInput(_)
+                | Message::SavePresence
+        )
+    }
 }
 
 impl Application for Retrix {
@@ -1167,7 +4676,79 @@ impl Application for Retrix {
     fn subscription(&self) -> Subscription<Self::Message> {
         match self {
             Retrix::LoggedIn(view) => {
-                matrix::MatrixSync::subscription(view.client.clone()).map(Message::Sync)
+                let mut subscriptions = vec![
+                    iced::time::every(Duration::from_secs(30)).map(|_| Message::IdleTick),
+                    iced::time::every(Duration::from_secs(60)).map(|_| Message::SaveHistory),
+                    iced_native::subscription::events_with(|event, _| match event {
+                        iced_native::Event::Window(iced_native::window::Event::Resized {
+                            width,
+                            height,
+                        }) => Some(Message::WindowResized(width, height)),
+                        _ => None,
+                    }),
+                ];
+                // Tick quickly while animations are playing on screen, and slowly while they're
+                // playing somewhere else, to notice when they're scrolled into view
+                let now = Instant::now();
+                let playing = view.animations.values().filter(|a| !a.paused);
+                match playing.clone().any(|a| a.is_active(now)) {
+                    true => subscriptions.push(
+                        iced::time::every(animation::FRAME_INTERVAL).map(Message::AnimationTick),
+                    ),
+                    false if playing.count() > 0 => subscriptions.push(
+                        iced::time::every(animation::IDLE_INTERVAL).map(Message::AnimationTick),
+                    ),
+                    false => (),
+                }
+                // Tab completes command names
+                if view.draft.starts_with('/') {
+                    subscriptions.push(iced_native::subscription::events_with(
+                        |event, status| match (event, status) {
+                            (
+                                iced_native::Event::Keyboard(
+                                    iced_native::keyboard::Event::KeyPressed {
+                                        key_code: iced_native::keyboard::KeyCode::Tab,
+                                        ..
+                                    },
+                                ),
+                                iced_native::event::Status::Ignored,
+                            ) => Some(Message::CompleteCommand),
+                            _ => None,
+                        },
+                    ));
+                }
+                if view.image_viewer.is_some() {
+                    subscriptions.push(iced_native::subscription::events_with(
+                        |event, status| match (event, status) {
+                            (
+                                iced_native::Event::Keyboard(
+                                    iced_native::keyboard::Event::KeyPressed { key_code, .. },
+                                ),
+                                iced_native::event::Status::Ignored,
+                            ) => viewer::key_message(key_code),
+                            _ => None,
+                        },
+                    ));
+                }
+                match view.online {
+                    true => subscriptions.push(
+                        matrix::MatrixSync::subscription(view.client.clone()).map(Message::Sync),
+                    ),
+                    false => subscriptions
+                        .push(iced::time::every(RECONNECT_INTERVAL).map(|_| Message::Connect)),
+                }
+                // Redraw while uploading, to show how much has been sent
+                if let Some(Upload {
+                    size: Some(_),
+                    waiting: None,
+                    ..
+                }) = view.upload
+                {
+                    subscriptions.push(
+                        iced::time::every(UPLOAD_PROGRESS_INTERVAL).map(|_| Message::UploadTick),
+                    );
+                }
+                Subscription::batch(subscriptions)
             }
             _ => Subscription::none(),
         }
@@ -1234,40 +4815,18 @@ impl Application for Retrix {
                     *self = Retrix::Prompt(view);
                 }
                 Message::LoggedIn(client, session) => {
-                    *self = Retrix::LoggedIn(MainView::new(client.clone(), session));
-                    let mut commands: Vec<Command<Message>> = Vec::new();
-                    for room in client.joined_rooms().into_iter() {
-                        let room = std::sync::Arc::new(room);
-                        let r = room.clone();
-                        let command: Command<_> = async move {
-                            let entry = RoomEntry::from_sdk(&r).await;
-                            Message::ResetRoom(r.room_id().to_owned(), entry)
-                        }
-                        .into();
-                        commands.push(command);
-                        // Fetch room avatar thumbnail if available
-                        commands.push(
-                            async move {
-                                match room
-                                    .avatar(Some(THUMBNAIL_SIZE), Some(THUMBNAIL_SIZE))
-                                    .await
-                                {
-                                    Ok(Some(avatar)) => Message::FetchedThumbnail(
-                                        room.avatar_url().unwrap(),
-                                        iced::image::Handle::from_memory(avatar),
-                                    ),
-                                    Ok(None) => Message::Noop,
-                                    Err(e) => Message::ErrorMessage(e.to_string()),
-                                }
-                            }
-                            .into(),
-                        )
-                    }
+                    let mut view = MainView::new(client, session);
+                    // Show what we have in the store right away, and sync in the background
+                    let commands = vec![view.load_rooms(), view.connect()];
+                    *self = Retrix::LoggedIn(view);
                     return Command::batch(commands);
                 }
                 _ => (),
             },
-            Retrix::LoggedIn(view) => return view.update(message),
+            Retrix::LoggedIn(view) => {
+                let activity = view.register_activity(&message);
+                return Command::batch(vec![activity, view.update(message)]);
+            }
         };
         Command::none()
     }
@@ -1286,6 +4845,442 @@ impl Application for Retrix {
     }
 }
 
+/// Shows a file, audio or video attachment as a card with buttons to save and open it
+fn view_attachment<'a>(
+    msgtype: &MessageType,
+    attachment: &matrix::file::Attachment,
+    thumbnail: Option<(Option<&iced::image::Handle>, (u32, u32))>,
+    download: Option<&Download>,
+    (download_button, open_button): (&'a mut iced::button::State, &'a mut iced::button::State),
+    id: &EventId,
+) -> Element<'a, Message> {
+    let kind = match msgtype {
+        MessageType::Audio(_) => "Audio",
+        MessageType::Video(_) => "Video",
+        _ => "File",
+    };
+    let mut details = Vec::new();
+    if let Some(size) = attachment.size {
+        details.push(matrix::upload::format_size(size));
+    }
+    if let Some(ref mimetype) = attachment.mimetype {
+        details.push(mimetype.clone());
+    }
+    let mut info = Column::new()
+        .spacing(2)
+        .width(Length::Fill)
+        .push(Text::new(format!("{}: {}", kind, attachment.name)));
+    if !details.is_empty() {
+        info = info.push(
+            Text::new(details.join(", "))
+                .size(14)
+                .color([0.4, 0.4, 0.4]),
+        );
+    }
+    let busy = matches!(download, Some(Download::InProgress));
+    match download {
+        Some(Download::InProgress) => info = info.push(Text::new("Downloading...").size(14)),
+        Some(Download::Saved(path)) => {
+            info = info.push(Text::new(format!("Saved to {}", path)).size(14))
+        }
+        Some(Download::Failed(e)) => {
+            info = info.push(
+                Text::new(format!("Download failed: {}", e))
+                    .size(14)
+                    .color([0.8, 0.0, 0.0]),
+            )
+        }
+        None => (),
+    }
+    let mut download_button = Button::new(download_button, Text::new("Download").size(14));
+    let mut open_button = Button::new(open_button, Text::new("Open").size(14));
+    if !busy {
+        download_button = download_button.on_press(Message::DownloadAttachment(id.clone()));
+        open_button = open_button.on_press(Message::OpenAttachment(id.clone()));
+    }
+    let mut row = Row::new().spacing(10).align_items(Align::Center);
+    if let Some((handle, size)) = thumbnail {
+        row = row.push(view_image(handle, size, "Loading thumbnail..."));
+    }
+    let row = row.push(info).push(download_button).push(open_button);
+    Container::new(row)
+        .padding(5)
+        .width(Length::Fill)
+        .style(theme::Attachment)
+        .into()
+}
+
+/// Number of notifications in a room since it was last opened, in red if any of them mention us
+fn unread_badge(room: &RoomEntry) -> Text {
+    let color = match room.highlights {
+        0 => [0.4, 0.4, 0.4],
+        _ => [0.8, 0.0, 0.0],
+    };
+    let text = match room.notifications.max(room.highlights) {
+        0 => String::new(),
+        count => format!(" ({})", count),
+    };
+    Text::new(text).color(color)
+}
+
+/// Shows where messages are missing from the timeline, with a button to load them
+fn view_gap<'a>(
+    button: &'a mut iced::button::State,
+    room_id: &RoomId,
+    chunk: usize,
+    filling: bool,
+) -> Element<'a, Message> {
+    let content: Element<_> = if filling {
+        Text::new("Loading...").into()
+    } else {
+        Button::new(button, Text::new("Load missing messages"))
+            .on_press(Message::FillGap(room_id.clone(), chunk))
+            .into()
+    };
+    Container::new(content)
+        .width(Length::Fill)
+        .center_x()
+        .into()
+}
+
+/// What the estimated layout of a room's timeline depends on
+#[derive(Clone, Debug, PartialEq)]
+struct TimelineLayout {
+    /// The room whose timeline it is
+    room: RoomId,
+    /// Version of the room's message buffer
+    version: u64,
+    /// Number of threads in the room, which get a summary below their root
+    threads: usize,
+    /// Width of the timeline, which decides where text wraps
+    width: u32,
+    /// Size images are scaled down to
+    image_bounds: (u32, u32),
+    /// Whether joins and leaves are hidden
+    hide_joins: bool,
+    /// Number of animated images, which get a button to play them
+    animations: usize,
+}
+
+/// Estimate the height of each event in a room's timeline, including the spacing after each
+/// widget it's shown as. This mirrors how the timeline is laid out, without making widgets.
+fn estimate_heights(
+    room: &RoomEntry,
+    layout: &TimelineLayout,
+    animations: &BTreeMap<String, Animation>,
+) -> timeline::Heights {
+    let spacing = TIMELINE_SPACING as f32;
+    let row = |height: f32| height + spacing;
+    let line_length = (layout.width.saturating_sub(MESSAGE_CONTROLS_WIDTH) as f32 / CHAR_WIDTH)
+        .max(10.0) as usize;
+    let text = |body: &str| {
+        let lines: usize = body
+            .lines()
+            .map(|line| 1 + line.chars().count().saturating_sub(1) / line_length)
+            .sum();
+        lines.max(1) as f32 * TEXT_HEIGHT
+    };
+    let messages = &room.messages;
+    let mut previous_chunk = None;
+    let mut last_sender: Option<&UserId> = None;
+    // Whether the previous event was a membership change, so this one joins its line
+    let mut in_run = false;
+    let heights = messages
+        .messages
+        .window(0..messages.messages.len())
+        .map(|(position, event)| {
+            let mut height = 0.0;
+            if previous_chunk != Some(position.chunk) {
+                previous_chunk = Some(position.chunk);
+                let chunk = messages.chunks.get(position.chunk);
+                if chunk.map_or(false, |chunk| chunk.gap.is_some()) {
+                    height += row(BUTTON_HEIGHT);
+                    in_run = false;
+                    last_sender = None;
+                }
+            }
+            let state_notice = match event {
+                AnyRoomEvent::State(state) => {
+                    notice::describe(state, &|user: &UserId| user.to_string())
+                }
+                _ => None,
+            };
+            if let Some(notice) = state_notice.as_ref() {
+                if let Some((change, _)) = notice.membership {
+                    if !(layout.hide_joins && change.is_join_or_leave()) {
+                        if !in_run {
+                            height += row(SMALL_TEXT_HEIGHT);
+                        }
+                        in_run = true;
+                        last_sender = None;
+                    }
+                    return height;
+                }
+            }
+            in_run = false;
+            // Space and the sender's name above the first of their messages in a row
+            let header = row(4.0) + row(TEXT_HEIGHT);
+            match event {
+                AnyRoomEvent::Message(_) if messages.is_redacted(event.event_id()) => {
+                    height += row(TEXT_HEIGHT);
+                }
+                AnyRoomEvent::Message(AnyMessageEvent::RoomMessage(message)) => {
+                    if last_sender != Some(&message.sender) {
+                        height += header;
+                    }
+                    last_sender = Some(&message.sender);
+                    let parent_id = reply::in_reply_to(event);
+                    if parent_id.is_some() {
+                        height += row(QUOTE_HEIGHT);
+                    }
+                    let body = |body: &str| match parent_id {
+                        Some(_) => text(reply::strip_fallback(body)),
+                        None => text(body),
+                    };
+                    let latest = messages.latest_content(message);
+                    let content = match &latest.msgtype {
+                        MessageType::Audio(_) | MessageType::File(_) | MessageType::Video(_) => {
+                            match matrix::file::message_attachment(&latest.msgtype) {
+                                Some(attachment) => {
+                                    let thumbnail = attachment.thumbnail.as_ref().map_or(0, |t| {
+                                        fit_image(t.dimensions, ATTACHMENT_THUMBNAIL).1
+                                    });
+                                    let info = TEXT_HEIGHT + 2.0 + SMALL_TEXT_HEIGHT;
+                                    (thumbnail as f32).max(info).max(SMALL_BUTTON_HEIGHT) + 10.0
+                                }
+                                None => TEXT_HEIGHT,
+                            }
+                        }
+                        MessageType::Emote(emote) => body(&emote.body),
+                        MessageType::Image(_) => match matrix::message_image(&latest.msgtype) {
+                            Some(source) => {
+                                let (_, height) = fit_image(source.dimensions, layout.image_bounds);
+                                match animations.contains_key(source.media.url()) {
+                                    true => height as f32 + 2.0 + SMALL_BUTTON_HEIGHT,
+                                    false => height as f32,
+                                }
+                            }
+                            None => TEXT_HEIGHT,
+                        },
+                        MessageType::Location(_) => {
+                            (TEXT_HEIGHT + 2.0 + SMALL_TEXT_HEIGHT).max(SMALL_BUTTON_HEIGHT)
+                        }
+                        MessageType::Notice(notice) => body(&notice.body),
+                        MessageType::ServerNotice(notice) => text(&notice.body),
+                        MessageType::Text(text) => body(&text.body),
+                        _ => TEXT_HEIGHT,
+                    };
+                    // Next to the time and the buttons
+                    height += row(content.max(SMALL_BUTTON_HEIGHT));
+                    if !messages.reactions(&message.event_id).is_empty() {
+                        height += row(SMALL_BUTTON_HEIGHT);
+                    }
+                    if room.threads.contains_key(&message.event_id) {
+                        height += row(SMALL_BUTTON_HEIGHT);
+                    }
+                }
+                AnyRoomEvent::Message(AnyMessageEvent::Sticker(sticker)) => {
+                    if last_sender != Some(&sticker.sender) {
+                        height += header;
+                    }
+                    last_sender = Some(&sticker.sender);
+                    let source = matrix::sticker_image(&sticker.content);
+                    let (_, image) = match source.dimensions {
+                        Some(_) => fit_image(source.dimensions, layout.image_bounds),
+                        None => STICKER_SIZE,
+                    };
+                    height += row((image as f32).max(SMALL_BUTTON_HEIGHT));
+                }
+                AnyRoomEvent::Message(AnyMessageEvent::RoomEncrypted(_))
+                | AnyRoomEvent::RedactedMessage(_) => {
+                    height += row(TEXT_HEIGHT);
+                }
+                AnyRoomEvent::State(_) => {
+                    if state_notice.is_some() {
+                        height += row(SMALL_TEXT_HEIGHT);
+                        last_sender = None;
+                    }
+                }
+                _ => (),
+            }
+            height
+        });
+    timeline::Heights::new(heights)
+}
+
+/// Add empty space to a scrollable, in several pieces if it's taller than one widget can be
+fn push_space<'a>(mut scroll: Scrollable<'a, Message>, height: f32) -> Scrollable<'a, Message> {
+    let mut height = height.max(0.0).round() as u32;
+    while height > 0 {
+        let piece = height.min(u16::MAX as u32);
+        scroll = scroll.push(iced::Space::with_height(Length::Units(piece as u16)));
+        height -= piece;
+    }
+    scroll
+}
+
+/// Shows a location as a card with its coordinates and a button to open it on a map
+fn view_location<'a>(
+    geo_uri: &str,
+    body: &str,
+    map_button: &'a mut iced::button::State,
+) -> Element<'a, Message> {
+    // The body usually ends with the URI, which the coordinates already show
+    let description = body
+        .strip_suffix(&format!(" ({})", geo_uri))
+        .unwrap_or(body);
+    let mut info = Column::new()
+        .spacing(2)
+        .width(Length::Fill)
+        .push(Text::new(format!("Location: {}", description)));
+    let mut row = Row::new().spacing(10).align_items(Align::Center);
+    match Location::parse(geo_uri) {
+        Ok(location) => {
+            info = info.push(
+                Text::new(location.to_string())
+                    .size(14)
+                    .color([0.4, 0.4, 0.4]),
+            );
+            row = row.push(info).push(
+                Button::new(map_button, Text::new("Open map").size(14))
+                    .on_press(Message::OpenMap(location.map_url())),
+            );
+        }
+        Err(e) => {
+            info = info.push(
+                Text::new(format!("Invalid location: {}", e))
+                    .size(14)
+                    .color([0.8, 0.0, 0.0]),
+            );
+            row = row.push(info);
+        }
+    }
+    Container::new(row)
+        .padding(5)
+        .width(Length::Fill)
+        .style(theme::Attachment)
+        .into()
+}
+
+/// Scale image dimensions down to fit in `bounds`, keeping the aspect ratio. Images of unknown
+/// size get a 4:3 box.
+fn fit_image(dimensions: Option<(u32, u32)>, bounds: (u32, u32)) -> (u32, u32) {
+    let (width, height) = match dimensions {
+        Some((width, height)) if width > 0 && height > 0 => (width, height),
+        _ => (bounds.0, bounds.0 * 3 / 4),
+    };
+    let scale = f64::min(
+        1.0,
+        f64::min(
+            bounds.0 as f64 / width as f64,
+            bounds.1 as f64 / height as f64,
+        ),
+    );
+    let scaled = |n: u32| ((n as f64 * scale).round() as u32).max(1);
+    (scaled(width), scaled(height))
+}
+
+/// Shows an image at the given size, or a placeholder of that size with `alt` if it hasn't
+/// loaded yet
+fn view_image<'a>(
+    handle: Option<&iced::image::Handle>,
+    size: (u32, u32),
+    alt: &str,
+) -> Element<'a, Message> {
+    let (width, height) = (Length::Units(size.0 as u16), Length::Units(size.1 as u16));
+    match handle {
+        Some(handle) => Image::new(handle.clone())
+            .width(width)
+            .height(height)
+            .into(),
+        None => Container::new(Text::new(alt).size(14))
+            .width(width)
+            .height(height)
+            .center_x()
+            .center_y()
+            .style(theme::ImagePlaceholder)
+            .into(),
+    }
+}
+
+/// A short, single-line preview of an event that's being replied to
+fn reply_preview(event: &AnyRoomEvent) -> String {
+    let body = match event {
+        AnyRoomEvent::Message(AnyMessageEvent::RoomMessage(message)) => {
+            reply::plain_body(&message.content, &message.content)
+        }
+        AnyRoomEvent::Message(AnyMessageEvent::RoomEncrypted(_)) => "Encrypted message",
+        AnyRoomEvent::Message(AnyMessageEvent::Sticker(_)) => "Sticker",
+        AnyRoomEvent::RedactedMessage(_) => "Deleted message",
+        _ => "",
+    };
+    let line = body.lines().next().unwrap_or_default();
+    match line.char_indices().nth(80) {
+        Some((idx, _)) => format!("{}...", &line[..idx]),
+        None => line.to_owned(),
+    }
+}
+
+/// Text to show for a message we're sending
+fn pending_preview(content: &AnyMessageEventContent) -> String {
+    match content {
+        AnyMessageEventContent::RoomMessage(message) => {
+            reply::plain_body(message, message).to_owned()
+        }
+        AnyMessageEventContent::Sticker(sticker) => format!("Sticker: {}", sticker.body),
+        AnyMessageEventContent::Reaction(reaction) => {
+            format!("Reacting with {}", reaction.relation.emoji)
+        }
+        AnyMessageEventContent::Custom(custom) => custom
+            .data
+            .get("body")
+            .and_then(|body| body.as_str())
+            .map_or_else(|| String::from("Event"), String::from),
+        _ => String::from("Event"),
+    }
+}
+
+/// Display a message body, using the formatted body if it's in a format we understand
+fn view_body<'a>(body: &str, formatted: Option<&FormattedBody>) -> Element<'a, Message> {
+    match formatted {
+        Some(formatted) if matches!(formatted.format, MessageFormat::Html) => {
+            html::Html::parse(&formatted.body).view()
+        }
+        _ => Text::new(body).width(Length::Fill).into(),
+    }
+}
+
+/// Map the result of a command to a message, showing errors by the composer
+fn command_result(result: Result<(), matrix::Error>) -> Message {
+    match result {
+        Ok(()) => Message::Noop,
+        Err(e) => Message::CommandFailed(e.to_string()),
+    }
+}
+
+/// Map the result of a push rule change to a message
+fn push_result(result: Result<(), matrix::Error>) -> Message {
+    match result {
+        Ok(()) => Message::Noop,
+        Err(e) => Message::PushRulesFailed(e.to_string()),
+    }
+}
+
+/// Format a duration in its largest whole unit, e.g. "3 hours"
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    let (amount, unit) = match secs {
+        0..=59 => (secs, "second"),
+        60..=3599 => (secs / 60, "minute"),
+        3600..=86399 => (secs / 3600, "hour"),
+        _ => (secs / 86400, "day"),
+    };
+    match amount {
+        1 => format!("1 {}", unit),
+        _ => format!("{} {}s", amount, unit),
+    }
+}
+
 fn format_systime(time: std::time::SystemTime) -> String {
     let offset = time::UtcOffset::try_current_local_offset().unwrap_or(time::UtcOffset::UTC);
     let time = time::OffsetDateTime::from(time).to_offset(offset);
@@ -0,0 +1,230 @@
+//! Playback of animated GIF, WebP and PNG images.
+//!
+//! Animations are decoded into frames up front. A subscription ticks while any animation is
+//! playing, and each animation only advances if it was drawn recently, so animations scrolled
+//! out of view or in other rooms are paused.
+
+use std::{
+    cell::Cell,
+    hash::Hash,
+    io::Cursor,
+    time::{Duration, Instant},
+};
+
+use ::image::{
+    codecs::{gif::GifDecoder, png::PngDecoder, webp::WebPDecoder},
+    AnimationDecoder, ImageFormat,
+};
+use iced::{Element, Length};
+use iced_native::{image, layout, Hasher, Layout, Point, Rectangle, Size, Widget};
+
+use super::Message;
+
+/// How often playing animations are advanced while they're visible
+pub const FRAME_INTERVAL: Duration = Duration::from_millis(40);
+/// How often we check whether a playing animation has come into view
+pub const IDLE_INTERVAL: Duration = Duration::from_millis(250);
+/// Frames shorter than this get the same treatment as in browsers, which show them for 100ms
+const MIN_DELAY: Duration = Duration::from_millis(20);
+/// Most frames we keep of an animation
+const MAX_FRAMES: usize = 500;
+/// Most bytes of decoded frames we keep of an animation, so huge animations don't use all the
+/// memory. Animations are cut short when they go over this.
+const MAX_DECODED_SIZE: usize = 128 * 1024 * 1024;
+/// MIME types that may be animated. APNGs sent as `image/png` are shown still, since finding
+/// them would mean downloading the original of every PNG instead of a thumbnail.
+const ANIMATED_TYPES: &[&str] = &["image/gif", "image/webp", "image/apng"];
+
+/// Whether images of a MIME type may be animated, and need to be decoded as animations
+pub fn may_be_animated(mimetype: &str) -> bool {
+    ANIMATED_TYPES.contains(&mimetype)
+}
+
+/// A frame of an animation
+#[derive(Debug, Clone)]
+struct Frame {
+    handle: image::Handle,
+    /// How long the frame is shown
+    delay: Duration,
+}
+
+/// A decoded animation and how far it has played
+#[derive(Debug, Clone)]
+pub struct Animation {
+    frames: Vec<Frame>,
+    /// Index of the frame being shown
+    current: usize,
+    /// How long the current frame has been shown
+    elapsed: Duration,
+    /// When the animation was last advanced
+    last_tick: Option<Instant>,
+    /// When the animation was last drawn on screen
+    last_drawn: Cell<Option<Instant>>,
+    /// Whether playback is paused
+    pub paused: bool,
+}
+
+impl Animation {
+    /// Decode an animated image. Returns `None` for images that aren't animated or can't be
+    /// decoded, which should be shown as still images instead.
+    pub fn decode(data: &[u8], paused: bool) -> Option<Self> {
+        let frames = match ::image::guess_format(data).ok()? {
+            ImageFormat::Gif => GifDecoder::new(Cursor::new(data)).ok()?.into_frames(),
+            ImageFormat::Png => {
+                let decoder = PngDecoder::new(Cursor::new(data)).ok()?;
+                if !decoder.is_apng() {
+                    return None;
+                }
+                decoder.apng().into_frames()
+            }
+            ImageFormat::WebP => WebPDecoder::new(Cursor::new(data)).ok()?.into_frames(),
+            _ => return None,
+        };
+        let mut decoded = Vec::new();
+        let mut size = 0;
+        for frame in frames.take(MAX_FRAMES) {
+            let frame = frame.ok()?;
+            size += frame.buffer().len();
+            if size > MAX_DECODED_SIZE {
+                break;
+            }
+            decoded.push(frame);
+        }
+        let frames: Vec<Frame> = decoded
+            .into_iter()
+            .map(|frame| {
+                let delay = match Duration::from(frame.delay()) {
+                    delay if delay < MIN_DELAY => Duration::from_millis(100),
+                    delay => delay,
+                };
+                let buffer = frame.into_buffer();
+                let (width, height) = buffer.dimensions();
+                // iced wants BGRA
+                let mut pixels = buffer.into_raw();
+                for pixel in pixels.chunks_exact_mut(4) {
+                    pixel.swap(0, 2);
+                }
+                Frame {
+                    handle: image::Handle::from_pixels(width, height, pixels),
+                    delay,
+                }
+            })
+            .collect();
+        if frames.len() < 2 {
+            return None;
+        }
+        Some(Self {
+            frames,
+            current: 0,
+            elapsed: Duration::default(),
+            last_tick: None,
+            last_drawn: Cell::new(None),
+            paused,
+        })
+    }
+
+    /// The frame currently being shown
+    pub fn handle(&self) -> &image::Handle {
+        &self.frames[self.current].handle
+    }
+
+    /// Whether the animation has been on screen since the last tick
+    fn visible(&self, now: Instant) -> bool {
+        match self.last_drawn.get() {
+            Some(drawn) => now.saturating_duration_since(drawn) < IDLE_INTERVAL * 2,
+            None => false,
+        }
+    }
+
+    /// Whether the animation should be advanced quickly, because it's playing and on screen
+    pub fn is_active(&self, now: Instant) -> bool {
+        !self.paused && self.visible(now)
+    }
+
+    /// Move the animation forward to `now`. Paused and hidden animations stay where they are.
+    pub fn tick(&mut self, now: Instant) {
+        let last_tick = self.last_tick.replace(now);
+        if !self.is_active(now) {
+            return;
+        }
+        if let Some(last_tick) = last_tick {
+            self.elapsed += now.saturating_duration_since(last_tick);
+        }
+        while self.elapsed >= self.frames[self.current].delay {
+            self.elapsed -= self.frames[self.current].delay;
+            self.current = (self.current + 1) % self.frames.len();
+        }
+    }
+
+    /// Show the animation at a fixed size
+    pub fn view(&self, width: u32, height: u32) -> Element<Message> {
+        AnimatedImage {
+            handle: self.handle().clone(),
+            last_drawn: &self.last_drawn,
+            width: Length::Units(width as u16),
+            height: Length::Units(height as u16),
+        }
+        .into()
+    }
+}
+
+/// An image that records when it was last drawn inside the visible part of the window
+struct AnimatedImage<'a> {
+    handle: image::Handle,
+    last_drawn: &'a Cell<Option<Instant>>,
+    width: Length,
+    height: Length,
+}
+
+impl<'a, Message, Renderer> Widget<Message, Renderer> for AnimatedImage<'a>
+where
+    Renderer: image::Renderer,
+{
+    fn width(&self) -> Length {
+        self.width
+    }
+
+    fn height(&self) -> Length {
+        self.height
+    }
+
+    fn layout(&self, _renderer: &Renderer, limits: &layout::Limits) -> layout::Node {
+        let limits = limits.width(self.width).height(self.height);
+        layout::Node::new(limits.resolve(Size::ZERO))
+    }
+
+    fn draw(
+        &self,
+        renderer: &mut Renderer,
+        _defaults: &Renderer::Defaults,
+        layout: Layout<'_>,
+        _cursor_position: Point,
+        viewport: &Rectangle,
+    ) -> Renderer::Output {
+        let bounds = layout.bounds();
+        let visible = bounds.x < viewport.x + viewport.width
+            && viewport.x < bounds.x + bounds.width
+            && bounds.y < viewport.y + viewport.height
+            && viewport.y < bounds.y + bounds.height;
+        if visible {
+            self.last_drawn.set(Some(Instant::now()));
+        }
+        image::Renderer::draw(renderer, self.handle.clone(), layout)
+    }
+
+    fn hash_layout(&self, state: &mut Hasher) {
+        struct Marker;
+        std::any::TypeId::of::<Marker>().hash(state);
+        self.width.hash(state);
+        self.height.hash(state);
+    }
+}
+
+impl<'a, Message, Renderer> From<AnimatedImage<'a>> for iced_native::Element<'a, Message, Renderer>
+where
+    Renderer: 'a + image::Renderer,
+{
+    fn from(image: AnimatedImage<'a>) -> Self {
+        iced_native::Element::new(image)
+    }
+}
@@ -0,0 +1,869 @@
+//! Rendering of formatted message bodies.
+//!
+//! Only the subset of HTML the spec allows in `formatted_body` is interpreted. Unknown tags are
+//! ignored while keeping their contents, except for tags like `<script>` whose contents are
+//! never meant to be shown. Since iced can't switch font style in the middle of a text widget,
+//! inline formatting is approximated with colours and sizes.
+
+use iced::{Color, Column, Container, Element, Length, Row, Rule, Text};
+
+use super::{theme, Message};
+use crate::matrix::markdown;
+
+/// Default text size
+const TEXT_SIZE: u16 = 20;
+/// Approximate number of characters that fit on a line, used to wrap styled text
+const LINE_CHARS: usize = 100;
+
+/// Tags whose contents should never be displayed. `mx-reply` is the fallback for replies,
+/// which are displayed separately.
+const DROPPED_TAGS: &[&str] = &[
+    "script", "style", "head", "title", "iframe", "object", "mx-reply",
+];
+/// Tags that never have contents
+const VOID_TAGS: &[&str] = &["br", "hr", "img"];
+
+/// A parsed and sanitized formatted message body
+#[derive(Clone, Debug, Default)]
+pub struct Html {
+    blocks: Vec<Block>,
+}
+
+/// Block-level content
+#[derive(Clone, Debug)]
+enum Block {
+    Paragraph(Vec<Span>),
+    Heading(u8, Vec<Span>),
+    Quote(Vec<Block>),
+    Code(String),
+    List {
+        /// Number of the first item, if the list is ordered
+        start: Option<u64>,
+        items: Vec<Vec<Block>>,
+    },
+    Table(Vec<Vec<Vec<Span>>>),
+    Rule,
+}
+
+/// A run of text with the same style
+#[derive(Clone, Debug)]
+struct Span {
+    text: String,
+    style: Style,
+}
+
+/// Inline formatting
+#[derive(Clone, Debug, Default)]
+struct Style {
+    bold: bool,
+    italic: bool,
+    strike: bool,
+    code: bool,
+    /// Link target
+    link: Option<String>,
+    /// Whether the span is a mention of a user or room
+    pill: bool,
+    color: Option<Color>,
+}
+
+/// A node in the parsed document tree
+#[derive(Clone, Debug)]
+enum Node {
+    Text(String),
+    Element {
+        tag: String,
+        attrs: Vec<(String, String)>,
+        children: Vec<Node>,
+    },
+}
+
+impl Node {
+    fn attr(&self, name: &str) -> Option<&str> {
+        match self {
+            Node::Element { attrs, .. } => attrs
+                .iter()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.as_str()),
+            Node::Text(_) => None,
+        }
+    }
+
+    /// All text in the node and its children
+    fn text(&self) -> String {
+        match self {
+            Node::Text(text) => text.clone(),
+            Node::Element { children, .. } => children.iter().map(Node::text).collect(),
+        }
+    }
+}
+
+/// A lexical HTML token
+enum Token {
+    Open {
+        tag: String,
+        attrs: Vec<(String, String)>,
+        self_closing: bool,
+    },
+    Close(String),
+    Text(String),
+}
+
+impl Html {
+    /// Parse and sanitize a formatted body.
+    pub fn parse(html: &str) -> Self {
+        let nodes = build_tree(tokenize(html));
+        let mut builder = Builder::default();
+        builder.walk(&nodes, &Style::default(), false);
+        builder.flush();
+        Self {
+            blocks: builder.blocks,
+        }
+    }
+
+    /// Lay out the body as widgets
+    pub fn view<'a>(&self) -> Element<'a, Message> {
+        view_blocks(&self.blocks).width(Length::Fill).into()
+    }
+}
+
+/// Split HTML into tokens.
+fn tokenize(html: &str) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    let mut rest = html;
+    while !rest.is_empty() {
+        let lt = match rest.find('<') {
+            Some(lt) => lt,
+            None => {
+                tokens.push(Token::Text(decode_entities(rest)));
+                break;
+            }
+        };
+        if lt > 0 {
+            tokens.push(Token::Text(decode_entities(&rest[..lt])));
+        }
+        rest = &rest[lt..];
+        if let Some(comment) = rest.strip_prefix("<!--") {
+            rest = match comment.find("-->") {
+                Some(end) => &comment[end + 3..],
+                None => "",
+            };
+            continue;
+        }
+        let gt = match rest.find('>') {
+            Some(gt) => gt,
+            None => {
+                tokens.push(Token::Text(decode_entities(rest)));
+                break;
+            }
+        };
+        let inner = &rest[1..gt];
+        if let Some(name) = inner.strip_prefix('/') {
+            tokens.push(Token::Close(name.trim().to_ascii_lowercase()));
+        } else if inner.starts_with(|c: char| c.is_ascii_alphabetic()) {
+            tokens.push(parse_tag(inner));
+        } else {
+            // Not a tag, like in "a <3 b"
+            tokens.push(Token::Text(String::from("<")));
+            rest = &rest[1..];
+            continue;
+        }
+        rest = &rest[gt + 1..];
+    }
+    tokens
+}
+
+/// Parse the inside of an opening tag, e.g. `a href="url"`
+fn parse_tag(inner: &str) -> Token {
+    let self_closing = inner.ends_with('/');
+    let inner = inner.trim_end_matches('/');
+    let name_end = inner
+        .find(|c: char| !(c.is_ascii_alphanumeric() || c == '-'))
+        .unwrap_or_else(|| inner.len());
+    let tag = inner[..name_end].to_ascii_lowercase();
+    let mut attrs = Vec::new();
+    let mut rest = inner[name_end..].trim_start();
+    while !rest.is_empty() {
+        let key_end = rest
+            .find(|c: char| c.is_whitespace() || c == '=')
+            .unwrap_or_else(|| rest.len());
+        let key = rest[..key_end].to_ascii_lowercase();
+        rest = rest[key_end..].trim_start();
+        let value = match rest.strip_prefix('=') {
+            Some(value) => {
+                let value = value.trim_start();
+                let (value, remainder) = match value.chars().next() {
+                    Some(quote @ '"') | Some(quote @ '\'') => match value[1..].find(quote) {
+                        Some(end) => (&value[1..end + 1], &value[end + 2..]),
+                        None => (&value[1..], ""),
+                    },
+                    _ => {
+                        let end = value
+                            .find(char::is_whitespace)
+                            .unwrap_or_else(|| value.len());
+                        (&value[..end], &value[end..])
+                    }
+                };
+                rest = remainder.trim_start();
+                decode_entities(value)
+            }
+            None => String::new(),
+        };
+        if !key.is_empty() {
+            attrs.push((key, value));
+        }
+    }
+    Token::Open {
+        tag,
+        attrs,
+        self_closing,
+    }
+}
+
+/// Replace character references with the characters they represent
+fn decode_entities(text: &str) -> String {
+    let mut decoded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(amp) = rest.find('&') {
+        decoded.push_str(&rest[..amp]);
+        rest = &rest[amp..];
+        let entity = rest
+            .find(';')
+            .filter(|&end| end <= 10)
+            .map(|end| (&rest[1..end], end));
+        let c = entity.and_then(|(name, _)| match name {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            "nbsp" => Some('\u{a0}'),
+            _ => match name.strip_prefix('#') {
+                Some(hex) if hex.starts_with(|c| c == 'x' || c == 'X') => {
+                    u32::from_str_radix(&hex[1..], 16)
+                        .ok()
+                        .and_then(char::from_u32)
+                }
+                Some(dec) => dec.parse().ok().and_then(char::from_u32),
+                None => None,
+            },
+        });
+        match (c, entity) {
+            (Some(c), Some((_, end))) => {
+                decoded.push(c);
+                rest = &rest[end + 1..];
+            }
+            _ => {
+                decoded.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    decoded.push_str(rest);
+    decoded
+}
+
+/// Build a document tree from tokens, tolerating unbalanced tags.
+fn build_tree(tokens: Vec<Token>) -> Vec<Node> {
+    // Stack of open elements, with the root at the bottom
+    let mut stack: Vec<(String, Vec<(String, String)>, Vec<Node>)> =
+        vec![(String::new(), Vec::new(), Vec::new())];
+    // Name of a tag whose contents we're skipping
+    let mut dropping: Option<String> = None;
+    for token in tokens {
+        if let Some(ref dropped) = dropping {
+            if matches!(token, Token::Close(ref tag) if tag == dropped) {
+                dropping = None;
+            }
+            continue;
+        }
+        match token {
+            Token::Text(text) => stack.last_mut().unwrap().2.push(Node::Text(text)),
+            Token::Open { tag, .. } if DROPPED_TAGS.contains(&tag.as_str()) => {
+                dropping = Some(tag);
+            }
+            Token::Open {
+                tag,
+                attrs,
+                self_closing,
+            } => {
+                if self_closing || VOID_TAGS.contains(&tag.as_str()) {
+                    let node = Node::Element {
+                        tag,
+                        attrs,
+                        children: Vec::new(),
+                    };
+                    stack.last_mut().unwrap().2.push(node);
+                } else {
+                    stack.push((tag, attrs, Vec::new()));
+                }
+            }
+            Token::Close(tag) => {
+                // Close everything up to the matching tag, if it's open at all
+                if let Some(pos) = stack.iter().skip(1).rposition(|(open, ..)| *open == tag) {
+                    while stack.len() > pos + 1 {
+                        close_element(&mut stack);
+                    }
+                }
+            }
+        }
+    }
+    while stack.len() > 1 {
+        close_element(&mut stack);
+    }
+    stack.pop().unwrap().2
+}
+
+fn close_element(stack: &mut Vec<(String, Vec<(String, String)>, Vec<Node>)>) {
+    let (tag, attrs, children) = stack.pop().unwrap();
+    let node = Node::Element {
+        tag,
+        attrs,
+        children,
+    };
+    stack.last_mut().unwrap().2.push(node);
+}
+
+/// Converts the document tree to blocks of styled text.
+#[derive(Default)]
+struct Builder {
+    blocks: Vec<Block>,
+    /// Inline content of the paragraph being built
+    spans: Vec<Span>,
+}
+
+impl Builder {
+    /// Finish the current paragraph
+    fn flush(&mut self) {
+        let spans = std::mem::take(&mut self.spans);
+        if spans.iter().any(|s| !s.text.trim().is_empty()) {
+            self.blocks.push(Block::Paragraph(spans));
+        }
+    }
+
+    /// Blocks built from the given nodes
+    fn blocks(nodes: &[Node], style: &Style) -> Vec<Block> {
+        let mut builder = Builder::default();
+        builder.walk(nodes, style, false);
+        builder.flush();
+        builder.blocks
+    }
+
+    /// Inline content built from the given nodes, ignoring any block structure
+    fn spans(nodes: &[Node], style: &Style) -> Vec<Span> {
+        let mut builder = Builder::default();
+        builder.walk(nodes, style, true);
+        builder.spans
+    }
+
+    fn walk(&mut self, nodes: &[Node], style: &Style, inline: bool) {
+        for node in nodes {
+            let (tag, children) = match node {
+                Node::Text(text) => {
+                    self.spans.push(Span {
+                        text: collapse_whitespace(text),
+                        style: style.clone(),
+                    });
+                    continue;
+                }
+                Node::Element { tag, children, .. } => (tag.as_str(), children),
+            };
+            let mut inner = style.clone();
+            match tag {
+                // Inline formatting
+                "b" | "strong" => inner.bold = true,
+                "i" | "em" => inner.italic = true,
+                "del" | "strike" | "s" => inner.strike = true,
+                "code" => inner.code = true,
+                "a" => {
+                    let href = node
+                        .attr("href")
+                        .filter(|href| markdown::allowed_link(href));
+                    inner.pill = href.map_or(false, is_pill);
+                    inner.link = href.map(String::from);
+                }
+                "font" | "span" => {
+                    let color = node.attr("data-mx-color").or_else(|| node.attr("color"));
+                    if let Some(color) = color.and_then(parse_color) {
+                        inner.color = Some(color);
+                    }
+                }
+                "br" => {
+                    self.spans.push(Span {
+                        text: String::from("\n"),
+                        style: style.clone(),
+                    });
+                    continue;
+                }
+                "img" => {
+                    let alt = node
+                        .attr("alt")
+                        .or_else(|| node.attr("title"))
+                        .unwrap_or("image");
+                    self.spans.push(Span {
+                        text: format!("[{}]", alt),
+                        style: style.clone(),
+                    });
+                    continue;
+                }
+                _ if inline => (),
+                // Block structure
+                "p" | "div" | "details" | "caption" => {
+                    self.flush();
+                    self.walk(children, &inner, false);
+                    self.flush();
+                    continue;
+                }
+                "summary" => {
+                    self.flush();
+                    inner.bold = true;
+                    self.walk(children, &inner, false);
+                    self.flush();
+                    continue;
+                }
+                "h1" | "h2" | "h3" | "h4" | "h5" | "h6" => {
+                    self.flush();
+                    let level = tag[1..].parse().unwrap_or(1);
+                    self.blocks
+                        .push(Block::Heading(level, Builder::spans(children, &inner)));
+                    continue;
+                }
+                "blockquote" => {
+                    self.flush();
+                    self.blocks
+                        .push(Block::Quote(Builder::blocks(children, &inner)));
+                    continue;
+                }
+                "pre" => {
+                    self.flush();
+                    let code = node.text();
+                    self.blocks
+                        .push(Block::Code(code.trim_end_matches('\n').to_owned()));
+                    continue;
+                }
+                "ul" | "ol" => {
+                    self.flush();
+                    let start = match tag {
+                        "ol" => Some(node.attr("start").and_then(|s| s.parse().ok()).unwrap_or(1)),
+                        _ => None,
+                    };
+                    let items = children
+                        .iter()
+                        .filter_map(|child| match child {
+                            Node::Element { tag, children, .. } if tag == "li" => {
+                                Some(Builder::blocks(children, &inner))
+                            }
+                            _ => None,
+                        })
+                        .collect();
+                    self.blocks.push(Block::List { start, items });
+                    continue;
+                }
+                "hr" => {
+                    self.flush();
+                    self.blocks.push(Block::Rule);
+                    continue;
+                }
+                "table" => {
+                    self.flush();
+                    let mut rows = Vec::new();
+                    collect_rows(children, &inner, &mut rows);
+                    self.blocks.push(Block::Table(rows));
+                    continue;
+                }
+                "li" => {
+                    // List item outside of a list
+                    self.flush();
+                    self.walk(children, &inner, false);
+                    self.flush();
+                    continue;
+                }
+                // Unknown tags are transparent
+                _ => (),
+            }
+            self.walk(children, &inner, inline);
+        }
+    }
+}
+
+/// Find the rows of a table, looking through `thead`, `tbody` and `tfoot`.
+fn collect_rows(nodes: &[Node], style: &Style, rows: &mut Vec<Vec<Vec<Span>>>) {
+    for node in nodes {
+        if let Node::Element { tag, children, .. } = node {
+            match tag.as_str() {
+                "tr" => rows.push(
+                    children
+                        .iter()
+                        .filter_map(|cell| match cell {
+                            Node::Element { tag, children, .. } if tag == "th" => {
+                                let style = Style {
+                                    bold: true,
+                                    ..style.clone()
+                                };
+                                Some(Builder::spans(children, &style))
+                            }
+                            Node::Element { tag, children, .. } if tag == "td" => {
+                                Some(Builder::spans(children, style))
+                            }
+                            _ => None,
+                        })
+                        .collect(),
+                ),
+                "thead" | "tbody" | "tfoot" => collect_rows(children, style, rows),
+                _ => (),
+            }
+        }
+    }
+}
+
+/// Whether a link points to a user or room
+fn is_pill(href: &str) -> bool {
+    href.strip_prefix("https://matrix.to/#/")
+        .map_or(false, |id| {
+            id.starts_with(|c| c == '@' || c == '#' || c == '!')
+        })
+}
+
+/// Parse a `#rrggbb` colour
+fn parse_color(color: &str) -> Option<Color> {
+    let hex = color.strip_prefix('#')?;
+    if hex.len() != 6 {
+        return None;
+    }
+    let value = u32::from_str_radix(hex, 16).ok()?;
+    Some(Color::from_rgb8(
+        (value >> 16) as u8,
+        (value >> 8) as u8,
+        value as u8,
+    ))
+}
+
+/// Collapse runs of whitespace to a single space, the way HTML is displayed
+fn collapse_whitespace(text: &str) -> String {
+    let mut collapsed = String::with_capacity(text.len());
+    let mut space = false;
+    for c in text.chars() {
+        if c.is_whitespace() && c != '\u{a0}' {
+            if !space {
+                collapsed.push(' ');
+            }
+            space = true;
+        } else {
+            collapsed.push(c);
+            space = false;
+        }
+    }
+    collapsed
+}
+
+fn view_blocks<'a>(blocks: &[Block]) -> Column<'a, Message> {
+    let mut column = Column::new().spacing(4);
+    for block in blocks {
+        let element: Element<_> = match block {
+            Block::Paragraph(spans) => view_spans(spans, TEXT_SIZE),
+            Block::Heading(level, spans) => {
+                let size = TEXT_SIZE + 2 * (7 - u16::from((*level).clamp(1, 6)));
+                view_spans(spans, size)
+            }
+            Block::Quote(blocks) => Container::new(view_blocks(blocks))
+                .padding(4)
+                .width(Length::Fill)
+                .style(theme::Quote)
+                .into(),
+            Block::Code(code) => Container::new(
+                Text::new(code.as_str())
+                    .font(theme::MONOSPACE)
+                    .size(TEXT_SIZE - 2),
+            )
+            .padding(4)
+            .width(Length::Fill)
+            .style(theme::CodeBlock)
+            .into(),
+            Block::List { start, items } => {
+                let mut list = Column::new().spacing(2);
+                for (idx, item) in items.iter().enumerate() {
+                    let marker = match start {
+                        Some(start) => format!("{}.", start + idx as u64),
+                        None => String::from("\u{2022}"),
+                    };
+                    list = list.push(
+                        Row::new()
+                            .spacing(5)
+                            .push(Text::new(marker))
+                            .push(view_blocks(item).width(Length::Fill)),
+                    );
+                }
+                list.into()
+            }
+            Block::Table(rows) => {
+                let mut table = Column::new().spacing(2);
+                for row in rows {
+                    let mut cells = Row::new().spacing(10);
+                    for cell in row {
+                        cells = cells
+                            .push(Container::new(view_spans(cell, TEXT_SIZE)).width(Length::Fill));
+                    }
+                    table = table.push(cells);
+                }
+                table.into()
+            }
+            Block::Rule => Rule::horizontal(2).into(),
+        };
+        column = column.push(element);
+    }
+    column
+}
+
+/// Lay out styled text, wrapping it into lines since a row of text widgets doesn't wrap.
+fn view_spans<'a>(spans: &[Span], size: u16) -> Element<'a, Message> {
+    // Plain text can be left to iced to wrap
+    if let [span] = spans {
+        if span.style.is_plain() {
+            return Text::new(span.text.trim())
+                .size(size)
+                .width(Length::Fill)
+                .into();
+        }
+    }
+    let mut lines = Column::new();
+    let mut line = Row::new();
+    let mut line_len = 0;
+    // Split spans into words, keeping the whitespace after each word
+    let words = spans.iter().flat_map(|span| {
+        span.text
+            .split_inclusive(|c| c == ' ' || c == '\n')
+            .map(move |word| (word, &span.style))
+    });
+    for (word, style) in words {
+        let len = word.chars().count();
+        if line_len > 0 && line_len + len > LINE_CHARS {
+            lines = lines.push(std::mem::replace(&mut line, Row::new()));
+            line_len = 0;
+        }
+        let text = word.trim_end_matches('\n');
+        if !(line_len == 0 && text.trim().is_empty()) {
+            line = line.push(style.view(text, size));
+            line_len += len;
+        }
+        if word.ends_with('\n') {
+            lines = lines.push(std::mem::replace(&mut line, Row::new()));
+            line_len = 0;
+        }
+    }
+    lines.push(line).into()
+}
+
+impl Style {
+    fn is_plain(&self) -> bool {
+        !(self.bold || self.italic || self.strike || self.code || self.pill)
+            && self.link.is_none()
+            && self.color.is_none()
+    }
+
+    /// Display a piece of text in this style
+    fn view<'a>(&self, text: &str, size: u16) -> Element<'a, Message> {
+        let mut widget = Text::new(text).size(if self.bold { size + 1 } else { size });
+        let color = if self.link.is_some() {
+            Some(Color::from_rgb(0.0, 0.3, 0.8))
+        } else if self.strike {
+            Some(Color::from_rgb(0.6, 0.6, 0.6))
+        } else if self.italic {
+            Some(Color::from_rgb(0.35, 0.35, 0.35))
+        } else {
+            self.color
+        };
+        if let Some(color) = color {
+            widget = widget.color(color);
+        }
+        if self.pill {
+            Container::new(widget).padding(1).style(theme::Pill).into()
+        } else if self.code {
+            Container::new(widget.font(theme::MONOSPACE).size(size - 2))
+                .style(theme::CodeBlock)
+                .into()
+        } else {
+            widget.into()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The text of each block, with nested blocks on separate lines
+    fn texts(html: &str) -> Vec<String> {
+        Html::parse(html).blocks.iter().map(block_text).collect()
+    }
+
+    fn block_text(block: &Block) -> String {
+        match block {
+            Block::Paragraph(spans) | Block::Heading(_, spans) => spans_text(spans),
+            Block::Quote(blocks) => lines(blocks.iter()),
+            Block::Code(code) => code.clone(),
+            Block::List { items, .. } => lines(items.iter().flatten()),
+            Block::Table(rows) => rows
+                .iter()
+                .map(|row| row.iter().map(|cell| spans_text(cell)).collect::<Vec<_>>())
+                .map(|row| row.join("|"))
+                .collect::<Vec<_>>()
+                .join("\n"),
+            Block::Rule => String::from("---"),
+        }
+    }
+
+    fn lines<'a>(blocks: impl Iterator<Item = &'a Block>) -> String {
+        blocks.map(block_text).collect::<Vec<_>>().join("\n")
+    }
+
+    fn spans_text(spans: &[Span]) -> String {
+        spans
+            .iter()
+            .map(|s| s.text.as_str())
+            .collect::<String>()
+            .trim()
+            .to_owned()
+    }
+
+    /// The spans of a body that's a single paragraph
+    fn spans(html: &str) -> Vec<Span> {
+        match Html::parse(html).blocks.as_slice() {
+            [Block::Paragraph(spans)] => spans.clone(),
+            blocks => panic!("expected a single paragraph, got {:?}", blocks),
+        }
+    }
+
+    #[test]
+    fn disallowed_tags() {
+        assert_eq!(
+            texts("<p>a</p><script>alert(1)</script><style>p { color: red }</style><p>b</p>"),
+            ["a", "b"]
+        );
+        assert_eq!(texts("<iframe src=\"x\">frame</iframe>ok"), ["ok"]);
+        assert_eq!(texts("<object><b>inside</b></object>after"), ["after"]);
+        // Unknown tags are ignored, but their contents kept
+        assert_eq!(
+            texts("<marquee>hi <blink>there</blink></marquee>"),
+            ["hi there"]
+        );
+        assert_eq!(texts("<form><input value=\"x\">text</form>"), ["text"]);
+    }
+
+    #[test]
+    fn disallowed_attributes() {
+        let link = &spans("<a href=\"javascript:alert(1)\" onclick=\"steal()\">click</a>")[0];
+        assert_eq!(link.text, "click");
+        assert_eq!(link.style.link, None);
+        assert!(link.style.is_plain());
+        let link = &spans("<a href=\"/relative\">here</a>")[0];
+        assert_eq!(link.style.link, None);
+        let link = &spans("<a href=\"HTTPS://example.org\">site</a>")[0];
+        assert_eq!(link.style.link.as_deref(), Some("HTTPS://example.org"));
+        let pill = &spans("<a href=\"https://matrix.to/#/@bob:example.org\">Bob</a>")[0];
+        assert!(pill.style.pill);
+        // Colours only come from the attributes meant for them, not from `style`
+        let span = &spans("<span style=\"color: red\">red</span>")[0];
+        assert!(span.style.is_plain());
+        let span = &spans("<font color=\"red\">named</font>")[0];
+        assert_eq!(span.style.color, None);
+        let span = &spans("<span data-mx-color=\"#ff0000\" color=\"#00ff00\">hex</span>")[0];
+        assert_eq!(span.style.color, Some(Color::from_rgb8(255, 0, 0)));
+    }
+
+    #[test]
+    fn unclosed_tags() {
+        let spans = spans("<b>bold <i>both");
+        assert_eq!(spans.len(), 2);
+        assert!(spans[0].style.bold && !spans[0].style.italic);
+        assert!(spans[1].style.bold && spans[1].style.italic);
+        assert_eq!(texts("<p>one<p>two"), ["one", "two"]);
+        assert_eq!(texts("<ul><li>a<li>b</ul>"), ["a\nb"]);
+        assert_eq!(texts("<blockquote>quoted"), ["quoted"]);
+        assert_eq!(texts("text <b"), ["text <b"]);
+    }
+
+    #[test]
+    fn misnested_tags() {
+        // Closing `b` also closes the `i` opened inside it
+        let spans = spans("<b>a<i>b</b>c</i>");
+        let styles: Vec<_> = spans
+            .iter()
+            .map(|s| (s.text.as_str(), s.style.bold, s.style.italic))
+            .collect();
+        assert_eq!(
+            styles,
+            [("a", true, false), ("b", true, true), ("c", false, false)]
+        );
+        // Stray closing tags are ignored
+        assert_eq!(texts("</div>text</p>"), ["text"]);
+        assert_eq!(texts("<em>a</strong>b</em>"), ["ab"]);
+    }
+
+    #[test]
+    fn entities() {
+        assert_eq!(
+            texts("&lt;b&gt; &amp;amp; &quot;&apos; &#65;&#x42;&#X43;"),
+            ["<b> &amp; \"' ABC"]
+        );
+        assert_eq!(texts("a&nbsp;b"), ["a\u{a0}b"]);
+        // Things that aren't entities are left alone
+        assert_eq!(
+            texts("fish & chips &unknown; &#xZZ; &#1114112;"),
+            ["fish & chips &unknown; &#xZZ; &#1114112;"]
+        );
+        assert_eq!(texts("&amp"), ["&amp"]);
+        // Attribute values are decoded too
+        let link = &spans("<a href=\"https://example.org/?a=1&amp;b=2\">link</a>")[0];
+        assert_eq!(
+            link.style.link.as_deref(),
+            Some("https://example.org/?a=1&b=2")
+        );
+    }
+
+    #[test]
+    fn reply_fallback_is_removed() {
+        let html =
+            "<mx-reply><blockquote><a href=\"https://matrix.to/#/!room:example.org/$event\">\
+                    In reply to</a> <a href=\"https://matrix.to/#/@bob:example.org\">@bob</a>\
+                    <br />original</blockquote></mx-reply>the <b>answer</b>";
+        assert_eq!(texts(html), ["the answer"]);
+    }
+
+    #[test]
+    fn comments_and_text_that_looks_like_tags() {
+        assert_eq!(texts("a<!-- hidden <b>tag</b> -->b"), ["ab"]);
+        assert_eq!(texts("a <!-- never closed"), ["a"]);
+        assert_eq!(texts("I <3 matrix"), ["I <3 matrix"]);
+    }
+
+    #[test]
+    fn blocks() {
+        let html = "<h2>Title</h2><pre><code>let a = 1;\n  b();\n</code></pre><hr>\
+                    <ol start=\"3\"><li>three</li><li>four</li></ol>\
+                    <table><thead><tr><th>k</th><th>v</th></tr></thead>\
+                    <tbody><tr><td>a</td><td>1</td></tr></tbody></table>";
+        assert_eq!(
+            texts(html),
+            [
+                "Title",
+                "let a = 1;\n  b();",
+                "---",
+                "three\nfour",
+                "k|v\na|1"
+            ]
+        );
+        let blocks = Html::parse(html).blocks;
+        assert!(matches!(blocks[0], Block::Heading(2, _)));
+        assert!(matches!(blocks[3], Block::List { start: Some(3), .. }));
+        let code = &spans("inline <code>x()</code>")[1];
+        assert!(code.style.code);
+        assert!(matches!(
+            Html::parse("<ol start=\"x\"><li>a</li></ol>").blocks[0],
+            Block::List { start: Some(1), .. }
+        ));
+    }
+
+    #[test]
+    fn whitespace_is_collapsed() {
+        assert_eq!(texts("a \n\t  b<br>c"), ["a b\nc"]);
+    }
+}
@@ -0,0 +1,262 @@
+//! Timeline notices describing state events, like members joining or the topic changing.
+//!
+//! Consecutive membership changes are collapsed into one line, so a room where lots of people
+//! come and go doesn't bury its messages.
+
+use std::{convert::TryFrom, time::SystemTime};
+
+use iced::{Element, Length, Row, Scrollable, Text};
+use matrix_sdk::{
+    events::{room::member::MembershipState, AnyStateEvent},
+    identifiers::UserId,
+};
+
+use super::{format_systime, Message};
+
+/// How many names a collapsed run lists before counting the rest
+const LISTED_NAMES: usize = 2;
+/// Colour of notices
+const NOTICE_COLOR: [f32; 3] = [0.4, 0.4, 0.4];
+
+/// What a membership event changed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Change {
+    Joined,
+    Left,
+    Invited,
+    Knocked,
+    Kicked,
+    Banned,
+    Unbanned,
+    /// Display name or avatar changed
+    Profile,
+}
+
+impl Change {
+    /// Whether the change is a join or leave, which can be hidden
+    pub fn is_join_or_leave(self) -> bool {
+        matches!(self, Change::Joined | Change::Left)
+    }
+
+    /// How a run of changes describes users making this change
+    fn verb(self, plural: bool) -> &'static str {
+        match (self, plural) {
+            (Change::Joined, _) => "joined",
+            (Change::Left, _) => "left",
+            (Change::Invited, false) => "was invited",
+            (Change::Invited, true) => "were invited",
+            (Change::Knocked, _) => "asked to join",
+            (Change::Kicked, false) => "was kicked",
+            (Change::Kicked, true) => "were kicked",
+            (Change::Banned, false) => "was banned",
+            (Change::Banned, true) => "were banned",
+            (Change::Unbanned, false) => "was unbanned",
+            (Change::Unbanned, true) => "were unbanned",
+            (Change::Profile, _) => "changed their profile",
+        }
+    }
+}
+
+/// A state event described for the timeline
+#[derive(Clone, Debug)]
+pub struct Notice {
+    pub text: String,
+    /// For membership events, what changed and the name of the member
+    pub membership: Option<(Change, String)>,
+}
+
+impl Notice {
+    fn new(text: String) -> Self {
+        Self {
+            text,
+            membership: None,
+        }
+    }
+}
+
+/// Describe a state event. Returns `None` for events that aren't worth showing.
+pub fn describe(event: &AnyStateEvent, name: &dyn Fn(&UserId) -> String) -> Option<Notice> {
+    let sender = name(event.sender());
+    Some(match event {
+        AnyStateEvent::RoomMember(member) => {
+            let target = UserId::try_from(member.state_key.as_str()).ok()?;
+            let prev = member.prev_content.as_ref();
+            let target_name = member
+                .content
+                .displayname
+                .clone()
+                .or_else(|| prev.and_then(|p| p.displayname.clone()))
+                .unwrap_or_else(|| name(&target));
+            let own = member.sender == target;
+            let (change, text) = match (prev.map(|p| &p.membership), &member.content.membership) {
+                (Some(MembershipState::Join), MembershipState::Join) => {
+                    let prev = prev.unwrap();
+                    let text = match (&prev.displayname, &member.content.displayname) {
+                        (Some(old), Some(new)) if old != new => {
+                            format!("{} changed their name to {}", old, new)
+                        }
+                        (None, Some(new)) => format!("{} set their name to {}", target, new),
+                        (Some(old), None) => format!("{} removed their name", old),
+                        _ if prev.avatar_url != member.content.avatar_url => {
+                            format!("{} changed their avatar", target_name)
+                        }
+                        _ => return None,
+                    };
+                    (Change::Profile, text)
+                }
+                (_, MembershipState::Join) => (Change::Joined, format!("{} joined", target_name)),
+                (Some(MembershipState::Invite), MembershipState::Leave) if own => (
+                    Change::Left,
+                    format!("{} rejected the invitation", target_name),
+                ),
+                (Some(MembershipState::Invite), MembershipState::Leave) => (
+                    Change::Kicked,
+                    format!("{} withdrew the invitation of {}", sender, target_name),
+                ),
+                (Some(MembershipState::Ban), MembershipState::Leave) => (
+                    Change::Unbanned,
+                    format!("{} unbanned {}", sender, target_name),
+                ),
+                (_, MembershipState::Leave) if own => {
+                    (Change::Left, format!("{} left", target_name))
+                }
+                (_, MembershipState::Leave) => {
+                    (Change::Kicked, format!("{} kicked {}", sender, target_name))
+                }
+                (_, MembershipState::Ban) => {
+                    (Change::Banned, format!("{} banned {}", sender, target_name))
+                }
+                (_, MembershipState::Invite) => (
+                    Change::Invited,
+                    format!("{} invited {}", sender, target_name),
+                ),
+                (_, MembershipState::Knock) => {
+                    (Change::Knocked, format!("{} asked to join", target_name))
+                }
+                _ => return None,
+            };
+            Notice {
+                text,
+                membership: Some((change, target_name)),
+            }
+        }
+        AnyStateEvent::RoomTopic(topic) => match topic.content.topic.as_str() {
+            "" => Notice::new(format!("{} removed the topic", sender)),
+            new => Notice::new(format!("{} changed the topic to \"{}\"", sender, new)),
+        },
+        AnyStateEvent::RoomName(room_name) => match room_name.content.name() {
+            Some(new) => Notice::new(format!("{} renamed the room to {}", sender, new)),
+            None => Notice::new(format!("{} removed the room name", sender)),
+        },
+        AnyStateEvent::RoomAvatar(_) => Notice::new(format!("{} changed the room avatar", sender)),
+        AnyStateEvent::RoomEncryption(_) => {
+            Notice::new(format!("{} enabled end-to-end encryption", sender))
+        }
+        AnyStateEvent::RoomCreate(_) => Notice::new(format!("{} created the room", sender)),
+        AnyStateEvent::RoomPowerLevels(levels) => {
+            let prev = match levels.prev_content {
+                Some(ref prev) => prev,
+                None => return None,
+            };
+            let new = &levels.content;
+            // Users whose level changed, including those who went back to the default
+            let users = new.users.keys().chain(prev.users.keys());
+            let mut changes: Vec<String> = Vec::new();
+            let mut seen: Vec<&UserId> = Vec::new();
+            for user in users {
+                if seen.contains(&user) {
+                    continue;
+                }
+                seen.push(user);
+                let old_level = prev.users.get(user).unwrap_or(&prev.users_default);
+                let new_level = new.users.get(user).unwrap_or(&new.users_default);
+                if old_level != new_level {
+                    changes.push(format!(
+                        "{} from {} to {}",
+                        name(user),
+                        old_level,
+                        new_level
+                    ));
+                }
+            }
+            match changes.is_empty() {
+                true => Notice::new(format!("{} changed the room's permissions", sender)),
+                false => Notice::new(format!(
+                    "{} changed the power level of {}",
+                    sender,
+                    changes.join(", ")
+                )),
+            }
+        }
+        _ => return None,
+    })
+}
+
+/// Describe a run of membership changes in one line, like "Alice, Bob and 3 others joined;
+/// Carol left". Each kind of change is listed in the order it first happened.
+pub fn summarize(changes: &[(Change, String)]) -> String {
+    let mut groups: Vec<(Change, Vec<&str>)> = Vec::new();
+    for (change, name) in changes {
+        match groups.iter_mut().find(|(c, _)| c == change) {
+            Some((_, names)) => {
+                if !names.contains(&name.as_str()) {
+                    names.push(name);
+                }
+            }
+            None => groups.push((*change, vec![name])),
+        }
+    }
+    groups
+        .into_iter()
+        .map(|(change, names)| {
+            let listed = match names.len() {
+                1 => names[0].to_owned(),
+                n if n <= LISTED_NAMES + 1 => {
+                    format!("{} and {}", names[..n - 1].join(", "), names[n - 1])
+                }
+                n => format!(
+                    "{} and {} others",
+                    names[..LISTED_NAMES].join(", "),
+                    n - LISTED_NAMES
+                ),
+            };
+            format!("{} {}", listed, change.verb(names.len() > 1))
+        })
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
+/// Show a notice in the timeline
+pub fn view<'a>(text: String, time: Option<String>) -> Element<'a, Message> {
+    let mut row = Row::new().spacing(5).push(
+        Text::new(text)
+            .size(14)
+            .color(NOTICE_COLOR)
+            .width(Length::Fill),
+    );
+    if let Some(time) = time {
+        row = row.push(Text::new(time).size(14).color(NOTICE_COLOR));
+    }
+    row.into()
+}
+
+/// Show a run of membership changes, collapsed into one line if there's more than one change,
+/// and clear it
+pub fn push_run<'a>(
+    scroll: Scrollable<'a, Message>,
+    run: &mut Vec<(Notice, SystemTime)>,
+) -> Scrollable<'a, Message> {
+    let notice = match run.as_slice() {
+        [] => return scroll,
+        [(notice, time)] => view(notice.text.clone(), Some(format_systime(*time))),
+        [.., (_, last)] => {
+            let changes: Vec<(Change, String)> = run
+                .iter()
+                .filter_map(|(notice, _)| notice.membership.clone())
+                .collect();
+            view(summarize(&changes), Some(format_systime(*last)))
+        }
+    };
+    run.clear();
+    scroll.push(notice)
+}
@@ -1,8 +1,47 @@
 //! Settings view.
 
-use iced::{Button, Column, Container, Element, Length, Radio, Row, Text, TextInput};
+use iced::{
+    Button, Checkbox, Column, Container, Element, Length, Radio, Row, Scrollable, Text, TextInput,
+};
 
-use super::{Message, RoomSorting};
+use super::{Message, PresenceSetting, RoomSorting};
+use crate::matrix::{
+    push::{RuleKind, Ruleset},
+    upload::format_size,
+};
+
+/// Server-default push rules that can be toggled, with a label and the rules they cover.
+const DEFAULT_RULES: &[(&str, RuleKind, &[&str])] = &[
+    (
+        "Messages in direct chats",
+        RuleKind::Underride,
+        &[
+            ".m.rule.room_one_to_one",
+            ".m.rule.encrypted_room_one_to_one",
+        ],
+    ),
+    (
+        "Messages in group chats",
+        RuleKind::Underride,
+        &[".m.rule.message", ".m.rule.encrypted"],
+    ),
+    (
+        "Messages containing my display name",
+        RuleKind::Override,
+        &[".m.rule.contains_display_name"],
+    ),
+    (
+        "Messages containing my user name",
+        RuleKind::Content,
+        &[".m.rule.contains_user_name"],
+    ),
+    ("@room mentions", RuleKind::Override, &[".m.rule.roomnotif"]),
+    (
+        "Invites to rooms",
+        RuleKind::Override,
+        &[".m.rule.invite_for_me"],
+    ),
+];
 
 /// Settings menu
 #[derive(Clone, Default, Debug)]
@@ -17,6 +56,18 @@ pub struct SettingsView {
     /// Button to set display name
     pub display_name_button: iced::button::State,
 
+    /// Presence to set
+    pub presence: PresenceSetting,
+    /// Status message to set
+    pub status: String,
+    /// Are we saving the presence?
+    pub saving_presence: bool,
+
+    /// Status message text input
+    pub status_input: iced::text_input::State,
+    /// Button to set presence and status message
+    pub presence_button: iced::button::State,
+
     /// Path to import encryption keys from
     pub key_path: String,
     /// Password to decrypt the keys with
@@ -28,6 +79,27 @@ pub struct SettingsView {
     pub key_password_input: iced::text_input::State,
     /// Button to import keys
     pub key_import_button: iced::button::State,
+
+    /// Notification keyword to add
+    pub keyword: String,
+    /// Keyword text input
+    pub keyword_input: iced::text_input::State,
+    /// Button to add a keyword
+    pub keyword_button: iced::button::State,
+    /// Buttons to remove keywords
+    pub keyword_remove_buttons: Vec<iced::button::State>,
+
+    /// Media cache size limit in MiB
+    pub cache_limit: String,
+    /// Media cache size limit text input
+    pub cache_limit_input: iced::text_input::State,
+    /// Button to apply the media cache size limit
+    pub cache_limit_button: iced::button::State,
+    /// Button to clear the media cache
+    pub clear_cache_button: iced::button::State,
+
+    /// Settings scrollbar state
+    pub scroll: iced::scrollable::State,
     /// Button  to close settings view
     pub close_button: iced::button::State,
 }
@@ -37,8 +109,16 @@ impl SettingsView {
         Self::default()
     }
 
-    pub fn view(&mut self, sort: RoomSorting) -> Element<Message> {
-        let content = Column::new()
+    pub fn view(
+        &mut self,
+        sort: RoomSorting,
+        markdown: bool,
+        autoplay: bool,
+        hide_joins: bool,
+        rules: &Ruleset,
+        cache_size: u64,
+    ) -> Element<Message> {
+        let mut content = Scrollable::new(&mut self.scroll)
             .width(500.into())
             .spacing(5)
             .push(Text::new("Profile").size(25))
@@ -64,6 +144,50 @@ impl SettingsView {
                         }),
                 ),
             )
+            .push(Text::new("Online status"))
+            .push(
+                Row::new()
+                    .spacing(15)
+                    .push(Radio::new(
+                        PresenceSetting::Online,
+                        "Online",
+                        Some(self.presence),
+                        Message::SetPresenceInput,
+                    ))
+                    .push(Radio::new(
+                        PresenceSetting::Unavailable,
+                        "Away",
+                        Some(self.presence),
+                        Message::SetPresenceInput,
+                    ))
+                    .push(Radio::new(
+                        PresenceSetting::Offline,
+                        "Offline",
+                        Some(self.presence),
+                        Message::SetPresenceInput,
+                    )),
+            )
+            .push(
+                Column::new().push(Text::new("Status message")).push(
+                    Row::new()
+                        .push(
+                            TextInput::new(
+                                &mut self.status_input,
+                                "Out for lunch",
+                                &self.status,
+                                Message::SetStatusInput,
+                            )
+                            .width(Length::Fill)
+                            .padding(5)
+                            .on_submit(Message::SavePresence),
+                        )
+                        .push(match self.saving_presence {
+                            false => Button::new(&mut self.presence_button, Text::new("Save"))
+                                .on_press(Message::SavePresence),
+                            true => Button::new(&mut self.presence_button, Text::new("Saving...")),
+                        }),
+                ),
+            )
             .push(Text::new("Appearance").size(25))
             .push(Text::new("Sort messages by:"))
             .push(Radio::new(
@@ -78,6 +202,51 @@ impl SettingsView {
                 Some(sort),
                 Message::SetSort,
             ))
+            .push(Text::new("Messages").size(25))
+            .push(Checkbox::new(
+                markdown,
+                "Format sent messages with markdown",
+                Message::SetMarkdown,
+            ))
+            .push(Checkbox::new(
+                autoplay,
+                "Play animated images automatically",
+                Message::SetAutoplay,
+            ))
+            .push(Checkbox::new(
+                hide_joins,
+                "Hide members joining and leaving",
+                Message::SetHideJoins,
+            ))
+            .push(Text::new("Media cache").size(25))
+            .push(Text::new(format!(
+                "Downloaded images and files use {} of disk space",
+                format_size(cache_size)
+            )))
+            .push(
+                Column::new().push(Text::new("Maximum size (MiB)")).push(
+                    Row::new()
+                        .push(
+                            TextInput::new(
+                                &mut self.cache_limit_input,
+                                "500",
+                                &self.cache_limit,
+                                Message::SetCacheLimitInput,
+                            )
+                            .width(Length::Fill)
+                            .padding(5)
+                            .on_submit(Message::SaveCacheLimit),
+                        )
+                        .push(
+                            Button::new(&mut self.cache_limit_button, Text::new("Save"))
+                                .on_press(Message::SaveCacheLimit),
+                        ),
+                ),
+            )
+            .push(
+                Button::new(&mut self.clear_cache_button, Text::new("Clear cache"))
+                    .on_press(Message::ClearCache),
+            )
             .push(Text::new("Encryption").size(25))
             .push(
                 Column::new()
@@ -108,6 +277,61 @@ impl SettingsView {
                 Button::new(&mut self.key_import_button, Text::new("Import keys"))
                     .on_press(Message::ImportKeys),
             )
+            .push(Text::new("Notifications").size(25))
+            .push(Checkbox::new(
+                rules.is_enabled(RuleKind::Override, ".m.rule.master"),
+                "Disable all notifications",
+                |disabled| {
+                    Message::SetRulesEnabled(RuleKind::Override, &[".m.rule.master"], disabled)
+                },
+            ))
+            .push(Text::new("Notify me for:"));
+        for &(label, kind, ids) in DEFAULT_RULES.iter() {
+            content = content.push(Checkbox::new(
+                ids.iter().any(|id| rules.is_enabled(kind, id)),
+                label,
+                move |enabled| Message::SetRulesEnabled(kind, ids, enabled),
+            ));
+        }
+        // Keywords
+        let keywords = rules.keywords();
+        self.keyword_remove_buttons
+            .resize_with(keywords.len(), Default::default);
+        content = content.push(Text::new("Keywords"));
+        for (rule, button) in keywords
+            .into_iter()
+            .zip(self.keyword_remove_buttons.iter_mut())
+        {
+            let keyword = rule.pattern.as_deref().unwrap_or_default();
+            content = content.push(
+                Row::new()
+                    .spacing(5)
+                    .push(Text::new(keyword).width(Length::Fill))
+                    .push(
+                        Button::new(button, Text::new("Remove"))
+                            .on_press(Message::RemoveKeyword(rule.rule_id.clone())),
+                    ),
+            );
+        }
+        let content = content
+            .push(
+                Row::new()
+                    .push(
+                        TextInput::new(
+                            &mut self.keyword_input,
+                            "Keyword",
+                            &self.keyword,
+                            Message::SetKeywordInput,
+                        )
+                        .width(Length::Fill)
+                        .padding(5)
+                        .on_submit(Message::AddKeyword),
+                    )
+                    .push(
+                        Button::new(&mut self.keyword_button, Text::new("Add"))
+                            .on_press(Message::AddKeyword),
+                    ),
+            )
             .push(
                 Row::new().width(Length::Fill).push(
                     Button::new(&mut self.close_button, Text::new("Close"))
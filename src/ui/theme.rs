@@ -1,6 +1,123 @@
 //! Theming for widgets.
 
+use iced::{button, container, Background, Color, Font};
+
+/// Monospace font for code
+pub const MONOSPACE: Font = Font::External {
+    name: "DejaVu Sans Mono",
+    bytes: include_bytes!("../../fonts/DejaVuSansMono.ttf"),
+};
+
 /// Which colorscheme to use
 pub enum Theme {
     Default,
 }
+
+/// Background for code
+pub struct CodeBlock;
+
+impl container::StyleSheet for CodeBlock {
+    fn style(&self) -> container::Style {
+        container::Style {
+            background: Some(Background::Color(Color::from_rgb(0.93, 0.93, 0.93))),
+            border_radius: 3.0,
+            ..container::Style::default()
+        }
+    }
+}
+
+/// Block quotes
+pub struct Quote;
+
+impl container::StyleSheet for Quote {
+    fn style(&self) -> container::Style {
+        container::Style {
+            text_color: Some(Color::from_rgb(0.3, 0.3, 0.3)),
+            border_width: 1.0,
+            border_color: Color::from_rgb(0.8, 0.8, 0.8),
+            ..container::Style::default()
+        }
+    }
+}
+
+/// Mentions of users and rooms
+pub struct Pill;
+
+impl container::StyleSheet for Pill {
+    fn style(&self) -> container::Style {
+        container::Style {
+            background: Some(Background::Color(Color::from_rgb(0.85, 0.9, 1.0))),
+            border_radius: 8.0,
+            ..container::Style::default()
+        }
+    }
+}
+
+/// Cards showing file attachments
+pub struct Attachment;
+
+impl container::StyleSheet for Attachment {
+    fn style(&self) -> container::Style {
+        container::Style {
+            background: Some(Background::Color(Color::from_rgb(0.97, 0.97, 0.97))),
+            border_radius: 5.0,
+            border_width: 1.0,
+            border_color: Color::from_rgb(0.8, 0.8, 0.8),
+            ..container::Style::default()
+        }
+    }
+}
+
+/// Space reserved for an image that hasn't loaded yet
+pub struct ImagePlaceholder;
+
+impl container::StyleSheet for ImagePlaceholder {
+    fn style(&self) -> container::Style {
+        container::Style {
+            text_color: Some(Color::from_rgb(0.4, 0.4, 0.4)),
+            background: Some(Background::Color(Color::from_rgb(0.93, 0.93, 0.93))),
+            border_radius: 3.0,
+            ..container::Style::default()
+        }
+    }
+}
+
+/// Buttons that are only their content, like clickable images
+pub struct Borderless;
+
+impl button::StyleSheet for Borderless {
+    fn active(&self) -> button::Style {
+        button::Style {
+            background: None,
+            border_width: 0.0,
+            ..button::Style::default()
+        }
+    }
+}
+
+/// Reaction chips, highlighted if we've reacted with that key
+pub struct Reaction {
+    pub selected: bool,
+}
+
+impl button::StyleSheet for Reaction {
+    fn active(&self) -> button::Style {
+        let (background, border) = match self.selected {
+            true => (
+                Color::from_rgb(0.85, 0.9, 1.0),
+                Color::from_rgb(0.4, 0.55, 0.9),
+            ),
+            false => (
+                Color::from_rgb(0.95, 0.95, 0.95),
+                Color::from_rgb(0.8, 0.8, 0.8),
+            ),
+        };
+        button::Style {
+            background: Some(Background::Color(background)),
+            border_radius: 10.0,
+            border_width: 1.0,
+            border_color: border,
+            ..button::Style::default()
+        }
+    }
+}
@@ -0,0 +1,445 @@
+//! Ordered storage for the events of a timeline, and the window of it that's drawn.
+//!
+//! Rooms can have thousands of loaded events, so events are kept in a tree instead of a vector
+//! that's sorted after every insert, and only the events around the visible part of the
+//! timeline get widgets. Which events those are is worked out from estimates of how tall each
+//! event is. This module only depends on std, so the benchmarks can use it directly.
+//!
+//! Events are ordered by where the server put them rather than by when their sender claims to
+//! have sent them. Runs of events we got without anything missing between them are kept in
+//! chunks, and a sync that skips events starts a new chunk with a gap before it.
+
+use std::{
+    collections::{btree_map, BTreeMap, HashMap},
+    fmt,
+    hash::Hash,
+    iter::{Skip, Take},
+    ops::Range,
+};
+
+/// Events ordered by a key, with the ID of each event as a tie breaker. Inserting, removing and
+/// looking up an event by its ID all take `O(log n)`, but getting the events at a range of
+/// indices doesn't, see [`Timeline::window`].
+#[derive(Clone)]
+pub struct Timeline<K, I, T> {
+    /// The events, in timeline order
+    entries: BTreeMap<(K, I), T>,
+    /// The key each event is stored under, by event ID
+    keys: HashMap<I, K>,
+}
+
+impl<K, I, T> Default for Timeline<K, I, T>
+where
+    I: Hash + Eq,
+{
+    fn default() -> Self {
+        Self {
+            entries: BTreeMap::new(),
+            keys: HashMap::new(),
+        }
+    }
+}
+
+impl<K, I, T> fmt::Debug for Timeline<K, I, T>
+where
+    K: fmt::Debug,
+    I: fmt::Debug,
+    T: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.entries.iter()).finish()
+    }
+}
+
+impl<K, I, T> Timeline<K, I, T>
+where
+    K: Ord + Clone,
+    I: Ord + Hash + Clone,
+{
+    /// Add an event. Returns `false` and leaves the timeline as it was if an event with the same
+    /// ID is already in it.
+    pub fn insert(&mut self, key: K, id: I, event: T) -> bool {
+        if self.keys.contains_key(&id) {
+            return false;
+        }
+        self.keys.insert(id.clone(), key.clone());
+        self.entries.insert((key, id), event);
+        true
+    }
+
+    /// Remove an event by its ID
+    pub fn remove(&mut self, id: &I) -> Option<T> {
+        let key = self.keys.remove(id)?;
+        self.entries.remove(&(key, id.clone()))
+    }
+
+    /// Gets an event by its ID
+    pub fn get(&self, id: &I) -> Option<&T> {
+        let key = self.keys.get(id)?;
+        self.entries.get(&(key.clone(), id.clone()))
+    }
+}
+
+impl<K, I, T> Timeline<K, I, T> {
+    /// Number of events
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Whether there are no events
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// The events, oldest first
+    pub fn iter(&self) -> btree_map::Values<'_, (K, I), T> {
+        self.entries.values()
+    }
+
+    /// The most recent event
+    pub fn last(&self) -> Option<&T> {
+        self.entries.values().next_back()
+    }
+
+    /// The events in a range of indices with their keys, oldest first. The tree can't skip to an
+    /// index, so the events before the window are walked past from whichever end is closer.
+    /// That makes windows in the middle of a long timeline take `O(n)`, though walking the tree
+    /// is cheap next to making widgets, and windows near the most recent events, where the user
+    /// usually is, only walk past a few.
+    pub fn window(&self, range: Range<usize>) -> Window<'_, K, I, T> {
+        let end = range.end.min(self.len());
+        let start = range.start.min(end);
+        if start <= self.len() - end {
+            Window::Front(self.entries.iter().skip(start).take(end - start))
+        } else {
+            let mut events: Vec<_> = (self.entries.iter().rev())
+                .skip(self.len() - end)
+                .take(end - start)
+                .collect();
+            events.reverse();
+            Window::Back(events.into_iter())
+        }
+    }
+}
+
+/// Iterator over a window of a timeline
+pub enum Window<'a, K, I, T> {
+    Front(Take<Skip<btree_map::Iter<'a, (K, I), T>>>),
+    Back(std::vec::IntoIter<(&'a (K, I), &'a T)>),
+}
+
+impl<'a, K, I, T> Iterator for Window<'a, K, I, T> {
+    type Item = (&'a K, &'a T);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let ((key, _), event) = match self {
+            Window::Front(iter) => iter.next(),
+            Window::Back(iter) => iter.next(),
+        }?;
+        Some((key, event))
+    }
+}
+
+/// Where an event is in the timeline, in the order the server gave us events. This doesn't
+/// depend on the send time the sender claims, so messages from senders with wrong clocks still
+/// show up where they arrived.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Position {
+    /// The chunk the event is in. Chunks are numbered from oldest to newest.
+    pub chunk: usize,
+    /// Place in the chunk. Events from sync get increasing numbers and events from paginating
+    /// backwards decreasing ones, so this can be negative.
+    pub index: i64,
+}
+
+/// Events we have without any missing between them
+#[derive(Clone, Debug, Default)]
+pub struct Chunk {
+    /// Lowest index used in the chunk
+    first: i64,
+    /// Index after the highest one used in the chunk
+    next: i64,
+    /// Token for paginating back from the start of the chunk, if there are events missing
+    /// between this chunk and the previous one
+    pub gap: Option<String>,
+    /// Whether we're fetching the events missing before this chunk
+    pub filling: bool,
+}
+
+impl Chunk {
+    /// Whether no events have been put in the chunk
+    pub fn is_empty(&self) -> bool {
+        self.first == self.next
+    }
+}
+
+/// The chunks of a timeline, which hand out positions for new events
+#[derive(Clone, Debug)]
+pub struct Chunks {
+    chunks: Vec<Chunk>,
+}
+
+impl Default for Chunks {
+    fn default() -> Self {
+        Self {
+            chunks: vec![Chunk::default()],
+        }
+    }
+}
+
+impl Chunks {
+    /// Position for an event after all others, like one from sync
+    pub fn after(&mut self) -> Position {
+        let chunk = self.chunks.len() - 1;
+        let last = &mut self.chunks[chunk];
+        last.next += 1;
+        Position {
+            chunk,
+            index: last.next - 1,
+        }
+    }
+
+    /// Position for an event before the others in a chunk, like one from paginating backwards
+    pub fn before(&mut self, chunk: usize) -> Position {
+        let chunk = chunk.min(self.chunks.len() - 1);
+        let first = &mut self.chunks[chunk];
+        first.first -= 1;
+        Position {
+            chunk,
+            index: first.first,
+        }
+    }
+
+    /// Start a new chunk after a gap, which can be filled by paginating back from `token`.
+    /// Nothing is missing before the first events, so there's no gap until there are events.
+    pub fn gap(&mut self, token: String) {
+        let last = self.chunks.len() - 1;
+        if self.chunks[last].is_empty() {
+            if last > 0 {
+                self.chunks[last].gap = Some(token);
+            }
+        } else {
+            self.chunks.push(Chunk {
+                gap: Some(token),
+                ..Chunk::default()
+            });
+        }
+    }
+
+    /// Gets a chunk by its number
+    pub fn get(&self, chunk: usize) -> Option<&Chunk> {
+        self.chunks.get(chunk)
+    }
+
+    /// Gets a chunk by its number
+    pub fn get_mut(&mut self, chunk: usize) -> Option<&mut Chunk> {
+        self.chunks.get_mut(chunk)
+    }
+
+    /// Number of chunks. There's always at least one.
+    #[allow(clippy::len_without_is_empty)]
+    pub fn len(&self) -> usize {
+        self.chunks.len()
+    }
+
+    /// The first chunk with events missing before it, if there is one
+    pub fn first_gap(&self) -> Option<usize> {
+        self.chunks.iter().position(|chunk| chunk.gap.is_some())
+    }
+}
+
+/// Where the events of a timeline are, from estimates of how tall each of them is
+#[derive(Clone, Debug)]
+pub struct Heights {
+    /// Top of each event, followed by the bottom of the last one
+    tops: Vec<f32>,
+}
+
+impl Default for Heights {
+    fn default() -> Self {
+        Self { tops: vec![0.0] }
+    }
+}
+
+impl Heights {
+    /// Stack events with the given heights, which include the space after each event
+    pub fn new(heights: impl IntoIterator<Item = f32>) -> Self {
+        let mut tops = vec![0.0];
+        let mut bottom = 0.0;
+        for height in heights {
+            bottom += height.max(0.0);
+            tops.push(bottom);
+        }
+        Self { tops }
+    }
+
+    /// Number of events
+    fn len(&self) -> usize {
+        self.tops.len() - 1
+    }
+
+    /// Height of all the events
+    pub fn total(&self) -> f32 {
+        self.tops[self.len()]
+    }
+
+    /// Top of the event at `index`, or the bottom of the last event for indices past the end
+    pub fn top(&self, index: usize) -> f32 {
+        self.tops[index.min(self.len())]
+    }
+
+    /// Events the timeline has to make widgets for, given how far the view is scrolled.
+    ///
+    /// `offset` is how far the view is scrolled from the top of the first event in pixels and
+    /// `visible` the height of the view. `overscan` extra events are included on both sides, so
+    /// scrolling a little doesn't show empty space before the estimates are corrected.
+    pub fn visible_range(&self, offset: f32, visible: f32, overscan: usize) -> Range<usize> {
+        let bottom = offset + visible.max(0.0);
+        let offset = offset.max(0.0);
+        // The first event that ends below the top of the view, and the first one that starts
+        // below its bottom
+        let first = self.tops[1..].partition_point(|&end| end <= offset);
+        let last = self.tops[..self.len()].partition_point(|&top| top < bottom);
+        let start = first.saturating_sub(overscan).min(self.len());
+        let end = last.saturating_add(overscan).min(self.len()).max(start);
+        start..end
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn timeline(ids: &[(u32, &str)]) -> Timeline<u32, String, String> {
+        let mut timeline = Timeline::default();
+        for &(key, id) in ids {
+            timeline.insert(key, id.to_owned(), id.to_uppercase());
+        }
+        timeline
+    }
+
+    fn ids<'a>(window: Window<'a, u32, String, String>) -> Vec<&'a str> {
+        window.map(|(_, event)| event.as_str()).collect()
+    }
+
+    #[test]
+    fn insert_and_remove() {
+        let mut timeline = timeline(&[(2, "b"), (1, "a"), (3, "c")]);
+        assert_eq!(timeline.iter().collect::<Vec<_>>(), ["A", "B", "C"]);
+        // The ID decides, not the key
+        assert!(!timeline.insert(0, "a".to_owned(), "again".to_owned()));
+        assert_eq!(timeline.get(&"a".to_owned()).map(String::as_str), Some("A"));
+        // Events with the same key are ordered by ID
+        assert!(timeline.insert(2, "a2".to_owned(), "A2".to_owned()));
+        assert_eq!(timeline.iter().collect::<Vec<_>>(), ["A", "A2", "B", "C"]);
+        assert_eq!(timeline.remove(&"b".to_owned()).as_deref(), Some("B"));
+        assert_eq!(timeline.remove(&"b".to_owned()), None);
+        assert_eq!(timeline.last().map(String::as_str), Some("C"));
+        assert_eq!(timeline.len(), 3);
+    }
+
+    #[test]
+    fn window() {
+        let timeline = timeline(&[(1, "a"), (2, "b"), (3, "c"), (4, "d"), (5, "e"), (6, "f")]);
+        // Near the start, walked from the front
+        assert_eq!(ids(timeline.window(0..2)), ["A", "B"]);
+        assert!(matches!(timeline.window(1..3), Window::Front(_)));
+        // Near the end, walked from the back but still oldest first
+        assert_eq!(ids(timeline.window(4..6)), ["E", "F"]);
+        assert!(matches!(timeline.window(4..6), Window::Back(_)));
+        assert_eq!(ids(timeline.window(2..5)), ["C", "D", "E"]);
+        assert_eq!(ids(timeline.window(0..6)), ["A", "B", "C", "D", "E", "F"]);
+        // Keys come along
+        let keys: Vec<u32> = timeline.window(3..5).map(|(key, _)| *key).collect();
+        assert_eq!(keys, [4, 5]);
+        // Ranges past the end are cut off
+        assert_eq!(ids(timeline.window(5..10)), ["F"]);
+        assert!(ids(timeline.window(8..10)).is_empty());
+        assert!(ids(timeline.window(3..3)).is_empty());
+        assert!(ids(Timeline::<u32, String, String>::default().window(0..5)).is_empty());
+    }
+
+    #[test]
+    fn chunks() {
+        let mut chunks = Chunks::default();
+        // Nothing can be missing before the first events
+        chunks.gap("t0".to_owned());
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks.first_gap(), None);
+
+        let first = chunks.after();
+        let second = chunks.after();
+        assert_eq!((first.chunk, first.index), (0, 0));
+        assert_eq!((second.chunk, second.index), (0, 1));
+        // Backfilled events go before everything in the chunk
+        let older = chunks.before(0);
+        let oldest = chunks.before(0);
+        assert_eq!((older.index, oldest.index), (-1, -2));
+        assert!(oldest < older && older < first);
+
+        // A gap starts a new chunk, which sorts after the old one
+        chunks.gap("t1".to_owned());
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks.first_gap(), Some(1));
+        assert_eq!(chunks.get(1).and_then(|c| c.gap.as_deref()), Some("t1"));
+        // A second gap before anything arrived replaces the token
+        chunks.gap("t2".to_owned());
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks.get(1).and_then(|c| c.gap.as_deref()), Some("t2"));
+
+        let after_gap = chunks.after();
+        assert_eq!((after_gap.chunk, after_gap.index), (1, 0));
+        assert!(second < after_gap);
+        // Filling the gap puts events before the new chunk but after the old one
+        let filled = chunks.before(1);
+        assert_eq!((filled.chunk, filled.index), (1, -1));
+        assert!(second < filled && filled < after_gap);
+        // Chunks that don't exist mean the last one
+        assert_eq!(chunks.before(7).chunk, 1);
+
+        chunks.get_mut(1).unwrap().gap = None;
+        assert_eq!(chunks.first_gap(), None);
+    }
+
+    #[test]
+    fn heights() {
+        let heights = Heights::new(vec![10.0, 20.0, 30.0]);
+        assert_eq!(heights.len(), 3);
+        assert_eq!(heights.total(), 60.0);
+        assert_eq!(heights.top(0), 0.0);
+        assert_eq!(heights.top(2), 30.0);
+        assert_eq!(heights.top(3), 60.0);
+        assert_eq!(heights.top(10), 60.0);
+        assert_eq!(Heights::new(Vec::new()).len(), 0);
+        assert_eq!(Heights::default().total(), 0.0);
+    }
+
+    #[test]
+    fn visible_range() {
+        // Ten events of 10 pixels, then one of 100, then ten more of 10
+        let heights = Heights::new(
+            std::iter::repeat(10.0)
+                .take(10)
+                .chain(Some(100.0))
+                .chain(std::iter::repeat(10.0).take(10)),
+        );
+        assert_eq!(heights.visible_range(0.0, 25.0, 0), 0..3);
+        assert_eq!(heights.visible_range(5.0, 10.0, 0), 0..2);
+        // Events that end right at the top of the view or start at its bottom aren't visible
+        assert_eq!(heights.visible_range(10.0, 10.0, 0), 1..2);
+        // The tall event covers the whole view
+        assert_eq!(heights.visible_range(120.0, 50.0, 0), 10..11);
+        assert_eq!(heights.visible_range(190.0, 30.0, 0), 10..13);
+        // Overscan adds events on both sides, as far as there are any
+        assert_eq!(heights.visible_range(120.0, 50.0, 2), 8..13);
+        assert_eq!(heights.visible_range(0.0, 10.0, 5), 0..6);
+        assert_eq!(heights.visible_range(290.0, 100.0, 3), 17..21);
+        // Scrolled past the end, or before the start
+        assert_eq!(heights.visible_range(1000.0, 50.0, 0), 21..21);
+        assert_eq!(heights.visible_range(1000.0, 50.0, 2), 19..21);
+        assert_eq!(heights.visible_range(-50.0, 60.0, 0), 0..1);
+        // Nothing to show
+        assert_eq!(Heights::default().visible_range(0.0, 500.0, 20), 0..0);
+        assert_eq!(heights.visible_range(0.0, 0.0, 0), 0..0);
+    }
+}
@@ -0,0 +1,333 @@
+//! Full window image viewer.
+
+use std::{hash::Hash, time::SystemTime};
+
+use iced::{Align, Button, Column, Container, Element, Length, Row, Text, TextInput};
+use iced_native::{
+    event, image, keyboard::KeyCode, layout, mouse, Clipboard, Event, Hasher, Layout, Point,
+    Rectangle, Size, Vector, Widget,
+};
+
+use super::{format_systime, Message};
+use crate::matrix::{file, Media};
+
+/// How much one step zooms in or out
+const ZOOM_STEP: f32 = 1.25;
+/// How far the arrow keys move the image, in pixels
+const PAN_STEP: f32 = 50.0;
+/// Zoom limits, relative to fitting the image in the window
+const ZOOM_RANGE: (f32, f32) = (0.1, 20.0);
+
+/// Image viewer
+#[derive(Debug, Clone)]
+pub struct ImageViewer {
+    /// Display name of the sender
+    sender: String,
+    /// When the image was sent
+    time: SystemTime,
+    /// File name of the image
+    name: String,
+    /// The full resolution image
+    pub media: Media,
+    /// Zoom relative to fitting the image in the window
+    zoom: f32,
+    /// How far the image has been moved from the center
+    offset: (f32, f32),
+    /// Path to save the image to
+    pub save_path: String,
+    /// Whether the image is being saved
+    pub saving: bool,
+    /// Outcome of the last save
+    pub status: Option<String>,
+
+    /// Mouse state of the image
+    image_state: State,
+    /// Button to zoom out
+    zoom_out_button: iced::button::State,
+    /// Button to zoom in
+    zoom_in_button: iced::button::State,
+    /// Button to fit the image in the window again
+    reset_button: iced::button::State,
+    /// Text input for the path to save to
+    save_path_input: iced::text_input::State,
+    /// Button to save the image
+    save_button: iced::button::State,
+    /// Button to close the viewer
+    close_button: iced::button::State,
+}
+
+impl ImageViewer {
+    pub fn new(sender: String, time: SystemTime, name: String, media: Media) -> Self {
+        let save_path = file::default_path(&name).to_string_lossy().into_owned();
+        Self {
+            sender,
+            time,
+            name,
+            media,
+            zoom: 1.0,
+            offset: (0.0, 0.0),
+            save_path,
+            saving: false,
+            status: None,
+            image_state: State::default(),
+            zoom_out_button: Default::default(),
+            zoom_in_button: Default::default(),
+            reset_button: Default::default(),
+            save_path_input: Default::default(),
+            save_button: Default::default(),
+            close_button: Default::default(),
+        }
+    }
+
+    /// Zoom by a factor, keeping the point in the middle of the window where it is
+    pub fn zoom(&mut self, factor: f32) {
+        let zoom = (self.zoom * factor).max(ZOOM_RANGE.0).min(ZOOM_RANGE.1);
+        let ratio = zoom / self.zoom;
+        self.offset = (self.offset.0 * ratio, self.offset.1 * ratio);
+        self.zoom = zoom;
+    }
+
+    /// Move the image
+    pub fn pan(&mut self, x: f32, y: f32) {
+        self.offset = (self.offset.0 + x, self.offset.1 + y);
+    }
+
+    /// Fit the image in the window again
+    pub fn reset(&mut self) {
+        self.zoom = 1.0;
+        self.offset = (0.0, 0.0);
+    }
+
+    /// Show the viewer. `handle` is the best version of the image we have so far, and `loading`
+    /// is set while the full resolution version is downloading.
+    pub fn view(&mut self, handle: Option<&image::Handle>, loading: bool) -> Element<Message> {
+        let header = Row::new()
+            .spacing(10)
+            .align_items(Align::Center)
+            .push(Text::new(&self.sender).color([0.0, 0.0, 1.0]))
+            .push(Text::new(format_systime(self.time)))
+            .push(Text::new(&self.name).width(Length::Fill))
+            .push(
+                Button::new(&mut self.close_button, Text::new("Close"))
+                    .on_press(Message::CloseImage),
+            );
+        let image: Element<_> = match handle {
+            Some(handle) => ZoomImage {
+                state: &mut self.image_state,
+                handle: handle.clone(),
+                zoom: self.zoom,
+                offset: self.offset,
+            }
+            .into(),
+            None => Container::new(Text::new("Loading image..."))
+                .width(Length::Fill)
+                .height(Length::Fill)
+                .center_x()
+                .center_y()
+                .into(),
+        };
+        let mut toolbar = Row::new()
+            .spacing(5)
+            .align_items(Align::Center)
+            .push(
+                Button::new(&mut self.zoom_out_button, Text::new("-"))
+                    .on_press(Message::ZoomImage(1.0 / ZOOM_STEP)),
+            )
+            .push(Text::new(format!("{:.0}%", self.zoom * 100.0)))
+            .push(
+                Button::new(&mut self.zoom_in_button, Text::new("+"))
+                    .on_press(Message::ZoomImage(ZOOM_STEP)),
+            )
+            .push(
+                Button::new(&mut self.reset_button, Text::new("Fit")).on_press(Message::ResetZoom),
+            );
+        if loading {
+            toolbar = toolbar.push(Text::new("Loading full resolution...").size(14));
+        }
+        toolbar = toolbar.push(
+            TextInput::new(
+                &mut self.save_path_input,
+                "Path to save to",
+                &self.save_path,
+                Message::SetImageSavePath,
+            )
+            .width(Length::Fill)
+            .padding(5)
+            .on_submit(Message::SaveImage),
+        );
+        toolbar = toolbar.push(match self.saving {
+            false => Button::new(&mut self.save_button, Text::new("Save as..."))
+                .on_press(Message::SaveImage),
+            true => Button::new(&mut self.save_button, Text::new("Saving...")),
+        });
+        if let Some(ref status) = self.status {
+            toolbar = toolbar.push(Text::new(status).size(14));
+        }
+        Column::new()
+            .spacing(5)
+            .padding(5)
+            .width(Length::Fill)
+            .height(Length::Fill)
+            .push(header)
+            .push(image)
+            .push(toolbar)
+            .into()
+    }
+}
+
+/// Keyboard shortcuts of the viewer
+pub fn key_message(key_code: KeyCode) -> Option<Message> {
+    match key_code {
+        KeyCode::Plus | KeyCode::Equals | KeyCode::Add => Some(Message::ZoomImage(ZOOM_STEP)),
+        KeyCode::Minus | KeyCode::Subtract => Some(Message::ZoomImage(1.0 / ZOOM_STEP)),
+        KeyCode::Key0 | KeyCode::Numpad0 => Some(Message::ResetZoom),
+        KeyCode::Left => Some(Message::PanImage(PAN_STEP, 0.0)),
+        KeyCode::Right => Some(Message::PanImage(-PAN_STEP, 0.0)),
+        KeyCode::Up => Some(Message::PanImage(0.0, PAN_STEP)),
+        KeyCode::Down => Some(Message::PanImage(0.0, -PAN_STEP)),
+        KeyCode::Escape => Some(Message::CloseImage),
+        _ => None,
+    }
+}
+
+/// Mouse state of a [`ZoomImage`]
+#[derive(Debug, Clone, Copy, Default)]
+struct State {
+    /// Where the cursor was the last time the image was dragged
+    grabbed_at: Option<Point>,
+}
+
+/// An image that's zoomed with the scroll wheel and moved by dragging it. The zoom and position
+/// are kept by the viewer so the keyboard can change them too.
+struct ZoomImage<'a> {
+    state: &'a mut State,
+    handle: image::Handle,
+    zoom: f32,
+    offset: (f32, f32),
+}
+
+impl<'a> ZoomImage<'a> {
+    /// Where the image is drawn within `bounds`
+    fn image_bounds<Renderer: image::Renderer>(
+        &self,
+        renderer: &Renderer,
+        bounds: Rectangle,
+    ) -> Rectangle {
+        let (width, height) = renderer.dimensions(&self.handle);
+        let (width, height) = (width.max(1) as f32, height.max(1) as f32);
+        // Fit the image in the window, without blowing up small images
+        let fit = (bounds.width / width).min(bounds.height / height).min(1.0);
+        let size = Size::new(width * fit * self.zoom, height * fit * self.zoom);
+        Rectangle {
+            x: bounds.center_x() - size.width / 2.0 + self.offset.0,
+            y: bounds.center_y() - size.height / 2.0 + self.offset.1,
+            width: size.width,
+            height: size.height,
+        }
+    }
+}
+
+impl<'a, Renderer> Widget<Message, Renderer> for ZoomImage<'a>
+where
+    Renderer: image::Renderer + image::viewer::Renderer,
+{
+    fn width(&self) -> Length {
+        Length::Fill
+    }
+
+    fn height(&self) -> Length {
+        Length::Fill
+    }
+
+    fn layout(&self, _renderer: &Renderer, limits: &layout::Limits) -> layout::Node {
+        let limits = limits.width(Length::Fill).height(Length::Fill);
+        layout::Node::new(limits.resolve(Size::ZERO))
+    }
+
+    fn draw(
+        &self,
+        renderer: &mut Renderer,
+        _defaults: &Renderer::Defaults,
+        layout: Layout<'_>,
+        cursor_position: Point,
+        _viewport: &Rectangle,
+    ) -> Renderer::Output {
+        let bounds = layout.bounds();
+        let image = self.image_bounds(renderer, bounds);
+        // The viewer renderer clips the image to the bounds. Its state only picks the cursor.
+        image::viewer::Renderer::draw(
+            renderer,
+            &image::viewer::State::new(),
+            bounds,
+            image,
+            Vector::new(0.0, 0.0),
+            self.handle.clone(),
+            bounds.contains(cursor_position),
+        )
+    }
+
+    fn hash_layout(&self, state: &mut Hasher) {
+        struct Marker;
+        std::any::TypeId::of::<Marker>().hash(state);
+    }
+
+    fn on_event(
+        &mut self,
+        event: Event,
+        layout: Layout<'_>,
+        cursor_position: Point,
+        _renderer: &Renderer,
+        _clipboard: &mut dyn Clipboard,
+        messages: &mut Vec<Message>,
+    ) -> event::Status {
+        let bounds = layout.bounds();
+        match event {
+            Event::Mouse(mouse::Event::WheelScrolled { delta })
+                if bounds.contains(cursor_position) =>
+            {
+                let y = match delta {
+                    mouse::ScrollDelta::Lines { y, .. } | mouse::ScrollDelta::Pixels { y, .. } => y,
+                };
+                if y > 0.0 {
+                    messages.push(Message::ZoomImage(ZOOM_STEP));
+                } else if y < 0.0 {
+                    messages.push(Message::ZoomImage(1.0 / ZOOM_STEP));
+                }
+                event::Status::Captured
+            }
+            Event::Mouse(mouse::Event::ButtonPressed(mouse::Button::Left))
+                if bounds.contains(cursor_position) =>
+            {
+                self.state.grabbed_at = Some(cursor_position);
+                event::Status::Captured
+            }
+            Event::Mouse(mouse::Event::CursorMoved { position }) => match self.state.grabbed_at {
+                Some(grabbed_at) => {
+                    messages.push(Message::PanImage(
+                        position.x - grabbed_at.x,
+                        position.y - grabbed_at.y,
+                    ));
+                    self.state.grabbed_at = Some(position);
+                    event::Status::Captured
+                }
+                None => event::Status::Ignored,
+            },
+            Event::Mouse(mouse::Event::ButtonReleased(mouse::Button::Left))
+                if self.state.grabbed_at.is_some() =>
+            {
+                self.state.grabbed_at = None;
+                event::Status::Captured
+            }
+            _ => event::Status::Ignored,
+        }
+    }
+}
+
+impl<'a, Renderer> From<ZoomImage<'a>> for iced_native::Element<'a, Message, Renderer>
+where
+    Renderer: 'a + image::Renderer + image::viewer::Renderer,
+{
+    fn from(image: ZoomImage<'a>) -> Self {
+        iced_native::Element::new(image)
+    }
+}